@@ -1,6 +1,7 @@
 use cap_common::bucket::Bucket;
 use cap_common::did::*;
 use cap_common::transaction::Event;
+use certified_vars::AsHashTree;
 use ic_kit::candid::{candid_method, export_service};
 use ic_kit::macros::*;
 use ic_kit::{ic, Principal};
@@ -28,7 +29,16 @@ fn init(arg: BucketInitArgs) {
     let mut data = ic::get_mut::<Data>();
     data.bucket = Bucket::new(arg.contract, arg.offset);
     data.bucket.set_next_canisters(arg.next_canisters);
+    data.bucket.init_ranges(ic::id());
+    for key in arg.detail_indexes.unwrap_or_default() {
+        data.bucket.add_detail_index(key);
+    }
+    if let Some(keys) = arg.user_index_exclusions {
+        data.bucket.set_user_index_exclusions(keys.into_iter().collect());
+    }
     data.parent = ic::caller();
+
+    ic::set_certified_data(&data.bucket.root_hash());
 }
 
 #[query]
@@ -45,13 +55,13 @@ fn get_transaction(arg: WithIdArg) -> GetTransactionResponse {
 
 #[query]
 #[candid_method(query)]
-fn get_transactions(arg: GetTransactionsArg) -> GetTransactionsResponseBorrowed<'static> {
+fn get_transactions(arg: GetTransactionsArg) -> GetTransactionsResponse {
     ic::get::<Data>().bucket.get_transactions(arg)
 }
 
 #[query]
 #[candid_method(query)]
-fn get_user_transactions(arg: GetUserTransactionsArg) -> GetTransactionsResponseBorrowed<'static> {
+fn get_user_transactions(arg: GetUserTransactionsArg) -> GetTransactionsResponse {
     ic::get::<Data>().bucket.get_user_transactions(arg)
 }
 
@@ -59,10 +69,72 @@ fn get_user_transactions(arg: GetUserTransactionsArg) -> GetTransactionsResponse
 #[candid_method(query)]
 fn get_token_transactions(
     arg: GetTokenTransactionsArg,
-) -> GetTransactionsResponseBorrowed<'static> {
+) -> GetTransactionsResponse {
     ic::get::<Data>().bucket.get_token_transactions(arg)
 }
 
+#[query]
+#[candid_method(query)]
+fn get_operation_transactions(
+    arg: GetOperationTransactionsArg,
+) -> GetTransactionsResponse {
+    ic::get::<Data>().bucket.get_operation_transactions(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_account_transactions(
+    arg: GetAccountTransactionsArg,
+) -> GetTransactionsResponse {
+    ic::get::<Data>().bucket.get_account_transactions(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_writer_transactions(arg: GetWriterTransactionsArg) -> GetTransactionsResponse {
+    ic::get::<Data>().bucket.get_writer_transactions(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_transactions_by_detail(
+    arg: GetTransactionsByDetailArg,
+) -> GetTransactionsResponse {
+    ic::get::<Data>().bucket.get_transactions_by_detail(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_transactions_in_range(
+    arg: GetTransactionsInRangeArg,
+) -> GetTransactionsInRangeResponse {
+    ic::get::<Data>().bucket.get_transactions_in_range(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_transactions_cursor(
+    arg: GetTransactionsCursorArg,
+) -> GetTransactionsCursorResponse {
+    ic::get::<Data>().bucket.get_transactions_cursor(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_user_transactions_cursor(
+    arg: GetUserTransactionsCursorArg,
+) -> GetTransactionsCursorResponse {
+    ic::get::<Data>().bucket.get_user_transactions_cursor(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_token_transactions_cursor(
+    arg: GetTokenTransactionsCursorArg,
+) -> GetTransactionsCursorResponse {
+    ic::get::<Data>().bucket.get_token_transactions_cursor(arg)
+}
+
 #[query]
 #[candid_method(query)]
 fn get_bucket_for(arg: WithIdArg) -> GetBucketResponse {
@@ -75,6 +147,12 @@ fn size() -> u64 {
     ic::get::<Data>().bucket.size()
 }
 
+#[query]
+#[candid_method(query)]
+fn get_size(arg: WithWitnessArg) -> GetSizeResponse {
+    ic::get::<Data>().bucket.get_size(arg)
+}
+
 #[query]
 #[candid_method(query)]
 fn contract_id() -> &'static Principal {
@@ -87,18 +165,39 @@ fn balance() -> u64 {
     ic::balance()
 }
 
+/// Insert the events copied from the parent, along with the canister which inserted each one
+/// of them into the parent, if it's known.
 #[update]
 #[candid_method(update)]
-fn insert_many(transactions: Vec<Event>) {
+fn insert_many(transactions: Vec<Event>, writers: Option<Vec<Option<Principal>>>) {
     let data = ic::get_mut::<Data>();
 
     if ic::caller() != data.parent {
         panic!("Non authorized caller.");
     }
 
+    let mut writers = writers.unwrap_or_default().into_iter();
     for tx in transactions {
-        data.bucket.insert(tx);
+        data.bucket.insert_from(writers.next().flatten(), tx);
     }
+
+    ic::set_certified_data(&data.bucket.root_hash());
+}
+
+/// Move the range of this bucket forward to `to`, the transactions before it are held by the
+/// given canister. This is used by replicas to follow the rollovers of their root bucket.
+#[update]
+#[candid_method(update)]
+fn roll_over(to: TransactionId, canister: BucketId) {
+    let data = ic::get_mut::<Data>();
+
+    if ic::caller() != data.parent {
+        panic!("Non authorized caller.");
+    }
+
+    data.bucket.roll_over(to, canister);
+
+    ic::set_certified_data(&data.bucket.root_hash());
 }
 
 #[query]
@@ -1,7 +1,9 @@
 use crate::Data;
-use ic_cdk::api::stable::StableWriter;
+use certified_vars::AsHashTree;
+use ic_cdk::api::stable::{StableReader, StableWriter};
 use ic_kit::ic;
 use ic_kit::macros::*;
+use serde::Deserialize;
 
 #[pre_upgrade]
 fn pre_upgrade() {
@@ -9,3 +11,16 @@ fn pre_upgrade() {
     let writer = StableWriter::default();
     serde_cbor::to_writer(writer, &data).expect("Failed to serialize data.");
 }
+
+#[post_upgrade]
+fn post_upgrade() {
+    let reader = StableReader::default();
+    let mut deserializer = serde_cbor::Deserializer::from_reader(reader);
+    // The stable memory is not trimmed, so there is trailing data after the value.
+    let mut data: Data =
+        Deserialize::deserialize(&mut deserializer).expect("Failed to deserialize data.");
+    // Buckets created before the ranges were recorded at init.
+    data.bucket.init_ranges(ic::id());
+    ic::set_certified_data(&data.bucket.root_hash());
+    ic::store(data);
+}
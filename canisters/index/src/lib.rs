@@ -0,0 +1,327 @@
+//! An index canister holds the root buckets of a shard of the users, the users are sharded
+//! over the index canisters listed by the router, see [`index_canister_for`].
+//!
+//! The root buckets send their new users to the index canister of each user, and the router
+//! repairs the missing root buckets during a reconciliation. When the list of index canisters
+//! changes, the router moves the users to their new index canister, see [`user_index`].
+
+use certified_vars::{
+    hashtree::{fork, fork_hash},
+    AsHashTree, Hash, HashTree, Map, Seq,
+};
+use ic_kit::candid::{candid_method, export_service, CandidType};
+use ic_kit::macros::*;
+use ic_kit::{ic, Principal};
+use serde::{Deserialize, Serialize};
+
+use cap_common::user_index::{self, add_user_root_bucket, UserCanisters};
+use cap_common::*;
+
+/// Merkle tree of the canister, it has the same shape as the one of the router so the
+/// responses are verified the same way.
+///
+/// 0: Canister Map, the root buckets known to this index canister
+/// 1: User canisters
+/// 2: Index canisters list, always empty
+///
+///      ROOT
+///     /   \
+///   / \    2
+///  0   1
+#[derive(CandidType, Serialize, Deserialize)]
+pub struct Data {
+    /// The router which created this index canister.
+    pub router: Principal,
+    /// Map: TokenContractId -> RootBucketId, filled from the router when a root bucket first
+    /// inserts its users.
+    pub root_buckets: Map<TokenContractId, RootBucketId>,
+    /// Map each user of the shard to RootBucketId
+    pub user_canisters: UserCanisters,
+}
+
+impl Default for Data {
+    fn default() -> Self {
+        Data {
+            router: Principal::management_canister(),
+            root_buckets: Map::new(),
+            user_canisters: Map::new(),
+        }
+    }
+}
+
+impl Data {
+    /// Return the root hash of the merkle tree of the canister.
+    pub fn root_hash(&self) -> Hash {
+        fork_hash(
+            &fork_hash(
+                &self.root_buckets.root_hash(),
+                &self.user_canisters.root_hash(),
+            ),
+            &Seq::<IndexCanisterId>::new().root_hash(),
+        )
+    }
+}
+
+#[init]
+fn init(router: Principal) {
+    let data = ic::get_mut::<Data>();
+    data.router = router;
+    ic::set_certified_data(&data.root_hash());
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    ic::stable_store((ic::get::<Data>(),)).expect("Failed to serialize data.");
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let (data,): (Data,) = ic::stable_restore().expect("Failed to deserialize.");
+    ic::set_certified_data(&data.root_hash());
+    ic::store(data);
+}
+
+#[query]
+#[candid_method(query)]
+fn get_user_root_buckets(arg: GetUserRootBucketsArg) -> GetUserRootBucketsResponse {
+    let data = ic::get::<Data>();
+
+    let witness = match arg.witness {
+        false => None,
+        true => Some(
+            fork(
+                fork(
+                    HashTree::Pruned(data.root_buckets.root_hash()),
+                    data.user_canisters.witness(&arg.user),
+                ),
+                HashTree::Pruned(Seq::<IndexCanisterId>::new().root_hash()),
+            )
+            .into(),
+        ),
+    };
+
+    let contracts = data
+        .user_canisters
+        .get(&arg.user)
+        .unwrap_or(&Seq::new())
+        .as_vec()
+        .clone();
+
+    GetUserRootBucketsResponse { contracts, witness }
+}
+
+/// Add the root bucket of the caller to the given users, the caller must be the root bucket of
+/// the contract.
+#[update]
+#[candid_method(update)]
+async fn insert_new_users(contract_id: Principal, users: Vec<Principal>) {
+    let root_bucket = ic::caller();
+
+    if ic::get::<Data>().root_buckets.get(&contract_id) != Some(&root_bucket) {
+        let router = ic::get::<Data>().router;
+        let (response,): (GetTokenContractRootBucketResponse,) = ic::call(
+            router,
+            "get_token_contract_root_bucket",
+            (GetTokenContractRootBucketArg {
+                canister: contract_id,
+                witness: false,
+            },),
+        )
+        .await
+        .expect("Failed to retrieve the root bucket of the contract.");
+
+        assert_eq!(response.canister, Some(root_bucket), "Access denied.");
+        ic::get_mut::<Data>()
+            .root_buckets
+            .insert(contract_id, root_bucket);
+    }
+
+    let data = ic::get_mut::<Data>();
+    for user in users {
+        add_user_root_bucket(&mut data.user_canisters, user, root_bucket);
+    }
+
+    ic::set_certified_data(&data.root_hash());
+}
+
+/// Add the root bucket to the users which are missing it, returns the number of repaired
+/// users. Only the router can call this method, see `reconcile_users`.
+#[update]
+#[candid_method(update)]
+fn repair_users(root_bucket: RootBucketId, users: Vec<UserId>) -> u64 {
+    let data = ic::get_mut::<Data>();
+
+    if ic::caller() != data.router {
+        panic!("Only the router can repair the users.")
+    }
+
+    let mut repaired = 0;
+    for user in users {
+        if add_user_root_bucket(&mut data.user_canisters, user, root_bucket) {
+            repaired += 1;
+        }
+    }
+
+    ic::set_certified_data(&data.root_hash());
+    repaired
+}
+
+/// Return a page of the users which belong to another of the given index canisters, only the
+/// router can call this method when it moves the users.
+#[query]
+#[candid_method(query)]
+fn export_users(
+    canisters: Vec<IndexCanisterId>,
+    cursor: Option<UserId>,
+    limit: u32,
+) -> ExportUsersResponse {
+    let data = ic::get::<Data>();
+
+    if ic::caller() != data.router {
+        panic!("Only the router can export the users.")
+    }
+
+    user_index::export_users(&data.user_canisters, &ic::id(), &canisters, cursor, limit)
+}
+
+/// Add the root buckets of the users moved from another index canister, only the router can call
+/// this method.
+#[update]
+#[candid_method(update)]
+fn import_users(users: Vec<(UserId, Vec<RootBucketId>)>) {
+    let data = ic::get_mut::<Data>();
+
+    if ic::caller() != data.router {
+        panic!("Only the router can import the users.")
+    }
+
+    user_index::import_users(&mut data.user_canisters, users);
+    ic::set_certified_data(&data.root_hash());
+}
+
+/// Remove the users which were moved to another index canister, returns the number of removed
+/// users. Only the router can call this method.
+#[update]
+#[candid_method(update)]
+fn remove_users(users: Vec<(UserId, Vec<RootBucketId>)>) -> u64 {
+    let data = ic::get_mut::<Data>();
+
+    if ic::caller() != data.router {
+        panic!("Only the router can remove the users.")
+    }
+
+    let removed = user_index::remove_users(&mut data.user_canisters, &users);
+    ic::set_certified_data(&data.root_hash());
+    removed
+}
+
+#[query]
+#[candid_method(query)]
+fn git_commit_hash() -> String {
+    compile_time_run::run_command_str!("git", "rev-parse", "HEAD").into()
+}
+
+#[query(name = "__get_candid_interface_tmp_hack")]
+fn export_candid() -> String {
+    export_service!();
+    __export_service()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_kit::candid::{decode_args, encode_args};
+    use ic_kit::{mock_principals, MockContext, RawHandler};
+
+    #[test]
+    fn save_candid() {
+        use std::env;
+        use std::fs::write;
+        use std::path::PathBuf;
+
+        let dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+        let dir = dir.parent().unwrap().parent().unwrap().join("candid");
+        write(dir.join("index.did"), export_candid()).expect("Write failed.");
+    }
+
+    #[async_std::test]
+    async fn test_insert_new_users() {
+        let router = mock_principals::john();
+        let (xtc, root) = (mock_principals::xtc(), Principal::from_slice(&[1]));
+        let alice = mock_principals::alice();
+
+        let ctx = MockContext::new()
+            .with_caller(router)
+            .with_handler(RawHandler::raw(Box::new(
+                move |_, args, canister, method| {
+                    assert_eq!(canister, &router);
+                    assert_eq!(method, "get_token_contract_root_bucket");
+                    let (arg,): (GetTokenContractRootBucketArg,) = decode_args(args).unwrap();
+                    let canister = Some(root).filter(|_| arg.canister == xtc);
+                    Ok(encode_args((GetTokenContractRootBucketResponse {
+                        canister,
+                        witness: None,
+                    },))
+                    .unwrap())
+                },
+            )))
+            .inject();
+
+        init(router);
+
+        ctx.update_caller(root);
+        insert_new_users(xtc, vec![alice]).await;
+        ctx.call_state_reset();
+        insert_new_users(xtc, vec![alice]).await;
+        assert_eq!(ic::get::<Data>().root_buckets.get(&xtc), Some(&root));
+
+        let response = get_user_root_buckets(GetUserRootBucketsArg {
+            user: alice,
+            witness: false,
+        });
+        assert_eq!(response.contracts, vec![root]);
+
+        ctx.call_state_reset();
+        ctx.update_caller(router);
+        let other = Principal::from_slice(&[2]);
+        assert_eq!(repair_users(other, vec![alice, mock_principals::bob()]), 2);
+        assert_eq!(repair_users(other, vec![alice]), 0);
+        assert_eq!(
+            ic::get::<Data>()
+                .user_canisters
+                .get(&alice)
+                .unwrap()
+                .as_vec(),
+            &[root, other]
+        );
+
+        // The router moves the users to another index canister.
+        let page = export_users(vec![Principal::from_slice(&[3])], None, 10);
+        assert_eq!(page.users.len(), 2);
+        assert_eq!(page.next_cursor, None);
+        assert_eq!(remove_users(page.users.clone()), 2);
+        assert!(ic::get::<Data>().user_canisters.is_empty());
+        import_users(page.users);
+        assert_eq!(ic::get::<Data>().user_canisters.len(), 2);
+    }
+
+    #[async_std::test]
+    #[should_panic(expected = "Access denied.")]
+    async fn test_insert_new_users_denied() {
+        let router = mock_principals::john();
+
+        MockContext::new()
+            .with_caller(mock_principals::bob())
+            .with_handler(RawHandler::raw(Box::new(|_, _, _, _| {
+                Ok(encode_args((GetTokenContractRootBucketResponse {
+                    canister: Some(Principal::from_slice(&[1])),
+                    witness: None,
+                },))
+                .unwrap())
+            })))
+            .inject();
+
+        ic::get_mut::<Data>().router = router;
+        insert_new_users(mock_principals::xtc(), vec![mock_principals::alice()]).await;
+    }
+}
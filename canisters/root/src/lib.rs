@@ -1,36 +1,66 @@
 use cap_common::transaction::{Event, IndefiniteEvent};
 use certified_vars::AsHashTree;
-use ic_kit::candid::{candid_method, export_service, CandidType};
+use ic_kit::candid::{candid_method, export_service};
 use ic_kit::{ic, Principal};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeSet;
+use std::ops::Bound::{Excluded, Unbounded};
 
 use crate::multi_stage_reader::InProgressReadFromStable;
+use crate::notifications::Outbox;
+use crate::replicas::Replicas;
+use crate::rollover::Rollover;
+use crate::upgrade::store_data;
 use cap_common::bucket::Bucket;
+use cap_common::memory::{Memory, StableMemory, WASM_PAGE_SIZE};
 use cap_common::did::*;
 use ic_kit::macros::*;
 
 mod migration;
 mod multi_stage_reader;
+pub mod notifications;
+pub mod replicas;
+pub mod rollover;
 pub mod upgrade;
 
+/// The maximum number of events indexed under the new detail keys in one call.
+const BACKFILL_BATCH_SIZE: usize = 5_000;
+
+/// The maximum number of blocks of the events removed by a rollover which are freed in one call.
+const FREE_BATCH_SIZE: usize = 10_000;
+
 /// Merkle tree of the canister.
 ///
 /// 0: Bucket
 /// 1: Buckets Lookup Map
 /// 2: Next buckets
+/// 3: Writers
 ///
 ///      ROOT
-///     /   \
-///   / \    2
-///  0   1
-#[derive(CandidType, Serialize, Deserialize)]
+///     /    \
+///   / \    / \
+///  0   1  2   3
+#[derive(Serialize, Deserialize)]
 pub struct Data {
     pub bucket: Bucket,
     pub users: BTreeSet<Principal>,
     pub cap_id: Principal,
     pub allow_migration: bool,
     pub writers: BTreeSet<TokenContractId>,
+    /// When to move the events to a new bucket canister, rollovers are disabled if not set.
+    pub rollover_config: Option<RolloverConfig>,
+    /// The wasm installed on the bucket canisters created by this root.
+    pub bucket_wasm: Option<Vec<u8>>,
+    /// The rollover that is in progress, if any.
+    pub rollover: Option<Rollover>,
+    /// The read replicas of this bucket, replicas are disabled if not set.
+    pub replicas: Option<Replicas>,
+    /// The new users which are not sent to the router yet.
+    pub router_outbox: Option<Outbox>,
+    /// The replica canisters which were removed from the config and are not deleted yet.
+    pub removed_replicas: Option<Vec<BucketId>>,
+    /// The events stored before the upgrade which are not moved to the list yet.
+    pub reader: Option<InProgressReadFromStable>,
 }
 
 struct OldData(Data);
@@ -53,6 +83,13 @@ impl Default for Data {
                 cap_id: Principal::from_text("lj532-6iaaa-aaaah-qcc7a-cai").unwrap(),
                 allow_migration: false,
                 writers: writers.into_iter().collect(),
+                rollover_config: None,
+                bucket_wasm: None,
+                rollover: None,
+                replicas: None,
+                router_outbox: None,
+                removed_replicas: None,
+                reader: None,
             };
         }
 
@@ -62,38 +99,74 @@ impl Default for Data {
             cap_id: Principal::management_canister(),
             allow_migration: true,
             writers: BTreeSet::new(),
+            rollover_config: None,
+            bucket_wasm: None,
+            rollover: None,
+            replicas: None,
+            router_outbox: None,
+            removed_replicas: None,
+            reader: None,
         }
     }
 }
 
+/// Sets a flag kept on the heap for as long as it is alive. It is dropped when the call returns
+/// and when one of its callbacks traps, so the flag is cleared on every exit path.
+pub(crate) struct FlagGuard(fn(bool));
+
+impl FlagGuard {
+    pub(crate) fn new(set: fn(bool)) -> Self {
+        set(true);
+        Self(set)
+    }
+}
+
+impl Drop for FlagGuard {
+    fn drop(&mut self) {
+        (self.0)(false)
+    }
+}
+
 #[init]
-fn init(contract: Principal, writers: BTreeSet<Principal>) {
+fn init(contract: Principal, writers: BTreeSet<Principal>, detail_indexes: Option<Vec<String>>) {
     let data = ic::get_mut::<Data>();
     data.cap_id = ic::caller();
     data.bucket = Bucket::new(contract, 0);
+    data.bucket.init_ranges(ic::id());
+    data.bucket.bucket.set_stable();
     data.writers = writers;
+    data.bucket.set_writers(&data.writers);
+
+    for key in detail_indexes.unwrap_or_default() {
+        data.bucket.add_detail_index(key);
+    }
+
+    ic::set_certified_data(&data.bucket.root_hash());
 }
 
 #[query]
 #[candid_method(query)]
 fn get_upgrade_status() -> (usize, bool) {
-    ic::get_maybe::<InProgressReadFromStable>()
+    ic::get::<Data>()
+        .reader
+        .as_ref()
         .expect("Not running an upgrade")
         .status()
 }
 
 #[query]
 #[candid_method(query)]
-fn get_stable(offset: usize, size: usize) -> Vec<u8> {
-    let mut buf = vec![0; size];
-    ic::stable_read(offset as u32, buf.as_mut_slice());
+fn get_stable(offset: u64, size: u64) -> Vec<u8> {
+    let mut buf = vec![0; size as usize];
+    StableMemory.read(offset, buf.as_mut_slice());
     buf
 }
 
+/// Return the number of pages of the stable memory.
 #[query]
 #[candid_method(query)]
-fn get_stable_size() -> u32 {
-    ic::stable_size()
+fn get_stable_size() -> u64 {
+    StableMemory.size() / WASM_PAGE_SIZE
 }
 
 #[query]
@@ -110,13 +183,13 @@ fn get_transaction(arg: WithIdArg) -> GetTransactionResponse {
 
 #[query]
 #[candid_method(query)]
-fn get_transactions(arg: GetTransactionsArg) -> GetTransactionsResponseBorrowed<'static> {
+fn get_transactions(arg: GetTransactionsArg) -> GetTransactionsResponse {
     ic::get::<Data>().bucket.get_transactions(arg)
 }
 
 #[query]
 #[candid_method(query)]
-fn get_user_transactions(arg: GetUserTransactionsArg) -> GetTransactionsResponseBorrowed<'static> {
+fn get_user_transactions(arg: GetUserTransactionsArg) -> GetTransactionsResponse {
     ic::get::<Data>().bucket.get_user_transactions(arg)
 }
 
@@ -124,10 +197,72 @@ fn get_user_transactions(arg: GetUserTransactionsArg) -> GetTransactionsResponse
 #[candid_method(query)]
 fn get_token_transactions(
     arg: GetTokenTransactionsArg,
-) -> GetTransactionsResponseBorrowed<'static> {
+) -> GetTransactionsResponse {
     ic::get::<Data>().bucket.get_token_transactions(arg)
 }
 
+#[query]
+#[candid_method(query)]
+fn get_operation_transactions(
+    arg: GetOperationTransactionsArg,
+) -> GetTransactionsResponse {
+    ic::get::<Data>().bucket.get_operation_transactions(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_account_transactions(
+    arg: GetAccountTransactionsArg,
+) -> GetTransactionsResponse {
+    ic::get::<Data>().bucket.get_account_transactions(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_writer_transactions(arg: GetWriterTransactionsArg) -> GetTransactionsResponse {
+    ic::get::<Data>().bucket.get_writer_transactions(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_transactions_by_detail(
+    arg: GetTransactionsByDetailArg,
+) -> GetTransactionsResponse {
+    ic::get::<Data>().bucket.get_transactions_by_detail(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_transactions_in_range(
+    arg: GetTransactionsInRangeArg,
+) -> GetTransactionsInRangeResponse {
+    ic::get::<Data>().bucket.get_transactions_in_range(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_transactions_cursor(
+    arg: GetTransactionsCursorArg,
+) -> GetTransactionsCursorResponse {
+    ic::get::<Data>().bucket.get_transactions_cursor(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_user_transactions_cursor(
+    arg: GetUserTransactionsCursorArg,
+) -> GetTransactionsCursorResponse {
+    ic::get::<Data>().bucket.get_user_transactions_cursor(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_token_transactions_cursor(
+    arg: GetTokenTransactionsCursorArg,
+) -> GetTransactionsCursorResponse {
+    ic::get::<Data>().bucket.get_token_transactions_cursor(arg)
+}
+
 #[query]
 #[candid_method(query)]
 fn get_bucket_for(arg: WithIdArg) -> GetBucketResponse {
@@ -140,6 +275,12 @@ fn time() -> u64 {
     ic::time()
 }
 
+#[query]
+#[candid_method(query)]
+fn get_size(arg: WithWitnessArg) -> GetSizeResponse {
+    ic::get::<Data>().bucket.get_size(arg)
+}
+
 #[query]
 #[candid_method(query)]
 fn size() -> u64 {
@@ -152,97 +293,300 @@ fn contract_id() -> &'static Principal {
     ic::get::<Data>().bucket.contract_id()
 }
 
+#[query]
+#[candid_method(query)]
+fn get_detail_indexes() -> Vec<String> {
+    ic::get::<Data>().bucket.detail_indexes()
+}
+
+/// Start indexing the given detail key, the existing events are indexed in batches by this call,
+/// the following inserts and `detail_index_progress`.
+#[update]
+#[candid_method(update)]
+fn add_detail_index(key: String) {
+    let data = ic::get_mut::<Data>();
+    let caller = ic::caller();
+
+    if !(&caller == data.bucket.contract_id() || data.writers.contains(&caller)) {
+        ic::trap("The method can only be invoked by one of the writers.");
+    }
+
+    if data.bucket.add_detail_index(key) {
+        data.bucket.backfill_detail_indexes(BACKFILL_BATCH_SIZE);
+        ic::set_certified_data(&data.bucket.root_hash());
+    }
+}
+
+/// Return the detail keys whose index does not hold all of the events yet.
+#[query]
+#[candid_method(query)]
+fn get_detail_index_backfills() -> Vec<DetailIndexBackfill> {
+    ic::get::<Data>().bucket.detail_backfills()
+}
+
+/// Index the next batch of events under the detail keys which are not fully indexed yet, and
+/// return the ones which are still not fully indexed.
+#[update]
+#[candid_method(update)]
+fn detail_index_progress() -> Vec<DetailIndexBackfill> {
+    let data = ic::get_mut::<Data>();
+    if data.bucket.backfill_detail_indexes(BACKFILL_BATCH_SIZE) > 0 {
+        ic::set_certified_data(&data.bucket.root_hash());
+    }
+
+    data.bucket.detail_backfills()
+}
+
+#[query]
+#[candid_method(query)]
+fn get_transaction_id_by_key(arg: GetTransactionIdByKeyArg) -> GetTransactionIdByKeyResponse {
+    ic::get::<Data>().bucket.get_transaction_id_by_key(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_tip(arg: WithWitnessArg) -> GetTipResponse {
+    ic::get::<Data>().bucket.get_tip(arg)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_consistency_proof(arg: GetConsistencyProofArg) -> GetConsistencyProofResponse {
+    ic::get::<Data>().bucket.get_consistency_proof(arg)
+}
+
+/// Return the users of the events ordered by principal, starting after the cursor. This is used
+/// by the router to repair its index of the users, see `reconcile_users`.
+#[query]
+#[candid_method(query)]
+fn get_users(cursor: Option<UserId>, limit: u32) -> GetUsersResponse {
+    let users = &ic::get::<Data>().users;
+
+    let mut iter = match cursor {
+        Some(cursor) => users.range((Excluded(cursor), Unbounded)),
+        None => users.range(..),
+    };
+
+    let limit = limit.clamp(1, 10_000) as usize;
+    let page = iter.by_ref().take(limit).copied().collect::<Vec<_>>();
+    let next_cursor = match iter.next() {
+        Some(_) => page.last().copied(),
+        None => None,
+    };
+
+    GetUsersResponse {
+        users: page,
+        next_cursor,
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn list_writers(arg: WithWitnessArg) -> GetWritersResponse {
+    ic::get::<Data>().bucket.get_writers(arg)
+}
+
+/// Allow the given canister to insert events, returns `false` if it already was a writer. Only
+/// the contract can change the writers.
+#[update]
+#[candid_method(update)]
+fn add_writer(writer: Principal) -> bool {
+    update_writers(|writers| writers.insert(writer))
+}
+
+/// Stop accepting events from the given canister, returns `false` if it was not a writer.
 #[update]
 #[candid_method(update)]
-fn insert(event: IndefiniteEvent) -> TransactionId {
-    if ic::get_maybe::<InProgressReadFromStable>().is_some() {
-        let caller = ic::caller();
-        return ic::get_mut::<InProgressReadFromStable>().insert_batch(&caller, vec![event]);
+fn remove_writer(writer: Principal) -> bool {
+    update_writers(|writers| writers.remove(&writer))
+}
+
+fn update_writers(f: impl FnOnce(&mut BTreeSet<Principal>) -> bool) -> bool {
+    let data = ic::get_mut::<Data>();
+
+    if &ic::caller() != data.bucket.contract_id() {
+        ic::trap("The method can only be invoked by the contract.");
     }
 
+    let changed = f(&mut data.writers);
+    if changed {
+        data.bucket.set_writers(&data.writers);
+        ic::set_certified_data(&data.bucket.root_hash());
+    }
+
+    changed
+}
+
+#[query]
+#[candid_method(query)]
+fn get_user_index_exclusions() -> Vec<String> {
+    ic::get::<Data>().bucket.user_index_exclusions()
+}
+
+/// Set the detail keys whose principals are not added to the user index, this only applies to
+/// the events inserted afterwards.
+#[update]
+#[candid_method(update)]
+fn set_user_index_exclusions(keys: Vec<String>) {
     let data = ic::get_mut::<Data>();
     let caller = ic::caller();
 
     if !(&caller == data.bucket.contract_id() || data.writers.contains(&caller)) {
-        panic!("The method can only be invoked by one of the writers.");
+        ic::trap("The method can only be invoked by one of the writers.");
     }
 
+    data.bucket
+        .set_user_index_exclusions(keys.into_iter().collect());
+}
+
+#[update]
+#[candid_method(update)]
+fn insert(event: IndefiniteEvent) -> Result<TransactionId, InsertError> {
+    let data = ic::get_mut::<Data>();
+    let caller = ic::caller();
+
+    if !(&caller == data.bucket.contract_id() || data.writers.contains(&caller)) {
+        return Err(InsertError::NotAuthorized);
+    }
+
+    InsertError::check(std::slice::from_ref(&event))?;
+
+    if let Some(id) = replayed(data, &event) {
+        return Ok(id);
+    }
+
+    let key = event.idempotency_key.clone();
     let event = event.to_event(ic::time() / 1_000_000);
 
     let mut new_users = Vec::new();
-    for principal in event.extract_principal_ids() {
+    for principal in data.bucket.indexed_users(&event) {
         if data.users.insert(*principal) {
             new_users.push(*principal);
         }
     }
 
-    #[cfg(not(test))]
-    ic_cdk::spawn(write_new_users_to_cap(
-        data.cap_id,
-        *data.bucket.contract_id(),
-        new_users,
-    ));
-
-    let id = data.bucket.insert(event);
+    let id = append(data, caller, event, key);
+    data.bucket.backfill_detail_indexes(BACKFILL_BATCH_SIZE);
+    data.bucket.free_retired(FREE_BATCH_SIZE);
+    upgrade::progress(data, upgrade::INSERT_UPGRADE_SIZE);
 
     data.allow_migration = false;
 
     ic::set_certified_data(&data.bucket.root_hash());
+    notifications::push(data, new_users);
+    rollover::check(data);
+    replicas::check(data);
 
-    id
+    Ok(id)
 }
 
+/// Insert the events and return the id of the first one that was inserted, the events inserted
+/// by the call have contiguous ids starting at it. An event with an idempotency key which was
+/// recently used is not inserted again, when none of the events is new the id of the original
+/// event of the first one is returned.
 #[update]
 #[candid_method(update)]
-fn insert_many(transactions: Vec<IndefiniteEvent>) -> TransactionId {
-    if ic::get_maybe::<InProgressReadFromStable>().is_some() {
-        let caller = ic::caller();
-        return ic::get_mut::<InProgressReadFromStable>().insert_batch(&caller, transactions);
-    }
-
+fn insert_many(transactions: Vec<IndefiniteEvent>) -> Result<TransactionId, InsertError> {
     let data = ic::get_mut::<Data>();
     let caller = ic::caller();
     let time = ic::time() / 1_000_000;
 
     if !(&caller == data.bucket.contract_id() || data.writers.contains(&caller)) {
-        panic!("The method can only be invoked by one of the writers.");
+        return Err(InsertError::NotAuthorized);
     }
 
-    let id = data.bucket.size();
+    InsertError::check(&transactions)?;
+
+    let mut id = None;
+    let mut replayed_id = None;
     let mut new_users = Vec::new();
 
     for tx in transactions {
+        if let Some(original) = replayed(data, &tx) {
+            replayed_id.get_or_insert(original);
+            continue;
+        }
+
+        let key = tx.idempotency_key.clone();
         let event = tx.to_event(time);
 
-        for principal in event.extract_principal_ids() {
+        for principal in data.bucket.indexed_users(&event) {
             if data.users.insert(*principal) {
                 new_users.push(*principal);
             }
         }
 
-        data.bucket.insert(event);
+        let inserted = append(data, caller, event, key);
+        id.get_or_insert(inserted);
     }
 
-    ic_cdk::spawn(write_new_users_to_cap(
-        data.cap_id,
-        *data.bucket.contract_id(),
-        new_users,
-    ));
-
+    let next = next_id(data);
+    data.bucket.backfill_detail_indexes(BACKFILL_BATCH_SIZE);
+    data.bucket.free_retired(FREE_BATCH_SIZE);
+    upgrade::progress(data, upgrade::INSERT_UPGRADE_SIZE);
     ic::set_certified_data(&data.bucket.root_hash());
+    notifications::push(data, new_users);
+    rollover::check(data);
+    replicas::check(data);
+
+    Ok(id.or(replayed_id).unwrap_or(next))
+}
+
+/// Insert the event to the list, during a read from stable the event is queued after the events
+/// which are not moved to the list yet.
+#[inline]
+fn append(data: &mut Data, caller: Principal, event: Event, key: Option<String>) -> TransactionId {
+    match &mut data.reader {
+        Some(reader) => reader.push(&data.bucket, caller, event, key),
+        None => data.bucket.insert_idempotent(Some(caller), event, key),
+    }
+}
+
+/// Return the id of the next event which is inserted.
+#[inline]
+fn next_id(data: &Data) -> TransactionId {
+    match &data.reader {
+        Some(reader) => reader.size(&data.bucket),
+        None => data.bucket.size(),
+    }
+}
+
+/// Return the id of the original event if the event has an idempotency key which was
+/// recently used.
+#[inline]
+fn replayed(data: &Data, event: &IndefiniteEvent) -> Option<TransactionId> {
+    event.idempotency_key.as_deref().and_then(|key| {
+        data.reader
+            .as_ref()
+            .and_then(|reader| reader.get_id_for_key(key))
+            .or_else(|| data.bucket.get_id_for_key(key))
+    })
+}
 
-    id
+/// Run the checks of `insert_many` on the events without inserting them.
+#[query]
+#[candid_method(query)]
+fn validate_events(transactions: Vec<IndefiniteEvent>) -> Result<(), InsertError> {
+    let data = ic::get::<Data>();
+    let caller = ic::caller();
+
+    if !(&caller == data.bucket.contract_id() || data.writers.contains(&caller)) {
+        return Err(InsertError::NotAuthorized);
+    }
+
+    InsertError::check(&transactions)
 }
 
 #[update]
 #[candid_method(update)]
 fn migrate(events: Vec<Event>) {
-    if ic::get_maybe::<InProgressReadFromStable>().is_some() {
-        ic::trap("Migration is not allowed during a read from stable.");
-    }
     let data = ic::get_mut::<Data>();
     let caller = ic::caller();
 
+    if data.reader.is_some() {
+        ic::trap("Migration is not allowed during a read from stable.");
+    }
+
     if !(&caller == data.bucket.contract_id() || data.writers.contains(&caller)) {
         ic::trap("The method can only be invoked by one of the writers.");
     }
@@ -254,7 +598,7 @@ fn migrate(events: Vec<Event>) {
     let mut new_users = Vec::new();
 
     for event in events {
-        for principal in event.extract_principal_ids() {
+        for principal in data.bucket.indexed_users(&event) {
             if data.users.insert(*principal) {
                 new_users.push(*principal);
             }
@@ -263,30 +607,8 @@ fn migrate(events: Vec<Event>) {
         data.bucket.insert(event);
     }
 
-    #[cfg(not(test))]
-    ic_cdk::spawn(write_new_users_to_cap(
-        data.cap_id,
-        *data.bucket.contract_id(),
-        new_users,
-    ));
-
     ic::set_certified_data(&data.bucket.root_hash());
-}
-
-pub async fn write_new_users_to_cap(
-    cap_id: Principal,
-    contract_id: Principal,
-    users: Vec<Principal>,
-) {
-    for _ in 0..10 {
-        let args = (contract_id, &users);
-        if ic::call::<(Principal, &Vec<Principal>), (), &str>(cap_id, "insert_new_users", args)
-            .await
-            .is_ok()
-        {
-            break;
-        }
-    }
+    notifications::push(data, new_users);
 }
 
 #[query]
@@ -333,12 +655,11 @@ fn complete_data_restore() {
 
     let new_data = ic::take::<Data>().unwrap_or_default();
 
-    for event in new_data.bucket.bucket.events.iter() {
-        let event = unsafe { event.as_ref().clone() };
+    for event in new_data.bucket.bucket.into_events() {
         data.bucket.insert(event);
     }
 
-    ic::store::<Data>(data);
+    store_data(data);
 }
 
 #[query]
@@ -361,4 +682,298 @@ mod tests {
         let dir = dir.parent().unwrap().parent().unwrap().join("candid");
         write(dir.join("root.did"), export_candid()).expect("Write failed.");
     }
+
+    #[test]
+    fn test_get_stable() {
+        use ic_kit::MockContext;
+
+        MockContext::new().inject();
+        StableMemory.grow_to(WASM_PAGE_SIZE + 1);
+        StableMemory.write(WASM_PAGE_SIZE - 2, &[1, 2, 3, 4]);
+
+        assert!(get_stable_size() >= 2);
+        assert_eq!(get_stable(WASM_PAGE_SIZE - 1, 2), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_insert_errors() {
+        use cap_common::transaction::{DetailValue, EventError};
+        use ic_kit::{mock_principals, MockContext};
+
+        let contract = mock_principals::xtc();
+        let ctx = MockContext::new().with_caller(contract).inject();
+        init(contract, Default::default(), None);
+
+        let event = |operation: &str, details: Vec<(String, DetailValue)>| IndefiniteEvent {
+            caller: mock_principals::alice(),
+            operation: operation.into(),
+            details,
+            idempotency_key: None,
+        };
+        let duplicate = vec![("to".into(), 1u64.into()), ("to".into(), 2u64.into())];
+
+        assert_eq!(insert(event("mint", vec![])), Ok(0));
+        assert_eq!(
+            insert(event(&"x".repeat(256), vec![])),
+            Err(InsertError::InvalidEvent {
+                index: 0,
+                error: EventError::OperationTooLong { len: 256, max: 255 }
+            })
+        );
+
+        // A batch is rejected as a whole.
+        let batch = vec![event("mint", vec![]), event("transfer", duplicate)];
+        let error = InsertError::InvalidEvent {
+            index: 1,
+            error: EventError::DuplicateKey("to".into()),
+        };
+        assert_eq!(validate_events(batch.clone()), Err(error.clone()));
+        assert_eq!(insert_many(batch), Err(error));
+        assert_eq!(ic::get::<Data>().bucket.size(), 1);
+        assert_eq!(insert_many(vec![event("burn", vec![])]), Ok(1));
+
+        ctx.update_caller(mock_principals::bob());
+        assert_eq!(validate_events(vec![]), Err(InsertError::NotAuthorized));
+        assert_eq!(
+            insert(event("mint", vec![])),
+            Err(InsertError::NotAuthorized)
+        );
+        assert_eq!(ic::get::<Data>().bucket.size(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "A witness can not be requested along with roles.")]
+    fn test_user_transactions_with_roles_witness() {
+        use ic_kit::{mock_principals, MockContext};
+
+        let contract = mock_principals::xtc();
+        MockContext::new().with_caller(contract).inject();
+        init(contract, Default::default(), None);
+
+        insert(IndefiniteEvent {
+            caller: mock_principals::alice(),
+            operation: "mint".into(),
+            details: vec![],
+            idempotency_key: None,
+        })
+        .unwrap();
+
+        let response = get_user_transactions(GetUserTransactionsArg {
+            user: mock_principals::alice(),
+            page: None,
+            witness: false,
+            roles: Some(vec!["caller".into()]),
+        });
+        assert_eq!(response.data.len(), 1);
+
+        get_user_transactions(GetUserTransactionsArg {
+            user: mock_principals::alice(),
+            page: None,
+            witness: true,
+            roles: Some(vec!["caller".into()]),
+        });
+    }
+
+    #[test]
+    fn test_user_index_exclusions() {
+        use cap_common::transaction::DetailValue;
+        use ic_kit::{mock_principals, MockContext};
+
+        let contract = mock_principals::xtc();
+        MockContext::new().with_caller(contract).inject();
+        init(contract, Default::default(), None);
+        set_user_index_exclusions(vec!["spender".into()]);
+
+        let event = |details: Vec<(String, DetailValue)>| IndefiniteEvent {
+            caller: mock_principals::alice(),
+            operation: "approve".into(),
+            details,
+            idempotency_key: None,
+        };
+
+        // The users found only under the excluded keys are not new users.
+        insert(event(vec![("spender".into(), mock_principals::bob().into())])).unwrap();
+        insert_many(vec![event(vec![
+            ("spender".into(), mock_principals::john().into()),
+            ("to".into(), mock_principals::bob().into()),
+        ])])
+        .unwrap();
+
+        let users = &ic::get::<Data>().users;
+        assert!(users.contains(&mock_principals::alice()));
+        assert!(users.contains(&mock_principals::bob()));
+        assert!(!users.contains(&mock_principals::john()));
+    }
+
+    #[test]
+    fn test_idempotent_insert() {
+        use ic_kit::{mock_principals, MockContext};
+
+        let contract = mock_principals::xtc();
+        MockContext::new().with_caller(contract).inject();
+        init(contract, Default::default(), None);
+
+        let event = |key: Option<&str>| IndefiniteEvent {
+            caller: mock_principals::alice(),
+            operation: "mint".into(),
+            details: vec![],
+            idempotency_key: key.map(String::from),
+        };
+
+        assert_eq!(insert(event(Some("a"))), Ok(0));
+        assert_eq!(insert(event(None)), Ok(1));
+        assert_eq!(insert(event(Some("a"))), Ok(0));
+
+        // A retried batch keeps the ids of the events which were already inserted, the id of
+        // the first new event is returned when there is one.
+        let batch = vec![event(Some("b")), event(Some("c"))];
+        assert_eq!(insert_many(batch.clone()), Ok(2));
+        assert_eq!(insert_many(batch), Ok(2));
+        assert_eq!(insert_many(vec![event(Some("c")), event(Some("d"))]), Ok(4));
+        assert_eq!(ic::get::<Data>().bucket.size(), 5);
+
+        let response = get_transaction_id_by_key(GetTransactionIdByKeyArg {
+            key: "d".into(),
+            witness: false,
+        });
+        assert_eq!(response.id, Some(4));
+
+        // The replays are not added to the accumulator.
+        let tip = get_tip(WithWitnessArg { witness: false }).tip;
+        assert_eq!(tip.size, 5);
+        let proof = get_consistency_proof(GetConsistencyProofArg {
+            old_size: 2,
+            new_size: 5,
+        });
+        assert!(proof.proof.is_some());
+    }
+
+    #[test]
+    fn test_writers() {
+        use ic_kit::{mock_principals, MockContext};
+
+        let contract = mock_principals::xtc();
+        let ctx = MockContext::new().with_caller(contract).inject();
+        init(contract, Default::default(), None);
+
+        let event = IndefiniteEvent {
+            caller: mock_principals::alice(),
+            operation: "mint".into(),
+            details: vec![],
+            idempotency_key: None,
+        };
+
+        assert!(add_writer(mock_principals::bob()));
+        assert!(!add_writer(mock_principals::bob()));
+        let response = list_writers(WithWitnessArg { witness: false });
+        assert_eq!(response.writers, vec![mock_principals::bob()]);
+        assert_eq!(
+            ctx.get_certified_data(),
+            Some(ic::get::<Data>().bucket.root_hash().to_vec())
+        );
+
+        ctx.update_caller(mock_principals::bob());
+        assert_eq!(insert(event.clone()), Ok(0));
+
+        // The writer is recorded along with the event.
+        let response = get_writer_transactions(GetWriterTransactionsArg {
+            writer: mock_principals::bob(),
+            page: None,
+            witness: false,
+        });
+        assert_eq!(response.total_items, 1);
+        assert_eq!(
+            ic::get::<Data>().bucket.bucket.get_writer(0),
+            Some(mock_principals::bob())
+        );
+
+        ctx.update_caller(contract);
+        assert!(remove_writer(mock_principals::bob()));
+        assert!(!remove_writer(mock_principals::bob()));
+        assert!(list_writers(WithWitnessArg { witness: false }).writers.is_empty());
+
+        ctx.update_caller(mock_principals::bob());
+        assert_eq!(insert(event), Err(InsertError::NotAuthorized));
+    }
+
+    #[test]
+    fn test_detail_index_backfill() {
+        use cap_common::transaction::DetailValue;
+        use ic_kit::{mock_principals, MockContext};
+
+        let contract = mock_principals::xtc();
+        MockContext::new().with_caller(contract).inject();
+        init(contract, Default::default(), None);
+
+        let event = |i: u64| IndefiniteEvent {
+            caller: mock_principals::alice(),
+            operation: "mint".into(),
+            details: vec![("memo".into(), DetailValue::U64(i % 2))],
+            idempotency_key: None,
+        };
+        let count = || {
+            get_transactions_by_detail(GetTransactionsByDetailArg {
+                key: "memo".into(),
+                value: DetailValue::U64(1),
+                page: None,
+                witness: false,
+            })
+            .total_items
+        };
+
+        let len = BACKFILL_BATCH_SIZE as u64 + 10;
+        insert_many((0..len).map(event).collect()).unwrap();
+
+        // The first batch is indexed right away, the next ones by the following calls.
+        add_detail_index("memo".into());
+        assert_eq!(count(), BACKFILL_BATCH_SIZE as u64 / 2);
+        let backfill = DetailIndexBackfill {
+            key: "memo".into(),
+            cursor: BACKFILL_BATCH_SIZE as u64,
+        };
+        assert_eq!(get_detail_index_backfills(), vec![backfill]);
+
+        insert(event(len)).unwrap();
+        assert!(get_detail_index_backfills().is_empty());
+        assert_eq!(count(), len.div_ceil(2));
+        assert!(detail_index_progress().is_empty());
+    }
+
+    #[test]
+    fn test_get_users() {
+        use ic_kit::{mock_principals, MockContext};
+
+        let contract = mock_principals::xtc();
+        MockContext::new().with_caller(contract).inject();
+        init(contract, Default::default(), None);
+
+        let users = (0..5u8)
+            .map(|i| Principal::from_slice(&[i]))
+            .collect::<Vec<_>>();
+        for user in &users {
+            let event = IndefiniteEvent {
+                caller: *user,
+                operation: "mint".into(),
+                details: vec![],
+                idempotency_key: None,
+            };
+            insert(event).unwrap();
+        }
+
+        let page = get_users(None, 2);
+        assert_eq!(page.users, users[..2]);
+        assert_eq!(page.next_cursor, Some(users[1]));
+
+        let page = get_users(page.next_cursor, 2);
+        assert_eq!(page.users, users[2..4]);
+
+        let page = get_users(page.next_cursor, 2);
+        assert_eq!(page.users, users[4..]);
+        assert_eq!(page.next_cursor, None);
+
+        // The last page is full.
+        let page = get_users(Some(users[2]), 2);
+        assert_eq!(page.users, users[3..]);
+        assert_eq!(page.next_cursor, None);
+    }
 }
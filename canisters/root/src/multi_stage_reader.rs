@@ -1,35 +1,38 @@
-use crate::migration::v2;
-
-use crate::write_new_users_to_cap;
 use cap_common::bucket::Bucket;
-use cap_common::transaction::IndefiniteEvent;
-use cap_common::{TransactionId, TransactionList};
-use ic_kit::{ic, Principal};
-
-/// An in progress read from the stable storage.
+use cap_common::transaction::Event;
+use cap_common::TransactionId;
+use ic_kit::Principal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+/// An in progress read of the events which were stored before the list was kept in the stable
+/// memory. The events are moved to the list of the bucket in batches, and the reader is kept in
+/// the data so the read continues after an upgrade.
+#[derive(Serialize, Deserialize)]
 pub struct InProgressReadFromStable {
-    /// Index of the current event which we should start writing from.
+    /// Number of the events which are already moved to the list.
     pub cursor: usize,
-    /// The decoded information and metadata.
-    pub v2: v2::Data,
-    /// The transaction list that we're building.
-    pub list: TransactionList,
+    /// The events which are not in the list yet, along with the writer and the idempotency key
+    /// of the events inserted during the read. The events read from the stable storage have no
+    /// recorded writer or key.
+    events: VecDeque<(Event, Option<Inserted>)>,
+    /// The idempotency keys used during the read and the id of their event.
+    keys: BTreeMap<String, TransactionId>,
 }
 
-impl Default for InProgressReadFromStable {
-    fn default() -> Self {
-        ic::trap("Not expected to be called.");
-    }
+/// The writer and the idempotency key of an event inserted during the read.
+#[derive(Serialize, Deserialize)]
+struct Inserted {
+    writer: Principal,
+    key: Option<String>,
 }
 
 impl InProgressReadFromStable {
-    pub fn new(v2: v2::Data) -> Self {
-        let list = TransactionList::new(v2.bucket.bucket.1, v2.bucket.bucket.0);
-
+    pub fn new(events: Vec<Event>) -> Self {
         Self {
             cursor: 0,
-            v2,
-            list,
+            events: events.into_iter().map(|event| (event, None)).collect(),
+            keys: BTreeMap::new(),
         }
     }
 
@@ -37,90 +40,66 @@ impl InProgressReadFromStable {
         (self.cursor, self.is_complete())
     }
 
-    /// Insert a batch of transaction to the hash queue, this is used when an in-progress reader
-    /// is still working.
-    pub fn insert_batch(
+    /// Return the id of the event with the given idempotency key if it was used during the read.
+    #[inline]
+    pub fn get_id_for_key(&self, key: &str) -> Option<TransactionId> {
+        self.keys.get(key).copied()
+    }
+
+    /// Queue an event inserted during the read after the events which are not moved yet, and
+    /// return its id.
+    pub fn push(
         &mut self,
-        caller: &Principal,
-        transactions: Vec<IndefiniteEvent>,
+        bucket: &Bucket,
+        writer: Principal,
+        event: Event,
+        key: Option<String>,
     ) -> TransactionId {
-        let data = &mut self.v2;
-        let time = ic::time() / 1_000_000;
+        let id = self.size(bucket);
 
-        if !(caller == &data.bucket.contract || data.writers.contains(caller)) {
-            panic!("The method can only be invoked by one of the writers.");
+        if let Some(key) = &key {
+            self.keys.insert(key.clone(), id);
         }
 
-        let id = data.bucket.bucket.0 + (data.bucket.bucket.2.len() as u64);
-        let mut new_users = Vec::new();
-
-        for tx in transactions {
-            let event = tx.to_event(time);
-
-            for principal in event.extract_principal_ids() {
-                if data.users.insert(*principal) {
-                    new_users.push(*principal);
-                }
-            }
-
-            data.bucket.bucket.2.push(event);
-        }
-
-        #[cfg(not(test))]
-        ic_cdk::spawn(write_new_users_to_cap(
-            data.cap_id,
-            data.bucket.contract,
-            new_users,
-        ));
-
-        // let's also make some progress.
-        self.progress(100);
-
+        self.events
+            .push_back((event, Some(Inserted { writer, key })));
         id
     }
 
-    /// Make further progress on this reader by reading `n` items from the v2 structure and putting
-    /// it in the transaction list.
-    pub fn progress(&mut self, n: usize) {
-        let from = self.cursor;
-        let to = (from + n).min(self.v2.bucket.bucket.2.len());
+    /// Make further progress on this reader by moving `n` events to the list of the bucket,
+    /// return the number of the events which were moved.
+    pub fn progress(&mut self, bucket: &mut Bucket, n: usize) -> usize {
+        let n = n.min(self.events.len());
 
-        for i in from..to {
-            let event = self.v2.bucket.bucket.2[i].clone();
-            self.list.insert(event);
-            self.cursor += 1;
+        for (event, inserted) in self.events.drain(..n) {
+            match inserted {
+                Some(Inserted { writer, key }) => {
+                    bucket.insert_idempotent(Some(writer), event, key)
+                }
+                None => bucket.insert(event),
+            };
         }
+
+        self.cursor += n;
+        n
     }
 
-    /// Returns `true` if all of the events have been processes and we're ready to convert to a
+    /// Returns `true` if all of the events have been moved to the list, the reader should be
+    /// deleted once this is the case.
+    #[inline]
     pub fn is_complete(&self) -> bool {
-        self.cursor >= self.v2.bucket.bucket.2.len()
+        self.events.is_empty()
     }
 
     /// Return the number of remaining events.
+    #[inline]
     pub fn rem(&self) -> usize {
-        self.v2.bucket.bucket.2.len() - self.cursor
+        self.events.len()
     }
 
-    /// If there are no more work to be done on this reader, return the Data instance.
-    /// the InProgressReadFromStable should be deleted after this call.
-    pub fn get_data(&mut self) -> Option<crate::Data> {
-        if !self.is_complete() {
-            return None;
-        }
-
-        let list = std::mem::replace(
-            &mut self.list,
-            TransactionList::new(Principal::management_canister(), 0),
-        );
-        let users = std::mem::take(&mut self.v2.users);
-
-        Some(crate::Data {
-            bucket: Bucket::with_transaction_list(list),
-            users,
-            cap_id: self.v2.cap_id,
-            allow_migration: self.v2.allow_migration,
-            writers: self.v2.writers.clone(),
-        })
+    /// Return the number of the events in the bucket including the ones which are not moved yet.
+    #[inline]
+    pub fn size(&self, bucket: &Bucket) -> u64 {
+        bucket.size() + self.events.len() as u64
     }
 }
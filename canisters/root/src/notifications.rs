@@ -0,0 +1,269 @@
+//! Sending the new users of the root bucket to the index canisters, which index the root
+//! buckets of each user for `get_user_root_buckets`. The users are sharded over the index
+//! canisters listed by the router, which is the only index canister until it is sharded.
+//!
+//! The users are added to an outbox which is part of the [`Data`], so it survives the upgrades,
+//! and are sent in batches. When a batch can not be sent, the next attempt is delayed with an
+//! exponential backoff. The outbox is flushed after the inserts once the delay is over, or by
+//! calling `flush_router_notifications`.
+
+use crate::{Data, FlagGuard};
+use cap_common::did::{
+    index_canister_for, GetIndexCanistersResponse, IndexCanisterId, PendingRouterNotifications,
+    WithWitnessArg,
+};
+use ic_kit::candid::{candid_method, CandidType};
+use ic_kit::macros::*;
+use ic_kit::{ic, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The maximum number of users sent in one batch.
+const BATCH_SIZE: usize = 1_000;
+
+/// The delay before the first retry, in nanoseconds.
+const MIN_BACKOFF: u64 = 10_000_000_000;
+
+/// The maximum delay between two attempts, in nanoseconds.
+const MAX_BACKOFF: u64 = 3_600_000_000_000;
+
+/// The users which are not sent to the router yet.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Outbox {
+    /// The users to send, the oldest first.
+    pub users: Vec<Principal>,
+    /// The number of failed attempts since the last batch that was sent.
+    pub failures: u32,
+    /// The time before which no attempt is made, in nanoseconds.
+    pub retry_at: u64,
+}
+
+/// Marks that a call to [`flush`] is running, this is kept on the heap so it is reset on
+/// upgrades.
+#[derive(Default)]
+struct Flushing(bool);
+
+#[query]
+#[candid_method(query)]
+fn pending_router_notifications() -> PendingRouterNotifications {
+    let outbox = ic::get::<Data>().router_outbox.clone().unwrap_or_default();
+
+    PendingRouterNotifications {
+        users: outbox.users.len() as u64,
+        failures: outbox.failures,
+        retry_at: outbox.retry_at,
+    }
+}
+
+/// Send the pending users to the router, unless the next attempt is delayed.
+#[update]
+#[candid_method(update)]
+async fn flush_router_notifications() -> Result<(), String> {
+    flush().await
+}
+
+/// Add the users to the outbox and start sending them, should be called after inserting events.
+pub fn push(data: &mut Data, users: Vec<Principal>) {
+    if !users.is_empty() {
+        data.router_outbox
+            .get_or_insert_with(Outbox::default)
+            .users
+            .extend(users);
+    }
+
+    #[cfg(not(test))]
+    if is_due(data) && !ic::get::<Flushing>().0 {
+        ic_cdk::spawn(async {
+            let _ = flush().await;
+        });
+    }
+}
+
+/// Returns `true` if there are users to send and the next attempt is not delayed.
+#[cfg(not(test))]
+fn is_due(data: &Data) -> bool {
+    matches!(
+        &data.router_outbox,
+        Some(outbox) if !outbox.users.is_empty() && ic::time() >= outbox.retry_at
+    )
+}
+
+/// Send the pending users to the router, only one call is running at a time.
+pub async fn flush() -> Result<(), String> {
+    if ic::get::<Flushing>().0 {
+        return Ok(());
+    }
+
+    let _flushing = FlagGuard::new(|flushing| ic::get_mut::<Flushing>().0 = flushing);
+    run().await
+}
+
+async fn run() -> Result<(), String> {
+    loop {
+        let data = ic::get::<Data>();
+        let outbox = match &data.router_outbox {
+            Some(outbox) if !outbox.users.is_empty() => outbox,
+            _ => return Ok(()),
+        };
+
+        if ic::time() < outbox.retry_at {
+            return Err(format!(
+                "The next attempt is delayed until {}.",
+                outbox.retry_at
+            ));
+        }
+
+        let batch = outbox.users[..outbox.users.len().min(BATCH_SIZE)].to_vec();
+        let result = send(data.cap_id, *data.bucket.contract_id(), &batch).await;
+
+        // Users are only added at the back while the call is running.
+        let outbox = ic::get_mut::<Data>()
+            .router_outbox
+            .get_or_insert_with(Outbox::default);
+
+        match result {
+            Ok(()) => {
+                outbox.users.drain(..batch.len());
+                outbox.failures = 0;
+                outbox.retry_at = 0;
+            }
+            Err(e) => {
+                outbox.failures = outbox.failures.saturating_add(1);
+                outbox.retry_at = ic::time().saturating_add(backoff(outbox.failures));
+                return Err(format!("Failed to notify the index canisters: {}", e));
+            }
+        }
+    }
+}
+
+/// Send each user to its index canister, the whole batch is sent again if any of the calls
+/// fails since inserting the users is idempotent.
+async fn send(router: Principal, contract: Principal, users: &[Principal]) -> Result<(), String> {
+    let (response,): (GetIndexCanistersResponse,) = ic::call(
+        router,
+        "get_index_canisters",
+        (WithWitnessArg { witness: false },),
+    )
+    .await
+    .map_err(|(_, e)| e)?;
+
+    let mut shards = BTreeMap::<IndexCanisterId, Vec<Principal>>::new();
+    for user in users {
+        let canister = index_canister_for(&response.canisters, user).unwrap_or(router);
+        shards.entry(canister).or_default().push(*user);
+    }
+
+    for (canister, users) in shards {
+        ic::call::<_, (), _>(canister, "insert_new_users", (contract, users))
+            .await
+            .map_err(|(_, e)| e)?;
+    }
+
+    Ok(())
+}
+
+/// The delay before the next attempt after the given number of failed attempts.
+#[inline]
+fn backoff(failures: u32) -> u64 {
+    let exponent = failures.saturating_sub(1).min(32);
+    MIN_BACKOFF.saturating_mul(1 << exponent).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init, insert};
+    use cap_common::transaction::IndefiniteEvent;
+    use ic_kit::candid::{decode_args, encode_args};
+    use ic_kit::{mock_principals, MockContext, RawHandler, RejectionCode};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn event(caller: Principal) -> IndefiniteEvent {
+        IndefiniteEvent {
+            caller,
+            operation: "mint".into(),
+            details: vec![],
+            idempotency_key: None,
+        }
+    }
+
+    #[async_std::test]
+    async fn test_outbox() {
+        let contract = mock_principals::xtc();
+        let online = Rc::new(RefCell::new(false));
+        let received = Rc::new(RefCell::new(Vec::<Principal>::new()));
+        let (is_online, users) = (online.clone(), received.clone());
+        let shards = vec![Principal::from_slice(&[1]), Principal::from_slice(&[2])];
+
+        MockContext::new()
+            .with_caller(contract)
+            .with_handler(RawHandler::raw(Box::new(
+                move |_, args, canister, method| {
+                    if method == "get_index_canisters" {
+                        return Ok(encode_args((GetIndexCanistersResponse {
+                            canisters: shards.clone(),
+                            witness: None,
+                        },))
+                        .unwrap());
+                    }
+
+                    assert_eq!(method, "insert_new_users");
+                    if !*is_online.borrow() {
+                        return Err((RejectionCode::SysTransient, "Offline.".into()));
+                    }
+
+                    let (_, batch): (Principal, Vec<Principal>) = decode_args(args).unwrap();
+                    for user in &batch {
+                        assert_eq!(index_canister_for(&shards, user), Some(*canister));
+                    }
+                    users.borrow_mut().extend(batch);
+                    Ok(encode_args(()).unwrap())
+                },
+            )))
+            .inject();
+
+        init(contract, Default::default(), None);
+        insert(event(mock_principals::alice())).unwrap();
+        insert(event(mock_principals::bob())).unwrap();
+        insert(event(mock_principals::alice())).unwrap();
+        assert_eq!(pending_router_notifications().users, 2);
+
+        assert!(flush().await.is_err());
+        let pending = pending_router_notifications();
+        assert_eq!(pending.users, 2);
+        assert_eq!(pending.failures, 1);
+        assert!(pending.retry_at > ic::time());
+
+        // The next attempt waits for the backoff.
+        *online.borrow_mut() = true;
+        assert!(flush().await.is_err());
+        assert!(received.borrow().is_empty());
+
+        ic::get_mut::<Data>()
+            .router_outbox
+            .as_mut()
+            .unwrap()
+            .retry_at = 0;
+        assert_eq!(flush().await, Ok(()));
+        received.borrow_mut().sort();
+        let mut expected = vec![mock_principals::alice(), mock_principals::bob()];
+        expected.sort();
+        assert_eq!(*received.borrow(), expected);
+        assert_eq!(
+            pending_router_notifications(),
+            PendingRouterNotifications {
+                users: 0,
+                failures: 0,
+                retry_at: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), MIN_BACKOFF);
+        assert_eq!(backoff(2), MIN_BACKOFF * 2);
+        assert_eq!(backoff(100), MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,446 @@
+//! Read-only replicas of the root bucket, so the queries of popular contracts are not all sent
+//! to the root.
+//!
+//! Each replica is a bucket canister which receives a copy of all of the events held by the
+//! root, starting from the offset of the root when the replica was created. It certifies its own tree, so its responses are verified just like the
+//! ones of the root, and its certified size tells how far it is synced. The replicas lag behind
+//! the root, the sizes known by the root are returned by `get_replicas`.
+//!
+//! When the root rolls over, its replicas drop the events that moved to the new bucket and
+//! delegate to it instead.
+//!
+//! The replicas removed from the config are stopped and deleted by the next sync.
+
+use crate::rollover::{create_bucket, install_bucket, read_batch};
+use crate::{Data, FlagGuard};
+use cap_common::did::*;
+use ic_kit::candid::{candid_method, CandidType};
+use ic_kit::interfaces::management::{DeleteCanister, StopCanister, WithCanisterId};
+use ic_kit::interfaces::Method;
+use ic_kit::macros::*;
+use ic_kit::{ic, Principal};
+use serde::{Deserialize, Serialize};
+
+/// The replicas of the root bucket.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Replicas {
+    pub config: ReplicaConfig,
+    pub replicas: Vec<ReplicaState>,
+}
+
+/// The state of a replica as known by the root bucket.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ReplicaState {
+    /// The replica canister, once it is created.
+    pub canister: Option<BucketId>,
+    /// Whether the bucket wasm is installed on the canister.
+    pub installed: bool,
+    /// The id of the first event held by the replica.
+    pub offset: TransactionId,
+    /// The id after the last event copied to the replica.
+    pub size: TransactionId,
+}
+
+/// Marks that a call to [`sync`] is running, this is kept on the heap so it is reset on
+/// upgrades.
+#[derive(Default)]
+struct Syncing(bool);
+
+/// Set the number of read replicas of the root bucket, `None` stops syncing the replicas and
+/// removes them from `get_replicas`. The replicas are created with the wasm set using
+/// `set_bucket_wasm`, the removed replicas are deleted, see `get_removed_replicas`. Can only be
+/// invoked by the contract.
+#[update]
+#[candid_method(update)]
+fn set_replica_config(config: Option<ReplicaConfig>) {
+    let data = ic::get_mut::<Data>();
+
+    if &ic::caller() != data.bucket.contract_id() {
+        ic::trap("The method can only be invoked by the contract.");
+    }
+
+    let mut replicas = data.replicas.take().map_or(vec![], |r| r.replicas);
+    let keep = config.as_ref().map_or(0, |config| config.replicas as usize);
+    for state in replicas.drain(keep.min(replicas.len())..) {
+        if let Some(canister) = state.canister {
+            remove_replica(data, canister);
+        }
+    }
+
+    data.replicas = config.map(|config| Replicas { config, replicas });
+    check(data);
+}
+
+/// Return the replica canisters which were removed from the config and are not deleted yet.
+#[query]
+#[candid_method(query)]
+fn get_removed_replicas() -> Vec<BucketId> {
+    ic::get::<Data>()
+        .removed_replicas
+        .clone()
+        .unwrap_or_default()
+}
+
+#[query]
+#[candid_method(query)]
+fn get_replica_config() -> Option<ReplicaConfig> {
+    ic::get::<Data>()
+        .replicas
+        .as_ref()
+        .map(|r| r.config.clone())
+}
+
+/// Return the replicas that can be queried, along with the number of transactions copied to
+/// each one of them.
+#[query]
+#[candid_method(query)]
+fn get_replicas() -> Vec<Replica> {
+    let replicas = match &ic::get::<Data>().replicas {
+        Some(replicas) => &replicas.replicas,
+        None => return vec![],
+    };
+
+    replicas
+        .iter()
+        .filter(|state| state.installed)
+        .filter_map(|state| {
+            state.canister.map(|canister| Replica {
+                canister,
+                size: state.size,
+            })
+        })
+        .collect()
+}
+
+/// Copy the new events to the replicas, creating the missing replicas.
+#[update]
+#[candid_method(update)]
+async fn replicas_progress() -> Result<(), String> {
+    sync().await
+}
+
+/// Returns `true` if a replica has to be created, deleted or is behind the root bucket.
+fn is_behind(data: &Data) -> bool {
+    if has_removed_replicas(data) {
+        return true;
+    }
+
+    let replicas = match &data.replicas {
+        Some(replicas) => replicas,
+        None => return false,
+    };
+
+    replicas.replicas.len() < replicas.config.replicas as usize
+        || replicas.replicas.iter().any(|state| {
+            state.size < data.bucket.size() || state.offset < data.bucket.bucket.global_offset
+        })
+}
+
+/// Start syncing the replicas if they are behind, should be called after inserting events.
+pub fn check(data: &Data) {
+    if is_behind(data) && !ic::get::<Syncing>().0 {
+        #[cfg(not(test))]
+        ic_cdk::spawn(async {
+            let _ = sync().await;
+        });
+    }
+}
+
+/// Bring all of the replicas up to date with the root bucket, only one call is running at a
+/// time.
+pub async fn sync() -> Result<(), String> {
+    let data = ic::get::<Data>();
+    if (data.replicas.is_none() && !has_removed_replicas(data)) || ic::get::<Syncing>().0 {
+        return Ok(());
+    }
+
+    let _syncing = FlagGuard::new(|syncing| ic::get_mut::<Syncing>().0 = syncing);
+    run().await
+}
+
+async fn run() -> Result<(), String> {
+    delete_removed_replicas().await?;
+
+    let mut index = 0;
+
+    loop {
+        let data = ic::get_mut::<Data>();
+        let offset = data.bucket.bucket.global_offset;
+        let replicas = match &mut data.replicas {
+            Some(replicas) if index < replicas.config.replicas as usize => replicas,
+            _ => return Ok(()),
+        };
+
+        if replicas.replicas.len() == index {
+            replicas.replicas.push(ReplicaState {
+                canister: None,
+                installed: false,
+                offset,
+                size: offset,
+            });
+        }
+
+        sync_replica(index).await?;
+        index += 1;
+    }
+}
+
+async fn sync_replica(index: usize) -> Result<(), String> {
+    let canister = match replica(index)?.canister {
+        Some(canister) => canister,
+        None => {
+            let cycles = match &ic::get::<Data>().replicas {
+                Some(replicas) => replicas.config.cycles,
+                None => return Err("Replicas are disabled.".into()),
+            };
+
+            let canister = create_bucket(cycles).await?;
+            match replica(index) {
+                Ok(state) => state.canister = Some(canister),
+                // The replica was removed while its canister was created.
+                Err(e) => {
+                    remove_replica(ic::get_mut::<Data>(), canister);
+                    return Err(e);
+                }
+            }
+            canister
+        }
+    };
+
+    if !replica(index)?.installed {
+        install_bucket(canister, replica(index)?.offset).await?;
+        replica(index)?.installed = true;
+    }
+
+    loop {
+        let data = ic::get::<Data>();
+        let state = replica(index)?.clone();
+        let offset = data.bucket.bucket.global_offset;
+
+        // The root rolled over since the last sync, the events before its offset are now
+        // held by the bucket at the head of the chain.
+        if state.offset < offset {
+            let bucket = *data
+                .bucket
+                .next_canisters()
+                .first()
+                .ok_or_else(|| "The root bucket has no next canister.".to_string())?;
+
+            ic::call::<_, (), _>(canister, "roll_over", (offset, bucket))
+                .await
+                .map_err(|(_, e)| format!("Failed to roll over the replica: {}", e))?;
+
+            let state = replica(index)?;
+            state.offset = offset;
+            state.size = state.size.max(offset);
+            continue;
+        }
+
+        let size = data.bucket.size();
+        if state.size >= size {
+            return Ok(());
+        }
+
+        let (events, writers) = read_batch(state.size, size);
+        if events.is_empty() {
+            return Err(format!("Transaction {} is missing.", state.size));
+        }
+
+        let len = events.len() as u64;
+        ic::call::<_, (), _>(canister, "insert_many", (events, Some(writers)))
+            .await
+            .map_err(|(_, e)| format!("Failed to copy the events to the replica: {}", e))?;
+
+        replica(index)?.size += len;
+    }
+}
+
+/// Stop and delete the replicas which were removed from the config, a replica is forgotten
+/// once its canister is deleted.
+async fn delete_removed_replicas() -> Result<(), String> {
+    while let Some(canister) = ic::get::<Data>()
+        .removed_replicas
+        .as_ref()
+        .and_then(|removed| removed.first().copied())
+    {
+        let arg = WithCanisterId {
+            canister_id: canister,
+        };
+
+        StopCanister::perform(Principal::management_canister(), (arg.clone(),))
+            .await
+            .map_err(|(_, e)| format!("Failed to stop the replica {}: {}", canister, e))?;
+        DeleteCanister::perform(Principal::management_canister(), (arg,))
+            .await
+            .map_err(|(_, e)| format!("Failed to delete the replica {}: {}", canister, e))?;
+
+        if let Some(removed) = &mut ic::get_mut::<Data>().removed_replicas {
+            removed.retain(|c| c != &canister);
+        }
+    }
+
+    Ok(())
+}
+
+/// Record that the replica canister has to be deleted.
+#[inline]
+fn remove_replica(data: &mut Data, canister: BucketId) {
+    data.removed_replicas
+        .get_or_insert_with(Vec::new)
+        .push(canister);
+}
+
+#[inline]
+fn has_removed_replicas(data: &Data) -> bool {
+    matches!(&data.removed_replicas, Some(removed) if !removed.is_empty())
+}
+
+#[inline]
+fn replica(index: usize) -> Result<&'static mut ReplicaState, String> {
+    ic::get_mut::<Data>()
+        .replicas
+        .as_mut()
+        .and_then(|replicas| replicas.replicas.get_mut(index))
+        .ok_or_else(|| "The replica was removed.".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init, insert};
+    use cap_common::transaction::{Event, IndefiniteEvent};
+    use ic_kit::candid::{decode_args, encode_args};
+    use ic_kit::interfaces::management::WithCanisterId;
+    use ic_kit::{mock_principals, MockContext, Principal, RawHandler};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    fn event(i: u64) -> IndefiniteEvent {
+        IndefiniteEvent {
+            caller: mock_principals::alice(),
+            operation: format!("op-{}", i),
+            details: vec![],
+            idempotency_key: None,
+        }
+    }
+
+    #[async_std::test]
+    async fn test_replicas() {
+        let contract = mock_principals::xtc();
+        let archive = mock_principals::john();
+        let copied = Rc::new(RefCell::new(HashMap::<Principal, Vec<Event>>::new()));
+        let roll_overs = Rc::new(RefCell::new(Vec::new()));
+        let deleted = Rc::new(RefCell::new(Vec::new()));
+        let (events, calls, deletes) = (copied.clone(), roll_overs.clone(), deleted.clone());
+        let created = RefCell::new(0u8);
+
+        let ctx = MockContext::new()
+            .with_caller(contract)
+            .with_handler(RawHandler::raw(Box::new(
+                move |_, args, canister, method| {
+                    Ok(match method {
+                        "create_canister" => {
+                            *created.borrow_mut() += 1;
+                            encode_args((WithCanisterId {
+                                canister_id: Principal::from_slice(&[*created.borrow()]),
+                            },))
+                        }
+                        "insert_many" => {
+                            let (batch,): (Vec<Event>,) = decode_args(args).unwrap();
+                            events
+                                .borrow_mut()
+                                .entry(*canister)
+                                .or_default()
+                                .extend(batch);
+                            encode_args(())
+                        }
+                        "roll_over" => {
+                            let (to, bucket): (u64, Principal) = decode_args(args).unwrap();
+                            calls.borrow_mut().push((*canister, to, bucket));
+                            encode_args(())
+                        }
+                        "delete_canister" => {
+                            let (arg,): (WithCanisterId,) = decode_args(args).unwrap();
+                            deletes.borrow_mut().push(arg.canister_id);
+                            encode_args(())
+                        }
+                        _ => encode_args(()),
+                    }
+                    .unwrap())
+                },
+            )))
+            .inject();
+
+        init(contract, Default::default(), None);
+        ic::get_mut::<Data>().bucket_wasm = Some(vec![0, 97, 115, 109]);
+        set_replica_config(Some(ReplicaConfig {
+            replicas: 2,
+            cycles: 1_000_000_000_000,
+        }));
+
+        for i in 0..10 {
+            insert(event(i)).unwrap();
+        }
+        sync().await.unwrap();
+        ctx.call_state_reset();
+
+        let replicas = get_replicas();
+        assert_eq!(replicas.len(), 2);
+        assert!(replicas.iter().all(|replica| replica.size == 10));
+        assert_eq!(copied.borrow()[&replicas[0].canister].len(), 10);
+        assert_eq!(copied.borrow()[&replicas[1].canister][7].operation, "op-7");
+
+        // The replicas follow the rollovers of the root, even if they are behind it.
+        for i in 10..15 {
+            insert(event(i)).unwrap();
+        }
+        ic::get_mut::<Data>().bucket.roll_over(12, archive);
+        insert(event(15)).unwrap();
+        sync().await.unwrap();
+        ctx.call_state_reset();
+
+        assert!(get_replicas().iter().all(|replica| replica.size == 16));
+        assert_eq!(
+            *roll_overs.borrow(),
+            vec![
+                (replicas[0].canister, 12, archive),
+                (replicas[1].canister, 12, archive)
+            ]
+        );
+        // The events that moved to the archive before they were synced are skipped.
+        assert_eq!(copied.borrow()[&replicas[0].canister].len(), 14);
+        assert_eq!(
+            copied.borrow()[&replicas[0].canister][10].operation,
+            "op-12"
+        );
+
+        set_replica_config(Some(ReplicaConfig {
+            replicas: 1,
+            cycles: 1_000_000_000_000,
+        }));
+        assert_eq!(
+            get_replicas(),
+            vec![Replica {
+                canister: replicas[0].canister,
+                size: 16
+            }]
+        );
+        assert_eq!(get_removed_replicas(), vec![replicas[1].canister]);
+
+        set_replica_config(None);
+        assert_eq!(get_replicas(), vec![]);
+        assert_eq!(
+            get_removed_replicas(),
+            vec![replicas[1].canister, replicas[0].canister]
+        );
+
+        // The removed replicas are deleted even though the replicas are disabled.
+        sync().await.unwrap();
+        assert_eq!(
+            *deleted.borrow(),
+            vec![replicas[1].canister, replicas[0].canister]
+        );
+        assert_eq!(get_removed_replicas(), vec![]);
+    }
+}
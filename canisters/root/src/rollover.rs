@@ -0,0 +1,553 @@
+//! Moving the oldest events of the root bucket to a new bucket canister once the root is full.
+//!
+//! A rollover goes through the following steps, each of them is recorded in [`Rollover`] so an
+//! interrupted rollover continues from where it stopped:
+//!
+//! 1. Seal the events that are currently in the root, newer events keep going to the root.
+//! 2. Create a bucket canister and install the bucket wasm on it.
+//! 3. Copy the sealed events to the bucket in batches, and the events inserted meanwhile as well
+//!    while there are more than [`MAX_REMAINING`] of them.
+//! 4. Remove the sealed events from the root and record the bucket in the certified tree. The
+//!    blocks of the removed events are freed in batches by the following inserts.
+
+use crate::{replicas, Data, FlagGuard};
+use cap_common::did::*;
+use cap_common::transaction::Event;
+use certified_vars::AsHashTree;
+use ic_kit::candid::{candid_method, encode_args, CandidType};
+use ic_kit::interfaces::management::{
+    CreateCanister, CreateCanisterArgument, InstallCode, InstallCodeArgument, InstallMode,
+};
+use ic_kit::interfaces::Method;
+use ic_kit::macros::*;
+use ic_kit::{ic, Principal};
+use serde::{Deserialize, Serialize};
+
+/// The maximum number of events sent to the bucket in one call.
+const BATCH_SIZE: u32 = 1_000;
+
+/// The maximum size of the events sent to the bucket in one call.
+const BATCH_MAX_BYTES: u32 = 1_500_000;
+
+/// The maximum number of events inserted during a rollover which stay in the root, they are
+/// indexed again when the rollover completes. While there are more, they are moved as well.
+const MAX_REMAINING: u64 = 1_000;
+
+/// The state of a rollover that is in progress.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Rollover {
+    /// The id of the first event that is moved.
+    pub from: TransactionId,
+    /// The id after the last event that is moved.
+    pub to: TransactionId,
+    /// The bucket canister the events are moved to, once it is created.
+    pub canister: Option<BucketId>,
+    /// Whether the bucket wasm is installed on the canister.
+    pub installed: bool,
+    /// The id of the next event to copy to the bucket.
+    pub cursor: TransactionId,
+}
+
+/// Marks that a call to [`progress`] is running, this is kept on the heap so it is reset on
+/// upgrades.
+#[derive(Default)]
+struct Running(bool);
+
+/// Set the rollover config of the root bucket, `None` disables rollovers. Can only be invoked by
+/// the contract.
+#[update]
+#[candid_method(update)]
+fn set_rollover_config(config: Option<RolloverConfig>) {
+    let data = ic::get_mut::<Data>();
+
+    if &ic::caller() != data.bucket.contract_id() {
+        ic::trap("The method can only be invoked by the contract.");
+    }
+
+    data.rollover_config = config;
+}
+
+#[query]
+#[candid_method(query)]
+fn get_rollover_config() -> Option<RolloverConfig> {
+    ic::get::<Data>().rollover_config.clone()
+}
+
+/// Set the wasm that is installed on the bucket canisters created by this root. Can only be
+/// invoked by the contract or the router.
+#[update]
+#[candid_method(update)]
+fn set_bucket_wasm(wasm: Vec<u8>) {
+    let data = ic::get_mut::<Data>();
+    let caller = ic::caller();
+
+    if !(caller == data.cap_id || &caller == data.bucket.contract_id()) {
+        ic::trap("The method can only be invoked by the contract or the router.");
+    }
+
+    data.bucket_wasm = Some(wasm);
+}
+
+/// Return the rollover that is in progress, if any.
+#[query]
+#[candid_method(query)]
+fn get_rollover() -> Option<Rollover> {
+    ic::get::<Data>().rollover.clone()
+}
+
+/// Continue the rollover that is in progress, if any.
+#[update]
+#[candid_method(update)]
+async fn rollover_progress() -> Result<(), String> {
+    progress().await
+}
+
+/// Returns `true` if the root bucket should move its events to a new bucket.
+fn is_full(data: &Data) -> bool {
+    let config = match &data.rollover_config {
+        Some(config) if data.bucket_wasm.is_some() => config,
+        _ => return false,
+    };
+
+    if data.bucket.bucket.is_empty() {
+        return false;
+    }
+
+    // The stable memory never shrinks, so only the events still held by the root are counted.
+    let memory = data.bucket.bucket.memory_size();
+    data.bucket.bucket.len() as u64 >= config.max_transactions
+        || matches!(config.max_memory, Some(max) if memory >= max)
+}
+
+/// Start a rollover if the root bucket is full and make progress on the rollover, should be
+/// called after inserting events. No rollover is started before all of the events stored before
+/// the upgrade are moved to the list.
+pub fn check(data: &mut Data) {
+    if data.rollover.is_none() && data.reader.is_none() && is_full(data) {
+        data.rollover = Some(Rollover {
+            from: data.bucket.bucket.global_offset,
+            to: data.bucket.size(),
+            canister: None,
+            installed: false,
+            cursor: data.bucket.bucket.global_offset,
+        });
+    }
+
+    #[cfg(not(test))]
+    if data.rollover.is_some() && !ic::get::<Running>().0 {
+        ic_cdk::spawn(async {
+            let _ = progress().await;
+        });
+    }
+}
+
+/// Make progress on the rollover that is in progress, only one call is running at a time.
+pub async fn progress() -> Result<(), String> {
+    if ic::get::<Data>().rollover.is_none() || ic::get::<Running>().0 {
+        return Ok(());
+    }
+
+    let _running = FlagGuard::new(|running| ic::get_mut::<Running>().0 = running);
+    run().await
+}
+
+async fn run() -> Result<(), String> {
+    let canister = match rollover().canister {
+        Some(canister) => canister,
+        None => {
+            let cycles = match &ic::get::<Data>().rollover_config {
+                Some(config) => config.cycles,
+                None => return Err("Rollovers are disabled.".into()),
+            };
+
+            let canister = create_bucket(cycles).await?;
+            rollover_mut().canister = Some(canister);
+            canister
+        }
+    };
+
+    if !rollover().installed {
+        install_bucket(canister, rollover().from).await?;
+        rollover_mut().installed = true;
+    }
+
+    loop {
+        while rollover().cursor < rollover().to {
+            let Rollover { cursor, to, .. } = *rollover();
+            let (events, writers) = read_batch(cursor, to);
+
+            if events.is_empty() {
+                return Err(format!("Transaction {} is missing.", cursor));
+            }
+
+            let len = events.len() as u64;
+            ic::call::<_, (), _>(canister, "insert_many", (events, Some(writers)))
+                .await
+                .map_err(|(_, e)| format!("Failed to copy the events to the bucket: {}", e))?;
+
+            rollover_mut().cursor += len;
+        }
+
+        let (size,): (u64,) = ic::call(canister, "size", ())
+            .await
+            .map_err(|(_, e)| format!("Failed to read the size of the bucket: {}", e))?;
+
+        let to = rollover().to;
+        if size != to {
+            return Err(format!(
+                "Expected the bucket to have {} transactions, found {}.",
+                to, size
+            ));
+        }
+
+        // The events inserted meanwhile are indexed again by the root, so they are copied as
+        // well while there are too many of them.
+        let size = ic::get::<Data>().bucket.size();
+        if size - to <= MAX_REMAINING {
+            break;
+        }
+
+        rollover_mut().to = size;
+    }
+
+    let to = rollover().to;
+    let data = ic::get_mut::<Data>();
+    data.bucket.roll_over(to, canister);
+    data.rollover = None;
+    ic::set_certified_data(&data.bucket.root_hash());
+    replicas::check(data);
+
+    Ok(())
+}
+
+/// Create a new canister with the given amount of cycles.
+pub(crate) async fn create_bucket(cycles: u64) -> Result<BucketId, String> {
+    let arg = CreateCanisterArgument { settings: None };
+    let (res,) =
+        CreateCanister::perform_with_payment(Principal::management_canister(), (arg,), cycles)
+            .await
+            .map_err(|(_, e)| format!("Failed to create the bucket canister: {}", e))?;
+
+    Ok(res.canister_id)
+}
+
+/// Install the bucket wasm on the canister, the bucket starts at the given offset and follows
+/// the same chain as the root bucket.
+pub(crate) async fn install_bucket(
+    canister: BucketId,
+    offset: TransactionId,
+) -> Result<(), String> {
+    let data = ic::get::<Data>();
+    let wasm_module = data
+        .bucket_wasm
+        .clone()
+        .ok_or_else(|| "The bucket wasm is not set.".to_string())?;
+    let init = BucketInitArgs {
+        contract: *data.bucket.contract_id(),
+        offset,
+        next_canisters: data.bucket.next_canisters().to_vec(),
+        detail_indexes: Some(data.bucket.detail_indexes()),
+        user_index_exclusions: Some(data.bucket.user_index_exclusions()),
+    };
+    let arg = InstallCodeArgument {
+        mode: InstallMode::Install,
+        canister_id: canister,
+        wasm_module,
+        arg: encode_args((init,)).expect("Failed to serialize the install argument."),
+    };
+
+    InstallCode::perform(Principal::management_canister(), (arg,))
+        .await
+        .map_err(|(_, e)| format!("Failed to install the bucket: {}", e))
+}
+
+/// Return the next batch of events to copy to a bucket, starting at `cursor` and stopping
+/// before `to`, along with the writer of each event. The cursor must not be before the offset
+/// of the root bucket.
+pub(crate) fn read_batch(
+    cursor: TransactionId,
+    to: TransactionId,
+) -> (Vec<Event>, Vec<Option<Principal>>) {
+    let list = &ic::get::<Data>().bucket.bucket;
+    let (mut events, _) = list.get_transactions_for_contract_cursor(
+        Some(cursor),
+        Direction::Forward,
+        BATCH_SIZE,
+        Some(BATCH_MAX_BYTES),
+    );
+    events.truncate(to.saturating_sub(cursor) as usize);
+
+    let writers = (cursor..cursor + events.len() as u64)
+        .map(|id| list.get_writer(id))
+        .collect();
+
+    (events, writers)
+}
+
+#[inline]
+fn rollover() -> &'static Rollover {
+    ic::get::<Data>()
+        .rollover
+        .as_ref()
+        .expect("No rollover in progress.")
+}
+
+#[inline]
+fn rollover_mut() -> &'static mut Rollover {
+    ic::get_mut::<Data>()
+        .rollover
+        .as_mut()
+        .expect("No rollover in progress.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{init, insert};
+    use cap_common::transaction::{Event, IndefiniteEvent};
+    use ic_kit::candid::{decode_args, encode_args};
+    use ic_kit::interfaces::management::WithCanisterId;
+    use ic_kit::{mock_principals, MockContext, RawHandler, RejectionCode};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn event(i: u64) -> IndefiniteEvent {
+        IndefiniteEvent {
+            caller: mock_principals::alice(),
+            operation: format!("op-{}", i),
+            details: vec![],
+            idempotency_key: None,
+        }
+    }
+
+    #[async_std::test]
+    async fn test_rollover() {
+        let contract = mock_principals::xtc();
+        let bucket = mock_principals::john();
+        let copied = Rc::new(RefCell::new(Vec::<Event>::new()));
+        let events = copied.clone();
+        let copied_writers = Rc::new(RefCell::new(Vec::<Option<Principal>>::new()));
+        let writers = copied_writers.clone();
+
+        MockContext::new()
+            .with_caller(contract)
+            .with_handler(RawHandler::raw(Box::new(move |_, args, _, method| {
+                Ok(match method {
+                    "create_canister" => encode_args((WithCanisterId {
+                        canister_id: bucket,
+                    },)),
+                    "insert_many" => {
+                        let (batch, batch_writers): (Vec<Event>, Option<Vec<Option<Principal>>>) =
+                            decode_args(args).unwrap();
+                        events.borrow_mut().extend(batch);
+                        writers.borrow_mut().extend(batch_writers.unwrap());
+                        encode_args(())
+                    }
+                    "size" => encode_args((events.borrow().len() as u64,)),
+                    _ => encode_args(()),
+                }
+                .unwrap())
+            })))
+            .inject();
+
+        init(contract, Default::default(), None);
+        set_bucket_wasm(vec![0, 97, 115, 109]);
+        set_rollover_config(Some(RolloverConfig {
+            max_transactions: 100,
+            max_memory: None,
+            cycles: 1_000_000_000_000,
+        }));
+
+        for i in 0..99 {
+            insert(event(i)).unwrap();
+        }
+        assert_eq!(get_rollover(), None);
+
+        insert(event(99)).unwrap();
+        assert_eq!(get_rollover().unwrap().to, 100);
+
+        // Events inserted during the rollover stay in the root.
+        insert(event(100)).unwrap();
+        progress().await.unwrap();
+
+        let data = ic::get::<Data>();
+        assert_eq!(get_rollover(), None);
+        assert_eq!(copied.borrow().len(), 100);
+        assert_eq!(copied.borrow()[42].operation, "op-42");
+        assert_eq!(*copied_writers.borrow(), vec![Some(contract); 100]);
+        assert_eq!(data.bucket.size(), 101);
+        assert_eq!(data.bucket.bucket.len(), 1);
+        assert_eq!(data.bucket.next_canisters(), &[bucket]);
+        assert_eq!(data.bucket.buckets(), vec![(0, bucket), (100, ic::id())]);
+
+        match data.bucket.get_transaction(WithIdArg {
+            id: 42,
+            witness: false,
+        }) {
+            GetTransactionResponse::Delegate(id, _) => assert_eq!(id, bucket),
+            _ => panic!("Expected a delegated response."),
+        }
+    }
+
+    #[async_std::test]
+    async fn test_memory_rollover() {
+        let contract = mock_principals::xtc();
+        let copied = Rc::new(RefCell::new(0u64));
+        let count = copied.clone();
+
+        let ctx = MockContext::new()
+            .with_caller(contract)
+            .with_handler(RawHandler::raw(Box::new(move |_, args, _, method| {
+                Ok(match method {
+                    "create_canister" => encode_args((WithCanisterId {
+                        canister_id: mock_principals::john(),
+                    },)),
+                    "insert_many" => {
+                        let (batch,): (Vec<Event>,) = decode_args(args).unwrap();
+                        *count.borrow_mut() += batch.len() as u64;
+                        encode_args(())
+                    }
+                    "size" => encode_args((*count.borrow(),)),
+                    _ => encode_args(()),
+                }
+                .unwrap())
+            })))
+            .inject();
+
+        init(contract, Default::default(), None);
+        set_bucket_wasm(vec![0, 97, 115, 109]);
+
+        insert(event(0)).unwrap();
+        let event_size = ic::get::<Data>().bucket.bucket.memory_size();
+        set_rollover_config(Some(RolloverConfig {
+            max_transactions: 1_000_000,
+            max_memory: Some(event_size * 10),
+            cycles: 1_000_000_000_000,
+        }));
+
+        for i in 1..10 {
+            insert(event(i)).unwrap();
+        }
+        assert_eq!(get_rollover().unwrap().to, 10);
+        progress().await.unwrap();
+        ctx.call_state_reset();
+        assert_eq!(get_rollover(), None);
+        assert_eq!(*copied.borrow(), 10);
+
+        // The stable memory does not shrink after the rollover, but the root is not full.
+        insert(event(10)).unwrap();
+        assert_eq!(get_rollover(), None);
+        assert_eq!(ic::get::<Data>().bucket.bucket.len(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_rollover_moves_new_events() {
+        let contract = mock_principals::xtc();
+        let copied = Rc::new(RefCell::new(0u64));
+        let count = copied.clone();
+        let sizes = Rc::new(RefCell::new(0));
+        let calls = sizes.clone();
+
+        MockContext::new()
+            .with_caller(contract)
+            .with_handler(RawHandler::raw(Box::new(move |_, args, _, method| {
+                Ok(match method {
+                    "create_canister" => encode_args((WithCanisterId {
+                        canister_id: mock_principals::john(),
+                    },)),
+                    "insert_many" => {
+                        let (batch,): (Vec<Event>,) = decode_args(args).unwrap();
+                        *count.borrow_mut() += batch.len() as u64;
+                        encode_args(())
+                    }
+                    "size" => {
+                        // Too many events are inserted during the rollover to keep them.
+                        *calls.borrow_mut() += 1;
+                        if *calls.borrow() == 1 {
+                            let data = ic::get_mut::<Data>();
+                            for i in 100..1_200 {
+                                data.bucket.insert(event(i).to_event(i));
+                            }
+                        }
+                        encode_args((*count.borrow(),))
+                    }
+                    _ => encode_args(()),
+                }
+                .unwrap())
+            })))
+            .inject();
+
+        init(contract, Default::default(), None);
+        set_bucket_wasm(vec![0, 97, 115, 109]);
+        set_rollover_config(Some(RolloverConfig {
+            max_transactions: 100,
+            max_memory: None,
+            cycles: 1_000_000_000_000,
+        }));
+
+        for i in 0..100 {
+            insert(event(i)).unwrap();
+        }
+        progress().await.unwrap();
+
+        let data = ic::get::<Data>();
+        assert_eq!(get_rollover(), None);
+        assert_eq!(*sizes.borrow(), 2);
+        assert_eq!(*copied.borrow(), 1_200);
+        assert_eq!(data.bucket.size(), 1_200);
+        assert!(data.bucket.bucket.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_rollover_retry() {
+        let contract = mock_principals::xtc();
+        let attempts = Rc::new(RefCell::new(0));
+        let count = attempts.clone();
+
+        MockContext::new()
+            .with_caller(contract)
+            .with_handler(RawHandler::raw(Box::new(
+                move |_, _, _, method| match method {
+                    "create_canister" => {
+                        *count.borrow_mut() += 1;
+                        Err((RejectionCode::SysTransient, "Out of cycles.".into()))
+                    }
+                    _ => Ok(encode_args(()).unwrap()),
+                },
+            )))
+            .inject();
+
+        init(contract, Default::default(), None);
+        set_bucket_wasm(vec![0, 97, 115, 109]);
+        set_rollover_config(Some(RolloverConfig {
+            max_transactions: 1,
+            max_memory: None,
+            cycles: 1_000_000_000_000,
+        }));
+        insert(event(0)).unwrap();
+
+        // A failed attempt doesn't keep the next ones from running.
+        assert!(progress().await.is_err());
+        assert!(progress().await.is_err());
+        assert_eq!(*attempts.borrow(), 2);
+        assert!(!ic::get::<Running>().0);
+    }
+
+    #[test]
+    #[should_panic(expected = "The method can only be invoked by the contract.")]
+    fn test_set_rollover_config_denied() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::xtc())
+            .inject();
+
+        init(
+            mock_principals::xtc(),
+            vec![mock_principals::bob()].into_iter().collect(),
+            None,
+        );
+
+        // Writers can only insert events.
+        ctx.update_caller(mock_principals::bob());
+        set_rollover_config(None);
+    }
+}
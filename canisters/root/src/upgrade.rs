@@ -1,22 +1,49 @@
 use crate::migration::{v0, v1, v2};
-use crate::Data;
-use crate::{migration, InProgressReadFromStable};
-use ic_cdk::{id, spawn};
+use crate::multi_stage_reader::InProgressReadFromStable;
+use crate::{migration, Data};
+use cap_common::bucket::Bucket;
+use cap_common::memory::StableMemory;
+use cap_common::store;
+use cap_common::TransactionList;
+use certified_vars::AsHashTree;
+use ic_cdk::spawn;
 use ic_kit::macros::{post_upgrade, pre_upgrade, update};
 use ic_kit::{ic, Principal};
 use std::collections::HashSet;
 
-const UPGRADE_SIZE: usize = 10_000;
+/// The number of the events moved to the list by each call to `upgrade_progress`.
+pub(crate) const UPGRADE_SIZE: usize = 2_000;
+
+/// The number of the events moved to the list by an insert during a read from stable.
+pub(crate) const INSERT_UPGRADE_SIZE: usize = 100;
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    if ic::get_maybe::<InProgressReadFromStable>().is_some() {
+    // If data doesn't exits, don't rewrite the stable store.
+    if ic::get_maybe::<Data>().is_none() {
         return;
     }
-    // If data doesn't exits, don't rewrite the stable store.
-    if let Some(data) = ic::get_maybe::<Data>() {
-        ic::stable_store((data,)).expect("Failed to serialize data.");
+
+    let data = ic::get_mut::<Data>();
+    if !data.bucket.bucket.is_stable() {
+        data.bucket.bucket.set_stable();
+    }
+
+    // The list is already in the stable memory, only store the rest of the data after it.
+    let bytes = serde_cbor::to_vec(&*data).expect("Failed to serialize data.");
+    data.bucket.bucket.write_upgrade_data(&bytes);
+}
+
+/// Move the list of the data to the stable memory if it's not already there, and store it.
+pub(crate) fn store_data(mut data: Data) {
+    if !data.bucket.bucket.is_stable() {
+        data.bucket.bucket.set_stable();
     }
+    data.bucket.set_writers(&data.writers);
+    data.bucket.init_ranges(ic::id());
+
+    ic::set_certified_data(&data.bucket.root_hash());
+    ic::store(data);
 }
 
 // Currently all of the Cap bucket's are on the following git hashes:
@@ -29,39 +56,28 @@ fn pre_upgrade() {
 // whq4n-xiaaa-aaaam-qaazq-cai  - WICPs canister
 #[post_upgrade]
 pub fn post_upgrade() {
-    if Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap() == id() {
-        let (data,): (Data,) = ic::stable_restore().expect("Failed to deserialize");
+    // Canisters that already keep their list in the stable memory only need to read the data
+    // stored after it, the events and the indexes are left in place.
+    if let Some(bytes) = store::take_upgrade_data(&mut StableMemory) {
+        let mut data: Data = serde_cbor::from_slice(&bytes).expect("Failed to deserialize");
+        data.bucket.set_writers(&data.writers);
+        data.bucket.init_ranges(ic::id());
+        ic::set_certified_data(&data.bucket.root_hash());
         ic::store(data);
         return;
     }
 
+    if Principal::from_text("whq4n-xiaaa-aaaam-qaazq-cai").unwrap() == ic::id() {
+        let (data,): (v2::Data,) = ic::stable_restore().expect("Failed to deserialize");
+        store_data(migrate_v2(data));
+        return;
+    }
+
     let from_v0 = ["3qxje-uqaaa-aaaah-qcn4q-cai", "whq4n-xiaaa-aaaam-qaazq-cai"]
         .iter()
         .map(|text| Principal::from_text(text).unwrap())
         .collect::<HashSet<_>>();
 
-    let more_than_10k = [
-        "riufi-uyaaa-aaaam-qaaiq-cai",
-        "ugjiu-taaaa-aaaam-qaaua-cai",
-        "mq76s-ciaaa-aaaah-qc2va-cai",
-        "j5ua3-6yaaa-aaaak-aaggq-cai",
-        "q675d-biaaa-aaaam-qaanq-cai",
-        "mm3ed-viaaa-aaaah-qc2xa-cai",
-        "he2ur-tqaaa-aaaan-qabja-cai",
-        "6rjbk-7qaaa-aaaah-qczvq-cai",
-        "eineg-yqaaa-aaaan-qabda-cai",
-        "szgqq-gyaaa-aaaab-qaebq-cai",
-        "m5na3-faaaa-aaaan-qaawa-cai",
-        "bqswi-zaaaa-aaaah-abkza-cai",
-        "ebop2-oyaaa-aaaan-qabcq-cai",
-        "rrr4x-hqaaa-aaaam-qamga-cai",
-        "mm3ed-viaaa-aaaah-qc2xa-cai",
-        "myux7-2yaaa-aaaap-aah3q-cai",
-    ]
-    .iter()
-    .map(|text| Principal::from_text(text).unwrap())
-    .collect::<HashSet<_>>();
-
     let id = ic::id();
 
     if from_v0.contains(&id) {
@@ -69,21 +85,31 @@ pub fn post_upgrade() {
         return;
     }
 
-    if more_than_10k.contains(&id) {
-        let (data,): (v2::Data,) = ic::stable_restore().unwrap_or_else(|m| {
-            ic::trap(&format!(
-                "M10K: Could not deserialize data as v2::Data: {}",
-                m
-            ))
-        });
-
-        ic::store(InProgressReadFromStable::new(data));
+    let (data,): (v2::Data,) = ic::stable_restore().expect("Failed to deserialize");
+    store_data(migrate_v2(data));
+}
 
-        return;
+/// Build the data from the data stored before the list was kept in the stable memory. The list
+/// is empty, the events are moved to it in batches by `upgrade_progress`.
+fn migrate_v2(data: v2::Data) -> Data {
+    let v1::TransactionListDe(offset, _, events) = data.bucket.bucket;
+    let mut list = TransactionList::new(data.bucket.contract, offset);
+    list.set_stable();
+
+    Data {
+        bucket: Bucket::with_transaction_list(list),
+        users: data.users,
+        cap_id: data.cap_id,
+        allow_migration: data.allow_migration,
+        writers: data.writers,
+        rollover_config: None,
+        bucket_wasm: None,
+        rollover: None,
+        replicas: None,
+        router_outbox: None,
+        removed_replicas: None,
+        reader: Some(InProgressReadFromStable::new(events)),
     }
-
-    let (data,): (Data,) = ic::stable_restore().expect("Failed to deserialize");
-    ic::store(data);
 }
 
 fn rescue() -> Result<(), String> {
@@ -91,8 +117,7 @@ fn rescue() -> Result<(), String> {
 
     match migration::from_stable::<v0::Data>() {
         Ok(v0) => {
-            let data = v0.migrate().migrate();
-            ic::store(InProgressReadFromStable::new(data));
+            store_data(migrate_v2(v0.migrate().migrate()));
             return Ok(());
         }
         Err(e) => message = format!("{} - ErrV0: {}", message, e),
@@ -100,8 +125,7 @@ fn rescue() -> Result<(), String> {
 
     match migration::from_stable::<v1::Data>() {
         Ok(v1) => {
-            let data = v1.migrate();
-            ic::store(InProgressReadFromStable::new(data));
+            store_data(migrate_v2(v1.migrate()));
             return Ok(());
         }
         Err(e) => message = format!("{} - ErrV0: {}", message, e),
@@ -110,40 +134,36 @@ fn rescue() -> Result<(), String> {
     Err(message)
 }
 
-/// Perform the leftover tasks from the upgrade.
-#[update]
-pub fn upgrade_progress() {
-    let number_of_spawns = {
-        if ic::get_maybe::<InProgressReadFromStable>().is_none() {
-            return;
-        }
+/// Move up to `n` of the events stored before the upgrade to the list, the reader is deleted
+/// once all of them are moved. Return the number of the events which were moved.
+pub(crate) fn progress(data: &mut Data, n: usize) -> usize {
+    let reader = match &mut data.reader {
+        Some(reader) => reader,
+        None => return 0,
+    };
 
-        let c = ic::get_mut::<InProgressReadFromStable>();
-        // are we the top-level upgrade_progress call?
-        let is_main = c.cursor <= 1_000;
-        c.progress(UPGRADE_SIZE);
+    let moved = reader.progress(&mut data.bucket, n);
+    if reader.is_complete() {
+        data.reader = None;
+    }
 
-        if c.is_complete() {
-            let data = c.get_data().unwrap();
-            ic::store(data);
-            ic::delete::<InProgressReadFromStable>();
-        }
+    if moved > 0 {
+        ic::set_certified_data(&data.bucket.root_hash());
+    }
 
-        if !is_main {
-            // if we're not the top-level call, don't spawn anything.
-            0
-        } else {
-            // ceiling division.
-            (c.rem() + UPGRADE_SIZE - 1) / UPGRADE_SIZE
-        }
-    };
+    moved
+}
 
-    for _ in 0..number_of_spawns {
+/// Perform the leftover tasks from the upgrade, each call moves a batch of the events to the
+/// list and calls itself again until all of them are moved.
+#[update]
+pub fn upgrade_progress() {
+    let data = ic::get_mut::<Data>();
+    progress(data, UPGRADE_SIZE);
+
+    if data.reader.is_some() {
         spawn(async {
-            match ic::call::<(), (), &str>(ic::id(), "upgrade_progress", ()).await {
-                Ok(_) => {}
-                Err(_) => {}
-            }
+            let _ = ic::call::<(), (), &str>(ic::id(), "upgrade_progress", ()).await;
         });
     }
 }
@@ -151,14 +171,18 @@ pub fn upgrade_progress() {
 #[cfg(test)]
 mod tests {
     use crate::migration::*;
-    use crate::upgrade::{post_upgrade, upgrade_progress};
-    use crate::{insert, insert_many, Data, Principal};
+    use crate::upgrade::{
+        post_upgrade, pre_upgrade, upgrade_progress, INSERT_UPGRADE_SIZE, UPGRADE_SIZE,
+    };
+    use crate::{init, insert, insert_many, Data, Principal};
     use candid::encode_args;
     use cap_common::transaction::{DetailValue, Event, IndefiniteEvent};
 
     use certified_vars::Map;
     use certified_vars::{AsHashTree, Seq};
     use ic_kit::{ic, MockContext, RawHandler};
+    use std::cell::Cell;
+    use std::rc::Rc;
 
     /// Create a mock indefinite event.
     fn event(i: usize) -> IndefiniteEvent {
@@ -166,6 +190,7 @@ mod tests {
             caller: Principal::management_canister(),
             operation: format!("op-{}", i),
             details: vec![("something".into(), DetailValue::U64(i as u64))],
+            idempotency_key: None,
         }
     }
 
@@ -183,15 +208,23 @@ mod tests {
         events
     }
 
+    /// Return the number of the events in the list.
+    fn size() -> u64 {
+        ic::get::<Data>().bucket.size()
+    }
+
     fn test_rescue<F: Fn(Vec<Event>)>(id: Principal, title: &'static str, store: F) {
-        MockContext::new()
-            .with_handler(RawHandler::raw(Box::new(move |_, _, _, _| {
-                println!("{}: Still running upgrade progress.", title);
-                upgrade_progress();
+        let caller = Principal::from_text("3xwpq-ziaaa-aaaah-qcn4a-cai").unwrap();
+        let self_calls = Rc::new(Cell::new(0));
+        let counter = self_calls.clone();
+        let ctx = MockContext::new()
+            .with_handler(RawHandler::raw(Box::new(move |_, _, _, method| {
+                assert_eq!(method, "upgrade_progress");
+                counter.set(counter.get() + 1);
                 Ok(encode_args(()).unwrap())
             })))
             .with_id(id)
-            .with_caller(Principal::from_text("3xwpq-ziaaa-aaaah-qcn4a-cai").unwrap())
+            .with_caller(caller)
             .inject();
 
         println!("{}: Creating events.", title);
@@ -201,27 +234,83 @@ mod tests {
         println!("{}: Storing data to stable storage", title);
         store(events);
 
-        // Now try to decode from v0 to latest using the post_upgrade
+        // Now try to decode from v0 to latest using the post_upgrade, which doesn't move any
+        // of the events.
         println!("{}: running post_upgrade", title);
         post_upgrade();
 
+        let data = ic::get::<Data>();
+        assert!(data.bucket.bucket.is_stable());
+        assert_eq!(size(), 0);
+        assert_eq!(data.reader.as_ref().unwrap().rem(), 25_000);
+
         println!(
             "{}: sending transactions during active upgrade process",
             title
         );
-        insert(event(25_000));
-        insert(event(25_001));
-        let _id = insert(event(25_002));
-        insert_many(vec![event(25_003), event(25_004), event(25_005)]);
-
-        // Auto called by router.
-        println!("{}: initial call to upgrade_progress", title);
-        upgrade_progress();
-
-        // Now we should have data.
-        let data = ic::get_maybe::<Data>().expect("Data is not created.");
-        data.bucket.root_hash();
-        assert_eq!(data.bucket.size(), 25_006);
+        let keyed = |i: usize, key: &str| IndefiniteEvent {
+            idempotency_key: Some(key.into()),
+            ..event(i)
+        };
+        // Each insert moves at most a small batch of the events.
+        let step = |f: &dyn Fn() -> u64| {
+            let before = size();
+            let id = f();
+            assert!(size() - before <= INSERT_UPGRADE_SIZE as u64);
+            id
+        };
+
+        assert_eq!(step(&|| insert(keyed(25_000, "a")).unwrap()), 25_000);
+        step(&|| insert(event(25_001)).unwrap());
+        assert_eq!(step(&|| insert(keyed(25_000, "a")).unwrap()), 25_000);
+        step(&|| insert(event(25_002)).unwrap());
+        let batch = || vec![keyed(25_000, "a"), event(25_003), keyed(25_004, "b")];
+        assert_eq!(step(&|| insert_many(batch()).unwrap()), 25_003);
+        step(&|| insert_many(vec![keyed(25_004, "b"), event(25_005)]).unwrap());
+
+        // The read continues after an upgrade.
+        let moved = size();
+        pre_upgrade();
+        ic::delete::<Data>();
+        post_upgrade();
+        assert_eq!(size(), moved);
+        assert_eq!(
+            ic::get::<Data>().reader.as_ref().unwrap().rem() as u64,
+            25_006 - moved
+        );
+
+        // Auto called by router, each call moves a batch and calls itself until it's done.
+        println!("{}: running upgrade_progress", title);
+        let mut calls = 0;
+        while ic::get::<Data>().reader.is_some() {
+            let before = size();
+            upgrade_progress();
+            calls += 1;
+            assert!(size() - before <= UPGRADE_SIZE as u64);
+        }
+        assert_eq!(self_calls.get(), calls - 1);
+
+        let data = ic::get::<Data>();
+        assert_eq!(size(), 25_006);
+        assert_eq!(
+            ctx.get_certified_data(),
+            Some(data.bucket.root_hash().to_vec())
+        );
+
+        // The idempotency keys and the writers of the events inserted during the upgrade are
+        // recorded.
+        assert_eq!(data.bucket.get_id_for_key("a"), Some(25_000));
+        assert_eq!(data.bucket.get_id_for_key("b"), Some(25_004));
+        assert_eq!(data.bucket.bucket.get_writer(24_999), None);
+        assert_eq!(data.bucket.bucket.get_writer(25_003), Some(caller));
+        assert_eq!(
+            data.bucket
+                .bucket
+                .get_transaction(25_005)
+                .unwrap()
+                .operation,
+            "op-25005"
+        );
     }
 
     #[test]
@@ -264,26 +353,6 @@ mod tests {
         });
     }
 
-    #[test]
-    fn test_from_v2_10k() {
-        let id = Principal::from_text("riufi-uyaaa-aaaam-qaaiq-cai").unwrap();
-        test_rescue(id, "v2-10k", |events| {
-            let data = v2::Data {
-                bucket: v2::Bucket {
-                    bucket: v1::TransactionListDe(0, ic::id(), events),
-                    buckets: Map::new(),
-                    next_canisters: Seq::new(),
-                    contract: Principal::from_text("3xwpq-ziaaa-aaaah-qcn4a-cai").unwrap(),
-                },
-                users: Default::default(),
-                cap_id: Principal::from_text("lj532-6iaaa-aaaah-qcc7a-cai").unwrap(),
-                allow_migration: false,
-                writers: Default::default(),
-            };
-            data.store();
-        });
-    }
-
     #[test]
     fn test_from_v2_normal() {
         let id = Principal::from_text("lhtux-ciaaa-aaaag-qakpa-cai").unwrap();
@@ -304,6 +373,47 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_upgrade_with_event_log() {
+        let contract = Principal::from_text("3xwpq-ziaaa-aaaah-qcn4a-cai").unwrap();
+        MockContext::new().with_caller(contract).inject();
+
+        init(contract, Default::default(), Some(vec!["something".into()]));
+        for i in 0..100 {
+            insert(event(i)).unwrap();
+        }
+
+        let data = ic::get::<Data>();
+        assert!(data.bucket.bucket.is_stable());
+        let root_hash = data.bucket.root_hash();
+
+        pre_upgrade();
+        ic::delete::<Data>();
+        post_upgrade();
+
+        let data = ic::get::<Data>();
+        assert!(data.bucket.bucket.is_stable());
+        assert_eq!(data.bucket.size(), 100);
+        assert_eq!(data.bucket.root_hash(), root_hash);
+        assert_eq!(data.bucket.detail_indexes(), vec!["something".to_string()]);
+
+        // The upgrade data is overwritten by the new events.
+        insert(event(100)).unwrap();
+        let root_hash = ic::get::<Data>().bucket.root_hash();
+
+        pre_upgrade();
+        ic::delete::<Data>();
+        post_upgrade();
+
+        let data = ic::get::<Data>();
+        assert_eq!(data.bucket.size(), 101);
+        assert_eq!(data.bucket.root_hash(), root_hash);
+        assert_eq!(
+            data.bucket.bucket.get_transaction(100).unwrap().operation,
+            "op-100"
+        );
+    }
+
     // #[test]
     // fn decode() {
     //     let data = include_bytes!("/Users/parsa/Projects/neuron-hunter/file.bin");
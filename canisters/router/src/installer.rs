@@ -26,12 +26,23 @@ pub struct InstallCodeArgumentBorrowed<'a> {
 
 #[update]
 #[candid_method(update)]
-async fn install_bucket_code(canister_id: RootBucketId) {
+async fn install_bucket_code(canister_id: RootBucketId, detail_indexes: Option<Vec<String>>) {
     let contract_id = ic::caller();
-    install_code(canister_id, contract_id, &[]).await;
+    install_code(
+        canister_id,
+        contract_id,
+        &[],
+        &detail_indexes.unwrap_or_default(),
+    )
+    .await;
 }
 
-pub async fn install_code(canister_id: Principal, contract_id: Principal, writers: &[Principal]) {
+pub async fn install_code(
+    canister_id: Principal,
+    contract_id: Principal,
+    writers: &[Principal],
+    detail_indexes: &[String],
+) {
     use management::{CanisterStatus, WithCanisterId};
 
     let data = ic::get_mut::<Data>();
@@ -61,8 +72,8 @@ pub async fn install_code(canister_id: Principal, contract_id: Principal, writer
         );
     }
 
-    let arg =
-        encode_args((contract_id, writers)).expect("Failed to serialize the install argument.");
+    let arg = encode_args((contract_id, writers, Some(detail_indexes)))
+        .expect("Failed to serialize the install argument.");
 
     let install_config = InstallCodeArgumentBorrowed {
         mode: InstallMode::Install,
@@ -85,4 +96,6 @@ pub async fn install_code(canister_id: Principal, contract_id: Principal, writer
         .entry(Principal::management_canister())
         .or_insert(Seq::new())
         .append(canister_id);
+
+    ic::set_certified_data(&data.root_hash());
 }
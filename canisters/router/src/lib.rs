@@ -1,7 +1,7 @@
 use certified_vars::Map;
 use certified_vars::{
     hashtree::{fork, fork_hash},
-    AsHashTree, HashTree, Seq,
+    AsHashTree, Hash, HashTree, Seq,
 };
 use ic_kit::{
     candid::{candid_method, export_service, CandidType},
@@ -16,17 +16,21 @@ use ic_kit::{
 use serde::{Deserialize, Serialize};
 
 // It's ok.
+use cap_common::user_index::{add_user_root_bucket, UserCanisters};
 use cap_common::*;
 
 mod installer;
+mod migration;
 mod plug;
+mod reconcile;
 mod upgrade;
 
 /// Merkle tree of the canister.
 ///
 /// 0: Canister Map
 /// 1: User canisters
-/// 2: Index canisters list
+/// 2: Index canisters list, the users are sharded over these canisters, see
+///    [`index_canister_for`]. The list only contains the router until index canisters are set.
 ///
 ///      ROOT
 ///     /   \
@@ -36,12 +40,26 @@ mod upgrade;
 pub struct Data {
     /// Map: TokenContractId -> RootBucketId
     pub root_buckets: Map<TokenContractId, RootBucketId>,
-    /// Map each user to RootBucketId
-    pub user_canisters: Map<UserId, Seq<RootBucketId>>,
+    /// Map each user to RootBucketId, only holds the users of the router once the users are
+    /// sharded over other index canisters.
+    pub user_canisters: UserCanisters,
     /// List of the index canisters.
     pub index_canisters: Seq<IndexCanisterId>,
 }
 
+impl Data {
+    /// Return the root hash of the merkle tree of the canister.
+    pub fn root_hash(&self) -> Hash {
+        fork_hash(
+            &fork_hash(
+                &self.root_buckets.root_hash(),
+                &self.user_canisters.root_hash(),
+            ),
+            &self.index_canisters.root_hash(),
+        )
+    }
+}
+
 impl Default for Data {
     fn default() -> Self {
         Data {
@@ -135,6 +153,28 @@ fn get_index_canisters(arg: WithWitnessArg) -> GetIndexCanistersResponse {
     GetIndexCanistersResponse { canisters, witness }
 }
 
+/// Shard the users over the given index canisters, the root buckets send their new users to
+/// the index canister of each user from then on. The users which are already indexed are moved
+/// to their new index canister by a migration, see `get_user_migration_report`.
+#[update]
+#[candid_method(update)]
+fn set_index_canisters(canisters: Vec<IndexCanisterId>) {
+    if !upgrade::is_admin(&ic::caller()) {
+        panic!("Only an admin can set the index canisters.")
+    }
+
+    if canisters.is_empty() {
+        panic!("Expected at least one index canister.")
+    }
+
+    let data = ic::get_mut::<Data>();
+    let previous = data.index_canisters.as_vec().clone();
+    data.index_canisters = canisters.into();
+    ic::set_certified_data(&data.root_hash());
+
+    migration::start(previous);
+}
+
 #[update]
 #[candid_method(update)]
 fn insert_new_users(contract_id: Principal, users: Vec<Principal>) {
@@ -148,11 +188,10 @@ fn insert_new_users(contract_id: Principal, users: Vec<Principal>) {
     );
 
     for user in users {
-        data.user_canisters
-            .entry(user)
-            .or_insert(Seq::new())
-            .append(root_bucket);
+        add_user_root_bucket(&mut data.user_canisters, user, root_bucket);
     }
+
+    ic::set_certified_data(&data.root_hash());
 }
 
 #[query]
@@ -0,0 +1,292 @@
+//! Moving the users to their index canister when the list of index canisters changes.
+//!
+//! The users are assigned to the index canisters by [`index_canister_for`], so a change of the
+//! list only moves some of the users. The users of each index canister of the previous or the
+//! new list which now belong to another one are exported in pages, imported in their new index
+//! canister and then removed from the old one. The router is migrated like any other index
+//! canister, so its users leave its heap once it is not in the list anymore.
+//!
+//! The outcome is reported by `get_user_migration_report`, a migration which failed or which
+//! was interrupted by an upgrade is started again with `migrate_users`.
+
+use crate::reconcile::rejected;
+use crate::upgrade::is_admin;
+use crate::Data;
+use cap_common::user_index;
+use cap_common::{
+    index_canister_for, ExportUsersResponse, IndexCanisterId, RootBucketId, UserId,
+    UserMigrationReport,
+};
+use ic_kit::candid::candid_method;
+use ic_kit::ic;
+use ic_kit::macros::*;
+use std::collections::BTreeMap;
+
+/// The number of users read from an index canister in one call.
+const PAGE_SIZE: u32 = 5_000;
+
+/// The report of the last migration, this is kept on the heap so a migration which is
+/// interrupted by an upgrade has to be started again.
+#[derive(Default)]
+struct Migration(Option<UserMigrationReport>);
+
+/// Move the users of the given index canisters and of the ones in the list to their index
+/// canister, only one migration runs at a time.
+#[update]
+#[candid_method(update)]
+fn migrate_users(canisters: Vec<IndexCanisterId>) {
+    if !is_admin(&ic::caller()) {
+        panic!("Only an admin can start a migration.")
+    }
+
+    start(canisters);
+}
+
+/// Return the report of the running migration, or of the last one.
+#[query]
+#[candid_method(query)]
+fn get_user_migration_report() -> Option<UserMigrationReport> {
+    ic::get::<Migration>().0.clone()
+}
+
+/// Start a migration of the users of the given index canisters and of the ones in the list.
+pub(crate) fn start(canisters: Vec<IndexCanisterId>) {
+    let migration = ic::get_mut::<Migration>();
+    if matches!(&migration.0, Some(report) if report.finished_at.is_none()) {
+        panic!("A migration is already running.")
+    }
+
+    let mut sources = canisters;
+    for canister in ic::get::<Data>().index_canisters.as_vec() {
+        if !sources.contains(canister) {
+            sources.push(*canister);
+        }
+    }
+
+    migration.0 = Some(UserMigrationReport {
+        started_at: ic::time(),
+        ..Default::default()
+    });
+
+    ic_cdk::spawn(run(sources));
+}
+
+async fn run(sources: Vec<IndexCanisterId>) {
+    for source in sources {
+        let result = migrate(source).await;
+        let report = report_mut();
+
+        match result {
+            Ok(moved) => report.migrated.push((source, moved)),
+            Err(e) => report.failed.push((source, e)),
+        }
+    }
+
+    report_mut().finished_at = Some(ic::time());
+}
+
+/// Move the users of the index canister which belong to another one, returns the number of
+/// moved users. The users which got a new root bucket while they were moved are kept by the
+/// index canister, they are moved by another pass over its users.
+async fn migrate(source: IndexCanisterId) -> Result<u64, String> {
+    let mut moved = 0;
+
+    loop {
+        let mut cursor: Option<UserId> = None;
+        let mut kept = 0;
+
+        loop {
+            let page = export(source, cursor).await?;
+            let count = page.users.len() as u64;
+            import(&page.users).await?;
+            let removed = remove(source, page.users).await?;
+
+            moved += removed;
+            kept += count - removed;
+
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        if kept == 0 {
+            return Ok(moved);
+        }
+    }
+}
+
+/// Read a page of the users of the index canister which belong to another one.
+async fn export(
+    source: IndexCanisterId,
+    cursor: Option<UserId>,
+) -> Result<ExportUsersResponse, String> {
+    let data = ic::get::<Data>();
+    let canisters = data.index_canisters.as_vec().clone();
+
+    if source == ic::id() {
+        let users = &data.user_canisters;
+        let page = user_index::export_users(users, &source, &canisters, cursor, PAGE_SIZE);
+        return Ok(page);
+    }
+
+    let (page,): (ExportUsersResponse,) =
+        ic::call(source, "export_users", (canisters, cursor, PAGE_SIZE))
+            .await
+            .map_err(rejected)?;
+
+    Ok(page)
+}
+
+/// Add the users to their index canister.
+async fn import(users: &[(UserId, Vec<RootBucketId>)]) -> Result<(), String> {
+    let index_canisters = ic::get::<Data>().index_canisters.as_vec();
+    let mut shards = BTreeMap::<IndexCanisterId, Vec<(UserId, Vec<RootBucketId>)>>::new();
+
+    for (user, root_buckets) in users {
+        let canister = index_canister_for(index_canisters, user).unwrap_or_else(ic::id);
+        shards
+            .entry(canister)
+            .or_default()
+            .push((*user, root_buckets.clone()));
+    }
+
+    for (canister, users) in shards {
+        if canister == ic::id() {
+            let data = ic::get_mut::<Data>();
+            user_index::import_users(&mut data.user_canisters, users);
+            ic::set_certified_data(&data.root_hash());
+        } else {
+            ic::call::<_, (), _>(canister, "import_users", (users,))
+                .await
+                .map_err(rejected)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the moved users from the index canister, returns the number of removed users.
+async fn remove(
+    source: IndexCanisterId,
+    users: Vec<(UserId, Vec<RootBucketId>)>,
+) -> Result<u64, String> {
+    if source == ic::id() {
+        let data = ic::get_mut::<Data>();
+        let removed = user_index::remove_users(&mut data.user_canisters, &users);
+        ic::set_certified_data(&data.root_hash());
+        return Ok(removed);
+    }
+
+    let (removed,): (u64,) = ic::call(source, "remove_users", (users,))
+        .await
+        .map_err(rejected)?;
+
+    Ok(removed)
+}
+
+#[inline]
+fn report_mut() -> &'static mut UserMigrationReport {
+    ic::get_mut::<Migration>()
+        .0
+        .get_or_insert_with(UserMigrationReport::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_common::user_index::{add_user_root_bucket, UserCanisters};
+    use ic_kit::candid::{decode_args, encode_args};
+    use ic_kit::{MockContext, Principal, RawHandler};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[async_std::test]
+    async fn test_migrate_users() {
+        let shard = Principal::from_slice(&[2]);
+        let root = Principal::from_slice(&[1]);
+        let users = (0..50u8)
+            .map(|i| Principal::from_slice(&[10, i]))
+            .collect::<Vec<_>>();
+
+        // The index canister of the shard.
+        let index = Rc::new(RefCell::new(UserCanisters::new()));
+        let held = index.clone();
+
+        MockContext::new()
+            .with_handler(RawHandler::raw(Box::new(
+                move |_, args, canister, method| {
+                    assert_eq!(canister, &shard);
+                    let mut index = held.borrow_mut();
+                    match method {
+                        "export_users" => {
+                            let (canisters, cursor, limit): (Vec<_>, _, _) =
+                                decode_args(args).unwrap();
+                            let page =
+                                user_index::export_users(&index, &shard, &canisters, cursor, limit);
+                            Ok(encode_args((page,)).unwrap())
+                        }
+                        "import_users" => {
+                            let (users,) = decode_args(args).unwrap();
+                            user_index::import_users(&mut index, users);
+                            Ok(encode_args(()).unwrap())
+                        }
+                        "remove_users" => {
+                            let (users,): (Vec<(UserId, Vec<RootBucketId>)>,) =
+                                decode_args(args).unwrap();
+                            let removed = user_index::remove_users(&mut index, &users);
+                            Ok(encode_args((removed,)).unwrap())
+                        }
+                        _ => panic!("Unexpected method {}.", method),
+                    }
+                },
+            )))
+            .inject();
+
+        let data = ic::get_mut::<Data>();
+        for user in &users {
+            add_user_root_bucket(&mut data.user_canisters, *user, root);
+        }
+
+        // Add an index canister next to the router, the users which go to it are moved.
+        data.index_canisters = vec![ic::id(), shard].into();
+        ic::get_mut::<Migration>().0 = Some(UserMigrationReport::default());
+        run(vec![ic::id(), shard]).await;
+
+        let data = ic::get::<Data>();
+        let index_canisters = data.index_canisters.as_vec();
+        let moved = users
+            .iter()
+            .filter(|user| index_canister_for(index_canisters, user) == Some(shard))
+            .count() as u64;
+        assert!(moved > 0);
+
+        let report = get_user_migration_report().unwrap();
+        assert_eq!(report.migrated, vec![(ic::id(), moved), (shard, 0)]);
+        assert!(report.failed.is_empty());
+        assert!(report.finished_at.is_some());
+
+        for user in &users {
+            let local = data.user_canisters.get(user).is_some();
+            let remote = index.borrow().get(user).is_some();
+            assert_ne!(local, remote);
+            assert_eq!(
+                remote,
+                index_canister_for(index_canisters, user) == Some(shard)
+            );
+        }
+
+        // Removing the router from the list moves all of its users out of its heap.
+        ic::get_mut::<Data>().index_canisters = vec![shard].into();
+        ic::get_mut::<Migration>().0 = Some(UserMigrationReport::default());
+        run(vec![ic::id(), shard]).await;
+
+        assert!(ic::get::<Data>().user_canisters.is_empty());
+        assert_eq!(index.borrow().len(), users.len());
+        assert_eq!(
+            get_user_migration_report().unwrap().migrated,
+            vec![(ic::id(), users.len() as u64 - moved), (shard, 0)]
+        );
+        assert_eq!(index.borrow().get(&users[0]).unwrap().as_vec(), &vec![root]);
+    }
+}
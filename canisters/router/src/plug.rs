@@ -42,5 +42,5 @@ async fn deploy_plug_bucket(contract_id: Principal, cycles: u64) {
     .expect("Failed to create the canister.");
     let canister_id = res.canister_id;
 
-    install_code(canister_id, contract_id, &[*PLUG_PROXY_ID]).await;
+    install_code(canister_id, contract_id, &[*PLUG_PROXY_ID], &[]).await;
 }
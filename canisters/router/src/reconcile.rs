@@ -0,0 +1,325 @@
+//! Repairing the index of the users from the root buckets.
+//!
+//! The root buckets send their new users to the router with `insert_new_users`, when one of
+//! these calls is lost the root bucket is missing from the `get_user_root_buckets` of the users.
+//! A reconciliation reads the users of every root bucket with `get_users` and adds the missing
+//! root buckets, the outcome is reported by `get_reconciliation_report`.
+//!
+//! Once the users are sharded over index canisters, the missing root buckets are added in the
+//! index canister of each user.
+
+use crate::upgrade::is_admin;
+use crate::Data;
+use cap_common::user_index::add_user_root_bucket;
+use cap_common::{
+    index_canister_for, GetUsersResponse, IndexCanisterId, ReconciliationReport, RootBucketId,
+    UserId,
+};
+use ic_kit::candid::candid_method;
+use ic_kit::macros::*;
+use ic_kit::{ic, RejectionCode};
+use std::collections::BTreeMap;
+
+/// The number of users requested from a root bucket in one call.
+const PAGE_SIZE: u32 = 5_000;
+
+/// The report of the last reconciliation, this is kept on the heap so a reconciliation which
+/// is interrupted by an upgrade has to be started again.
+#[derive(Default)]
+struct Reconciliation(Option<ReconciliationReport>);
+
+/// Start a reconciliation of the index of the users with the root buckets, only one
+/// reconciliation runs at a time.
+#[update]
+#[candid_method(update)]
+fn reconcile_users() {
+    if !is_admin(&ic::caller()) {
+        panic!("Only an admin can start a reconciliation.")
+    }
+
+    let reconciliation = ic::get_mut::<Reconciliation>();
+    if matches!(&reconciliation.0, Some(report) if report.finished_at.is_none()) {
+        panic!("A reconciliation is already running.")
+    }
+
+    reconciliation.0 = Some(ReconciliationReport {
+        started_at: ic::time(),
+        ..Default::default()
+    });
+
+    ic_cdk::spawn(run());
+}
+
+/// Return the report of the running reconciliation, or of the last one.
+#[query]
+#[candid_method(query)]
+fn get_reconciliation_report() -> Option<ReconciliationReport> {
+    ic::get::<Reconciliation>().0.clone()
+}
+
+/// Finishes the report when it is dropped, which is also the case when one of the callbacks of
+/// the reconciliation traps, so the next reconciliation can be started. Holds the root bucket
+/// which is being checked, it is reported as failed if the reconciliation is interrupted.
+struct Finish(Option<RootBucketId>);
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        let report = report_mut();
+        if let Some(root_bucket) = self.0 {
+            let error = "The reconciliation was interrupted.".to_string();
+            report.failed.push((root_bucket, error));
+        }
+        report.finished_at = Some(ic::time());
+    }
+}
+
+async fn run() {
+    let mut finish = Finish(None);
+    let root_buckets = ic::get::<Data>()
+        .root_buckets
+        .iter()
+        .map(|(_, root_bucket)| *root_bucket)
+        .collect::<Vec<_>>();
+
+    for root_bucket in root_buckets {
+        finish.0 = Some(root_bucket);
+        let result = reconcile_root_bucket(root_bucket).await;
+        finish.0 = None;
+        let report = report_mut();
+
+        match result {
+            Ok(0) => report.roots_checked += 1,
+            Ok(repaired) => {
+                report.roots_checked += 1;
+                report.repaired.push((root_bucket, repaired));
+            }
+            Err(e) => report.failed.push((root_bucket, e)),
+        }
+    }
+}
+
+/// Read the users of the root bucket and add it to the ones which are missing it, returns the
+/// number of repaired users.
+async fn reconcile_root_bucket(root_bucket: RootBucketId) -> Result<u64, String> {
+    let mut cursor: Option<UserId> = None;
+    let mut repaired = 0;
+
+    loop {
+        let (page,): (GetUsersResponse,) = ic::call(root_bucket, "get_users", (cursor, PAGE_SIZE))
+            .await
+            .map_err(rejected)?;
+
+        report_mut().users_checked += page.users.len() as u64;
+        repaired += repair(root_bucket, page.users).await?;
+
+        match page.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => return Ok(repaired),
+        }
+    }
+}
+
+/// Add the root bucket to the users which are missing it in their index canister, returns the
+/// number of repaired users.
+async fn repair(root_bucket: RootBucketId, users: Vec<UserId>) -> Result<u64, String> {
+    let index_canisters = ic::get::<Data>().index_canisters.as_vec();
+    let mut shards = BTreeMap::<IndexCanisterId, Vec<UserId>>::new();
+
+    for user in users {
+        let canister = index_canister_for(index_canisters, &user).unwrap_or_else(ic::id);
+        shards.entry(canister).or_default().push(user);
+    }
+
+    let mut repaired = 0;
+
+    for (canister, users) in shards {
+        if canister == ic::id() {
+            let data = ic::get_mut::<Data>();
+            for user in users {
+                if add_user_root_bucket(&mut data.user_canisters, user, root_bucket) {
+                    repaired += 1;
+                }
+            }
+            ic::set_certified_data(&data.root_hash());
+        } else {
+            let (count,): (u64,) = ic::call(canister, "repair_users", (root_bucket, users))
+                .await
+                .map_err(rejected)?;
+            repaired += count;
+        }
+    }
+
+    Ok(repaired)
+}
+
+#[inline]
+pub(crate) fn rejected((code, message): (RejectionCode, String)) -> String {
+    format!("Code: {:?}, Message: {}", code, message)
+}
+
+#[inline]
+fn report_mut() -> &'static mut ReconciliationReport {
+    ic::get_mut::<Reconciliation>()
+        .0
+        .get_or_insert_with(ReconciliationReport::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::insert_new_users;
+    use ic_kit::candid::{decode_args, encode_args};
+    use ic_kit::{mock_principals, MockContext, Principal, RawHandler};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[async_std::test]
+    async fn test_reconcile_users() {
+        let alice = mock_principals::alice();
+        let bob = mock_principals::bob();
+        let (xtc, root) = (mock_principals::xtc(), Principal::from_slice(&[1]));
+        let (other, offline) = (Principal::from_slice(&[2]), Principal::from_slice(&[3]));
+
+        let mut users = BTreeMap::new();
+        users.insert(root, vec![alice, bob]);
+        users.insert(other, vec![bob]);
+
+        MockContext::new()
+            .with_caller(root)
+            .with_handler(RawHandler::raw(Box::new(
+                move |_, args, canister, method| {
+                    assert_eq!(method, "get_users");
+                    let (cursor, _): (Option<UserId>, u32) = decode_args(args).unwrap();
+                    let users = match users.get(canister) {
+                        Some(users) => users,
+                        None => return Err((RejectionCode::DestinationInvalid, "Offline.".into())),
+                    };
+
+                    // Return one user at a time.
+                    let start = match cursor {
+                        Some(cursor) => users.iter().position(|u| u == &cursor).unwrap() + 1,
+                        None => 0,
+                    };
+                    let page = GetUsersResponse {
+                        users: vec![users[start]],
+                        next_cursor: users.get(start + 1).map(|_| users[start]),
+                    };
+                    Ok(encode_args((page,)).unwrap())
+                },
+            )))
+            .inject();
+
+        let data = ic::get_mut::<Data>();
+        data.root_buckets.insert(xtc, root);
+        data.root_buckets.insert(alice, other);
+        data.root_buckets.insert(bob, offline);
+
+        // Only the notification of bob was lost.
+        insert_new_users(xtc, vec![alice]);
+        insert_new_users(xtc, vec![alice]);
+        assert_eq!(
+            ic::get::<Data>().user_canisters.get(&alice).unwrap().len(),
+            1
+        );
+
+        ic::get_mut::<Reconciliation>().0 = Some(ReconciliationReport::default());
+        run().await;
+
+        let report = get_reconciliation_report().unwrap();
+        assert!(report.finished_at.is_some());
+        assert_eq!(report.roots_checked, 2);
+        assert_eq!(report.users_checked, 3);
+        assert_eq!(report.repaired.len(), 2);
+        assert!(report.repaired.contains(&(root, 1)));
+        assert!(report.repaired.contains(&(other, 1)));
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, offline);
+
+        let data = ic::get::<Data>();
+        assert_eq!(data.user_canisters.get(&alice).unwrap().as_vec(), &[root]);
+        assert_eq!(data.user_canisters.get(&bob).unwrap().len(), 2);
+
+        // A second run has nothing to repair.
+        ic::get_mut::<Reconciliation>().0 = Some(ReconciliationReport::default());
+        run().await;
+        assert!(get_reconciliation_report().unwrap().repaired.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_reconcile_shards() {
+        let (xtc, root) = (mock_principals::xtc(), Principal::from_slice(&[1]));
+        let shard = Principal::from_slice(&[2]);
+        let users = (0..20u8)
+            .map(|i| Principal::from_slice(&[10, i]))
+            .collect::<Vec<_>>();
+        let sent = Rc::new(RefCell::new(Vec::<UserId>::new()));
+        let (all, received) = (users.clone(), sent.clone());
+
+        MockContext::new()
+            .with_handler(RawHandler::raw(Box::new(
+                move |_, args, canister, method| match method {
+                    "get_users" => Ok(encode_args((GetUsersResponse {
+                        users: all.clone(),
+                        next_cursor: None,
+                    },))
+                    .unwrap()),
+                    "repair_users" => {
+                        assert_eq!(canister, &shard);
+                        let (root_bucket, users): (RootBucketId, Vec<UserId>) =
+                            decode_args(args).unwrap();
+                        assert_eq!(root_bucket, root);
+                        received.borrow_mut().extend(users.iter().copied());
+                        Ok(encode_args((users.len() as u64,)).unwrap())
+                    }
+                    _ => panic!("Unexpected method {}.", method),
+                },
+            )))
+            .inject();
+
+        let data = ic::get_mut::<Data>();
+        data.root_buckets.insert(xtc, root);
+        data.index_canisters = vec![ic::id(), shard].into();
+
+        ic::get_mut::<Reconciliation>().0 = Some(ReconciliationReport::default());
+        run().await;
+
+        let report = get_reconciliation_report().unwrap();
+        assert_eq!(report.repaired, vec![(root, 20)]);
+
+        // Each user is repaired in its own index canister.
+        let data = ic::get::<Data>();
+        let index_canisters = data.index_canisters.as_vec();
+        for user in &users {
+            let local = data.user_canisters.get(user).is_some();
+            let remote = sent.borrow().contains(user);
+            assert_ne!(local, remote);
+            assert_eq!(
+                local,
+                index_canister_for(index_canisters, user) == Some(ic::id())
+            );
+        }
+        assert!(!sent.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_reconcile_trap() {
+        let (xtc, root) = (mock_principals::xtc(), Principal::from_slice(&[1]));
+
+        MockContext::new()
+            .with_handler(RawHandler::raw(Box::new(|_, _, _, _| {
+                panic!("The callback trapped.")
+            })))
+            .inject();
+
+        ic::get_mut::<Data>().root_buckets.insert(xtc, root);
+        ic::get_mut::<Reconciliation>().0 = Some(ReconciliationReport::default());
+        let run = std::panic::AssertUnwindSafe(|| async_std::task::block_on(run()));
+        assert!(std::panic::catch_unwind(run).is_err());
+
+        // The interrupted reconciliation is finished, so another one can be started.
+        let report = get_reconciliation_report().unwrap();
+        assert!(report.finished_at.is_some());
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, root);
+    }
+}
@@ -5,7 +5,7 @@ use ic_kit::ic;
 use ic_kit::macros::{post_upgrade, pre_upgrade, query, update};
 use ic_kit::Principal;
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeSet, HashSet};
+use std::collections::BTreeSet;
 
 #[derive(Default, CandidType, Serialize, Deserialize)]
 struct RootBucketsToUpgrade(Vec<RootBucketId>);
@@ -24,6 +24,7 @@ fn post_upgrade() {
     let (data, to_upgrade): (Data, RootBucketsToUpgrade) =
         ic::stable_restore().expect("Failed to deserialize.");
 
+    ic::set_certified_data(&data.root_hash());
     ic::store(data);
     ic::store(to_upgrade);
 
@@ -94,34 +95,8 @@ async fn upgrade_root_bucket(canister_id: Principal) {
         return;
     }
 
-    let large_canisters = [
-        "3qxje-uqaaa-aaaah-qcn4q-cai",
-        "whq4n-xiaaa-aaaam-qaazq-cai",
-        "riufi-uyaaa-aaaam-qaaiq-cai",
-        "ugjiu-taaaa-aaaam-qaaua-cai",
-        "mq76s-ciaaa-aaaah-qc2va-cai",
-        "j5ua3-6yaaa-aaaak-aaggq-cai",
-        "q675d-biaaa-aaaam-qaanq-cai",
-        "mm3ed-viaaa-aaaah-qc2xa-cai",
-        "he2ur-tqaaa-aaaan-qabja-cai",
-        "6rjbk-7qaaa-aaaah-qczvq-cai",
-        "eineg-yqaaa-aaaan-qabda-cai",
-        "szgqq-gyaaa-aaaab-qaebq-cai",
-        "m5na3-faaaa-aaaan-qaawa-cai",
-        "bqswi-zaaaa-aaaah-abkza-cai",
-        "ebop2-oyaaa-aaaan-qabcq-cai",
-        "rrr4x-hqaaa-aaaam-qamga-cai",
-        "mm3ed-viaaa-aaaah-qc2xa-cai",
-        "myux7-2yaaa-aaaap-aah3q-cai",
-    ]
-    .iter()
-    .map(|text| Principal::from_text(text).unwrap())
-    .collect::<HashSet<_>>();
-
-    if !large_canisters.contains(&canister_id) {
-        return;
-    }
-
+    // Start moving the events of the canisters which were upgraded from the old layout to the
+    // stable memory, this is a no-op for the other ones.
     let _ = ic::call::<_, (), _>(canister_id, "upgrade_progress", ()).await;
 }
 
@@ -137,19 +112,7 @@ fn root_buckets_to_upgrade() -> (usize, Vec<RootBucketId>) {
 async fn custom_upgrade_root_bucket(canister_id: Principal, wasm: Option<Vec<u8>>) -> String {
     use crate::installer::{InstallCodeArgumentBorrowed, WASM};
     use ic_kit::interfaces::management::InstallMode;
-    let caller = ic::caller();
-
-    let parsa =
-        Principal::from_text("qti3e-ren42-maxnk-dwpe5-h4hhi-zgnmd-fm4ak-o2vfg-64r7w-al6hm-zqe")
-            .unwrap();
-    let janison =
-        Principal::from_text("63wyd-ar7cf-pnlor-3ovyf-i6gkl-rmbea-6cpau-pw3xk-epqjz-bqjvt-2qe")
-            .unwrap();
-    let oz =
-        Principal::from_text("ffuck-kxghi-gyvia-r5htr-246cy-acq5u-2tdgd-avtvf-jyqbt-xtmf7-cae")
-            .unwrap();
-
-    if !(caller == parsa || caller == janison || caller == oz) {
+    if !is_admin(&ic::caller()) {
         panic!("Only Janison or Parsa can call this method.")
     }
 
@@ -180,3 +143,18 @@ async fn custom_upgrade_root_bucket(canister_id: Principal, wasm: Option<Vec<u8>
         result.err().unwrap().1
     }
 }
+
+/// Returns `true` if the principal can call the admin methods of the router.
+pub(crate) fn is_admin(principal: &Principal) -> bool {
+    let parsa =
+        Principal::from_text("qti3e-ren42-maxnk-dwpe5-h4hhi-zgnmd-fm4ak-o2vfg-64r7w-al6hm-zqe")
+            .unwrap();
+    let janison =
+        Principal::from_text("63wyd-ar7cf-pnlor-3ovyf-i6gkl-rmbea-6cpau-pw3xk-epqjz-bqjvt-2qe")
+            .unwrap();
+    let oz =
+        Principal::from_text("ffuck-kxghi-gyvia-r5htr-246cy-acq5u-2tdgd-avtvf-jyqbt-xtmf7-cae")
+            .unwrap();
+
+    principal == &parsa || principal == &janison || principal == &oz
+}
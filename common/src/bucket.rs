@@ -1,18 +1,22 @@
 use crate::did::*;
+use crate::range_map::RangeMap;
 use crate::transaction::Event;
 use crate::TransactionList;
 use certified_vars::hashtree::{fork, fork_hash};
 use certified_vars::{AsHashTree, Hash, HashTree, Map, Seq};
-use ic_kit::candid::CandidType;
 use ic_kit::Principal;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 
-#[derive(CandidType, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct Bucket {
     pub bucket: TransactionList,
-    buckets: Map<TransactionId, Principal>,
+    buckets: RangeMap,
     next_canisters: Seq<BucketId>,
     contract: TokenContractId,
+    /// The canisters other than the contract which are allowed to insert into the root bucket,
+    /// each mapped to `true`. Buckets serialized before the writers were certified don't have it.
+    writers: Option<Map<Principal, bool>>,
 }
 
 impl Bucket {
@@ -20,9 +24,10 @@ impl Bucket {
     pub fn new(contract: TokenContractId, offset: u64) -> Self {
         Self {
             bucket: TransactionList::new(contract, offset),
-            buckets: Map::new(),
+            buckets: RangeMap::new(),
             next_canisters: Seq::new(),
             contract,
+            writers: None,
         }
     }
 
@@ -30,9 +35,10 @@ impl Bucket {
         let contract = *list.contract_id();
         Self {
             bucket: list,
-            buckets: Map::new(),
+            buckets: RangeMap::new(),
             next_canisters: Seq::new(),
             contract,
+            writers: None,
         }
     }
 
@@ -45,7 +51,10 @@ impl Bucket {
                         &self.bucket.root_hash(),
                         &self.buckets.root_hash(),
                     )),
-                    self.next_canisters.as_hash_tree(),
+                    fork(
+                        self.next_canisters.as_hash_tree(),
+                        HashTree::Pruned(self.writers_hash()),
+                    ),
                 )
                 .into(),
             ),
@@ -56,7 +65,39 @@ impl Bucket {
         GetNextCanistersResponse { canisters, witness }
     }
 
+    pub fn get_writers(&self, arg: WithWitnessArg) -> GetWritersResponse {
+        let witness = match arg.witness {
+            false => None,
+            true => Some(
+                fork(
+                    HashTree::Pruned(fork_hash(
+                        &self.bucket.root_hash(),
+                        &self.buckets.root_hash(),
+                    )),
+                    fork(
+                        HashTree::Pruned(self.next_canisters.root_hash()),
+                        self.writers
+                            .as_ref()
+                            .map_or(HashTree::Empty, |writers| writers.as_hash_tree()),
+                    ),
+                )
+                .into(),
+            ),
+        };
+
+        GetWritersResponse {
+            writers: self.writers(),
+            witness,
+        }
+    }
+
     pub fn get_transaction(&self, arg: WithIdArg) -> GetTransactionResponse {
+        if arg.id < self.bucket.global_offset {
+            if let Some(response) = self.delegate_transaction(&arg) {
+                return response;
+            }
+        }
+
         let witness = match arg.witness {
             false => None,
             true => Some(
@@ -65,7 +106,7 @@ impl Bucket {
                         self.bucket.witness_transaction(arg.id),
                         HashTree::Pruned(self.buckets.root_hash()),
                     ),
-                    HashTree::Pruned(self.next_canisters.root_hash()),
+                    HashTree::Pruned(self.canisters_hash()),
                 )
                 .into(),
             ),
@@ -73,15 +114,46 @@ impl Bucket {
 
         let event = self.bucket.get_transaction(arg.id);
 
-        // TODO(qti3e) We're going to be in this release, take another look.
-        // We are not multi-canistered yet.
-        GetTransactionResponse::Found(event.cloned(), witness)
+        GetTransactionResponse::Found(event, witness)
+    }
+
+    /// Return the bucket that holds a transaction which is older than the ones in this
+    /// bucket, this is either one of the buckets created by this bucket or the next canister
+    /// in the chain.
+    fn delegate_transaction(&self, arg: &WithIdArg) -> Option<GetTransactionResponse> {
+        let (canister, buckets, next_canisters) = match self.buckets.floor(arg.id) {
+            Some((_, canister)) => (
+                canister,
+                self.buckets.witness_floor(arg.id),
+                HashTree::Pruned(self.canisters_hash()),
+            ),
+            None => (
+                *self.next_canisters.as_vec().first()?,
+                HashTree::Pruned(self.buckets.root_hash()),
+                fork(
+                    self.next_canisters.as_hash_tree(),
+                    HashTree::Pruned(self.writers_hash()),
+                ),
+            ),
+        };
+
+        let witness = match arg.witness {
+            false => None,
+            true => Some(
+                fork(
+                    fork(self.bucket.witness_transaction(arg.id), buckets),
+                    next_canisters,
+                )
+                .into(),
+            ),
+        };
+
+        Some(GetTransactionResponse::Delegate(canister, witness))
     }
 
-    pub fn get_transactions(&self, arg: GetTransactionsArg) -> GetTransactionsResponseBorrowed {
-        let page = arg
-            .page
-            .unwrap_or_else(|| self.bucket.last_page_for_contract(&self.contract));
+    pub fn get_transactions(&self, arg: GetTransactionsArg) -> GetTransactionsResponse {
+        let last_page = self.bucket.last_page_for_contract(&self.contract);
+        let page = arg.page.unwrap_or(last_page);
 
         let witness = match arg.witness {
             false => None,
@@ -92,7 +164,7 @@ impl Bucket {
                             .witness_transactions_for_contract(&self.contract, page),
                         HashTree::Pruned(self.buckets.root_hash()),
                     ),
-                    HashTree::Pruned(self.next_canisters.root_hash()),
+                    HashTree::Pruned(self.canisters_hash()),
                 )
                 .into(),
             ),
@@ -102,20 +174,41 @@ impl Bucket {
             .bucket
             .get_transactions_for_contract(&self.contract, page);
 
-        GetTransactionsResponseBorrowed {
+        GetTransactionsResponse {
             data: events,
             page,
+            total_items: self.bucket.count_for_contract(&self.contract),
+            last_page,
+            has_more: page < last_page,
             witness,
         }
     }
 
-    pub fn get_user_transactions(
-        &self,
-        arg: GetUserTransactionsArg,
-    ) -> GetTransactionsResponseBorrowed {
-        let page = arg
-            .page
-            .unwrap_or_else(|| self.bucket.last_page_for_user(&arg.user));
+    pub fn get_user_transactions(&self, arg: GetUserTransactionsArg) -> GetTransactionsResponse {
+        if let Some(roles) = &arg.roles {
+            // The filtered pages are not certified, so there is no witness to return.
+            if arg.witness {
+                ic_kit::ic::trap("A witness can not be requested along with roles.");
+            }
+
+            let total_items = self.bucket.count_for_user_with_roles(&arg.user, roles);
+            let last_page = (total_items.saturating_sub(1) / 64) as u32;
+            let page = arg.page.unwrap_or(last_page);
+
+            return GetTransactionsResponse {
+                data: self
+                    .bucket
+                    .get_transactions_for_user_with_roles(&arg.user, roles, page),
+                page,
+                total_items,
+                last_page,
+                has_more: page < last_page,
+                witness: None,
+            };
+        }
+
+        let last_page = self.bucket.last_page_for_user(&arg.user);
+        let page = arg.page.unwrap_or(last_page);
 
         let witness = match arg.witness {
             false => None,
@@ -125,7 +218,7 @@ impl Bucket {
                         self.bucket.witness_transactions_for_user(&arg.user, page),
                         HashTree::Pruned(self.buckets.root_hash()),
                     ),
-                    HashTree::Pruned(self.next_canisters.root_hash()),
+                    HashTree::Pruned(self.canisters_hash()),
                 )
                 .into(),
             ),
@@ -133,20 +226,123 @@ impl Bucket {
 
         let events = self.bucket.get_transactions_for_user(&arg.user, page);
 
-        GetTransactionsResponseBorrowed {
+        GetTransactionsResponse {
+            data: events,
+            page,
+            total_items: self.bucket.count_for_user(&arg.user),
+            last_page,
+            has_more: page < last_page,
+            witness,
+        }
+    }
+
+    pub fn get_token_transactions(&self, arg: GetTokenTransactionsArg) -> GetTransactionsResponse {
+        let token = arg.token();
+        let last_page = self.bucket.last_page_for_token(&token);
+        let page = arg.page.unwrap_or(last_page);
+
+        let witness = match arg.witness {
+            false => None,
+            true => Some(
+                fork(
+                    fork(
+                        self.bucket.witness_transactions_for_token(&token, page),
+                        HashTree::Pruned(self.buckets.root_hash()),
+                    ),
+                    HashTree::Pruned(self.canisters_hash()),
+                )
+                .into(),
+            ),
+        };
+
+        let events = self.bucket.get_transactions_for_token(&token, page);
+
+        GetTransactionsResponse {
+            data: events,
+            page,
+            total_items: self.bucket.count_for_token(&token),
+            last_page,
+            has_more: page < last_page,
+            witness,
+        }
+    }
+
+    pub fn get_operation_transactions(
+        &self,
+        arg: GetOperationTransactionsArg,
+    ) -> GetTransactionsResponse {
+        let last_page = self.bucket.last_page_for_operation(&arg.operation);
+        let page = arg.page.unwrap_or(last_page);
+
+        let witness = match arg.witness {
+            false => None,
+            true => Some(
+                fork(
+                    fork(
+                        self.bucket
+                            .witness_transactions_for_operation(&arg.operation, page),
+                        HashTree::Pruned(self.buckets.root_hash()),
+                    ),
+                    HashTree::Pruned(self.canisters_hash()),
+                )
+                .into(),
+            ),
+        };
+
+        let events = self
+            .bucket
+            .get_transactions_for_operation(&arg.operation, page);
+
+        GetTransactionsResponse {
+            data: events,
+            page,
+            total_items: self.bucket.count_for_operation(&arg.operation),
+            last_page,
+            has_more: page < last_page,
+            witness,
+        }
+    }
+
+    pub fn get_writer_transactions(
+        &self,
+        arg: GetWriterTransactionsArg,
+    ) -> GetTransactionsResponse {
+        let last_page = self.bucket.last_page_for_writer(&arg.writer);
+        let page = arg.page.unwrap_or(last_page);
+
+        let witness = match arg.witness {
+            false => None,
+            true => Some(
+                fork(
+                    fork(
+                        self.bucket
+                            .witness_transactions_for_writer(&arg.writer, page),
+                        HashTree::Pruned(self.buckets.root_hash()),
+                    ),
+                    HashTree::Pruned(self.canisters_hash()),
+                )
+                .into(),
+            ),
+        };
+
+        let events = self.bucket.get_transactions_for_writer(&arg.writer, page);
+
+        GetTransactionsResponse {
             data: events,
             page,
+            total_items: self.bucket.count_for_writer(&arg.writer),
+            last_page,
+            has_more: page < last_page,
             witness,
         }
     }
 
-    pub fn get_token_transactions(
+    pub fn get_account_transactions(
         &self,
-        arg: GetTokenTransactionsArg,
-    ) -> GetTransactionsResponseBorrowed {
-        let page = arg
-            .page
-            .unwrap_or_else(|| self.bucket.last_page_for_token(&arg.token_id));
+        arg: GetAccountTransactionsArg,
+    ) -> GetTransactionsResponse {
+        let last_page = self.bucket.last_page_for_account(&arg.account);
+        let page = arg.page.unwrap_or(last_page);
 
         let witness = match arg.witness {
             false => None,
@@ -154,38 +350,213 @@ impl Bucket {
                 fork(
                     fork(
                         self.bucket
-                            .witness_transactions_for_token(&arg.token_id, page),
+                            .witness_transactions_for_account(&arg.account, page),
                         HashTree::Pruned(self.buckets.root_hash()),
                     ),
-                    HashTree::Pruned(self.next_canisters.root_hash()),
+                    HashTree::Pruned(self.canisters_hash()),
                 )
                 .into(),
             ),
         };
 
-        let events = self.bucket.get_transactions_for_token(&arg.token_id, page);
+        let events = self.bucket.get_transactions_for_account(&arg.account, page);
 
-        GetTransactionsResponseBorrowed {
+        GetTransactionsResponse {
             data: events,
             page,
+            total_items: self.bucket.count_for_account(&arg.account),
+            last_page,
+            has_more: page < last_page,
             witness,
         }
     }
 
+    pub fn get_transaction_id_by_key(
+        &self,
+        arg: GetTransactionIdByKeyArg,
+    ) -> GetTransactionIdByKeyResponse {
+        let witness = match arg.witness {
+            false => None,
+            true => Some(
+                fork(
+                    fork(
+                        self.bucket.witness_id_for_key(&arg.key),
+                        HashTree::Pruned(self.buckets.root_hash()),
+                    ),
+                    HashTree::Pruned(self.canisters_hash()),
+                )
+                .into(),
+            ),
+        };
+
+        GetTransactionIdByKeyResponse {
+            id: self.bucket.get_id_for_key(&arg.key),
+            witness,
+        }
+    }
+
+    pub fn get_tip(&self, arg: WithWitnessArg) -> GetTipResponse {
+        let witness = match arg.witness {
+            false => None,
+            true => Some(
+                fork(
+                    fork(
+                        self.bucket.witness_tip(),
+                        HashTree::Pruned(self.buckets.root_hash()),
+                    ),
+                    HashTree::Pruned(self.canisters_hash()),
+                )
+                .into(),
+            ),
+        };
+
+        GetTipResponse {
+            tip: self.bucket.tip(),
+            witness,
+        }
+    }
+
+    pub fn get_consistency_proof(
+        &self,
+        arg: GetConsistencyProofArg,
+    ) -> GetConsistencyProofResponse {
+        GetConsistencyProofResponse {
+            proof: self.bucket.consistency_proof(arg.old_size, arg.new_size),
+        }
+    }
+
+    pub fn get_transactions_by_detail(
+        &self,
+        arg: GetTransactionsByDetailArg,
+    ) -> GetTransactionsResponse {
+        let last_page = self.bucket.last_page_for_detail(&arg.key, &arg.value);
+        let page = arg.page.unwrap_or(last_page);
+
+        let witness = match arg.witness {
+            false => None,
+            true => Some(
+                fork(
+                    fork(
+                        self.bucket
+                            .witness_transactions_for_detail(&arg.key, &arg.value, page),
+                        HashTree::Pruned(self.buckets.root_hash()),
+                    ),
+                    HashTree::Pruned(self.canisters_hash()),
+                )
+                .into(),
+            ),
+        };
+
+        let events = self
+            .bucket
+            .get_transactions_for_detail(&arg.key, &arg.value, page);
+
+        GetTransactionsResponse {
+            data: events,
+            page,
+            total_items: self.bucket.count_for_detail(&arg.key, &arg.value),
+            last_page,
+            has_more: page < last_page,
+            witness,
+        }
+    }
+
+    pub fn get_transactions_in_range(
+        &self,
+        arg: GetTransactionsInRangeArg,
+    ) -> GetTransactionsInRangeResponse {
+        let witness = match arg.witness {
+            false => None,
+            true => Some(
+                fork(
+                    fork(
+                        self.bucket.witness_transactions_in_range(
+                            arg.from_ms,
+                            arg.to_ms,
+                            arg.cursor,
+                        ),
+                        HashTree::Pruned(self.buckets.root_hash()),
+                    ),
+                    HashTree::Pruned(self.canisters_hash()),
+                )
+                .into(),
+            ),
+        };
+
+        let (data, next_cursor) =
+            self.bucket
+                .get_transactions_in_range(arg.from_ms, arg.to_ms, arg.cursor);
+
+        GetTransactionsInRangeResponse {
+            data,
+            next_cursor,
+            witness,
+        }
+    }
+
+    pub fn get_transactions_cursor(
+        &self,
+        arg: GetTransactionsCursorArg,
+    ) -> GetTransactionsCursorResponse {
+        let (data, next_cursor) = self.bucket.get_transactions_for_contract_cursor(
+            arg.cursor,
+            arg.direction,
+            arg.limit,
+            arg.max_bytes,
+        );
+
+        GetTransactionsCursorResponse { data, next_cursor }
+    }
+
+    pub fn get_user_transactions_cursor(
+        &self,
+        arg: GetUserTransactionsCursorArg,
+    ) -> GetTransactionsCursorResponse {
+        let (data, next_cursor) = self.bucket.get_transactions_for_user_cursor(
+            &arg.user,
+            arg.roles.as_deref(),
+            arg.cursor,
+            arg.direction,
+            arg.limit,
+            arg.max_bytes,
+        );
+
+        GetTransactionsCursorResponse { data, next_cursor }
+    }
+
+    pub fn get_token_transactions_cursor(
+        &self,
+        arg: GetTokenTransactionsCursorArg,
+    ) -> GetTransactionsCursorResponse {
+        let (data, next_cursor) = self.bucket.get_transactions_for_token_cursor(
+            &arg.token(),
+            arg.cursor,
+            arg.direction,
+            arg.limit,
+            arg.max_bytes,
+        );
+
+        GetTransactionsCursorResponse { data, next_cursor }
+    }
+
+    /// Return the bucket holding the transaction with the given id, the witness proves the
+    /// range of ids that contains it. Ids that are not in any range are not held by any bucket,
+    /// this bucket is returned for them and the witness proves that no range contains them.
     pub fn get_bucket_for(&self, arg: WithIdArg) -> GetBucketResponse {
-        let id_witness = self.buckets.witness(&arg.id);
-        let id = id_witness
-            .get_leaf_values()
-            .get(0)
-            .map(|bytes| Principal::from_slice(bytes))
-            .unwrap_or_else(ic_kit::ic::id);
+        let id = match self.buckets.floor(arg.id) {
+            Some((_, canister)) => canister,
+            None => ic_kit::ic::id(),
+        };
 
         let witness = match arg.witness {
             false => None,
             true => Some(
                 fork(
-                    fork(HashTree::Pruned(self.bucket.root_hash()), id_witness),
-                    HashTree::Pruned(self.next_canisters.root_hash()),
+                    fork(
+                        HashTree::Pruned(self.bucket.root_hash()),
+                        self.buckets.witness_floor(arg.id),
+                    ),
+                    HashTree::Pruned(self.canisters_hash()),
                 )
                 .into(),
             ),
@@ -197,6 +568,28 @@ impl Bucket {
         }
     }
 
+    /// Return the number of transactions, the witness proves that the last transaction is
+    /// present and that there is none after it.
+    pub fn get_size(&self, arg: WithWitnessArg) -> GetSizeResponse {
+        let size = self.bucket.size();
+
+        let witness = match arg.witness {
+            false => None,
+            true => Some(
+                fork(
+                    fork(
+                        self.bucket.witness_transaction(size),
+                        HashTree::Pruned(self.buckets.root_hash()),
+                    ),
+                    HashTree::Pruned(self.canisters_hash()),
+                )
+                .into(),
+            ),
+        };
+
+        GetSizeResponse { size, witness }
+    }
+
     pub fn size(&self) -> u64 {
         self.bucket.size()
     }
@@ -210,24 +603,165 @@ impl Bucket {
         self.bucket.insert(event)
     }
 
+    #[inline]
+    pub fn insert_from(&mut self, writer: Option<Principal>, event: Event) -> u64 {
+        self.bucket.insert_from(writer, event)
+    }
+
+    #[inline]
+    pub fn insert_idempotent(
+        &mut self,
+        writer: Option<Principal>,
+        event: Event,
+        key: Option<String>,
+    ) -> u64 {
+        self.bucket.insert_idempotent(writer, event, key)
+    }
+
+    #[inline]
+    pub fn get_id_for_key(&self, key: &str) -> Option<TransactionId> {
+        self.bucket.get_id_for_key(key)
+    }
+
+    #[inline]
+    pub fn add_detail_index(&mut self, key: String) -> bool {
+        self.bucket.add_detail_index(key)
+    }
+
+    #[inline]
+    pub fn detail_indexes(&self) -> Vec<String> {
+        self.bucket.detail_indexes()
+    }
+
+    #[inline]
+    pub fn backfill_detail_indexes(&mut self, limit: usize) -> usize {
+        self.bucket.backfill_detail_indexes(limit)
+    }
+
+    pub fn detail_backfills(&self) -> Vec<DetailIndexBackfill> {
+        self.bucket
+            .detail_backfills()
+            .into_iter()
+            .map(|(key, cursor)| DetailIndexBackfill { key, cursor })
+            .collect()
+    }
+
+    #[inline]
+    pub fn set_user_index_exclusions(&mut self, keys: BTreeSet<String>) -> bool {
+        self.bucket.set_user_index_exclusions(keys)
+    }
+
+    #[inline]
+    pub fn user_index_exclusions(&self) -> Vec<String> {
+        self.bucket.user_index_exclusions()
+    }
+
+    /// Return the users of an event inserted next which are added to the user index, see
+    /// [`TransactionList::indexed_user_roles`].
+    #[inline]
+    pub fn indexed_users<'a>(&self, event: &'a Event) -> Vec<&'a Principal> {
+        self.bucket
+            .indexed_user_roles(self.size(), event)
+            .into_iter()
+            .map(|(user, _)| user)
+            .collect()
+    }
+
     #[inline]
     pub fn set_next_canisters(&mut self, canisters: Vec<Principal>) {
         self.next_canisters = canisters.into();
     }
+
+    /// Return the canisters that come after this bucket in the chain.
+    #[inline]
+    pub fn next_canisters(&self) -> &[BucketId] {
+        self.next_canisters.as_vec()
+    }
+
+    /// Replace the certified set of the writers.
+    #[inline]
+    pub fn set_writers(&mut self, writers: &BTreeSet<Principal>) {
+        self.writers = Some(writers.iter().map(|writer| (*writer, true)).collect());
+    }
+
+    /// Return the canisters other than the contract which are allowed to insert events, in
+    /// ascending order.
+    #[inline]
+    pub fn writers(&self) -> Vec<Principal> {
+        self.writers.as_ref().map_or(vec![], |writers| {
+            writers.iter().map(|(writer, _)| *writer).collect()
+        })
+    }
+
+    #[inline]
+    fn writers_hash(&self) -> Hash {
+        match &self.writers {
+            Some(writers) => writers.root_hash(),
+            None => HashTree::Empty.reconstruct(),
+        }
+    }
+
+    /// The hash of the right side of the tree, which holds the next canisters and the writers.
+    #[inline]
+    fn canisters_hash(&self) -> Hash {
+        fork_hash(&self.next_canisters.root_hash(), &self.writers_hash())
+    }
+
+    /// Return the start of each range of transaction ids and the bucket canister holding it,
+    /// the last range is the one held by this bucket.
+    pub fn buckets(&self) -> Vec<(TransactionId, BucketId)> {
+        self.buckets.iter().collect()
+    }
+
+    /// Record the ranges of ids known when the bucket is created: the ids from its offset on are
+    /// held by the given canister, the one holding this bucket, and the ones before it by the
+    /// first of the next canisters. It does nothing if the bucket already has ranges, so it can
+    /// be called again after an upgrade.
+    pub fn init_ranges(&mut self, canister: BucketId) {
+        if !self.buckets.is_empty() {
+            return;
+        }
+
+        let offset = self.bucket.global_offset;
+        if let Some(next) = self.next_canisters.as_vec().first().filter(|_| offset > 0) {
+            self.buckets.insert(0, *next);
+        }
+        self.buckets.insert(offset, canister);
+    }
+
+    /// Record that the transactions before `to` have been copied to the given bucket canister,
+    /// and remove them from this bucket. The new canister becomes the next one in the chain.
+    pub fn roll_over(&mut self, to: TransactionId, canister: BucketId) {
+        let from = self.bucket.global_offset;
+        self.bucket.remove_before(to);
+        self.buckets.insert(from, canister);
+        self.buckets.insert(to, ic_kit::ic::id());
+        self.next_canisters = vec![canister].into();
+    }
+
+    /// Free a batch of the blocks used by the transactions removed by a rollover, see
+    /// [`TransactionList::free_retired`].
+    #[inline]
+    pub fn free_retired(&mut self, limit: usize) -> usize {
+        self.bucket.free_retired(limit)
+    }
 }
 
 impl AsHashTree for Bucket {
     fn root_hash(&self) -> Hash {
         fork_hash(
             &fork_hash(&self.bucket.root_hash(), &self.buckets.root_hash()),
-            &self.next_canisters.root_hash(),
+            &self.canisters_hash(),
         )
     }
 
     fn as_hash_tree(&self) -> HashTree<'_> {
         fork(
             fork(self.bucket.as_hash_tree(), self.buckets.as_hash_tree()),
-            self.next_canisters.as_hash_tree(),
+            fork(
+                self.next_canisters.as_hash_tree(),
+                HashTree::Pruned(self.writers_hash()),
+            ),
         )
     }
 }
@@ -1,12 +1,14 @@
 //! This file contains all of the type definitions used in the candid
 //! files across the different canisters and the services.
 
-use crate::transaction::Event;
+use crate::mmr::{ConsistencyProof, Tip};
+use crate::transaction::{DetailValue, Event, EventError, IndefiniteEvent, TokenId};
 use certified_vars::{Hash, HashTree};
 use ic_kit::candid::{CandidType, Deserialize};
 use ic_kit::ic;
 use ic_kit::Principal;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 
 /// The numeric type used to represent a transaction id.
 pub type TransactionId = u64;
@@ -29,12 +31,14 @@ pub type UserId = Principal;
 /// Hash of an even which is obtained by `Event::hash`
 pub type EventHash = Hash;
 
-#[derive(Serialize, Deserialize, CandidType, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, CandidType, Debug, PartialEq, Clone)]
 pub struct Witness {
+    /// The certificate of the canister's certified data, as returned by `data_certificate`.
     #[serde(with = "serde_bytes")]
-    certificate: Vec<u8>,
+    pub certificate: Vec<u8>,
+    /// The CBOR encoded hash tree.
     #[serde(with = "serde_bytes")]
-    tree: Vec<u8>,
+    pub tree: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize, CandidType)]
@@ -72,12 +76,35 @@ pub struct GetIndexCanistersResponse {
     pub witness: Option<Witness>,
 }
 
+/// Return the index canister which holds the root buckets of the user among the index canisters
+/// listed by the router's `get_index_canisters`. Returns `None` if the list is empty.
+///
+/// Each user goes to the canister with the highest hash of the user and the canister, so adding
+/// a canister to the list only moves the users which now go to it, and removing one only moves
+/// the users it held.
+pub fn index_canister_for(canisters: &[IndexCanisterId], user: &UserId) -> Option<IndexCanisterId> {
+    canisters.iter().copied().max_by_key(|canister| {
+        let mut hasher = Sha256::new();
+        hasher.update([user.as_slice().len() as u8]);
+        hasher.update(user.as_slice());
+        hasher.update(canister.as_slice());
+        hasher.finalize()
+    })
+}
+
 #[derive(Serialize, Deserialize, CandidType)]
 pub struct GetNextCanistersResponse {
     pub canisters: Vec<IndexCanisterId>,
     pub witness: Option<Witness>,
 }
 
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetWritersResponse {
+    /// The canisters other than the contract which are allowed to insert events.
+    pub writers: Vec<Principal>,
+    pub witness: Option<Witness>,
+}
+
 #[derive(Serialize, Deserialize, CandidType)]
 pub struct WithIdArg {
     pub id: TransactionId,
@@ -100,13 +127,12 @@ pub struct GetTransactionsArg {
 pub struct GetTransactionsResponse {
     pub data: Vec<Event>,
     pub page: u32,
-    pub witness: Option<Witness>,
-}
-
-#[derive(Serialize, CandidType)]
-pub struct GetTransactionsResponseBorrowed<'a> {
-    pub data: Vec<&'a Event>,
-    pub page: u32,
+    /// The number of transactions in all of the pages.
+    pub total_items: u64,
+    /// The number of the last page, which is the page returned when no page is requested.
+    pub last_page: u32,
+    /// Whether there are pages after this one.
+    pub has_more: bool,
     pub witness: Option<Witness>,
 }
 
@@ -115,13 +141,184 @@ pub struct GetUserTransactionsArg {
     pub user: UserId,
     pub page: Option<u32>,
     pub witness: bool,
+    /// Only return the transactions where the user appears under one of these detail keys,
+    /// or is the caller for the `caller` role. The filtered pages are not certified, so a
+    /// witness can not be requested along with roles.
+    pub roles: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, CandidType)]
 pub struct GetTokenTransactionsArg {
+    /// The id of a token with an `u64` id, ignored when `token` is set.
     pub token_id: u64,
     pub page: Option<u32>,
     pub witness: bool,
+    /// The id of the token, which can be of any kind.
+    pub token: Option<TokenId>,
+}
+
+impl GetTokenTransactionsArg {
+    /// Return the id of the requested token.
+    #[inline]
+    pub fn token(&self) -> TokenId {
+        self.token.clone().unwrap_or(TokenId::U64(self.token_id))
+    }
+}
+
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetOperationTransactionsArg {
+    pub operation: String,
+    pub page: Option<u32>,
+    pub witness: bool,
+}
+
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetWriterTransactionsArg {
+    /// The canister which inserted the transactions, either the contract or one of its
+    /// writers.
+    pub writer: Principal,
+    pub page: Option<u32>,
+    pub witness: bool,
+}
+
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetAccountTransactionsArg {
+    /// The ledger identifier of the account, see [`Account::account_id`].
+    ///
+    /// [`Account::account_id`]: crate::transaction::Account::account_id
+    #[serde(with = "serde_bytes")]
+    pub account: Vec<u8>,
+    pub page: Option<u32>,
+    pub witness: bool,
+}
+
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetTransactionIdByKeyArg {
+    /// The idempotency key the event was inserted with.
+    pub key: String,
+    pub witness: bool,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Debug, PartialEq)]
+pub struct GetTransactionIdByKeyResponse {
+    /// The id of the event, `None` if the key was not used recently.
+    pub id: Option<TransactionId>,
+    pub witness: Option<Witness>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Debug, PartialEq)]
+pub struct GetTipResponse {
+    /// The tip of the accumulator over the hashes of the events.
+    pub tip: Tip,
+    pub witness: Option<Witness>,
+}
+
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetConsistencyProofArg {
+    pub old_size: u64,
+    pub new_size: u64,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Debug, PartialEq)]
+pub struct GetConsistencyProofResponse {
+    /// The proof, `None` if the sizes are not ordered or if the new size is past the size of
+    /// the current tip.
+    pub proof: Option<ConsistencyProof>,
+}
+
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetTransactionsByDetailArg {
+    pub key: String,
+    pub value: DetailValue,
+    pub page: Option<u32>,
+    pub witness: bool,
+}
+
+/// A detail key whose index does not hold all of the events yet, the events are indexed in
+/// batches starting from the oldest one.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct DetailIndexBackfill {
+    pub key: String,
+    /// The id of the next event to index.
+    pub cursor: TransactionId,
+}
+
+/// The order in which a cursor walks over the transactions.
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// From the oldest to the newest transaction.
+    Forward,
+    /// From the newest to the oldest transaction.
+    Backward,
+}
+
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetTransactionsCursorArg {
+    /// The id of the first transaction to return, the `next_cursor` of the previous response.
+    /// Starts from the oldest or newest transaction depending on the direction if not set.
+    pub cursor: Option<TransactionId>,
+    pub direction: Direction,
+    /// The maximum number of transactions to return.
+    pub limit: u32,
+    /// An upper bound on the size of the returned transactions, at least one transaction is
+    /// always returned so the cursor can make progress.
+    pub max_bytes: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetUserTransactionsCursorArg {
+    pub user: UserId,
+    pub cursor: Option<TransactionId>,
+    pub direction: Direction,
+    pub limit: u32,
+    pub max_bytes: Option<u32>,
+    /// Only return the transactions where the user has one of these roles, see
+    /// [`GetUserTransactionsArg::roles`].
+    pub roles: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetTokenTransactionsCursorArg {
+    /// The id of a token with an `u64` id, ignored when `token` is set.
+    pub token_id: u64,
+    pub cursor: Option<TransactionId>,
+    pub direction: Direction,
+    pub limit: u32,
+    pub max_bytes: Option<u32>,
+    /// The id of the token, which can be of any kind.
+    pub token: Option<TokenId>,
+}
+
+impl GetTokenTransactionsCursorArg {
+    /// Return the id of the requested token.
+    #[inline]
+    pub fn token(&self) -> TokenId {
+        self.token.clone().unwrap_or(TokenId::U64(self.token_id))
+    }
+}
+
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetTransactionsCursorResponse {
+    pub data: Vec<Event>,
+    pub next_cursor: Option<TransactionId>,
+}
+
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetTransactionsInRangeArg {
+    /// Start of the time range in ms, inclusive.
+    pub from_ms: u64,
+    /// End of the time range in ms, inclusive.
+    pub to_ms: u64,
+    /// The `next_cursor` returned by the previous page, if any.
+    pub cursor: Option<TransactionId>,
+    pub witness: bool,
+}
+
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetTransactionsInRangeResponse {
+    pub data: Vec<Event>,
+    pub next_cursor: Option<TransactionId>,
+    pub witness: Option<Witness>,
 }
 
 #[derive(Serialize, Deserialize, CandidType)]
@@ -139,9 +336,137 @@ impl From<HashTree<'_>> for Witness {
     }
 }
 
+/// When a root bucket moves its events to a new bucket canister.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct RolloverConfig {
+    /// Roll over once the root bucket holds this many transactions.
+    pub max_transactions: u64,
+    /// Roll over once the events held by the root bucket use this many bytes of stable memory.
+    pub max_memory: Option<u64>,
+    /// The cycles used to create each bucket canister.
+    pub cycles: u64,
+}
+
+/// How many read replicas a root bucket keeps.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct ReplicaConfig {
+    /// The number of replica canisters.
+    pub replicas: u32,
+    /// The cycles used to create each replica canister.
+    pub cycles: u64,
+}
+
+/// A read-only bucket canister which mirrors the events of a root bucket.
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq)]
+pub struct Replica {
+    pub canister: BucketId,
+    /// The number of transactions copied to the replica, it holds every transaction with a
+    /// smaller id which is not before the offset of the root bucket.
+    pub size: u64,
+}
+
+/// The new users of a root bucket which are not sent to the router yet.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub struct PendingRouterNotifications {
+    /// The number of users to send.
+    pub users: u64,
+    /// The number of failed attempts since the last batch that was sent.
+    pub failures: u32,
+    /// The time before which no attempt is made, in nanoseconds.
+    pub retry_at: u64,
+}
+
+/// A page of the users of a root bucket, ordered by principal.
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetUsersResponse {
+    pub users: Vec<UserId>,
+    /// The cursor of the next page, `None` if this is the last page.
+    pub next_cursor: Option<UserId>,
+}
+
+/// A page of the users of an index canister which belong to another index canister, ordered by
+/// principal.
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct ExportUsersResponse {
+    /// The users to move along with their root buckets.
+    pub users: Vec<(UserId, Vec<RootBucketId>)>,
+    /// The cursor of the next page, `None` if this is the last page.
+    pub next_cursor: Option<UserId>,
+}
+
+/// The outcome of the last migration of the users to their index canister, which runs when the
+/// router's list of index canisters changes.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, Default, PartialEq)]
+pub struct UserMigrationReport {
+    /// The time the migration started, in nanoseconds.
+    pub started_at: u64,
+    /// The time the migration finished, `None` while it is running.
+    pub finished_at: Option<u64>,
+    /// The index canisters whose users were all moved, along with the number of moved users.
+    pub migrated: Vec<(IndexCanisterId, u64)>,
+    /// The index canisters whose users could not be moved, along with the error.
+    pub failed: Vec<(IndexCanisterId, String)>,
+}
+
+/// The outcome of the last reconciliation of the router's index of the users with the root
+/// buckets.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, Default, PartialEq)]
+pub struct ReconciliationReport {
+    /// The time the reconciliation started, in nanoseconds.
+    pub started_at: u64,
+    /// The time the reconciliation finished, `None` while it is running.
+    pub finished_at: Option<u64>,
+    /// The number of root buckets whose users were all read.
+    pub roots_checked: u64,
+    /// The number of users read from the root buckets.
+    pub users_checked: u64,
+    /// The root buckets which were missing from the index of some of their users, along with
+    /// the number of users which were repaired.
+    pub repaired: Vec<(RootBucketId, u64)>,
+    /// The root buckets which could not be read, along with the error.
+    pub failed: Vec<(RootBucketId, String)>,
+}
+
+#[derive(Serialize, Deserialize, CandidType)]
+pub struct GetSizeResponse {
+    pub size: u64,
+    pub witness: Option<Witness>,
+}
+
+/// The error returned when events are not inserted, nothing is inserted if any of the events
+/// of a batch is rejected.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub enum InsertError {
+    /// The caller is neither the contract nor one of its writers.
+    NotAuthorized,
+    /// The event at the given position of the batch is invalid.
+    InvalidEvent { index: u64, error: EventError },
+}
+
+impl InsertError {
+    /// Validate each of the events, returning the error of the first invalid one.
+    pub fn check(events: &[IndefiniteEvent]) -> Result<(), InsertError> {
+        for (index, event) in events.iter().enumerate() {
+            event
+                .validate()
+                .map_err(|error| InsertError::InvalidEvent {
+                    index: index as u64,
+                    error,
+                })?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, CandidType)]
 pub struct BucketInitArgs {
     pub contract: TokenContractId,
     pub offset: u64,
     pub next_canisters: Vec<BucketId>,
+    /// The detail keys to index, should match the ones indexed by the root bucket.
+    pub detail_indexes: Option<Vec<String>>,
+    /// The detail keys which are not used to index the users, should match the ones of the
+    /// root bucket.
+    pub user_index_exclusions: Option<Vec<String>>,
 }
@@ -0,0 +1,146 @@
+//! An append-only log of events in a [`Store`]. Each event is a block holding its CBOR encoding,
+//! and the offsets of these blocks are kept in an [`Array`].
+
+use crate::store::{Array, Store};
+use crate::transaction::Event;
+use serde::{Deserialize, Serialize};
+
+/// An append-only list of events in a [`Store`].
+#[derive(Serialize, Deserialize, Default)]
+pub struct EventLog {
+    /// The offset of the block of each event.
+    records: Array<u64>,
+    /// The number of bytes used by the records.
+    bytes: u64,
+}
+
+impl EventLog {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append an event to the log and return its index.
+    pub fn push(&mut self, store: &mut Store, event: &Event) -> u32 {
+        let bytes = serde_cbor::to_vec(event).expect("Failed to encode the event.");
+        let ptr = store.alloc_bytes(&bytes);
+        self.records.push(store, &ptr);
+        self.bytes += 4 + bytes.len() as u64;
+        (self.records.len() - 1) as u32
+    }
+
+    /// Return the event with the given index.
+    #[inline]
+    pub fn get(&self, store: &Store, index: u32) -> Option<Event> {
+        self.records
+            .get(store, index as u64)
+            .map(|ptr| read_event(store, ptr))
+    }
+
+    /// Return the number of events in the log.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.records.len() as usize
+    }
+
+    /// Returns `true` if there are no events in the log.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Return an iterator over the events in the log.
+    #[inline]
+    pub fn iter<'a>(&self, store: &'a Store) -> impl Iterator<Item = Event> + 'a {
+        self.records
+            .iter(store)
+            .map(move |ptr| read_event(store, ptr))
+    }
+
+    /// Return the number of bytes used by the records of the log.
+    #[inline]
+    pub fn used_bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Free the blocks used by the log.
+    pub fn free(&self, store: &mut Store) {
+        let records = self.records.iter(store).collect::<Vec<_>>();
+        for ptr in records {
+            store.free_bytes(ptr);
+        }
+        self.records.free(store);
+    }
+
+    /// Free the blocks used by the log in steps, see [`Array::free_some`].
+    pub fn free_some(&mut self, store: &mut Store, budget: &mut usize) -> bool {
+        while *budget > 0 {
+            match self.records.pop(store) {
+                Some(ptr) => store.free_bytes(ptr),
+                None => break,
+            }
+            *budget -= 1;
+        }
+
+        self.records.free_some(store, budget)
+    }
+}
+
+#[inline]
+fn read_event(store: &Store, ptr: u64) -> Event {
+    serde_cbor::from_slice(&store.read_bytes(ptr)).expect("Failed to decode the event.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_kit::mock_principals;
+
+    fn e(time: u64) -> Event {
+        Event {
+            time,
+            caller: mock_principals::alice(),
+            operation: "transfer".into(),
+            details: vec![],
+        }
+    }
+
+    #[test]
+    fn test_log() {
+        let mut store = Store::new();
+        let mut log = EventLog::new();
+        for i in 0..100 {
+            assert_eq!(log.push(&mut store, &e(i)), i as u32);
+        }
+
+        assert_eq!(log.len(), 100);
+        assert_eq!(
+            log.used_bytes(),
+            (0..100)
+                .map(|i| 4 + serde_cbor::to_vec(&e(i)).unwrap().len() as u64)
+                .sum::<u64>()
+        );
+        assert_eq!(log.get(&store, 42), Some(e(42)));
+        assert_eq!(log.get(&store, 100), None);
+        assert!(log.iter(&store).map(|e| e.time).eq(0..100));
+    }
+
+    #[test]
+    fn test_free() {
+        let mut store = Store::new();
+        let mut log = EventLog::new();
+        for i in 0..100 {
+            log.push(&mut store, &e(i));
+        }
+
+        // The blocks of the freed log are reused.
+        let size = store.size();
+        log.free(&mut store);
+        let mut log = EventLog::new();
+        for i in 0..100 {
+            log.push(&mut store, &e(i));
+        }
+        assert_eq!(store.size(), size);
+        assert_eq!(log.get(&store, 0), Some(e(0)));
+    }
+}
@@ -2,8 +2,17 @@
 
 pub mod bucket;
 pub mod did;
+pub mod event_log;
+pub mod memory;
+pub mod mmr;
+pub mod range_map;
+pub mod store;
 pub mod transaction;
 pub mod transaction_list;
+pub mod tree;
+pub mod user_index;
+#[cfg(feature = "verify")]
+pub mod verify;
 
 pub use did::*;
 pub use transaction_list::TransactionList;
@@ -0,0 +1,231 @@
+//! The linear memories a [`Store`] can live in, which are either the stable memory of the
+//! canister or an in-memory stand-in for it.
+//!
+//! [`Store`]: crate::store::Store
+
+use ic_kit::ic;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+
+/// A linear memory which can hold a [`Store`].
+///
+/// [`Store`]: crate::store::Store
+pub trait Memory {
+    /// Return the size of the memory in bytes.
+    fn size(&self) -> u64;
+
+    /// Grow the memory so it is at least `size` bytes.
+    fn grow_to(&mut self, size: u64);
+
+    /// Read `buf.len()` bytes starting at the given offset.
+    fn read(&self, offset: u64, buf: &mut [u8]);
+
+    /// Write the buffer starting at the given offset.
+    fn write(&mut self, offset: u64, buf: &[u8]);
+
+    /// Return `true` if the content of this memory is preserved across upgrades.
+    fn is_stable(&self) -> bool;
+}
+
+/// The stable memory of the canister.
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+pub struct StableMemory;
+
+/// An in-memory stand-in for the stable memory, used by lists that don't need to survive
+/// upgrades, and to test the stable memory code without a canister. Only the pages which were
+/// written to are allocated, the rest of the memory reads as zeros.
+#[derive(Serialize, Deserialize, Default)]
+pub struct VecMemory {
+    size: u64,
+    pages: BTreeMap<u64, ByteBuf>,
+}
+
+/// The size of a page of the stable memory.
+pub const WASM_PAGE_SIZE: u64 = 1 << 16;
+
+/// The 64-bit stable memory API of the canister.
+#[cfg(target_family = "wasm")]
+mod stable64 {
+    use ic_cdk::api::stable;
+
+    #[inline]
+    pub fn size() -> u64 {
+        stable::stable64_size()
+    }
+
+    #[inline]
+    pub fn grow(pages: u64) -> bool {
+        stable::stable64_grow(pages).is_ok()
+    }
+
+    #[inline]
+    pub fn read(offset: u64, buf: &mut [u8]) {
+        stable::stable64_read(offset, buf)
+    }
+
+    #[inline]
+    pub fn write(offset: u64, buf: &[u8]) {
+        stable::stable64_write(offset, buf)
+    }
+}
+
+/// The stable memory of the mock context, which only has the 32-bit API, so the offsets are
+/// checked instead of wrapping around.
+#[cfg(not(target_family = "wasm"))]
+mod stable64 {
+    use ic_kit::ic;
+    use std::convert::TryFrom;
+
+    #[inline]
+    fn narrow(value: u64) -> u32 {
+        u32::try_from(value).expect("The mock stable memory is limited to 32-bit offsets.")
+    }
+
+    #[inline]
+    pub fn size() -> u64 {
+        ic::stable_size() as u64
+    }
+
+    #[inline]
+    pub fn grow(pages: u64) -> bool {
+        ic::stable_grow(narrow(pages)).is_ok()
+    }
+
+    #[inline]
+    pub fn read(offset: u64, buf: &mut [u8]) {
+        ic::stable_read(narrow(offset), buf)
+    }
+
+    #[inline]
+    pub fn write(offset: u64, buf: &[u8]) {
+        ic::stable_write(narrow(offset), buf)
+    }
+}
+
+impl Memory for StableMemory {
+    #[inline]
+    fn size(&self) -> u64 {
+        stable64::size() * WASM_PAGE_SIZE
+    }
+
+    fn grow_to(&mut self, size: u64) {
+        let current = self.size();
+        if size <= current {
+            return;
+        }
+
+        if !stable64::grow((size - current).div_ceil(WASM_PAGE_SIZE)) {
+            ic::trap("Failed to grow the stable memory.");
+        }
+    }
+
+    #[inline]
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        stable64::read(offset, buf)
+    }
+
+    #[inline]
+    fn write(&mut self, offset: u64, buf: &[u8]) {
+        stable64::write(offset, buf)
+    }
+
+    #[inline]
+    fn is_stable(&self) -> bool {
+        true
+    }
+}
+
+impl VecMemory {
+    /// Call `f` with the page number, the range within the page and the range within the
+    /// buffer of each part of the given access.
+    fn chunks(
+        &self,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(u64, std::ops::Range<usize>, std::ops::Range<usize>),
+    ) {
+        assert!(
+            offset.saturating_add(len as u64) <= self.size,
+            "Out of bounds memory access."
+        );
+
+        let mut done = 0;
+        while done < len {
+            let address = offset + done as u64;
+            let start = (address % WASM_PAGE_SIZE) as usize;
+            let n = (len - done).min(WASM_PAGE_SIZE as usize - start);
+            f(address / WASM_PAGE_SIZE, start..start + n, done..done + n);
+            done += n;
+        }
+    }
+
+    /// Return the pages which were written to, along with their offset.
+    pub(crate) fn pages(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.pages
+            .iter()
+            .map(|(page, data)| (page * WASM_PAGE_SIZE, data.as_slice()))
+    }
+}
+
+impl Memory for VecMemory {
+    #[inline]
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    #[inline]
+    fn grow_to(&mut self, size: u64) {
+        self.size = self.size.max(size);
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) {
+        self.chunks(offset, buf.len(), |page, range, part| {
+            match self.pages.get(&page) {
+                Some(page) => buf[part].copy_from_slice(&page[range]),
+                None => buf[part].fill(0),
+            }
+        });
+    }
+
+    fn write(&mut self, offset: u64, buf: &[u8]) {
+        let mut chunks = vec![];
+        self.chunks(offset, buf.len(), |page, range, part| {
+            chunks.push((page, range, part))
+        });
+
+        for (page, range, part) in chunks {
+            self.pages
+                .entry(page)
+                .or_insert_with(|| ByteBuf::from(vec![0; WASM_PAGE_SIZE as usize]))[range]
+                .copy_from_slice(&buf[part]);
+        }
+    }
+
+    #[inline]
+    fn is_stable(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_large_offsets() {
+        let offset = 1 << 32;
+        let mut memory = VecMemory::default();
+        memory.grow_to(offset + 16);
+        memory.write(offset - 4, b"across a page");
+        assert_eq!(memory.size(), offset + 16);
+
+        let mut buf = [1; 13];
+        memory.read(offset - 4, &mut buf);
+        assert_eq!(&buf, b"across a page");
+        let mut buf = [1; 4];
+        memory.read(4, &mut buf);
+        assert_eq!(buf, [0; 4]);
+        assert_eq!(memory.pages().count(), 2);
+    }
+}
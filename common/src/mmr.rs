@@ -0,0 +1,358 @@
+//! An append-only accumulator over the hashes of the events, in the form of a Merkle mountain
+//! range.
+//!
+//! The leaves are grouped in perfect binary trees, one for each bit set in the number of
+//! leaves, and the root of the accumulator is the hash of the size and of the roots of these
+//! trees, which are called the peaks. The trees of an older size are subtrees of the ones of
+//! any larger size, so a [`ConsistencyProof`] shows that the history at a size is a prefix of
+//! the history at a larger size by revealing the old peaks and the nodes over the new leaves.
+
+use crate::store::{Array, Store};
+use certified_vars::hashtree::leaf_hash;
+use certified_vars::{AsHashTree, Hash, HashTree};
+use ic_kit::candid::CandidType;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::slice::Iter;
+
+/// The nodes of the accumulator, the leaves are the hashes of the events. The nodes are kept in
+/// a [`Store`].
+#[derive(Serialize, Deserialize, Default)]
+pub struct Mmr {
+    /// The node at `levels[h][i]` is the root of the perfect tree over the leaves
+    /// `i * 2^h..(i + 1) * 2^h`.
+    levels: Vec<Array<Hash>>,
+}
+
+/// The root of the accumulator at a given size.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tip {
+    /// The number of events in the accumulator.
+    pub size: u64,
+    /// The hash of the size and of the peaks.
+    pub root: Hash,
+}
+
+/// A proof that the history at `old_size` is a prefix of the history at `new_size`.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConsistencyProof {
+    pub old_size: u64,
+    pub new_size: u64,
+    /// The peaks of the accumulator at the old size, the highest first.
+    pub old_peaks: Vec<Hash>,
+    /// The nodes over the leaves added after the old size, in the order they are used to
+    /// compute the new peaks.
+    pub nodes: Vec<Hash>,
+}
+
+impl Mmr {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the number of leaves.
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.levels.first().map_or(0, |leaves| leaves.len())
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Append a leaf, along with the nodes it completes.
+    pub fn push(&mut self, store: &mut Store, leaf: Hash) {
+        let mut node = leaf;
+        let mut height = 0;
+
+        loop {
+            if self.levels.len() == height {
+                self.levels.push(Array::new());
+            }
+
+            let level = &mut self.levels[height];
+            level.push(store, &node);
+
+            if level.len() % 2 == 1 {
+                break;
+            }
+
+            let left = level.get(store, level.len() - 2).unwrap();
+            node = node_hash(&left, &node);
+            height += 1;
+        }
+    }
+
+    /// Return the current tip of the accumulator.
+    #[inline]
+    pub fn tip(&self, store: &Store) -> Tip {
+        let size = self.len();
+        Tip {
+            size,
+            root: bag(size, &self.peaks(store, size)),
+        }
+    }
+
+    /// Return a proof that the history at `old_size` is a prefix of the one at `new_size`,
+    /// `None` if the sizes are not ordered or if the new size is past the current size.
+    pub fn consistency_proof(
+        &self,
+        store: &Store,
+        old_size: u64,
+        new_size: u64,
+    ) -> Option<ConsistencyProof> {
+        if old_size > new_size || new_size > self.len() {
+            return None;
+        }
+
+        fn collect(
+            mmr: &Mmr,
+            store: &Store,
+            height: usize,
+            index: u64,
+            old_size: u64,
+            nodes: &mut Vec<Hash>,
+        ) {
+            let (start, end) = leaves_range(height, index);
+            if end <= old_size {
+                // One of the old peaks.
+                return;
+            }
+
+            if start >= old_size {
+                nodes.push(mmr.levels[height].get(store, index).unwrap());
+                return;
+            }
+
+            collect(mmr, store, height - 1, index * 2, old_size, nodes);
+            collect(mmr, store, height - 1, index * 2 + 1, old_size, nodes);
+        }
+
+        let mut nodes = Vec::new();
+        for (height, index) in peak_positions(new_size) {
+            collect(self, store, height, index, old_size, &mut nodes);
+        }
+
+        Some(ConsistencyProof {
+            old_size,
+            new_size,
+            old_peaks: self.peaks(store, old_size),
+            nodes,
+        })
+    }
+
+    /// Return the peaks of the accumulator at the given size, the highest first.
+    #[inline]
+    fn peaks(&self, store: &Store, size: u64) -> Vec<Hash> {
+        peak_positions(size)
+            .map(|(height, index)| self.levels[height].get(store, index).unwrap())
+            .collect()
+    }
+}
+
+impl Tip {
+    /// The bytes of the leaf which certifies the tip, the size followed by the root.
+    #[inline]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(40);
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.extend_from_slice(&self.root);
+        bytes
+    }
+}
+
+impl AsHashTree for Tip {
+    #[inline]
+    fn root_hash(&self) -> Hash {
+        leaf_hash(&self.to_bytes())
+    }
+
+    #[inline]
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        HashTree::Leaf(Cow::Owned(self.to_bytes()))
+    }
+}
+
+impl ConsistencyProof {
+    /// Check that this proof shows that the history with the tip `old` is a prefix of the
+    /// history with the tip `new`. The tips must come from a trusted source, such as a
+    /// verified response of `get_tip`.
+    pub fn verify(&self, old: &Tip, new: &Tip) -> bool {
+        if self.old_size != old.size || self.new_size != new.size || old.size > new.size {
+            return false;
+        }
+
+        if peak_positions(old.size).count() != self.old_peaks.len()
+            || bag(old.size, &self.old_peaks) != old.root
+        {
+            return false;
+        }
+
+        fn rebuild(
+            height: usize,
+            index: u64,
+            old_size: u64,
+            old_peaks: &mut Iter<Hash>,
+            nodes: &mut Iter<Hash>,
+        ) -> Option<Hash> {
+            let (start, end) = leaves_range(height, index);
+            if end <= old_size {
+                return old_peaks.next().copied();
+            }
+
+            if start >= old_size {
+                return nodes.next().copied();
+            }
+
+            let left = rebuild(height - 1, index * 2, old_size, old_peaks, nodes)?;
+            let right = rebuild(height - 1, index * 2 + 1, old_size, old_peaks, nodes)?;
+            Some(node_hash(&left, &right))
+        }
+
+        let mut old_peaks = self.old_peaks.iter();
+        let mut nodes = self.nodes.iter();
+        let peaks = peak_positions(new.size)
+            .map(|(height, index)| rebuild(height, index, old.size, &mut old_peaks, &mut nodes))
+            .collect::<Option<Vec<_>>>();
+
+        match peaks {
+            Some(peaks) => {
+                old_peaks.next().is_none()
+                    && nodes.next().is_none()
+                    && bag(new.size, &peaks) == new.root
+            }
+            None => false,
+        }
+    }
+}
+
+/// Return the position of the peaks for the given size, as `(height, index)`, the highest
+/// first.
+fn peak_positions(size: u64) -> impl Iterator<Item = (usize, u64)> {
+    let mut start = 0u64;
+    (0..64usize)
+        .rev()
+        .filter(move |height| (size >> height) & 1 == 1)
+        .map(move |height| {
+            let index = start >> height;
+            start += 1 << height;
+            (height, index)
+        })
+}
+
+/// Return the range of leaves under the given node.
+#[inline]
+fn leaves_range(height: usize, index: u64) -> (u64, u64) {
+    (index << height, (index + 1) << height)
+}
+
+#[inline]
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut h = Sha256::new();
+    h.update([1]);
+    h.update(left);
+    h.update(right);
+    h.finalize().into()
+}
+
+#[inline]
+fn bag(size: u64, peaks: &[Hash]) -> Hash {
+    let mut h = Sha256::new();
+    h.update([2]);
+    h.update(size.to_be_bytes());
+    for peak in peaks {
+        h.update(peak);
+    }
+    h.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(i: u64) -> Hash {
+        Sha256::digest(i.to_be_bytes()).into()
+    }
+
+    fn mmr(store: &mut Store, size: u64) -> Mmr {
+        let mut mmr = Mmr::new();
+        for i in 0..size {
+            mmr.push(store, leaf(i));
+        }
+        mmr
+    }
+
+    fn tip(size: u64) -> Tip {
+        let mut store = Store::new();
+        mmr(&mut store, size).tip(&store)
+    }
+
+    #[test]
+    fn test_tip() {
+        let mut store = Store::new();
+        let mut tips = Vec::new();
+        let mut mmr = Mmr::new();
+        for i in 0..20 {
+            tips.push(mmr.tip(&store));
+            mmr.push(&mut store, leaf(i));
+        }
+
+        for (size, expected) in tips.iter().enumerate() {
+            assert_eq!(expected.size, size as u64);
+            assert_eq!(*expected, tip(size as u64));
+        }
+
+        let mut other = self::mmr(&mut store, 6);
+        other.push(&mut store, leaf(100));
+        assert_ne!(other.tip(&store).root, tip(7).root);
+        assert_eq!(mmr.len(), 20);
+    }
+
+    #[test]
+    fn test_consistency_proof() {
+        let mut store = Store::new();
+        let mmr = mmr(&mut store, 37);
+
+        for old in 0..=37 {
+            for new in old..=37 {
+                let proof = mmr.consistency_proof(&store, old, new).unwrap();
+                assert!(proof.verify(&tip(old), &tip(new)), "{} {}", old, new);
+            }
+        }
+
+        assert_eq!(mmr.consistency_proof(&store, 5, 4), None);
+        assert_eq!(mmr.consistency_proof(&store, 5, 38), None);
+    }
+
+    #[test]
+    fn test_rewritten_history() {
+        let mut store = Store::new();
+        let mmr = mmr(&mut store, 13);
+        let proof = mmr.consistency_proof(&store, 6, 13).unwrap();
+        let new = mmr.tip(&store);
+
+        // A history where one of the first 6 events was changed.
+        let mut rewritten = Mmr::new();
+        for i in 0..6 {
+            rewritten.push(&mut store, leaf(if i == 2 { 100 } else { i }));
+        }
+        assert!(!proof.verify(&rewritten.tip(&store), &new));
+
+        // The proof does not hold for other tips.
+        let old = tip(6);
+        assert!(proof.verify(&old, &new));
+        assert!(!proof.verify(&old, &tip(12)));
+        assert!(!proof.verify(&tip(5), &new));
+
+        let mut forged = proof.clone();
+        forged.nodes[0] = leaf(0);
+        assert!(!forged.verify(&old, &new));
+
+        let mut forged = proof;
+        forged.nodes.push(leaf(0));
+        assert!(!forged.verify(&old, &new));
+    }
+}
@@ -0,0 +1,167 @@
+//! A certified map from ranges of transaction ids to the bucket canister that holds them.
+//!
+//! Each range is stored under the id of its first transaction and ends where the next range
+//! starts, so the bucket of an id is found with a floor lookup on the start offsets.
+
+use crate::did::{BucketId, TransactionId};
+use certified_vars::{AsHashTree, Hash, HashTree, Map};
+use ic_kit::candid::types::{Serializer, Type};
+use ic_kit::candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RangeMap {
+    starts: Map<TransactionId, BucketId>,
+}
+
+impl RangeMap {
+    /// Create an empty range map.
+    #[inline]
+    pub fn new() -> Self {
+        Self { starts: Map::new() }
+    }
+
+    /// Returns `true` if the map does not contain any range.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.starts.is_empty()
+    }
+
+    /// Record that the range starting at the given id is held by the bucket, the range ends
+    /// where the next one starts.
+    #[inline]
+    pub fn insert(&mut self, start: TransactionId, bucket: BucketId) {
+        self.starts.insert(start, bucket);
+    }
+
+    /// Return the start of the range containing the given id and the bucket holding it.
+    pub fn floor(&self, id: TransactionId) -> Option<(TransactionId, BucketId)> {
+        // Walk down the tree towards the id, the last start smaller than the id on the path
+        // is the greatest start before the id.
+        let below = Cell::new(None);
+        let tree = self.starts.as_tree();
+        let found = tree.get_with(|start| {
+            if *start < id {
+                below.set(Some(*start));
+            }
+            id.cmp(start)
+        });
+
+        match found {
+            Some(bucket) => Some((id, *bucket)),
+            None => below.get().map(|start| (start, *tree.get(&start).unwrap())),
+        }
+    }
+
+    /// Return the ranges in the map as their start and the bucket holding them.
+    pub fn iter(&self) -> impl Iterator<Item = (TransactionId, BucketId)> + '_ {
+        self.starts.iter().map(|(start, bucket)| (*start, *bucket))
+    }
+
+    /// Create a witness for the range containing the given id. It reveals the start of the
+    /// range and its bucket along with the start of the next range, and proves that there is
+    /// no other start in between. If no range contains the id it proves that every start is
+    /// greater than the id.
+    pub fn witness_floor(&self, id: TransactionId) -> HashTree<'_> {
+        match self.floor(id) {
+            // The range ends after `id`, so the next start is revealed even if `id` is a start.
+            Some((start, _)) => self
+                .starts
+                .witness_value_range(&start, &id.saturating_add(1)),
+            None => self.starts.witness(&id),
+        }
+    }
+}
+
+impl Default for RangeMap {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsHashTree for RangeMap {
+    #[inline]
+    fn root_hash(&self) -> Hash {
+        self.starts.root_hash()
+    }
+
+    #[inline]
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        self.starts.as_hash_tree()
+    }
+}
+
+impl CandidType for RangeMap {
+    fn _ty() -> Type {
+        Map::<TransactionId, BucketId>::ty()
+    }
+
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: Serializer,
+    {
+        self.starts.idl_serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_kit::{mock_principals, Principal};
+
+    #[test]
+    fn test_floor() {
+        let mut map = RangeMap::new();
+        assert_eq!(map.floor(10), None);
+
+        map.insert(0, mock_principals::alice());
+        map.insert(100, mock_principals::bob());
+        map.insert(250, mock_principals::john());
+
+        assert_eq!(map.floor(0), Some((0, mock_principals::alice())));
+        assert_eq!(map.floor(99), Some((0, mock_principals::alice())));
+        assert_eq!(map.floor(100), Some((100, mock_principals::bob())));
+        assert_eq!(map.floor(249), Some((100, mock_principals::bob())));
+        assert_eq!(map.floor(1000), Some((250, mock_principals::john())));
+    }
+
+    #[test]
+    fn test_floor_many() {
+        let mut map = RangeMap::new();
+        for i in 1..500 {
+            map.insert(i * 10, Principal::from_slice(&(i as u32).to_be_bytes()));
+        }
+
+        assert_eq!(map.floor(9), None);
+        for id in 10..5000 {
+            let start = id / 10 * 10;
+            let bucket = Principal::from_slice(&((start / 10) as u32).to_be_bytes());
+            assert_eq!(map.floor(id), Some((start, bucket)));
+        }
+        assert_eq!(map.floor(u64::MAX).unwrap().0, 4990);
+    }
+
+    #[test]
+    fn test_witness_floor() {
+        let mut map = RangeMap::new();
+        for i in 1..20 {
+            map.insert(i * 100, mock_principals::alice());
+        }
+
+        for id in [0, 100, 150, 1250, 1900, 5000] {
+            assert_eq!(map.witness_floor(id).reconstruct(), map.root_hash());
+        }
+
+        let witness = map.witness_floor(1250);
+        let labels = witness.get_labels();
+        assert!(labels.contains(&&1200u64.to_be_bytes()[..]));
+        assert!(labels.contains(&&1300u64.to_be_bytes()[..]));
+
+        // The end of the range is revealed when the id is the start of a range.
+        let witness = map.witness_floor(1300);
+        assert!(witness.get_labels().contains(&&1400u64.to_be_bytes()[..]));
+    }
+}
@@ -0,0 +1,640 @@
+//! A heap of blocks in a linear memory, which holds the events and the indexes of a
+//! [`TransactionList`] so only the handles of its structures are kept on the heap of the
+//! canister. When the store is in the stable memory an upgrade only has to serialize these
+//! handles.
+//!
+//! The memory has the following layout, all numbers are big endian:
+//!
+//! ```text
+//! 0..8     magic
+//! 8..16    offset of the upgrade data
+//! 16..24   size of the upgrade data
+//! 64..     blocks
+//! ```
+//!
+//! Blocks are allocated in size classes, the free blocks of each class are linked through their
+//! first 8 bytes.
+//!
+//! [`TransactionList`]: crate::TransactionList
+
+use crate::memory::{Memory, StableMemory, VecMemory};
+use certified_vars::Hash;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::marker::PhantomData;
+
+const MAGIC: &[u8; 8] = b"CAP-MEM\x01";
+const UPGRADE_DATA_OFFSET: u64 = 8;
+const UPGRADE_DATA_SIZE: u64 = 16;
+const HEADER_SIZE: u64 = 64;
+
+/// Blocks up to this size are allocated in steps of 16 bytes, larger ones in powers of two.
+const SMALL_BLOCK_SIZE: usize = 4096;
+
+/// The memory holding the blocks of a [`Store`].
+#[derive(Serialize, Deserialize)]
+enum Backing {
+    Heap(VecMemory),
+    Stable(StableMemory),
+}
+
+/// An allocator of blocks in a [`Memory`], the blocks are identified by their offset which is
+/// never `0`.
+#[derive(Serialize, Deserialize)]
+pub struct Store {
+    memory: Backing,
+    /// The end of the last block.
+    end: u64,
+    /// The first free block of each size class.
+    free: Vec<u64>,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Store {
+    /// Create an empty store on the heap.
+    pub fn new() -> Self {
+        let mut store = Self {
+            memory: Backing::Heap(VecMemory::default()),
+            end: HEADER_SIZE,
+            free: Vec::new(),
+        };
+
+        let memory = store.memory_mut();
+        memory.grow_to(HEADER_SIZE);
+        memory.write(0, MAGIC);
+        store
+    }
+
+    /// Returns `true` if the store is in the stable memory.
+    #[inline]
+    pub fn is_stable(&self) -> bool {
+        self.memory().is_stable()
+    }
+
+    /// Move the store to the stable memory, anything stored in the stable memory is
+    /// overwritten.
+    pub fn set_stable(&mut self) {
+        let mut stable = StableMemory;
+        if let Backing::Heap(memory) = &self.memory {
+            stable.grow_to(memory.size());
+            for (offset, page) in memory.pages() {
+                let len = (memory.size() - offset).min(page.len() as u64) as usize;
+                stable.write(offset, &page[..len]);
+            }
+        }
+
+        self.memory = Backing::Stable(stable);
+    }
+
+    /// Return the number of bytes used by the store, including the free blocks.
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.end
+    }
+
+    /// Allocate a block of at least `size` bytes, its content is undefined.
+    pub fn alloc(&mut self, size: usize) -> u64 {
+        let (class, block_size) = size_class(size);
+        if let Some(ptr) = self.free.get(class).copied().filter(|ptr| *ptr != 0) {
+            self.free[class] = self.read_u64(ptr);
+            return ptr;
+        }
+
+        let ptr = self.end;
+        self.end += block_size;
+        let end = self.end;
+        self.memory_mut().grow_to(end);
+        ptr
+    }
+
+    /// Free a block allocated with the given size.
+    pub fn free(&mut self, ptr: u64, size: usize) {
+        let (class, _) = size_class(size);
+        if self.free.len() <= class {
+            self.free.resize(class + 1, 0);
+        }
+
+        let next = self.free[class];
+        self.write_u64(ptr, next);
+        self.free[class] = ptr;
+    }
+
+    /// Allocate a block holding the given bytes, it should be freed with a size of
+    /// `4 + bytes.len()`.
+    pub fn alloc_bytes(&mut self, bytes: &[u8]) -> u64 {
+        let ptr = self.alloc(4 + bytes.len());
+        self.write(ptr, &(bytes.len() as u32).to_be_bytes());
+        self.write(ptr + 4, bytes);
+        ptr
+    }
+
+    /// Return the bytes of a block allocated with [`Store::alloc_bytes`].
+    pub fn read_bytes(&self, ptr: u64) -> Vec<u8> {
+        let mut buf = [0; 4];
+        self.read(ptr, &mut buf);
+        let mut bytes = vec![0; u32::from_be_bytes(buf) as usize];
+        self.read(ptr + 4, &mut bytes);
+        bytes
+    }
+
+    /// Free a block allocated with [`Store::alloc_bytes`].
+    pub fn free_bytes(&mut self, ptr: u64) {
+        let mut buf = [0; 4];
+        self.read(ptr, &mut buf);
+        self.free(ptr, 4 + u32::from_be_bytes(buf) as usize);
+    }
+
+    #[inline]
+    pub fn read(&self, ptr: u64, buf: &mut [u8]) {
+        self.memory().read(ptr, buf)
+    }
+
+    #[inline]
+    pub fn write(&mut self, ptr: u64, buf: &[u8]) {
+        self.memory_mut().write(ptr, buf)
+    }
+
+    /// Read a value at the given offset.
+    #[inline]
+    pub fn get<T: Stored>(&self, ptr: u64) -> T {
+        let mut buf = vec![0; T::SIZE];
+        self.read(ptr, &mut buf);
+        T::load(&buf)
+    }
+
+    /// Write a value at the given offset.
+    #[inline]
+    pub fn set<T: Stored>(&mut self, ptr: u64, value: &T) {
+        let mut buf = vec![0; T::SIZE];
+        value.store(&mut buf);
+        self.write(ptr, &buf);
+    }
+
+    #[inline]
+    fn read_u64(&self, ptr: u64) -> u64 {
+        self.get(ptr)
+    }
+
+    #[inline]
+    fn write_u64(&mut self, ptr: u64, value: u64) {
+        self.set(ptr, &value)
+    }
+
+    /// Store the given data after the last block, it can be read back with
+    /// [`take_upgrade_data`] once the canister is upgraded. The data is overwritten by the
+    /// next block that is allocated.
+    pub fn write_upgrade_data(&mut self, data: &[u8]) {
+        let end = self.end;
+        let memory = self.memory_mut();
+        memory.grow_to(end + data.len() as u64);
+        memory.write(end, data);
+        memory.write(UPGRADE_DATA_OFFSET, &end.to_be_bytes());
+        memory.write(UPGRADE_DATA_SIZE, &(data.len() as u64).to_be_bytes());
+    }
+
+    #[inline]
+    fn memory(&self) -> &dyn Memory {
+        match &self.memory {
+            Backing::Heap(memory) => memory,
+            Backing::Stable(memory) => memory,
+        }
+    }
+
+    #[inline]
+    fn memory_mut(&mut self) -> &mut dyn Memory {
+        match &mut self.memory {
+            Backing::Heap(memory) => memory,
+            Backing::Stable(memory) => memory,
+        }
+    }
+}
+
+/// Return the data stored using [`Store::write_upgrade_data`] and remove it from the memory,
+/// returns `None` if the memory doesn't hold a store or if there is no data.
+pub fn take_upgrade_data(memory: &mut impl Memory) -> Option<Vec<u8>> {
+    if memory.size() < HEADER_SIZE {
+        return None;
+    }
+
+    let mut header = [0; 24];
+    memory.read(0, &mut header);
+    let size = u64::from_be_bytes(header[16..24].try_into().unwrap());
+    if &header[..8] != MAGIC || size == 0 {
+        return None;
+    }
+
+    let mut data = vec![0; size as usize];
+    memory.read(u64::from_be_bytes(header[8..16].try_into().unwrap()), &mut data);
+    memory.write(UPGRADE_DATA_SIZE, &0u64.to_be_bytes());
+    Some(data)
+}
+
+/// Return the size class and the size of the blocks used for the given size.
+#[inline]
+fn size_class(size: usize) -> (usize, u64) {
+    if size <= SMALL_BLOCK_SIZE {
+        let steps = size.max(1).div_ceil(16);
+        (steps - 1, steps as u64 * 16)
+    } else {
+        let bits = usize::BITS - (size - 1).leading_zeros();
+        let small_classes = SMALL_BLOCK_SIZE / 16;
+        let first_bits = SMALL_BLOCK_SIZE.trailing_zeros() + 1;
+        (small_classes + (bits - first_bits) as usize, 1 << bits)
+    }
+}
+
+/// A value of a fixed size which can be written to a [`Store`].
+pub trait Stored: Sized {
+    /// The number of bytes used by a value.
+    const SIZE: usize;
+
+    /// Write the value to the buffer, which is `SIZE` bytes.
+    fn store(&self, buf: &mut [u8]);
+
+    /// Read a value from the buffer, which is `SIZE` bytes.
+    fn load(buf: &[u8]) -> Self;
+}
+
+impl Stored for u32 {
+    const SIZE: usize = 4;
+
+    #[inline]
+    fn store(&self, buf: &mut [u8]) {
+        buf.copy_from_slice(&self.to_be_bytes());
+    }
+
+    #[inline]
+    fn load(buf: &[u8]) -> Self {
+        u32::from_be_bytes(buf.try_into().unwrap())
+    }
+}
+
+impl Stored for u64 {
+    const SIZE: usize = 8;
+
+    #[inline]
+    fn store(&self, buf: &mut [u8]) {
+        buf.copy_from_slice(&self.to_be_bytes());
+    }
+
+    #[inline]
+    fn load(buf: &[u8]) -> Self {
+        u64::from_be_bytes(buf.try_into().unwrap())
+    }
+}
+
+impl Stored for Hash {
+    const SIZE: usize = 32;
+
+    #[inline]
+    fn store(&self, buf: &mut [u8]) {
+        buf.copy_from_slice(self);
+    }
+
+    #[inline]
+    fn load(buf: &[u8]) -> Self {
+        buf.try_into().unwrap()
+    }
+}
+
+/// The number of items in the first segment of an [`Array`], each of the next segments is
+/// as large as all of the previous ones until they reach [`SEGMENT_LEN`].
+const FIRST_SEGMENT_LEN: u64 = 8;
+
+/// The number of items in the largest segments of an [`Array`].
+const SEGMENT_LEN: u64 = 4096;
+
+/// The number of segments which are smaller than [`SEGMENT_LEN`].
+const SMALL_SEGMENTS: u64 = (SEGMENT_LEN.trailing_zeros() - FIRST_SEGMENT_LEN.trailing_zeros()) as u64 + 1;
+
+/// A growable array in a [`Store`]. The items are stored in segments which never move, and the
+/// offset of each segment is kept in a directory.
+#[derive(Serialize, Deserialize)]
+pub struct Array<T> {
+    len: u64,
+    /// The offset of the directory and the number of segments it can hold.
+    directory: u64,
+    capacity: u64,
+    #[serde(skip)]
+    _marker: PhantomData<T>,
+}
+
+impl<T> Clone for Array<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Array<T> {}
+
+impl<T> Default for Array<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Array<T> {
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            len: 0,
+            directory: 0,
+            capacity: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Return the number of items in the array.
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<T: Stored> Array<T> {
+    /// Append an item to the array.
+    pub fn push(&mut self, store: &mut Store, value: &T) {
+        let (segment, position) = locate(self.len);
+        if position == 0 {
+            if segment == self.capacity {
+                let capacity = (self.capacity * 2).max(1);
+                let directory = store.alloc(capacity as usize * 8);
+                for i in 0..self.capacity {
+                    let ptr: u64 = store.get(self.directory + i * 8);
+                    store.set(directory + i * 8, &ptr);
+                }
+                if self.capacity > 0 {
+                    store.free(self.directory, self.capacity as usize * 8);
+                }
+
+                self.directory = directory;
+                self.capacity = capacity;
+            }
+
+            let ptr = store.alloc(segment_len(segment) as usize * T::SIZE);
+            store.set(self.directory + segment * 8, &ptr);
+        }
+
+        store.set(self.item(store, self.len), value);
+        self.len += 1;
+    }
+
+    /// Return the item at the given index.
+    #[inline]
+    pub fn get(&self, store: &Store, index: u64) -> Option<T> {
+        if index < self.len {
+            Some(store.get(self.item(store, index)))
+        } else {
+            None
+        }
+    }
+
+    /// Return the last item of the array.
+    #[inline]
+    pub fn last(&self, store: &Store) -> Option<T> {
+        self.len.checked_sub(1).and_then(|index| self.get(store, index))
+    }
+
+    /// Return an iterator over the items of the array.
+    #[inline]
+    pub fn iter<'a>(&self, store: &'a Store) -> impl DoubleEndedIterator<Item = T> + 'a
+    where
+        T: 'a,
+    {
+        let array = *self;
+        (0..self.len).map(move |index| store.get(array.item(store, index)))
+    }
+
+    /// Return the index of the first item for which the predicate is false, the predicate must
+    /// be true for all of the items before it and false for all of the items after it.
+    pub fn partition_point(&self, store: &Store, mut pred: impl FnMut(&T) -> bool) -> u64 {
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let mid = (low + high) / 2;
+            if pred(&store.get(self.item(store, mid))) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+
+    /// Free the blocks used by the array.
+    pub fn free(&self, store: &mut Store) {
+        let segments = self.len.checked_sub(1).map_or(0, |last| locate(last).0 + 1);
+        for segment in 0..segments {
+            let ptr = store.get(self.directory + segment * 8);
+            store.free(ptr, segment_len(segment) as usize * T::SIZE);
+        }
+
+        if self.capacity > 0 {
+            store.free(self.directory, self.capacity as usize * 8);
+        }
+    }
+
+    /// Remove the last item of the array, the segment holding it is freed once it is empty.
+    pub fn pop(&mut self, store: &mut Store) -> Option<T> {
+        let index = self.len.checked_sub(1)?;
+        let value = store.get(self.item(store, index));
+
+        let (segment, position) = locate(index);
+        if position == 0 {
+            let ptr = store.get(self.directory + segment * 8);
+            store.free(ptr, segment_len(segment) as usize * T::SIZE);
+        }
+
+        self.len = index;
+        Some(value)
+    }
+
+    /// Free the blocks used by the array in steps, at most `budget` blocks are freed and their
+    /// number is subtracted from it. Return `true` once all of the blocks are freed, the array
+    /// is empty then.
+    pub fn free_some(&mut self, store: &mut Store, budget: &mut usize) -> bool {
+        while *budget > 0 && self.len > 0 {
+            let (segment, position) = locate(self.len - 1);
+            let ptr = store.get(self.directory + segment * 8);
+            store.free(ptr, segment_len(segment) as usize * T::SIZE);
+            self.len -= position + 1;
+            *budget -= 1;
+        }
+
+        if *budget > 0 && self.len == 0 && self.capacity > 0 {
+            store.free(self.directory, self.capacity as usize * 8);
+            self.directory = 0;
+            self.capacity = 0;
+            *budget -= 1;
+        }
+
+        self.len == 0 && self.capacity == 0
+    }
+
+    /// Return the offset of the item at the given index.
+    #[inline]
+    fn item(&self, store: &Store, index: u64) -> u64 {
+        let (segment, position) = locate(index);
+        let ptr: u64 = store.get(self.directory + segment * 8);
+        ptr + position * T::SIZE as u64
+    }
+}
+
+impl<T> Stored for Array<T> {
+    const SIZE: usize = 24;
+
+    #[inline]
+    fn store(&self, buf: &mut [u8]) {
+        self.len.store(&mut buf[..8]);
+        self.directory.store(&mut buf[8..16]);
+        self.capacity.store(&mut buf[16..]);
+    }
+
+    #[inline]
+    fn load(buf: &[u8]) -> Self {
+        Self {
+            len: u64::load(&buf[..8]),
+            directory: u64::load(&buf[8..16]),
+            capacity: u64::load(&buf[16..]),
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Return the segment holding the given index and the position of the index in it.
+#[inline]
+fn locate(index: u64) -> (u64, u64) {
+    if index < FIRST_SEGMENT_LEN {
+        (0, index)
+    } else if index < SEGMENT_LEN {
+        let segment = (u64::BITS - (index / FIRST_SEGMENT_LEN).leading_zeros()) as u64;
+        (segment, index - (FIRST_SEGMENT_LEN << (segment - 1)))
+    } else {
+        (index / SEGMENT_LEN + SMALL_SEGMENTS - 1, index % SEGMENT_LEN)
+    }
+}
+
+/// Return the number of items in the given segment.
+#[inline]
+fn segment_len(segment: u64) -> u64 {
+    match segment {
+        0 => FIRST_SEGMENT_LEN,
+        _ => FIRST_SEGMENT_LEN << (segment - 1).min(SMALL_SEGMENTS - 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_kit::MockContext;
+
+    #[test]
+    fn test_size_class() {
+        assert_eq!(size_class(0), (0, 16));
+        assert_eq!(size_class(16), (0, 16));
+        assert_eq!(size_class(17), (1, 32));
+        assert_eq!(size_class(4096), (255, 4096));
+        assert_eq!(size_class(4097), (256, 8192));
+        assert_eq!(size_class(8192), (256, 8192));
+        assert_eq!(size_class(8193), (257, 16384));
+    }
+
+    #[test]
+    fn test_alloc() {
+        let mut store = Store::new();
+        let a = store.alloc(20);
+        let b = store.alloc(20);
+        assert_eq!(a, HEADER_SIZE);
+        assert_eq!(b, a + 32);
+
+        // The freed blocks are reused by the allocations of the same class.
+        store.free(a, 20);
+        assert_eq!(store.alloc(100), b + 32);
+        assert_eq!(store.alloc(32), a);
+
+        let bytes = store.alloc_bytes(b"event");
+        assert_eq!(store.read_bytes(bytes), b"event".to_vec());
+        store.free_bytes(bytes);
+        assert_eq!(store.alloc(9), bytes);
+    }
+
+    #[test]
+    fn test_array() {
+        for (index, expected) in [(0, (0, 0)), (7, (0, 7)), (8, (1, 0)), (16, (2, 0))] {
+            assert_eq!(locate(index), expected);
+        }
+        assert_eq!(locate(4095), (9, 2047));
+        assert_eq!(locate(4096), (10, 0));
+        assert_eq!(locate(8192), (11, 0));
+        assert_eq!(segment_len(10), SEGMENT_LEN);
+        assert_eq!(segment_len(11), SEGMENT_LEN);
+
+        let mut store = Store::new();
+        let mut array = Array::<u64>::new();
+        for i in 0..10_000u64 {
+            array.push(&mut store, &(i * 3));
+        }
+
+        assert_eq!(array.len(), 10_000);
+        assert_eq!(array.get(&store, 4095), Some(4095 * 3));
+        assert_eq!(array.get(&store, 10_000), None);
+        assert_eq!(array.last(&store), Some(9_999 * 3));
+        assert!(array.iter(&store).eq((0..10_000).map(|i| i * 3)));
+        assert_eq!(array.partition_point(&store, |v| *v < 3_001), 1_001);
+
+        // The blocks of a freed array are reused.
+        let end = store.size();
+        array.free(&mut store);
+        let mut array = Array::<u64>::new();
+        for i in 0..10_000u64 {
+            array.push(&mut store, &i);
+        }
+        assert_eq!(store.size(), end);
+    }
+
+    #[test]
+    fn test_large_offsets() {
+        // The blocks of a store past the first 4 GiB don't wrap around.
+        let mut store = Store::new();
+        store.end = 1 << 32;
+        let mut array = Array::<u32>::new();
+        for i in 0..100 {
+            array.push(&mut store, &i);
+        }
+        assert!(array.iter(&store).eq(0..100));
+        assert!(store.size() > 1 << 32);
+    }
+
+    #[test]
+    fn test_upgrade_data() {
+        MockContext::new().inject();
+        assert_eq!(take_upgrade_data(&mut StableMemory), None);
+
+        let mut store = Store::new();
+        let mut array = Array::<u32>::new();
+        for i in 0..100 {
+            array.push(&mut store, &i);
+        }
+        store.set_stable();
+        assert!(store.is_stable());
+        array.push(&mut store, &100);
+
+        store.write_upgrade_data(b"data");
+        assert_eq!(take_upgrade_data(&mut StableMemory), Some(b"data".to_vec()));
+        assert_eq!(take_upgrade_data(&mut StableMemory), None);
+
+        // Only the handles are serialized, the content stays in the stable memory.
+        let store: Store = serde_cbor::from_slice(&serde_cbor::to_vec(&store).unwrap()).unwrap();
+        assert!(array.iter(&store).eq(0..101));
+        assert_eq!(take_upgrade_data(&mut VecMemory::default()), None);
+    }
+}
@@ -1,11 +1,15 @@
 use crate::did::EventHash;
+use crate::tree::Key;
+use certified_vars::label::Label;
 use certified_vars::HashTree::Pruned;
 use certified_vars::{AsHashTree, Hash, HashTree};
-use ic_kit::candid::{CandidType, Deserialize, Nat};
+use ic_kit::candid::{CandidType, Deserialize, Int, Nat};
 use ic_kit::Principal;
 use serde::Serialize;
-use sha2::{Digest, Sha256};
-use std::collections::BTreeSet;
+use sha2::{Digest, Sha224, Sha256};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryInto;
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -28,8 +32,46 @@ pub struct IndefiniteEvent {
     pub operation: String,
     /// Details of the transaction.
     pub details: Vec<(String, DetailValue)>,
+    /// A key chosen by the client to make the insert idempotent, inserting an event with a
+    /// key which was recently used returns the id of the first event instead. It is not part
+    /// of the stored event.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
 }
 
+/// The role of the caller of an event in the user index.
+pub const CALLER_ROLE: &str = "caller";
+
+/// The maximum length of an operation in bytes.
+pub const MAX_OPERATION_LEN: usize = 255;
+
+/// The maximum length of a detail key in bytes, this applies to the keys of nested maps and
+/// to idempotency keys too.
+pub const MAX_KEY_LEN: usize = 255;
+
+/// The maximum size of an event, as estimated by [`Event::size_hint`].
+pub const MAX_EVENT_SIZE: usize = 64 * 1024;
+
+/// The maximum number of nested vectors, maps and options in the details of an event.
+pub const MAX_DETAIL_DEPTH: usize = 16;
+
+/// The reason an event is rejected on insert.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum EventError {
+    /// The operation is longer than [`MAX_OPERATION_LEN`].
+    OperationTooLong { len: u64, max: u64 },
+    /// A detail key or the idempotency key is longer than [`MAX_KEY_LEN`].
+    KeyTooLong { len: u64, max: u64 },
+    /// The key appears more than once in the same map.
+    DuplicateKey(String),
+    /// The event is larger than [`MAX_EVENT_SIZE`].
+    TooLarge { size: u64, max: u64 },
+    /// The details are nested deeper than [`MAX_DETAIL_DEPTH`].
+    TooDeep { max: u64 },
+}
+
+/// A value in the details of an event. Variants are only ever added, so the events stored by
+/// older versions of the canisters can still be decoded.
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum DetailValue {
     True,
@@ -43,6 +85,113 @@ pub enum DetailValue {
     Slice(Vec<u8>),
     Vec(Vec<DetailValue>),
     TokenIdU64(u64),
+    #[serde(with = "nat")]
+    Nat(Nat),
+    #[serde(with = "int")]
+    Int(Int),
+    #[serde(with = "u128_str")]
+    U128(u128),
+    Map(Vec<(String, DetailValue)>),
+    Option(Option<Box<DetailValue>>),
+    Account(Account),
+    #[serde(with = "serde_bytes")]
+    AccountId(Vec<u8>),
+    #[serde(with = "nat")]
+    TokenIdNat(Nat),
+    TokenIdText(String),
+}
+
+/// The id of a token, DIP721v1 uses `u64` ids, DIP721v2 uses `Nat` ids and EXT uses text
+/// identifiers.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TokenId {
+    U64(u64),
+    #[serde(with = "nat")]
+    Nat(Nat),
+    Text(String),
+}
+
+impl Label for TokenId {
+    /// The kind of the id followed by its content, a `Nat` is prefixed by its length so the
+    /// labels of the numbers are ordered by their value.
+    fn as_label(&self) -> Cow<'_, [u8]> {
+        let label = match self {
+            TokenId::U64(id) => [&[0], &id.to_be_bytes()[..]].concat(),
+            TokenId::Nat(id) => {
+                let bytes = id.0.to_bytes_be();
+                [&[1], &(bytes.len() as u32).to_be_bytes()[..], &bytes].concat()
+            }
+            TokenId::Text(id) => [&[2], id.as_bytes()].concat(),
+        };
+
+        Cow::Owned(label)
+    }
+}
+
+/// Token ids are ordered by their label, so the order of the indexes matches the order of
+/// the labels in their hash tree.
+impl Ord for TokenId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_label().cmp(&other.as_label())
+    }
+}
+
+impl Key for TokenId {}
+
+impl PartialOrd for TokenId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl From<u64> for TokenId {
+    fn from(id: u64) -> Self {
+        Self::U64(id)
+    }
+}
+
+impl From<Nat> for TokenId {
+    fn from(id: Nat) -> Self {
+        Self::Nat(id)
+    }
+}
+
+impl From<String> for TokenId {
+    fn from(id: String) -> Self {
+        Self::Text(id)
+    }
+}
+
+impl From<&str> for TokenId {
+    fn from(id: &str) -> Self {
+        Self::Text(id.into())
+    }
+}
+
+/// An account of a ledger, which is a principal along with an optional subaccount.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    /// Return the 32 byte identifier of this account on the ICP ledger, a missing subaccount
+    /// is the same as the subaccount filled with zeros.
+    pub fn account_id(&self) -> Vec<u8> {
+        let mut h = Sha224::new();
+        h.update(b"\x0Aaccount-id");
+        h.update(self.owner.as_slice());
+        match &self.subaccount {
+            Some(subaccount) => h.update(subaccount),
+            None => h.update([0; 32]),
+        }
+        let hash = h.finalize();
+
+        let mut id = crc32fast::hash(&hash).to_be_bytes().to_vec();
+        id.extend_from_slice(&hash);
+        id
+    }
 }
 
 impl Event {
@@ -53,51 +202,108 @@ impl Event {
 
         principals.insert(&self.caller);
 
-        fn visit<'a>(principals: &mut BTreeSet<&'a Principal>, value: &'a DetailValue) {
+        for (_, value) in &self.details {
+            visit_principals(value, &mut |p| {
+                principals.insert(p);
+            });
+        }
+
+        principals
+    }
+
+    /// Return the principal ids involved in an event along with their roles, which are the
+    /// detail keys they appear under, or [`CALLER_ROLE`] for the caller of the event.
+    pub fn extract_principal_roles(&self) -> BTreeMap<&Principal, BTreeSet<&str>> {
+        let mut roles = BTreeMap::<_, BTreeSet<_>>::new();
+
+        roles.entry(&self.caller).or_default().insert(CALLER_ROLE);
+
+        for (key, value) in &self.details {
+            visit_principals(value, &mut |p| {
+                roles.entry(p).or_default().insert(key.as_str());
+            });
+        }
+
+        roles
+    }
+
+    /// Return a set containing all of the token ids involved in an event.
+    #[inline]
+    pub fn extract_token_ids(&self) -> BTreeSet<TokenId> {
+        let mut tokens = BTreeSet::new();
+
+        fn visit(tokens: &mut BTreeSet<TokenId>, value: &DetailValue) {
             match value {
-                DetailValue::Principal(p) => {
-                    principals.insert(p);
+                DetailValue::TokenIdU64(id) => {
+                    tokens.insert(TokenId::U64(*id));
+                }
+                DetailValue::TokenIdNat(id) => {
+                    tokens.insert(TokenId::Nat(id.clone()));
+                }
+                DetailValue::TokenIdText(id) => {
+                    tokens.insert(TokenId::Text(id.clone()));
                 }
                 DetailValue::Vec(items) => {
                     for item in items {
-                        visit(principals, item);
+                        visit(tokens, item);
                     }
                 }
+                DetailValue::Map(items) => {
+                    for (_, item) in items {
+                        visit(tokens, item);
+                    }
+                }
+                DetailValue::Option(Some(item)) => visit(tokens, item),
                 _ => {}
             }
         }
 
         for (_, value) in &self.details {
-            visit(&mut principals, value);
+            visit(&mut tokens, value);
         }
 
-        principals
+        tokens
     }
 
-    /// Return a set containing all of the token ids involved in an event.
+    /// Return a set containing the ledger identifiers of all the accounts involved in an
+    /// event, see [`Account::account_id`].
     #[inline]
-    pub fn extract_token_ids(&self) -> BTreeSet<u64> {
-        let mut tokens = BTreeSet::new();
+    pub fn extract_account_ids(&self) -> BTreeSet<Vec<u8>> {
+        let mut accounts = BTreeSet::new();
 
-        fn visit(tokens: &mut BTreeSet<u64>, value: &DetailValue) {
+        fn visit(accounts: &mut BTreeSet<Vec<u8>>, value: &DetailValue) {
             match value {
-                DetailValue::TokenIdU64(id) => {
-                    tokens.insert(*id);
+                DetailValue::Account(account) => {
+                    accounts.insert(account.account_id());
+                }
+                DetailValue::AccountId(id) => {
+                    accounts.insert(id.clone());
                 }
                 DetailValue::Vec(items) => {
                     for item in items {
-                        visit(tokens, item);
+                        visit(accounts, item);
+                    }
+                }
+                DetailValue::Map(items) => {
+                    for (_, item) in items {
+                        visit(accounts, item);
                     }
                 }
+                DetailValue::Option(Some(item)) => visit(accounts, item),
                 _ => {}
             }
         }
 
         for (_, value) in &self.details {
-            visit(&mut tokens, value);
+            visit(&mut accounts, value);
         }
 
-        tokens
+        accounts
+    }
+
+    /// Return an estimate of the size of this event once it is encoded in a response.
+    pub fn size_hint(&self) -> usize {
+        event_size(&self.caller, &self.operation, &self.details)
     }
 
     /// Compute the hash for the given event.
@@ -112,49 +318,49 @@ impl Event {
         fn hash_value(h: &mut Sha256, value: &DetailValue) {
             match value {
                 DetailValue::True => {
-                    h.update(&[0]);
+                    h.update([0]);
                 }
                 DetailValue::False => {
-                    h.update(&[1]);
+                    h.update([1]);
                 }
                 DetailValue::U64(val) => {
                     let bytes = val.to_be_bytes();
-                    h.update(&[2]);
+                    h.update([2]);
                     h.update(&bytes.len().to_be_bytes() as &[u8]);
                     h.update(bytes);
                 }
                 DetailValue::I64(val) => {
                     let bytes = val.to_be_bytes();
-                    h.update(&[3]);
+                    h.update([3]);
                     h.update(&bytes.len().to_be_bytes() as &[u8]);
                     h.update(bytes);
                 }
                 DetailValue::Float(val) => {
                     let bytes = val.to_be_bytes();
-                    h.update(&[4]);
+                    h.update([4]);
                     h.update(&bytes.len().to_be_bytes() as &[u8]);
                     h.update(bytes);
                 }
                 DetailValue::Text(val) => {
                     let bytes = val.as_str().as_bytes();
-                    h.update(&[5]);
+                    h.update([5]);
                     h.update(&bytes.len().to_be_bytes() as &[u8]);
                     h.update(bytes);
                 }
                 DetailValue::Principal(val) => {
                     let bytes = val.as_slice();
-                    h.update(&[6]);
+                    h.update([6]);
                     h.update(&bytes.len().to_be_bytes() as &[u8]);
                     h.update(bytes);
                 }
                 DetailValue::Slice(val) => {
                     let bytes = val.as_slice();
-                    h.update(&[7]);
+                    h.update([7]);
                     h.update(&bytes.len().to_be_bytes() as &[u8]);
                     h.update(bytes);
                 }
                 DetailValue::Vec(val) => {
-                    h.update(&[8]);
+                    h.update([8]);
                     h.update(&val.len().to_be_bytes() as &[u8]);
                     for item in val.iter() {
                         hash_value(h, item);
@@ -162,29 +368,132 @@ impl Event {
                 }
                 DetailValue::TokenIdU64(val) => {
                     let bytes = val.to_be_bytes();
-                    h.update(&[9]);
+                    h.update([9]);
+                    h.update(&bytes.len().to_be_bytes() as &[u8]);
+                    h.update(bytes);
+                }
+                DetailValue::Nat(val) => {
+                    let bytes = val.0.to_bytes_be();
+                    h.update([10]);
+                    h.update(&bytes.len().to_be_bytes() as &[u8]);
+                    h.update(bytes);
+                }
+                DetailValue::Int(val) => {
+                    let bytes = val.0.to_signed_bytes_be();
+                    h.update([11]);
+                    h.update(&bytes.len().to_be_bytes() as &[u8]);
+                    h.update(bytes);
+                }
+                DetailValue::U128(val) => {
+                    let bytes = val.to_be_bytes();
+                    h.update([12]);
+                    h.update(&bytes.len().to_be_bytes() as &[u8]);
+                    h.update(bytes);
+                }
+                DetailValue::Map(val) => {
+                    h.update([13]);
+                    h.update(&val.len().to_be_bytes() as &[u8]);
+                    hash_map(h, val);
+                }
+                DetailValue::Option(None) => {
+                    h.update([14]);
+                    h.update([0]);
+                }
+                DetailValue::Option(Some(val)) => {
+                    h.update([14]);
+                    h.update([1]);
+                    hash_value(h, val);
+                }
+                DetailValue::Account(val) => {
+                    let owner = val.owner.as_slice();
+                    h.update([15]);
+                    h.update(&owner.len().to_be_bytes() as &[u8]);
+                    h.update(owner);
+                    match &val.subaccount {
+                        None => h.update([0]),
+                        Some(subaccount) => {
+                            h.update([1]);
+                            h.update(&subaccount.len().to_be_bytes() as &[u8]);
+                            h.update(subaccount);
+                        }
+                    }
+                }
+                DetailValue::AccountId(val) => {
+                    let bytes = val.as_slice();
+                    h.update([16]);
+                    h.update(&bytes.len().to_be_bytes() as &[u8]);
+                    h.update(bytes);
+                }
+                DetailValue::TokenIdNat(val) => {
+                    let bytes = val.0.to_bytes_be();
+                    h.update([17]);
+                    h.update(&bytes.len().to_be_bytes() as &[u8]);
+                    h.update(bytes);
+                }
+                DetailValue::TokenIdText(val) => {
+                    let bytes = val.as_str().as_bytes();
+                    h.update([18]);
                     h.update(&bytes.len().to_be_bytes() as &[u8]);
                     h.update(bytes);
                 }
             }
         }
 
-        for (key, value) in &self.details {
-            h.update(&key.len().to_be_bytes() as &[u8]);
-            h.update(key.as_str().as_bytes());
-            hash_value(&mut h, value);
+        fn hash_map(h: &mut Sha256, items: &[(String, DetailValue)]) {
+            for (key, value) in items {
+                h.update(&key.len().to_be_bytes() as &[u8]);
+                h.update(key.as_str().as_bytes());
+                hash_value(h, value);
+            }
         }
 
+        hash_map(&mut h, &self.details);
+
         h.finalize().into()
     }
 }
 
+impl DetailValue {
+    /// Return the bytes used to look up this value in a detail index, which is the tag used
+    /// for the value in `Event::hash` followed by its content. A `Vec` or a `Map` can not be
+    /// indexed.
+    pub fn index_key(&self) -> Option<Vec<u8>> {
+        let (tag, bytes) = match self {
+            DetailValue::True => (0, vec![]),
+            DetailValue::False => (1, vec![]),
+            DetailValue::U64(val) => (2, val.to_be_bytes().to_vec()),
+            DetailValue::I64(val) => (3, val.to_be_bytes().to_vec()),
+            DetailValue::Float(val) => (4, val.to_be_bytes().to_vec()),
+            DetailValue::Text(val) => (5, val.as_bytes().to_vec()),
+            DetailValue::Principal(val) => (6, val.as_slice().to_vec()),
+            DetailValue::Slice(val) => (7, val.clone()),
+            DetailValue::Vec(_) => return None,
+            DetailValue::TokenIdU64(val) => (9, val.to_be_bytes().to_vec()),
+            DetailValue::Nat(val) => (10, val.0.to_bytes_be()),
+            DetailValue::Int(val) => (11, val.0.to_signed_bytes_be()),
+            DetailValue::U128(val) => (12, val.to_be_bytes().to_vec()),
+            DetailValue::Map(_) => return None,
+            DetailValue::Option(None) => (14, vec![0]),
+            DetailValue::Option(Some(val)) => (14, [&[1], &val.index_key()?[..]].concat()),
+            DetailValue::Account(val) => (15, val.account_id()),
+            DetailValue::AccountId(val) => (16, val.clone()),
+            DetailValue::TokenIdNat(val) => (17, val.0.to_bytes_be()),
+            DetailValue::TokenIdText(val) => (18, val.as_bytes().to_vec()),
+        };
+
+        let mut key = vec![tag];
+        key.extend_from_slice(&bytes);
+        Some(key)
+    }
+}
+
 impl Into<IndefiniteEvent> for Event {
     fn into(self) -> IndefiniteEvent {
         IndefiniteEvent {
             caller: self.caller,
             operation: self.operation,
             details: self.details,
+            idempotency_key: None,
         }
     }
 }
@@ -200,6 +509,88 @@ impl IndefiniteEvent {
             details: self.details,
         }
     }
+
+    /// Check that the event can be inserted, see [`EventError`] for the limits.
+    pub fn validate(&self) -> Result<(), EventError> {
+        if self.operation.len() > MAX_OPERATION_LEN {
+            return Err(EventError::OperationTooLong {
+                len: self.operation.len() as u64,
+                max: MAX_OPERATION_LEN as u64,
+            });
+        }
+
+        if let Some(key) = &self.idempotency_key {
+            if key.len() > MAX_KEY_LEN {
+                return Err(EventError::KeyTooLong {
+                    len: key.len() as u64,
+                    max: MAX_KEY_LEN as u64,
+                });
+            }
+        }
+
+        fn check_depth(depth: usize) -> Result<(), EventError> {
+            if depth > MAX_DETAIL_DEPTH {
+                return Err(EventError::TooDeep {
+                    max: MAX_DETAIL_DEPTH as u64,
+                });
+            }
+
+            Ok(())
+        }
+
+        fn check_map(items: &[(String, DetailValue)], depth: usize) -> Result<(), EventError> {
+            check_depth(depth)?;
+
+            let mut keys = BTreeSet::new();
+            for (key, value) in items {
+                if key.len() > MAX_KEY_LEN {
+                    return Err(EventError::KeyTooLong {
+                        len: key.len() as u64,
+                        max: MAX_KEY_LEN as u64,
+                    });
+                }
+
+                if !keys.insert(key.as_str()) {
+                    return Err(EventError::DuplicateKey(key.clone()));
+                }
+
+                check_value(value, depth + 1)?;
+            }
+
+            Ok(())
+        }
+
+        fn check_value(value: &DetailValue, depth: usize) -> Result<(), EventError> {
+            match value {
+                DetailValue::Vec(items) => {
+                    check_depth(depth)?;
+                    for item in items {
+                        check_value(item, depth + 1)?;
+                    }
+                    Ok(())
+                }
+                DetailValue::Map(items) => check_map(items, depth),
+                DetailValue::Option(Some(item)) => {
+                    check_depth(depth)?;
+                    check_value(item, depth + 1)
+                }
+                _ => Ok(()),
+            }
+        }
+
+        check_map(&self.details, 0)?;
+
+        let size = event_size(&self.caller, &self.operation, &self.details);
+
+        if size > MAX_EVENT_SIZE {
+            return Err(EventError::TooLarge {
+                size: size as u64,
+                max: MAX_EVENT_SIZE as u64,
+            });
+        }
+
+        Ok(())
+    }
 }
 
 impl From<u64> for DetailValue {
@@ -294,31 +685,256 @@ impl TryInto<Principal> for DetailValue {
 
 impl From<Nat> for DetailValue {
     fn from(nat: Nat) -> Self {
-        let mut vec = vec![];
-
-        nat.encode(&mut vec).unwrap();
-
-        DetailValue::Slice(vec)
+        Self::Nat(nat)
     }
 }
 
 impl TryInto<Nat> for DetailValue {
     type Error = ();
 
+    /// Values stored as an `U64` are converted, as well as the `Slice` containing the LEB128
+    /// encoded number which older versions stored.
     fn try_into(self) -> Result<Nat, Self::Error> {
-        if let Self::Slice(nat) = self {
-            if let Ok(nat) = Nat::parse(&nat) {
-                Ok(nat)
-            } else {
-                Err(())
-            }
+        match self {
+            Self::Nat(nat) => Ok(nat),
+            Self::U64(num) => Ok(Nat::from(num)),
+            Self::U128(num) => Ok(Nat::from(num)),
+            Self::Slice(nat) => Nat::decode(&mut nat.as_slice()).map_err(|_| ()),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<Int> for DetailValue {
+    fn from(int: Int) -> Self {
+        Self::Int(int)
+    }
+}
+
+impl TryInto<Int> for DetailValue {
+    type Error = ();
+
+    fn try_into(self) -> Result<Int, Self::Error> {
+        match self {
+            Self::Int(int) => Ok(int),
+            Self::I64(num) => Ok(Int::from(num)),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<u128> for DetailValue {
+    fn from(num: u128) -> Self {
+        Self::U128(num)
+    }
+}
+
+impl TryInto<u128> for DetailValue {
+    type Error = ();
+
+    fn try_into(self) -> Result<u128, Self::Error> {
+        match self {
+            Self::U128(num) => Ok(num),
+            Self::U64(num) => Ok(num as u128),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<Vec<(String, DetailValue)>> for DetailValue {
+    fn from(map: Vec<(String, DetailValue)>) -> Self {
+        Self::Map(map)
+    }
+}
+
+impl TryInto<Vec<(String, DetailValue)>> for DetailValue {
+    type Error = ();
+
+    fn try_into(self) -> Result<Vec<(String, DetailValue)>, Self::Error> {
+        if let Self::Map(map) = self {
+            Ok(map)
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl<T: Into<DetailValue>> From<Option<T>> for DetailValue {
+    fn from(value: Option<T>) -> Self {
+        Self::Option(value.map(|value| Box::new(value.into())))
+    }
+}
+
+impl From<Account> for DetailValue {
+    fn from(account: Account) -> Self {
+        Self::Account(account)
+    }
+}
+
+impl TryInto<Account> for DetailValue {
+    type Error = ();
+
+    fn try_into(self) -> Result<Account, Self::Error> {
+        if let Self::Account(account) = self {
+            Ok(account)
         } else {
             Err(())
         }
     }
 }
 
+impl From<TokenId> for DetailValue {
+    fn from(id: TokenId) -> Self {
+        match id {
+            TokenId::U64(id) => Self::TokenIdU64(id),
+            TokenId::Nat(id) => Self::TokenIdNat(id),
+            TokenId::Text(id) => Self::TokenIdText(id),
+        }
+    }
+}
+
+impl TryInto<TokenId> for DetailValue {
+    type Error = ();
+
+    fn try_into(self) -> Result<TokenId, Self::Error> {
+        match self {
+            Self::TokenIdU64(id) => Ok(TokenId::U64(id)),
+            Self::TokenIdNat(id) => Ok(TokenId::Nat(id)),
+            Self::TokenIdText(id) => Ok(TokenId::Text(id)),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Big numbers are stored as decimal strings outside of candid, so the encoding does not
+/// depend on the serializer.
+mod nat {
+    use ic_kit::candid::{Deserialize, Nat};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(nat: &Nat, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&nat.0.to_str_radix(10))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Nat, D::Error> {
+        Nat::deserialize(deserializer)
+    }
+}
+
+mod int {
+    use ic_kit::candid::{Deserialize, Int};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(int: &Int, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&int.0.to_str_radix(10))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Int, D::Error> {
+        Int::deserialize(deserializer)
+    }
+}
+
+/// CBOR can only store numbers up to 64 bits, so an `u128` is stored as a decimal string
+/// outside of candid.
+mod u128_str {
+    use serde::de::{self, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(num: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&num.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+        struct U128Visitor;
+
+        impl<'de> Visitor<'de> for U128Visitor {
+            type Value = u128;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                formatter.write_str("an u128")
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<u128, E> {
+                Ok(v as u128)
+            }
+
+            fn visit_u128<E>(self, v: u128) -> Result<u128, E> {
+                Ok(v)
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<u128, E> {
+                v.parse()
+                    .map_err(|_| E::custom(format!("{:?} is not an u128", v)))
+            }
+        }
+
+        deserializer.deserialize_u128(U128Visitor)
+    }
+}
+
+/// Call the given function with every principal id contained in the value.
+fn visit_principals<'a>(value: &'a DetailValue, f: &mut impl FnMut(&'a Principal)) {
+    match value {
+        DetailValue::Principal(p) => f(p),
+        DetailValue::Account(account) => f(&account.owner),
+        DetailValue::Vec(items) => {
+            for item in items {
+                visit_principals(item, f);
+            }
+        }
+        DetailValue::Map(items) => {
+            for (_, item) in items {
+                visit_principals(item, f);
+            }
+        }
+        DetailValue::Option(Some(item)) => visit_principals(item, f),
+        _ => {}
+    }
+}
+
+fn value_size(value: &DetailValue) -> usize {
+    match value {
+        DetailValue::True | DetailValue::False => 1,
+        DetailValue::U64(_)
+        | DetailValue::I64(_)
+        | DetailValue::Float(_)
+        | DetailValue::TokenIdU64(_) => 9,
+        DetailValue::Text(val) => val.len() + 3,
+        DetailValue::Principal(val) => val.as_slice().len() + 2,
+        DetailValue::Slice(val) => val.len() + 3,
+        DetailValue::Vec(val) => val.iter().map(value_size).sum::<usize>() + 3,
+        DetailValue::Nat(val) => val.0.to_bytes_be().len() + 2,
+        DetailValue::Int(val) => val.0.to_signed_bytes_be().len() + 2,
+        DetailValue::U128(_) => 17,
+        DetailValue::Map(val) => map_size(val) + 3,
+        DetailValue::Option(val) => val.as_deref().map_or(0, value_size) + 2,
+        DetailValue::Account(val) => {
+            val.owner.as_slice().len() + val.subaccount.as_ref().map_or(0, Vec::len) + 5
+        }
+        DetailValue::AccountId(val) => val.len() + 3,
+        DetailValue::TokenIdNat(val) => val.0.to_bytes_be().len() + 2,
+        DetailValue::TokenIdText(val) => val.len() + 3,
+    }
+}
+
+/// The estimated size of an event with the given fields, see [`Event::size_hint`].
+#[inline]
+fn event_size(caller: &Principal, operation: &str, details: &[(String, DetailValue)]) -> usize {
+    8 + caller.as_slice().len() + 2 + operation.len() + 2 + map_size(details) + 2
+}
+
+fn map_size(items: &[(String, DetailValue)]) -> usize {
+    items
+        .iter()
+        .map(|(key, value)| key.len() + 2 + value_size(value))
+        .sum::<usize>()
+}
+
+/// The operation is prefixed by its length as a single byte, which is why the operations are
+/// limited to [`MAX_OPERATION_LEN`] bytes.
 fn domain_sep(s: &str) -> sha2::Sha256 {
+    debug_assert!(s.len() <= MAX_OPERATION_LEN);
     let buf: [u8; 1] = [s.len() as u8];
     let mut h = sha2::Sha256::new();
     h.update(&buf[..]);
@@ -336,4 +952,230 @@ impl AsHashTree for Event {
     }
 }
 
-// TODO(qti3e) Test
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_kit::candid::{decode_one, encode_one};
+    use ic_kit::mock_principals;
+
+    fn event(value: DetailValue) -> Event {
+        Event {
+            time: 0,
+            caller: mock_principals::alice(),
+            operation: "transfer".into(),
+            details: vec![("value".into(), value)],
+        }
+    }
+
+    fn values() -> Vec<DetailValue> {
+        vec![
+            DetailValue::U64(7),
+            DetailValue::TokenIdU64(7),
+            DetailValue::Nat(Nat::from(7u64)),
+            DetailValue::Int(Int::from(7i64)),
+            DetailValue::U128(7),
+            DetailValue::Nat(Nat::from(u128::MAX) * Nat::from(3u64)),
+            DetailValue::Int(Int::from(-7i64)),
+            DetailValue::U128(u128::MAX),
+            DetailValue::Map(vec![("amount".into(), DetailValue::U64(7))]),
+            DetailValue::Vec(vec![DetailValue::U64(7)]),
+            DetailValue::Option(None),
+            DetailValue::Option(Some(Box::new(DetailValue::U64(7)))),
+            DetailValue::Option(Some(Box::new(DetailValue::Option(None)))),
+            DetailValue::Account(Account {
+                owner: mock_principals::alice(),
+                subaccount: None,
+            }),
+            DetailValue::Account(Account {
+                owner: mock_principals::alice(),
+                subaccount: Some(vec![0; 32]),
+            }),
+            DetailValue::AccountId(vec![7; 32]),
+        ]
+    }
+
+    #[test]
+    fn test_hash() {
+        let hashes = values()
+            .into_iter()
+            .map(|value| event(value).hash())
+            .collect::<BTreeSet<_>>();
+        assert_eq!(hashes.len(), values().len());
+    }
+
+    #[test]
+    fn test_serialize() {
+        for value in values() {
+            let event = event(value);
+            let bytes = serde_cbor::to_vec(&event).unwrap();
+            assert_eq!(serde_cbor::from_slice::<Event>(&bytes).unwrap(), event);
+            let bytes = encode_one(&event).unwrap();
+            assert_eq!(decode_one::<Event>(&bytes).unwrap(), event);
+        }
+    }
+
+    #[test]
+    fn test_extract() {
+        let event = event(DetailValue::Map(vec![
+            ("to".into(), mock_principals::bob().into()),
+            (
+                "token".into(),
+                DetailValue::Option(Some(Box::new(DetailValue::TokenIdU64(3)))),
+            ),
+        ]));
+
+        assert!(event
+            .extract_principal_ids()
+            .contains(&mock_principals::bob()));
+        assert_eq!(
+            event.extract_token_ids(),
+            vec![TokenId::U64(3)].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_account_id() {
+        let account = Account {
+            owner: Principal::anonymous(),
+            subaccount: None,
+        };
+        assert_eq!(
+            hex(&account.account_id()),
+            "1c7a48ba6a562aa9eaa2481a9049cdf0433b9738c992d698c31d8abf89cadc79"
+        );
+
+        // The default subaccount is the same as no subaccount.
+        let default = Account {
+            subaccount: Some(vec![0; 32]),
+            ..account.clone()
+        };
+        assert_eq!(default.account_id(), account.account_id());
+        assert_ne!(event(account.into()).hash(), event(default.into()).hash());
+    }
+
+    #[test]
+    fn test_extract_accounts() {
+        let account = Account {
+            owner: mock_principals::bob(),
+            subaccount: Some(vec![1; 32]),
+        };
+        let event = event(DetailValue::Vec(vec![
+            account.clone().into(),
+            DetailValue::AccountId(vec![7; 32]),
+        ]));
+
+        assert!(event
+            .extract_principal_ids()
+            .contains(&mock_principals::bob()));
+        assert_eq!(
+            event.extract_account_ids(),
+            vec![account.account_id(), vec![7; 32]]
+                .into_iter()
+                .collect()
+        );
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Events sent by clients which don't know about idempotency keys are still accepted.
+    #[test]
+    fn test_decode_without_idempotency_key() {
+        #[derive(CandidType, Serialize)]
+        struct OldEvent {
+            caller: Principal,
+            operation: String,
+            details: Vec<(String, DetailValue)>,
+        }
+
+        let old = OldEvent {
+            caller: mock_principals::alice(),
+            operation: "mint".into(),
+            details: vec![("amount".into(), 5u64.into())],
+        };
+
+        let event: IndefiniteEvent = decode_one(&encode_one(&old).unwrap()).unwrap();
+        assert_eq!(event.idempotency_key, None);
+        assert_eq!(event.details, old.details);
+
+        let event: IndefiniteEvent =
+            serde_cbor::from_slice(&serde_cbor::to_vec(&old).unwrap()).unwrap();
+        assert_eq!(event.idempotency_key, None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = |value: DetailValue| -> Result<(), EventError> {
+            let event: IndefiniteEvent = event(value).into();
+            event.validate()
+        };
+
+        for value in values() {
+            assert_eq!(valid(value), Ok(()));
+        }
+
+        let nested = |depth: usize| {
+            (1..depth).fold(DetailValue::Vec(vec![]), |value, i| match i % 3 {
+                0 => DetailValue::Vec(vec![value]),
+                1 => DetailValue::Map(vec![("key".into(), value)]),
+                _ => Some(value).into(),
+            })
+        };
+        assert_eq!(valid(nested(MAX_DETAIL_DEPTH)), Ok(()));
+        assert_eq!(
+            valid(nested(MAX_DETAIL_DEPTH + 1)),
+            Err(EventError::TooDeep { max: 16 })
+        );
+
+        let map = DetailValue::Map(vec![("a".into(), 0u64.into()), ("a".into(), 1u64.into())]);
+        assert_eq!(valid(map), Err(EventError::DuplicateKey("a".into())));
+
+        let map = DetailValue::Map(vec![("k".repeat(256), 0u64.into())]);
+        assert_eq!(
+            valid(map),
+            Err(EventError::KeyTooLong { len: 256, max: 255 })
+        );
+
+        // The size is the one the responses are paged with.
+        let large = event(DetailValue::Slice(vec![0; 64 * 1024]));
+        assert_eq!(
+            valid(DetailValue::Slice(vec![0; 64 * 1024])),
+            Err(EventError::TooLarge {
+                size: large.size_hint() as u64,
+                max: MAX_EVENT_SIZE as u64
+            })
+        );
+
+        let mut event: IndefiniteEvent = event(DetailValue::True).into();
+        event.operation = "o".repeat(255);
+        assert_eq!(event.validate(), Ok(()));
+        event.operation.push('o');
+        assert_eq!(
+            event.validate(),
+            Err(EventError::OperationTooLong { len: 256, max: 255 })
+        );
+    }
+
+    #[test]
+    fn test_try_into() {
+        let nat = Nat::from(u128::MAX);
+        let value: DetailValue = nat.clone().into();
+        assert_eq!(value.try_into(), Ok(nat));
+
+        // Older versions stored a Nat as its LEB128 encoding.
+        let mut leb = vec![];
+        Nat::from(300u64).encode(&mut leb).unwrap();
+        let value: Result<Nat, ()> = DetailValue::Slice(leb).try_into();
+        assert_eq!(value, Ok(Nat::from(300u64)));
+
+        let value: Result<u128, ()> = DetailValue::U64(5).try_into();
+        assert_eq!(value, Ok(5));
+        let value: Result<Int, ()> = DetailValue::I64(-5).try_into();
+        assert_eq!(value, Ok(Int::from(-5i64)));
+        assert_eq!(
+            DetailValue::from(Some(5u64)),
+            DetailValue::Option(Some(Box::new(DetailValue::U64(5))))
+        );
+    }
+}
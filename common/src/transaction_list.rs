@@ -1,15 +1,16 @@
-use crate::transaction::Event;
+use crate::did::{Direction, TransactionId};
+use crate::event_log::EventLog;
+use crate::mmr::{ConsistencyProof, Mmr, Tip};
+use crate::store::{Array, Store, Stored};
+use crate::transaction::{DetailValue, Event, TokenId};
+use crate::tree::{Key, Page, Paged, PagedKey, Tree};
 use certified_vars::hashtree::{fork, fork_hash};
-use certified_vars::Paged;
-use certified_vars::{rbtree::RbTree, AsHashTree, Hash, HashTree};
-use ic_kit::candid::types::{Compound, Type};
-use ic_kit::candid::CandidType;
+use certified_vars::label::Label;
+use certified_vars::{AsHashTree, Hash, HashTree};
 use ic_kit::Principal;
-use serde::ser::{SerializeSeq, SerializeTuple};
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::alloc::{dealloc, Layout};
-use std::ptr;
-use std::ptr::NonNull;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 
 /// A list contains a series of transactions and appropriate indexers.
 ///
@@ -20,6 +21,14 @@ use std::ptr::NonNull;
 /// 3: user_indexer
 /// 4: contract_indexer
 /// 5: token_indexer
+/// 6: time_indexer
+/// 7: operation_indexer
+/// 8: detail_indexer
+/// 9: counts
+/// 10: account_indexer
+/// 11: idempotency_keys
+/// 12: tip
+/// 13: writer_indexer
 ///
 /// ```text
 ///       ROOT
@@ -29,40 +38,204 @@ use std::ptr::NonNull;
 ///   /  \     /  \
 ///  0    1   3    V
 ///               / \
-///              4   5
+///              4   V
+///                 / \
+///                5   V
+///                   / \
+///                  6   V
+///                     / \
+///                    7   V
+///                       / \
+///                      8   V
+///                         / \
+///                        9   V
+///                           / \
+///                          10  V
+///                             / \
+///                            11  V
+///                               / \
+///                              12  13
 /// ```
+///
+/// The events and the indexes are kept in a [`Store`], the indexes refer to the events by their
+/// local index. Only the handles of these structures are on the heap, so when the store is in
+/// the stable memory serializing the list does not depend on the number of events.
+#[derive(Serialize, Deserialize)]
 pub struct TransactionList {
+    /// The memory holding the events and the indexes.
+    store: Store,
     /// Map each local Transaction ID to its hash.
-    event_hashes: RbTree<u32, Hash>,
+    event_hashes: Tree<u32, Hash>,
     /// ID of the current contract.
     contract: Principal,
     /// The offset of this list, i.e the actual id of the first event in the list.
     pub global_offset: u64,
     /// Maps each user principal id to the vector of events they have.
-    user_indexer: Paged<Principal, NonNull<Event>, 64>,
+    user_indexer: Paged<Principal, EventRef, 64>,
+    /// The distinct sets of roles the users have in the events, sorted, the index of a set
+    /// identifies it in `user_roles_indexer`.
+    role_sets: Vec<Vec<String>>,
+    /// Maps each user and the set of roles they have in an event to the local index of these
+    /// events, the label of an entry is the one of the page of a user in a paged index with the
+    /// number of the set as the page. It's only used to filter the events of a user by their
+    /// roles and is not part of the certified tree.
+    user_roles_indexer: Tree<PagedKey<Principal>, Array<u32>>,
+    /// The detail keys which are not used to index the users, such as a fee collector which
+    /// would otherwise be involved in every event. Each set applies to the events starting at
+    /// its id, until the id of the next set.
+    user_exclusions: Vec<(TransactionId, BTreeSet<String>)>,
     /// Maps contract id to each transaction page.
-    contract_indexer: Paged<Principal, NonNull<Event>, 64>,
+    contract_indexer: Paged<Principal, EventRef, 64>,
     /// Map each token id to a map of transactions for that token.
-    token_indexer: Paged<u64, NonNull<Event>, 64>,
-    /// All of the events in this list, we store a pointer to an allocated memory. Which is used
-    /// only internally in this struct. And this Vec should be considered the actual owner of this
-    /// pointers.
-    /// So this should be the last thing that will be dropped.
-    pub events: Vec<NonNull<Event>>,
+    token_indexer: Paged<TokenId, EventRef, 64>,
+    /// Map the time of each event followed by its local index to the hash of the event.
+    time_indexer: Tree<TimeKey, Hash>,
+    /// Map each operation name to the transactions with that operation.
+    operation_indexer: Paged<String, EventRef, 64>,
+    /// For each detail key that is declared to be indexed, map the values of that detail to
+    /// the transactions containing them.
+    detail_indexer: Tree<String, Paged<Vec<u8>, EventRef, 64>>,
+    /// The detail keys whose index is still being filled with the events that were in the list
+    /// when the key was added, mapped to the local index of the next event to index. The new
+    /// events are indexed under these keys by the backfill as well, so the pages stay ordered.
+    detail_backfills: BTreeMap<String, u32>,
+    /// The number of events under each key of the paged indexes, see [`count_key`].
+    counts: Tree<Vec<u8>, u64>,
+    /// Maps the ledger identifier of each account to the events it is involved in.
+    account_indexer: Paged<Vec<u8>, EventRef, 64>,
+    /// Maps the idempotency keys of the recent events to their id, so a replayed insert
+    /// returns the id of the original event.
+    idempotency_keys: Tree<String, TransactionId>,
+    /// The offset of a ring of [`MAX_IDEMPOTENCY_KEYS`] slots holding the blocks of the keys
+    /// of `idempotency_keys` in the order they were used, `0` until the first key is used.
+    idempotency_order: u64,
+    /// The slot of the oldest key in `idempotency_order`, which is dropped first.
+    idempotency_start: u32,
+    /// The accumulator over the hashes of the events inserted since the list was created, it
+    /// is kept when the list is truncated, so for the root bucket it covers the whole history.
+    /// Its tip is certified.
+    mmr: Mmr,
+    /// Maps each canister that inserted events to them, this is either the contract or one
+    /// of its writers.
+    writer_indexer: Paged<Principal, EventRef, 64>,
+    /// The canisters which inserted events into the list.
+    writers: Vec<Principal>,
+    /// The writer of each event, by local index, as `1` plus its index in `writers`. Events
+    /// inserted without a writer have `0`.
+    event_writers: Array<u32>,
+    /// All of the events in this list, ordered by their local index.
+    events: EventLog,
+    /// The structures of the events which were removed from the list, their blocks are freed
+    /// in steps by [`TransactionList::free_retired`].
+    retired: Vec<Retired>,
+}
+
+/// The indexes and the events of a list which were replaced by [`TransactionList::remove_before`].
+#[derive(Serialize, Deserialize)]
+struct Retired {
+    event_hashes: Tree<u32, Hash>,
+    user_indexer: Paged<Principal, EventRef, 64>,
+    user_roles_indexer: Tree<PagedKey<Principal>, Array<u32>>,
+    contract_indexer: Paged<Principal, EventRef, 64>,
+    token_indexer: Paged<TokenId, EventRef, 64>,
+    time_indexer: Tree<TimeKey, Hash>,
+    operation_indexer: Paged<String, EventRef, 64>,
+    detail_indexer: Tree<String, Paged<Vec<u8>, EventRef, 64>>,
+    counts: Tree<Vec<u8>, u64>,
+    account_indexer: Paged<Vec<u8>, EventRef, 64>,
+    writer_indexer: Paged<Principal, EventRef, 64>,
+    event_writers: Array<u32>,
+    events: EventLog,
+}
+
+impl Retired {
+    /// Free the blocks of the structures in steps, see [`Tree::free_some`].
+    fn free_some(&mut self, store: &mut Store, budget: &mut usize) -> bool {
+        self.event_hashes.free_some(store, budget)
+            && self.user_indexer.free_some(store, budget)
+            && self.user_roles_indexer.free_some(store, budget)
+            && self.contract_indexer.free_some(store, budget)
+            && self.token_indexer.free_some(store, budget)
+            && self.time_indexer.free_some(store, budget)
+            && self.operation_indexer.free_some(store, budget)
+            && self.detail_indexer.free_some(store, budget)
+            && self.counts.free_some(store, budget)
+            && self.account_indexer.free_some(store, budget)
+            && self.writer_indexer.free_some(store, budget)
+            && self.event_writers.free_some(store, budget)
+            && self.events.free_some(store, budget)
+    }
+}
+
+/// A reference to an event in the list by its local index. The indexes certify the hash of
+/// the events, so it is stored along with the index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct EventRef {
+    local: u32,
+    hash: Hash,
+}
+
+impl AsHashTree for EventRef {
+    #[inline]
+    fn root_hash(&self) -> Hash {
+        self.hash
+    }
+
+    #[inline]
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        HashTree::Pruned(self.hash)
+    }
+}
+
+impl Stored for EventRef {
+    const SIZE: usize = 36;
+
+    #[inline]
+    fn store(&self, buf: &mut [u8]) {
+        self.local.store(&mut buf[..4]);
+        self.hash.store(&mut buf[4..]);
+    }
+
+    #[inline]
+    fn load(buf: &[u8]) -> Self {
+        Self {
+            local: u32::load(&buf[..4]),
+            hash: Hash::load(&buf[4..]),
+        }
+    }
 }
 
 impl TransactionList {
-    /// Create a new list with the given global offset.
+    /// Create a new list with the given global offset, the list is stored on the heap until
+    /// [`TransactionList::set_stable`] is called.
     #[inline]
     pub fn new(contract: Principal, offset: u64) -> Self {
         TransactionList {
-            events: vec![],
+            store: Store::new(),
+            events: EventLog::new(),
             contract,
-            event_hashes: RbTree::new(),
+            event_hashes: Tree::new(),
             global_offset: offset,
             user_indexer: Paged::new(),
+            role_sets: Vec::new(),
+            user_roles_indexer: Tree::new(),
+            user_exclusions: Vec::new(),
             contract_indexer: Paged::new(),
             token_indexer: Paged::new(),
+            time_indexer: Tree::new(),
+            operation_indexer: Paged::new(),
+            detail_indexer: Tree::new(),
+            detail_backfills: BTreeMap::new(),
+            counts: Tree::new(),
+            account_indexer: Paged::new(),
+            idempotency_keys: Tree::new(),
+            idempotency_order: 0,
+            idempotency_start: 0,
+            mmr: Mmr::new(),
+            writer_indexer: Paged::new(),
+            writers: Vec::new(),
+            event_writers: Array::new(),
+            retired: Vec::new(),
         }
     }
 
@@ -90,312 +263,1394 @@ impl TransactionList {
         self.events.is_empty()
     }
 
+    /// Return the events in this list, ordered by their id.
+    #[inline]
+    pub fn events(&self) -> impl Iterator<Item = Event> + '_ {
+        self.events.iter(&self.store)
+    }
+
+    /// Consume the list and return its events, ordered by their id.
+    #[inline]
+    pub fn into_events(self) -> Vec<Event> {
+        self.events().collect()
+    }
+
+    /// Return the number of bytes used to store the events of this list, the events before
+    /// the global offset are not counted.
+    #[inline]
+    pub fn memory_size(&self) -> u64 {
+        self.events.used_bytes()
+    }
+
+    /// Returns `true` if this list is stored in the stable memory.
+    #[inline]
+    pub fn is_stable(&self) -> bool {
+        self.store.is_stable()
+    }
+
+    /// Move this list to the stable memory, anything stored in the stable memory is
+    /// overwritten.
+    #[inline]
+    pub fn set_stable(&mut self) {
+        self.store.set_stable();
+    }
+
+    /// Remove the events before the given id, which have been moved to another bucket. The
+    /// indexes are replaced by new ones holding the remaining events, so the work done here
+    /// grows with the number of remaining events only, the blocks of the old ones are freed
+    /// later by [`TransactionList::free_retired`]. If the id is past the end of the list, the
+    /// list is emptied and the next event inserted gets the given id.
+    pub fn remove_before(&mut self, id: TransactionId) {
+        let offset = id.max(self.global_offset);
+        let start = (offset - self.global_offset).min(self.events.len() as u64);
+        let remaining = (start..self.events.len() as u64)
+            .map(|local| {
+                let event = self.events.get(&self.store, local as u32).unwrap();
+                let writer = self.event_writers.get(&self.store, local).unwrap();
+                (event, writer)
+            })
+            .collect::<Vec<_>>();
+        let detail_indexes = self.detail_indexes();
+
+        // The idempotency keys and the accumulator are kept, the ids of the events which moved
+        // to another bucket are still valid.
+        self.retired.push(Retired {
+            event_hashes: std::mem::take(&mut self.event_hashes),
+            user_indexer: std::mem::take(&mut self.user_indexer),
+            user_roles_indexer: std::mem::take(&mut self.user_roles_indexer),
+            contract_indexer: std::mem::take(&mut self.contract_indexer),
+            token_indexer: std::mem::take(&mut self.token_indexer),
+            time_indexer: std::mem::take(&mut self.time_indexer),
+            operation_indexer: std::mem::take(&mut self.operation_indexer),
+            detail_indexer: std::mem::take(&mut self.detail_indexer),
+            counts: std::mem::take(&mut self.counts),
+            account_indexer: std::mem::take(&mut self.account_indexer),
+            writer_indexer: std::mem::take(&mut self.writer_indexer),
+            event_writers: std::mem::take(&mut self.event_writers),
+            events: std::mem::take(&mut self.events),
+        });
+        self.role_sets.clear();
+        self.global_offset = offset;
+
+        for key in detail_indexes {
+            self.detail_indexer
+                .insert(&mut self.store, key.as_bytes(), Paged::new());
+        }
+        for next in self.detail_backfills.values_mut() {
+            *next = next.saturating_sub(start as u32);
+        }
+        let first = self
+            .user_exclusions
+            .partition_point(|(start, _)| *start <= offset);
+        self.user_exclusions.drain(..first.saturating_sub(1));
+
+        for (event, writer) in remaining {
+            self.append(&event, writer);
+        }
+    }
+
+    /// Free at most about `limit` blocks of the indexes and the events removed by
+    /// [`TransactionList::remove_before`], and return the number of the freed blocks.
+    pub fn free_retired(&mut self, limit: usize) -> usize {
+        let mut budget = limit;
+        while let Some(retired) = self.retired.last_mut() {
+            if !retired.free_some(&mut self.store, &mut budget) {
+                break;
+            }
+            self.retired.pop();
+        }
+
+        limit - budget
+    }
+
+    /// Store the given data after the list, see [`Store::write_upgrade_data`].
+    #[inline]
+    pub fn write_upgrade_data(&mut self, data: &[u8]) {
+        self.store.write_upgrade_data(data);
+    }
+
     /// Try to insert an event into the list.
+    #[inline]
     pub fn insert(&mut self, event: Event) -> u64 {
-        let local_index = self.events.len() as u32;
-        // let hash = event.hash();
-        let event: NonNull<Event> = Box::leak(Box::new(event)).into();
-        let eve = unsafe { event.as_ref() };
+        self.insert_from(None, event)
+    }
+
+    /// Insert an event and record the canister which inserted it, see
+    /// [`TransactionList::get_writer`].
+    pub fn insert_from(&mut self, writer: Option<Principal>, event: Event) -> TransactionId {
+        let writer = match writer {
+            Some(writer) => match self.writers.iter().position(|w| *w == writer) {
+                Some(index) => index as u32 + 1,
+                None => {
+                    self.writers.push(writer);
+                    self.writers.len() as u32
+                }
+            },
+            None => 0,
+        };
+
+        let local_index = self.append(&event, writer);
+        self.mmr.push(&mut self.store, event.hash());
+        self.global_offset + (local_index as u64)
+    }
+
+    /// Insert an event unless its idempotency key was already used, in which case nothing is
+    /// inserted and the id of the original event is returned. Only the last
+    /// [`MAX_IDEMPOTENCY_KEYS`] keys are remembered.
+    pub fn insert_idempotent(
+        &mut self,
+        writer: Option<Principal>,
+        event: Event,
+        key: Option<String>,
+    ) -> TransactionId {
+        let key = match key {
+            Some(key) => key,
+            None => return self.insert_from(writer, event),
+        };
+
+        if let Some(id) = self.get_id_for_key(&key) {
+            return id;
+        }
+
+        let id = self.insert_from(writer, event);
+        self.record_key(&key, id);
+        id
+    }
+
+    /// Record the idempotency key of a new event, the oldest key is dropped once more than
+    /// [`MAX_IDEMPOTENCY_KEYS`] keys are recorded.
+    fn record_key(&mut self, key: &str, id: TransactionId) {
+        if self.idempotency_order == 0 {
+            self.idempotency_order = self.store.alloc(MAX_IDEMPOTENCY_KEYS * 8);
+        }
+
+        let block = self.store.alloc_bytes(key.as_bytes());
+        self.idempotency_keys
+            .insert(&mut self.store, key.as_bytes(), id);
+
+        let len = self.idempotency_keys.len();
+        let slot = if len > MAX_IDEMPOTENCY_KEYS as u64 {
+            let slot = self.idempotency_start;
+            let oldest = self.store.get::<u64>(self.idempotency_slot(slot));
+            let oldest_key = self.store.read_bytes(oldest);
+            self.idempotency_keys.remove(&mut self.store, &oldest_key);
+            self.store.free_bytes(oldest);
+            self.idempotency_start = (slot + 1) % MAX_IDEMPOTENCY_KEYS as u32;
+            slot
+        } else {
+            (self.idempotency_start + len as u32 - 1) % MAX_IDEMPOTENCY_KEYS as u32
+        };
+
+        let slot = self.idempotency_slot(slot);
+        self.store.set(slot, &block);
+    }
+
+    /// Return the offset of the given slot of `idempotency_order`.
+    #[inline]
+    fn idempotency_slot(&self, slot: u32) -> u64 {
+        self.idempotency_order + slot as u64 * 8
+    }
+
+    /// Return the id of the event inserted with the given idempotency key, if the key is one
+    /// of the recent ones.
+    #[inline]
+    pub fn get_id_for_key(&self, key: &str) -> Option<TransactionId> {
+        self.idempotency_keys.get(&self.store, key.as_bytes())
+    }
+
+    /// Return the witness that can be used to prove the response from get_id_for_key.
+    #[inline]
+    pub fn witness_id_for_key(&self, key: &str) -> HashTree<'_> {
+        self.witness(Reveal {
+            idempotency_keys: Some(self.idempotency_keys.witness(&self.store, key.as_bytes())),
+            ..Reveal::default()
+        })
+    }
+
+    /// Return the tip of the accumulator over the event hashes.
+    #[inline]
+    pub fn tip(&self) -> Tip {
+        self.mmr.tip(&self.store)
+    }
+
+    /// Return the witness that can be used to prove the response from tip.
+    #[inline]
+    pub fn witness_tip(&self) -> HashTree<'_> {
+        self.witness(Reveal {
+            tip: Some(HashTree::Leaf(Cow::Owned(self.tip().to_bytes()))),
+            ..Reveal::default()
+        })
+    }
+
+    /// Return a proof that the history at `old_size` is a prefix of the history at
+    /// `new_size`, the sizes are the ones of the accumulator, see [`TransactionList::tip`].
+    #[inline]
+    pub fn consistency_proof(&self, old_size: u64, new_size: u64) -> Option<ConsistencyProof> {
+        self.mmr.consistency_proof(&self.store, old_size, new_size)
+    }
+
+    /// Add an event to the log along with its writer and add it to the indexes, the event is
+    /// not added to the accumulator. Returns the local index of the event.
+    fn append(&mut self, event: &Event, writer: u32) -> u32 {
+        let local_index = self.events.push(&mut self.store, event);
+        self.event_writers.push(&mut self.store, &writer);
+        self.index(event, local_index);
+        local_index
+    }
+
+    /// Return the canister which inserted the event with the given id, `None` if the event is
+    /// not in the list or if its writer was not recorded.
+    pub fn get_writer(&self, id: TransactionId) -> Option<Principal> {
+        if !(self.global_offset..self.size()).contains(&id) {
+            return None;
+        }
+
+        self.writer_at((id - self.global_offset) as u32)
+    }
+
+    #[inline]
+    fn writer_at(&self, local_index: u32) -> Option<Principal> {
+        match self.event_writers.get(&self.store, local_index as u64)? {
+            0 => None,
+            writer => Some(self.writers[writer as usize - 1]),
+        }
+    }
+
+    /// Add an event which is already in the log to the indexes.
+    fn index(&mut self, event: &Event, local_index: u32) {
+        let hash = event.hash();
+        let event_ref = EventRef {
+            local: local_index,
+            hash,
+        };
+
+        if let Some(writer) = self.writer_at(local_index) {
+            self.writer_indexer
+                .insert(&mut self.store, &writer, event_ref);
+            self.increment_count(count_key(WRITER_COUNT, writer.as_slice()));
+        }
 
         // Update the indexers for the transaction.
-        self.contract_indexer.insert(self.contract, event);
-        for user in eve.extract_principal_ids() {
-            self.user_indexer.insert(*user, event);
+        self.contract_indexer
+            .insert(&mut self.store, &self.contract, event_ref);
+        self.increment_count(count_key(CONTRACT_COUNT, self.contract.as_slice()));
+        let users = self.indexed_user_roles(self.global_offset + local_index as u64, event);
+        for (user, roles) in users {
+            let set = self.record_roles(&roles);
+            self.user_indexer.insert(&mut self.store, user, event_ref);
+            let label = PagedKey::<Principal>::label(user.as_slice(), set);
+            let pushed = self
+                .user_roles_indexer
+                .modify(&mut self.store, &label, |locals, store| {
+                    locals.push(store, &local_index)
+                });
+            if pushed.is_none() {
+                let mut locals = Array::new();
+                locals.push(&mut self.store, &local_index);
+                self.user_roles_indexer
+                    .insert(&mut self.store, &label, locals);
+            }
+            self.increment_count(count_key(USER_COUNT, user.as_slice()));
         }
-        for token_id in eve.extract_token_ids() {
-            self.token_indexer.insert(token_id, event);
+        for token_id in event.extract_token_ids() {
+            self.increment_count(count_key(TOKEN_COUNT, &token_id.as_label()));
+            self.token_indexer
+                .insert(&mut self.store, &token_id, event_ref);
         }
+        for account in event.extract_account_ids() {
+            self.increment_count(count_key(ACCOUNT_COUNT, &account));
+            self.account_indexer
+                .insert(&mut self.store, &account, event_ref);
+        }
+        self.operation_indexer
+            .insert(&mut self.store, &event.operation, event_ref);
+        self.increment_count(count_key(OPERATION_COUNT, event.operation.as_bytes()));
+        self.index_details(event, event_ref);
 
-        // Insert the event itself.
-        // self.event_hashes.insert(local_index, hash);
-        self.events.push(event);
+        self.time_indexer
+            .insert(&mut self.store, &time_key(event.time, local_index), hash);
 
-        self.global_offset + (local_index as u64)
+        self.event_hashes
+            .insert(&mut self.store, &local_index.to_be_bytes(), hash);
     }
 
-    /// Return the transactions associated with a user's principal id at the given page.
+    /// Start indexing the values of the given detail key. The events already in the list are
+    /// indexed by [`TransactionList::backfill_detail_indexes`], until then the index of the key
+    /// only holds some of the events. Returns `false` if the key was already indexed.
+    pub fn add_detail_index(&mut self, key: String) -> bool {
+        if self
+            .detail_indexer
+            .get(&self.store, key.as_bytes())
+            .is_some()
+        {
+            return false;
+        }
+
+        if !self.events.is_empty() {
+            self.detail_backfills.insert(key.clone(), 0);
+        }
+
+        self.detail_indexer
+            .insert(&mut self.store, key.as_bytes(), Paged::new());
+        true
+    }
+
+    /// Index at most `limit` events under the detail keys which are not fully indexed yet,
+    /// returns the number of events that were indexed.
+    pub fn backfill_detail_indexes(&mut self, limit: usize) -> usize {
+        let mut indexed = 0;
+
+        while indexed < limit {
+            let (key, local) = match self.detail_backfills.iter().next() {
+                Some((key, local)) => (key.clone(), *local),
+                None => break,
+            };
+
+            let event = match self.events.get(&self.store, local) {
+                Some(event) => event,
+                None => {
+                    self.detail_backfills.remove(&key);
+                    continue;
+                }
+            };
+
+            let event_ref = EventRef {
+                local,
+                hash: self
+                    .event_hashes
+                    .get(&self.store, &local.to_be_bytes())
+                    .unwrap(),
+            };
+            self.index_detail(&event, &key, event_ref);
+            indexed += 1;
+
+            if local as usize + 1 < self.events.len() {
+                self.detail_backfills.insert(key, local + 1);
+            } else {
+                self.detail_backfills.remove(&key);
+            }
+        }
+
+        indexed
+    }
+
+    /// Return the detail keys which are not fully indexed yet, along with the id of the next
+    /// event to index under each of them.
+    pub fn detail_backfills(&self) -> Vec<(String, TransactionId)> {
+        self.detail_backfills
+            .iter()
+            .map(|(key, local)| (key.clone(), self.global_offset + *local as u64))
+            .collect()
+    }
+
+    /// Return the id of the set of the given roles, a set seen for the first time is given the
+    /// next id.
+    fn record_roles(&mut self, roles: &[&str]) -> u32 {
+        let mut roles = roles.iter().map(|role| role.to_string()).collect::<Vec<_>>();
+        roles.sort();
+        roles.dedup();
+
+        match self.role_sets.iter().position(|set| *set == roles) {
+            Some(index) => index as u32,
+            None => {
+                self.role_sets.push(roles);
+                self.role_sets.len() as u32 - 1
+            }
+        }
+    }
+
+    /// Stop indexing the users found under the given detail keys in the events inserted from
+    /// now on, the events already in the list keep their users. Returns `false` if the keys
+    /// were already excluded.
+    pub fn set_user_index_exclusions(&mut self, keys: BTreeSet<String>) -> bool {
+        let id = self.size();
+        match self.user_exclusions.last_mut() {
+            Some((_, last)) if *last == keys => return false,
+            None if keys.is_empty() => return false,
+            Some((start, last)) if *start == id => *last = keys,
+            _ => self.user_exclusions.push((id, keys)),
+        }
+
+        true
+    }
+
+    /// Return the detail keys which are not used to index the users of the next events.
     #[inline]
-    pub fn get_transactions_for_user(&self, principal: &Principal, page: u32) -> Vec<&Event> {
-        if let Some(data) = self.user_indexer.get(principal, page as usize) {
-            data.iter().map(|v| unsafe { v.as_ref() }).collect()
-        } else {
-            vec![]
+    pub fn user_index_exclusions(&self) -> Vec<String> {
+        self.user_exclusions
+            .last()
+            .map_or_else(Vec::new, |(_, keys)| keys.iter().cloned().collect())
+    }
+
+    /// Return the users of the event with the given id along with the roles they are indexed
+    /// under, the users which only appear under excluded detail keys are left out.
+    pub fn indexed_user_roles<'a>(
+        &self,
+        id: TransactionId,
+        event: &'a Event,
+    ) -> Vec<(&'a Principal, Vec<&'a str>)> {
+        let excluded = self.exclusions_at(id);
+        event
+            .extract_principal_roles()
+            .into_iter()
+            .map(|(user, roles)| {
+                let roles = roles
+                    .into_iter()
+                    .filter(|role| !excluded.is_some_and(|keys| keys.contains(*role)))
+                    .collect::<Vec<_>>();
+                (user, roles)
+            })
+            .filter(|(_, roles)| !roles.is_empty())
+            .collect()
+    }
+
+    /// Return the detail keys which are not used to index the users of the given event.
+    #[inline]
+    fn exclusions_at(&self, id: TransactionId) -> Option<&BTreeSet<String>> {
+        let next = self.user_exclusions.partition_point(|(start, _)| *start <= id);
+        self.user_exclusions[..next].last().map(|(_, keys)| keys)
+    }
+
+    /// Increment the number of events stored under the given key of `counts`.
+    #[inline]
+    fn increment_count(&mut self, key: Vec<u8>) {
+        if self
+            .counts
+            .modify(&mut self.store, &key, |count, _| *count += 1)
+            .is_none()
+        {
+            self.counts.insert(&mut self.store, &key, 1);
+        }
+    }
+
+    /// Return the number of events stored under the given key of `counts`.
+    #[inline]
+    fn count(&self, key: &[u8]) -> u64 {
+        self.counts.get(&self.store, key).unwrap_or(0)
+    }
+
+    /// Return the detail keys which are indexed in this list.
+    pub fn detail_indexes(&self) -> Vec<String> {
+        self.detail_indexer
+            .iter_from(&self.store, None)
+            .map(|(key, _)| String::from_utf8(key).unwrap())
+            .collect()
+    }
+
+    /// Insert the event into the index of each of the indexed detail keys, except the keys
+    /// whose backfill has not reached the event yet.
+    fn index_details(&mut self, event: &Event, event_ref: EventRef) {
+        let keys = event
+            .details
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| {
+                self.detail_indexer
+                    .get(&self.store, key.as_bytes())
+                    .is_some()
+            })
+            .filter(|key| match self.detail_backfills.get(*key) {
+                Some(next) => event_ref.local < *next,
+                None => true,
+            })
+            .collect::<BTreeSet<_>>();
+
+        for key in keys {
+            self.index_detail(event, key, event_ref);
         }
     }
 
+    /// Insert the event into the index of the given detail key.
+    fn index_detail(&mut self, event: &Event, key: &str, event_ref: EventRef) {
+        let values = detail_values(event, key);
+        let counts = values
+            .iter()
+            .map(|value| detail_count_key(key, value))
+            .collect::<Vec<_>>();
+
+        self.detail_indexer
+            .modify(&mut self.store, key.as_bytes(), |index, store| {
+                for value in values {
+                    index.insert(store, &value, event_ref);
+                }
+            });
+
+        for count in counts {
+            self.increment_count(count);
+        }
+    }
+
+    /// Return the transactions associated with a user's principal id at the given page.
+    #[inline]
+    pub fn get_transactions_for_user(&self, principal: &Principal, page: u32) -> Vec<Event> {
+        self.resolve(
+            self.user_indexer
+                .get(&self.store, principal, page as usize),
+        )
+    }
+
     /// Return the last page number associated with the given user.
     #[inline]
     pub fn last_page_for_user(&self, principal: &Principal) -> u32 {
         self.user_indexer
-            .get_last_page_number(principal)
+            .get_last_page_number(&self.store, principal)
             .unwrap_or(0) as u32
     }
 
-    /// Return the transactions associated with a token's principal id at the given page.
+    /// Return the number of transactions associated with a user's principal id.
     #[inline]
-    pub fn get_transactions_for_contract(&self, principal: &Principal, page: u32) -> Vec<&Event> {
-        if let Some(data) = self.contract_indexer.get(principal, page as usize) {
-            data.iter().map(|v| unsafe { v.as_ref() }).collect()
-        } else {
-            vec![]
+    pub fn count_for_user(&self, principal: &Principal) -> u64 {
+        self.count(&count_key(USER_COUNT, principal.as_slice()))
+    }
+
+    /// Return the lists of `user_roles_indexer` holding the events of a user in which they
+    /// have one of the given roles, the lists don't have any event in common.
+    fn user_role_lists(&self, principal: &Principal, roles: &[String]) -> Vec<Array<u32>> {
+        let first = PagedKey::<Principal>::label(principal.as_slice(), 0);
+        self.user_roles_indexer
+            .iter_from(&self.store, Some(&first))
+            .take_while(|(label, _)| label[..label.len() - 4] == *principal.as_slice())
+            .filter(|(label, _)| {
+                self.role_sets[PagedKey::<Principal>::page(label) as usize]
+                    .iter()
+                    .any(|role| roles.contains(role))
+            })
+            .map(|(_, locals)| locals)
+            .collect()
+    }
+
+    /// Return the transactions of a user in which they have one of the given roles, the
+    /// filtered transactions are split in pages like the other indexes.
+    pub fn get_transactions_for_user_with_roles(
+        &self,
+        principal: &Principal,
+        roles: &[String],
+        page: u32,
+    ) -> Vec<Event> {
+        let store = &self.store;
+        let lists = self.user_role_lists(principal, roles);
+        let start = page as u64 * 64;
+        if start >= lists.iter().map(|list| list.len()).sum::<u64>() {
+            return vec![];
+        }
+
+        // The number of events in the lists with a local index smaller than the given one, it
+        // grows by one at each event so the first event of the page is found by a binary search.
+        let count_below = |local: u64| {
+            lists
+                .iter()
+                .map(|list| list.partition_point(store, |l| (*l as u64) < local))
+                .sum::<u64>()
+        };
+        let (mut low, mut high) = (0, self.events.len() as u64);
+        while low < high {
+            let mid = (low + high) / 2;
+            if count_below(mid + 1) <= start {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        // Merge the lists from the first event of the page.
+        let mut heads = lists
+            .iter()
+            .map(|list| list.partition_point(store, |l| (*l as u64) < low))
+            .collect::<Vec<_>>();
+        let mut events = Vec::with_capacity(64);
+        while events.len() < 64 {
+            let next = (0..lists.len())
+                .filter(|i| heads[*i] < lists[*i].len())
+                .map(|i| (i, lists[i].get(store, heads[i]).unwrap()))
+                .min_by_key(|(_, local)| *local);
+            let (i, local) = match next {
+                Some(next) => next,
+                None => break,
+            };
+
+            events.push(self.events.get(store, local).unwrap());
+            heads[i] += 1;
         }
+
+        events
+    }
+
+    /// Return the number of transactions of a user in which they have one of the given roles.
+    #[inline]
+    pub fn count_for_user_with_roles(&self, principal: &Principal, roles: &[String]) -> u64 {
+        self.user_role_lists(principal, roles)
+            .iter()
+            .map(|list| list.len())
+            .sum()
+    }
+
+    /// Return the transactions associated with a token's principal id at the given page.
+    #[inline]
+    pub fn get_transactions_for_contract(&self, principal: &Principal, page: u32) -> Vec<Event> {
+        self.resolve(
+            self.contract_indexer
+                .get(&self.store, principal, page as usize),
+        )
     }
 
     /// Return the last page number associated with the given token contract.
     #[inline]
     pub fn last_page_for_contract(&self, principal: &Principal) -> u32 {
         self.contract_indexer
-            .get_last_page_number(principal)
+            .get_last_page_number(&self.store, principal)
             .unwrap_or(0) as u32
     }
 
+    /// Return the number of transactions associated with the given token contract.
+    #[inline]
+    pub fn count_for_contract(&self, principal: &Principal) -> u64 {
+        self.count(&count_key(CONTRACT_COUNT, principal.as_slice()))
+    }
+
     /// Return the transactions for a specific token.
     #[inline]
-    pub fn get_transactions_for_token(&self, token_id: &u64, page: u32) -> Vec<&Event> {
-        if let Some(data) = self.token_indexer.get(token_id, page as usize) {
-            data.iter().map(|v| unsafe { v.as_ref() }).collect()
-        } else {
-            vec![]
-        }
+    pub fn get_transactions_for_token(&self, token_id: &TokenId, page: u32) -> Vec<Event> {
+        self.resolve(
+            self.token_indexer
+                .get(&self.store, token_id, page as usize),
+        )
     }
 
     #[inline]
-    pub fn last_page_for_token(&self, token_id: &u64) -> u32 {
+    pub fn last_page_for_token(&self, token_id: &TokenId) -> u32 {
         self.token_indexer
-            .get_last_page_number(token_id)
+            .get_last_page_number(&self.store, token_id)
+            .unwrap_or(0) as u32
+    }
+
+    /// Return the number of transactions for a specific token.
+    #[inline]
+    pub fn count_for_token(&self, token_id: &TokenId) -> u64 {
+        self.count(&count_key(TOKEN_COUNT, &token_id.as_label()))
+    }
+
+    /// Return the transactions with the given operation at the given page.
+    #[inline]
+    pub fn get_transactions_for_operation(&self, operation: &str, page: u32) -> Vec<Event> {
+        self.resolve(
+            self.operation_indexer
+                .get(&self.store, &operation.into(), page as usize),
+        )
+    }
+
+    /// Return the last page number associated with the given operation.
+    #[inline]
+    pub fn last_page_for_operation(&self, operation: &str) -> u32 {
+        self.operation_indexer
+            .get_last_page_number(&self.store, &operation.into())
             .unwrap_or(0) as u32
     }
 
+    /// Return the number of transactions with the given operation.
+    #[inline]
+    pub fn count_for_operation(&self, operation: &str) -> u64 {
+        self.count(&count_key(OPERATION_COUNT, operation.as_bytes()))
+    }
+
+    /// Return the transactions inserted by the given canister at the given page.
+    #[inline]
+    pub fn get_transactions_for_writer(&self, writer: &Principal, page: u32) -> Vec<Event> {
+        self.resolve(
+            self.writer_indexer
+                .get(&self.store, writer, page as usize),
+        )
+    }
+
+    /// Return the last page number associated with the given writer.
+    #[inline]
+    pub fn last_page_for_writer(&self, writer: &Principal) -> u32 {
+        self.writer_indexer
+            .get_last_page_number(&self.store, writer)
+            .unwrap_or(0) as u32
+    }
+
+    /// Return the number of transactions inserted by the given canister.
+    #[inline]
+    pub fn count_for_writer(&self, writer: &Principal) -> u64 {
+        self.count(&count_key(WRITER_COUNT, writer.as_slice()))
+    }
+
+    /// Return the transactions involving the account with the given ledger identifier at the
+    /// given page.
+    #[inline]
+    pub fn get_transactions_for_account(&self, account: &[u8], page: u32) -> Vec<Event> {
+        self.resolve(
+            self.account_indexer
+                .get(&self.store, &account.to_vec(), page as usize),
+        )
+    }
+
+    /// Return the last page number associated with the given account.
+    #[inline]
+    pub fn last_page_for_account(&self, account: &[u8]) -> u32 {
+        self.account_indexer
+            .get_last_page_number(&self.store, &account.to_vec())
+            .unwrap_or(0) as u32
+    }
+
+    /// Return the number of transactions involving the given account.
+    #[inline]
+    pub fn count_for_account(&self, account: &[u8]) -> u64 {
+        self.count(&count_key(ACCOUNT_COUNT, account))
+    }
+
+    /// Return the transactions where the given detail key has the given value, the key must be
+    /// declared using [`TransactionList::add_detail_index`].
+    #[inline]
+    pub fn get_transactions_for_detail(
+        &self,
+        key: &str,
+        value: &DetailValue,
+        page: u32,
+    ) -> Vec<Event> {
+        self.resolve(value.index_key().and_then(|value| {
+            self.detail_indexer
+                .get(&self.store, key.as_bytes())
+                .and_then(|index| index.get(&self.store, &value, page as usize))
+        }))
+    }
+
+    /// Return the last page number associated with the given detail value.
+    #[inline]
+    pub fn last_page_for_detail(&self, key: &str, value: &DetailValue) -> u32 {
+        value
+            .index_key()
+            .and_then(|value| {
+                self.detail_indexer
+                    .get(&self.store, key.as_bytes())
+                    .and_then(|index| index.get_last_page_number(&self.store, &value))
+            })
+            .unwrap_or(0) as u32
+    }
+
+    /// Return the number of transactions where the given detail key has the given value.
+    #[inline]
+    pub fn count_for_detail(&self, key: &str, value: &DetailValue) -> u64 {
+        value
+            .index_key()
+            .map_or(0, |value| self.count(&detail_count_key(key, &value)))
+    }
+
+    /// Return the events referenced by a page of one of the indexes.
+    #[inline]
+    fn resolve(&self, page: Option<Page<EventRef, 64>>) -> Vec<Event> {
+        page.map_or_else(Vec::new, |page| {
+            page.to_vec(&self.store)
+                .into_iter()
+                .map(|event| self.events.get(&self.store, event.local).unwrap())
+                .collect()
+        })
+    }
+
+    /// Return at most `limit` transactions of the contract, starting at the transaction with
+    /// the given id and walking in the given direction, along with the cursor for the next
+    /// call. Once the returned events exceed `max_bytes` no more events are added.
+    #[inline]
+    pub fn get_transactions_for_contract_cursor(
+        &self,
+        cursor: Option<TransactionId>,
+        direction: Direction,
+        limit: u32,
+        max_bytes: Option<u32>,
+    ) -> (Vec<Event>, Option<TransactionId>) {
+        let index = (&self.contract_indexer, &self.contract);
+        self.cursor_page(index, cursor, direction, limit, max_bytes)
+    }
+
+    /// Like [`TransactionList::get_transactions_for_contract_cursor`] for the transactions of
+    /// a user, if `roles` is set only the transactions in which the user has one of the roles
+    /// are returned.
+    #[inline]
+    pub fn get_transactions_for_user_cursor(
+        &self,
+        principal: &Principal,
+        roles: Option<&[String]>,
+        cursor: Option<TransactionId>,
+        direction: Direction,
+        limit: u32,
+        max_bytes: Option<u32>,
+    ) -> (Vec<Event>, Option<TransactionId>) {
+        let roles = match roles {
+            Some(roles) => roles,
+            None => {
+                let index = (&self.user_indexer, principal);
+                return self.cursor_page(index, cursor, direction, limit, max_bytes);
+            }
+        };
+
+        // Merge the lists of the roles from the cursor, so only the events of the page are read.
+        let store = &self.store;
+        let lists = self.user_role_lists(principal, roles);
+        let id = |local: &u32| self.global_offset + *local as u64;
+        let locals: Box<dyn Iterator<Item = u32>> = match direction {
+            Direction::Forward => {
+                let mut heads = lists
+                    .iter()
+                    .map(|list| {
+                        list.partition_point(store, |l| cursor.is_some_and(|c| id(l) < c))
+                    })
+                    .collect::<Vec<_>>();
+                Box::new(std::iter::from_fn(move || {
+                    let (i, local) = (0..lists.len())
+                        .filter(|i| heads[*i] < lists[*i].len())
+                        .map(|i| (i, lists[i].get(store, heads[i]).unwrap()))
+                        .min_by_key(|(_, local)| *local)?;
+                    heads[i] += 1;
+                    Some(local)
+                }))
+            }
+            Direction::Backward => {
+                let mut tails = lists
+                    .iter()
+                    .map(|list| {
+                        list.partition_point(store, |l| cursor.is_none_or(|c| id(l) <= c))
+                    })
+                    .collect::<Vec<_>>();
+                Box::new(std::iter::from_fn(move || {
+                    let (i, local) = (0..lists.len())
+                        .filter(|i| tails[*i] > 0)
+                        .map(|i| (i, lists[i].get(store, tails[i] - 1).unwrap()))
+                        .max_by_key(|(_, local)| *local)?;
+                    tails[i] -= 1;
+                    Some(local)
+                }))
+            }
+        };
+
+        self.collect_cursor_page(locals, limit, max_bytes)
+    }
+
+    /// Like [`TransactionList::get_transactions_for_contract_cursor`] for the transactions of
+    /// a token.
+    #[inline]
+    pub fn get_transactions_for_token_cursor(
+        &self,
+        token_id: &TokenId,
+        cursor: Option<TransactionId>,
+        direction: Direction,
+        limit: u32,
+        max_bytes: Option<u32>,
+    ) -> (Vec<Event>, Option<TransactionId>) {
+        let index = (&self.token_indexer, token_id);
+        self.cursor_page(index, cursor, direction, limit, max_bytes)
+    }
+
+    /// Walk over the events of the given key in a paged index starting at the cursor.
+    fn cursor_page<K: Key + Label>(
+        &self,
+        (index, key): (&Paged<K, EventRef, 64>, &K),
+        cursor: Option<TransactionId>,
+        direction: Direction,
+        limit: u32,
+        max_bytes: Option<u32>,
+    ) -> (Vec<Event>, Option<TransactionId>) {
+        let store = &self.store;
+        let len = match index.get_last_page_number(store, key) {
+            Some(page) => page * 64 + index.get(store, key, page).map_or(0, |page| page.len()),
+            None => return (vec![], None),
+        };
+
+        let at = |position: usize| {
+            index
+                .get(store, key, position / 64)
+                .and_then(|page| page.get(store, position % 64))
+                .unwrap()
+        };
+        let id = |position: usize| self.global_offset + at(position).local as u64;
+
+        // The number of events in the index with an id smaller than the given id.
+        let count_below = |target: TransactionId| {
+            let (mut low, mut high) = (0, len);
+            while low < high {
+                let mid = (low + high) / 2;
+                if id(mid) < target {
+                    low = mid + 1;
+                } else {
+                    high = mid;
+                }
+            }
+            low
+        };
+
+        let positions: Box<dyn Iterator<Item = usize>> = match direction {
+            Direction::Forward => Box::new(cursor.map_or(0, count_below)..len),
+            Direction::Backward => {
+                let end = cursor.map_or(len, |id| count_below(id.saturating_add(1)));
+                Box::new((0..end).rev())
+            }
+        };
+
+        self.collect_cursor_page(
+            positions.map(|position| at(position).local),
+            limit,
+            max_bytes,
+        )
+    }
+
+    /// Read the events at the given local indexes until the limit or the size of the page is
+    /// reached, the id of the first event left out is the cursor of the next page.
+    fn collect_cursor_page(
+        &self,
+        locals: impl Iterator<Item = u32>,
+        limit: u32,
+        max_bytes: Option<u32>,
+    ) -> (Vec<Event>, Option<TransactionId>) {
+        let limit = limit.clamp(1, MAX_CURSOR_LIMIT) as usize;
+        let max_bytes = max_bytes.map_or(usize::MAX, |max| max as usize);
+        let mut events = Vec::new();
+        let mut size = 0;
+
+        for local in locals {
+            let event = self.events.get(&self.store, local).unwrap();
+            size += event.size_hint();
+
+            if events.len() == limit || (!events.is_empty() && size > max_bytes) {
+                return (events, Some(self.global_offset + local as u64));
+            }
+
+            events.push(event);
+        }
+
+        (events, None)
+    }
+
     /// Return the witness that can be used to prove the response from get_transactions_for_user.
     #[inline]
-    pub fn witness_transactions_for_user(&self, principal: &Principal, page: u32) -> HashTree {
-        fork(
-            HashTree::Pruned(fork_hash(
-                &self.event_hashes.root_hash(),
-                &self.global_offset.root_hash(),
-            )),
-            fork(
-                self.user_indexer.witness(principal, page as usize),
-                HashTree::Pruned(fork_hash(
-                    &self.contract_indexer.root_hash(),
-                    &self.token_indexer.root_hash(),
-                )),
+    pub fn witness_transactions_for_user(&self, principal: &Principal, page: u32) -> HashTree<'_> {
+        self.witness(Reveal {
+            user_indexer: Some(
+                self.user_indexer
+                    .witness(&self.store, principal, page as usize),
             ),
-        )
+            counts: Some(self.count_witness(&count_key(USER_COUNT, principal.as_slice()))),
+            ..Reveal::default()
+        })
     }
 
     /// Return the witness that can be used to prove the response from get_transactions_for_token.
     #[inline]
-    pub fn witness_transactions_for_contract(&self, principal: &Principal, page: u32) -> HashTree {
-        fork(
-            HashTree::Pruned(fork_hash(
-                &self.event_hashes.root_hash(),
-                &self.global_offset.root_hash(),
-            )),
-            fork(
-                HashTree::Pruned(self.user_indexer.root_hash()),
-                fork(
-                    self.contract_indexer.witness(principal, page as usize),
-                    HashTree::Pruned(self.token_indexer.root_hash()),
-                ),
+    pub fn witness_transactions_for_contract(&self, principal: &Principal, page: u32) -> HashTree<'_> {
+        self.witness(Reveal {
+            contract_indexer: Some(
+                self.contract_indexer
+                    .witness(&self.store, principal, page as usize),
             ),
-        )
+            counts: Some(self.count_witness(&count_key(CONTRACT_COUNT, principal.as_slice()))),
+            ..Reveal::default()
+        })
     }
 
     /// Return the witness that can be used to prove the response from get_transactions_for_token.
     #[inline]
-    pub fn witness_transactions_for_token(&self, token_id: &u64, page: u32) -> HashTree {
-        fork(
-            HashTree::Pruned(fork_hash(
-                &self.event_hashes.root_hash(),
-                &self.global_offset.root_hash(),
+    pub fn witness_transactions_for_token(&self, token_id: &TokenId, page: u32) -> HashTree<'_> {
+        self.witness(Reveal {
+            token_indexer: Some(
+                self.token_indexer
+                    .witness(&self.store, token_id, page as usize),
+            ),
+            counts: Some(self.count_witness(&count_key(TOKEN_COUNT, &token_id.as_label()))),
+            ..Reveal::default()
+        })
+    }
+
+    /// Return the witness that can be used to prove the response from
+    /// get_transactions_for_operation.
+    #[inline]
+    pub fn witness_transactions_for_operation(&self, operation: &str, page: u32) -> HashTree<'_> {
+        self.witness(Reveal {
+            operation_indexer: Some(self.operation_indexer.witness(
+                &self.store,
+                &operation.into(),
+                page as usize,
+            )),
+            counts: Some(self.count_witness(&count_key(OPERATION_COUNT, operation.as_bytes()))),
+            ..Reveal::default()
+        })
+    }
+
+    /// Return the witness that can be used to prove the response from
+    /// get_transactions_for_writer.
+    #[inline]
+    pub fn witness_transactions_for_writer(&self, writer: &Principal, page: u32) -> HashTree<'_> {
+        self.witness(Reveal {
+            writer_indexer: Some(
+                self.writer_indexer
+                    .witness(&self.store, writer, page as usize),
+            ),
+            counts: Some(self.count_witness(&count_key(WRITER_COUNT, writer.as_slice()))),
+            ..Reveal::default()
+        })
+    }
+
+    /// Return the witness that can be used to prove the response from
+    /// get_transactions_for_account.
+    #[inline]
+    pub fn witness_transactions_for_account(&self, account: &[u8], page: u32) -> HashTree<'_> {
+        self.witness(Reveal {
+            account_indexer: Some(self.account_indexer.witness(
+                &self.store,
+                &account.to_vec(),
+                page as usize,
             )),
-            fork(
-                HashTree::Pruned(self.user_indexer.root_hash()),
-                fork(
-                    HashTree::Pruned(self.contract_indexer.root_hash()),
-                    self.token_indexer.witness(token_id, page as usize),
+            counts: Some(self.count_witness(&count_key(ACCOUNT_COUNT, account))),
+            ..Reveal::default()
+        })
+    }
+
+    /// Return the witness that can be used to prove the response from
+    /// get_transactions_for_detail.
+    #[inline]
+    pub fn witness_transactions_for_detail(
+        &self,
+        key: &str,
+        value: &DetailValue,
+        page: u32,
+    ) -> HashTree<'_> {
+        let value = value.index_key().unwrap_or_default();
+        let detail_indexer =
+            self.detail_indexer
+                .nested_witness(&self.store, key.as_bytes(), |index, store| {
+                    index.witness(store, &value, page as usize)
+                });
+
+        self.witness(Reveal {
+            detail_indexer: Some(detail_indexer),
+            counts: Some(self.count_witness(&detail_count_key(key, &value))),
+            ..Reveal::default()
+        })
+    }
+
+    /// Return the witness of the given key of `counts`.
+    #[inline]
+    fn count_witness(&self, key: &[u8]) -> HashTree<'static> {
+        self.counts.witness(&self.store, key)
+    }
+
+    /// Return the keys of the time index from the given key, up to the last key with a time
+    /// that is not past `to`.
+    #[inline]
+    fn time_keys(&self, start: (u64, u32), to: u64) -> impl Iterator<Item = (u64, u32)> + '_ {
+        self.time_indexer
+            .iter_from(&self.store, Some(&time_key(start.0, start.1)))
+            .map(|(label, _)| parse_time_key(&label))
+            .take_while(move |(time, _)| *time <= to)
+    }
+
+    /// Return the transactions with a time in the inclusive range `from..=to`, ordered by their
+    /// time. At most one page of events is returned, along with the id of the first event of
+    /// the next page, which can be passed as the cursor to continue the lookup.
+    pub fn get_transactions_in_range(
+        &self,
+        from: u64,
+        to: u64,
+        cursor: Option<TransactionId>,
+    ) -> (Vec<Event>, Option<TransactionId>) {
+        let start = match self.range_start(from, cursor) {
+            Some(start) if (from..=to).contains(&start.0) => start,
+            _ => return (vec![], None),
+        };
+
+        let mut keys = self.time_keys(start, to);
+        let events = keys
+            .by_ref()
+            .take(TIME_PAGE_SIZE)
+            .map(|(_, local)| self.events.get(&self.store, local).unwrap())
+            .collect();
+        let next = keys
+            .next()
+            .map(|(_, local)| self.global_offset + (local as u64));
+
+        (events, next)
+    }
+
+    /// Return the witness that can be used to prove the response from get_transactions_in_range.
+    ///
+    /// When the range is empty, the witness reveals the key of the event the cursor points to,
+    /// or proves that there is no such event.
+    #[inline]
+    pub fn witness_transactions_in_range(
+        &self,
+        from: u64,
+        to: u64,
+        cursor: Option<TransactionId>,
+    ) -> HashTree<'_> {
+        let (time_indexer, event_hashes) = match self.range_start(from, cursor) {
+            Some(start) if (from..=to).contains(&start.0) => {
+                // When the page is full, the key after it is revealed as the next cursor.
+                let last = self
+                    .time_keys(start, to)
+                    .nth(TIME_PAGE_SIZE)
+                    .unwrap_or((to, u32::MAX));
+                let time_indexer = self.time_indexer.value_range(
+                    &self.store,
+                    &time_key(start.0, start.1),
+                    &time_key(last.0, last.1),
+                );
+                (Some(time_indexer), None)
+            }
+            Some(start) => (
+                Some(
+                    self.time_indexer
+                        .witness(&self.store, &time_key(start.0, start.1)),
                 ),
+                None,
             ),
-        )
+            // The cursor does not point to an event, which is proven as in get_transaction.
+            None => (None, cursor.and_then(|id| self.witness_event_hash(id))),
+        };
+
+        self.witness(Reveal {
+            event_hashes,
+            global_offset: Some(self.global_offset.as_hash_tree()),
+            time_indexer,
+            ..Reveal::default()
+        })
+    }
+
+    /// Return the key in the time index to start a range lookup from, which is either the key
+    /// of the event the cursor points to, or the first possible key for the given time.
+    /// Returns `None` if the cursor does not point to an event.
+    #[inline]
+    fn range_start(&self, from: u64, cursor: Option<TransactionId>) -> Option<(u64, u32)> {
+        match cursor {
+            None => Some((from, 0)),
+            Some(id) => {
+                let event = self.get_transaction(id)?;
+                Some((event.time, (id - self.global_offset) as u32))
+            }
+        }
     }
 
     /// Return a transaction by its global id.
     #[inline]
-    pub fn get_transaction(&self, id: u64) -> Option<&Event> {
-        if id < self.global_offset {
-            None
+    pub fn get_transaction(&self, id: u64) -> Option<Event> {
+        let local = id.checked_sub(self.global_offset)?;
+        if local < self.events.len() as u64 {
+            self.events.get(&self.store, local as u32)
         } else {
-            let local = (id - self.global_offset) as usize;
-            if local < self.events.len() {
-                Some(unsafe { self.events[local].as_ref() })
-            } else {
-                None
-            }
+            None
         }
     }
 
     /// Return a witness which proves the response returned by get_transaction.
+    ///
+    /// For an id below the global offset the revealed offset is the proof of absence, for an
+    /// id past the end of the list the witness proves that there is no local index at or
+    /// after `len()`.
     #[inline]
-    pub fn witness_transaction(&self, id: u64) -> HashTree {
-        let left = if id < self.global_offset {
-            fork(
-                HashTree::Pruned(self.event_hashes.root_hash()),
-                self.global_offset.as_hash_tree(),
-            )
-        } else {
-            let local = (id - self.global_offset) as u32;
-            fork(
-                self.event_hashes.witness(&local),
-                self.global_offset.as_hash_tree(),
-            )
-        };
+    pub fn witness_transaction(&self, id: u64) -> HashTree<'_> {
+        self.witness(Reveal {
+            event_hashes: self.witness_event_hash(id),
+            global_offset: Some(self.global_offset.as_hash_tree()),
+            ..Reveal::default()
+        })
+    }
+
+    /// Return the witness of the hash of the event with the given id, or of the absence of a
+    /// local index at or after `len()`. Returns `None` for an id below the global offset.
+    #[inline]
+    fn witness_event_hash(&self, id: u64) -> Option<HashTree<'static>> {
+        if id < self.global_offset {
+            return None;
+        }
+
+        let local = (id - self.global_offset).min(self.events.len() as u64) as u32;
+        Some(self.event_hashes.witness(&self.store, &local.to_be_bytes()))
+    }
+
+    /// Build the tree of this list, where only the revealed parts are present and the rest
+    /// is pruned.
+    fn witness<'a>(&'a self, reveal: Reveal<'a>) -> HashTree<'a> {
+        fn node(hash: Hash, tree: Option<HashTree<'_>>) -> HashTree<'_> {
+            tree.unwrap_or(HashTree::Pruned(hash))
+        }
 
-        fork(
-            left,
-            HashTree::Pruned(fork_hash(
-                &self.user_indexer.root_hash(),
-                &fork_hash(
-                    &self.contract_indexer.root_hash(),
-                    &self.token_indexer.root_hash(),
+        let store = &self.store;
+        branch(
+            branch(
+                node(self.event_hashes.root_hash(store), reveal.event_hashes),
+                node(self.global_offset.root_hash(), reveal.global_offset),
+            ),
+            branch(
+                node(self.user_indexer.root_hash(store), reveal.user_indexer),
+                branch(
+                    node(self.contract_indexer.root_hash(store), reveal.contract_indexer),
+                    branch(
+                        node(self.token_indexer.root_hash(store), reveal.token_indexer),
+                        branch(
+                            node(self.time_indexer.root_hash(store), reveal.time_indexer),
+                            branch(
+                                node(
+                                    self.operation_indexer.root_hash(store),
+                                    reveal.operation_indexer,
+                                ),
+                                branch(
+                                    node(
+                                        self.detail_indexer.root_hash(store),
+                                        reveal.detail_indexer,
+                                    ),
+                                    branch(
+                                        node(self.counts.root_hash(store), reveal.counts),
+                                        branch(
+                                            node(
+                                                self.account_indexer.root_hash(store),
+                                                reveal.account_indexer,
+                                            ),
+                                            branch(
+                                                node(
+                                                    self.idempotency_keys.root_hash(store),
+                                                    reveal.idempotency_keys,
+                                                ),
+                                                branch(
+                                                    node(self.tip().root_hash(), reveal.tip),
+                                                    node(
+                                                        self.writer_indexer.root_hash(store),
+                                                        reveal.writer_indexer,
+                                                    ),
+                                                ),
+                                            ),
+                                        ),
+                                    ),
+                                ),
+                            ),
+                        ),
+                    ),
                 ),
-            )),
+            ),
         )
     }
 }
 
 impl AsHashTree for TransactionList {
     fn root_hash(&self) -> Hash {
-        fork_hash(
-            &fork_hash(
-                &self.event_hashes.root_hash(),
-                &self.global_offset.root_hash(),
-            ),
-            &fork_hash(
-                &self.user_indexer.root_hash(),
-                &fork_hash(
-                    &self.contract_indexer.root_hash(),
-                    &self.token_indexer.root_hash(),
-                ),
-            ),
-        )
+        self.witness(Reveal::default()).reconstruct()
     }
 
     fn as_hash_tree(&self) -> HashTree<'_> {
-        fork(
-            fork(
-                self.event_hashes.as_hash_tree(),
-                self.global_offset.as_hash_tree(),
-            ),
-            fork(
-                self.user_indexer.as_hash_tree(),
-                fork(
-                    self.contract_indexer.as_hash_tree(),
-                    self.token_indexer.as_hash_tree(),
-                ),
-            ),
-        )
+        let store = &self.store;
+        self.witness(Reveal {
+            event_hashes: Some(self.event_hashes.as_hash_tree(store)),
+            global_offset: Some(self.global_offset.as_hash_tree()),
+            user_indexer: Some(self.user_indexer.as_hash_tree(store)),
+            contract_indexer: Some(self.contract_indexer.as_hash_tree(store)),
+            token_indexer: Some(self.token_indexer.as_hash_tree(store)),
+            time_indexer: Some(self.time_indexer.as_hash_tree(store)),
+            operation_indexer: Some(self.operation_indexer.as_hash_tree(store)),
+            detail_indexer: Some(self.detail_indexer.as_hash_tree(store)),
+            counts: Some(self.counts.as_hash_tree(store)),
+            account_indexer: Some(self.account_indexer.as_hash_tree(store)),
+            idempotency_keys: Some(self.idempotency_keys.as_hash_tree(store)),
+            tip: Some(HashTree::Leaf(Cow::Owned(self.tip().to_bytes()))),
+            writer_indexer: Some(self.writer_indexer.as_hash_tree(store)),
+        })
     }
 }
 
-impl Drop for TransactionList {
-    fn drop(&mut self) {
-        unsafe {
-            for event in &self.events {
-                let as_mut_ref = &mut (*event.as_ptr());
-                ptr::drop_in_place(as_mut_ref);
-                dealloc(event.cast().as_ptr(), Layout::for_value(event.as_ref()));
-            }
-        }
-    }
+/// The parts of a [`TransactionList`] to reveal in a witness, anything left as `None` is
+/// pruned.
+#[derive(Default)]
+struct Reveal<'a> {
+    event_hashes: Option<HashTree<'a>>,
+    global_offset: Option<HashTree<'a>>,
+    user_indexer: Option<HashTree<'a>>,
+    contract_indexer: Option<HashTree<'a>>,
+    token_indexer: Option<HashTree<'a>>,
+    time_indexer: Option<HashTree<'a>>,
+    operation_indexer: Option<HashTree<'a>>,
+    detail_indexer: Option<HashTree<'a>>,
+    counts: Option<HashTree<'a>>,
+    account_indexer: Option<HashTree<'a>>,
+    idempotency_keys: Option<HashTree<'a>>,
+    tip: Option<HashTree<'a>>,
+    writer_indexer: Option<HashTree<'a>>,
 }
 
-struct EventsWrapper<'a>(&'a Vec<NonNull<Event>>);
-
-impl<'a> Serialize for EventsWrapper<'a> {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let mut s = serializer.serialize_seq(Some(self.0.len()))?;
-        for ev in self.0 {
-            s.serialize_element(unsafe { ev.as_ref() })?;
-        }
-        s.end()
+/// Create a fork of the two trees, or a single pruned node if both sides are pruned.
+#[inline]
+fn branch<'a>(left: HashTree<'a>, right: HashTree<'a>) -> HashTree<'a> {
+    match (&left, &right) {
+        (HashTree::Pruned(l), HashTree::Pruned(r)) => HashTree::Pruned(fork_hash(l, r)),
+        _ => fork(left, right),
     }
 }
 
-impl<'a> CandidType for EventsWrapper<'a> {
-    fn _ty() -> Type {
-        <Vec<Event>>::_ty()
-    }
+/// The number of recent idempotency keys remembered by a list.
+pub const MAX_IDEMPOTENCY_KEYS: usize = 10_000;
 
-    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
-    where
-        S: ic_kit::candid::types::Serializer,
-    {
-        let mut ser = serializer.serialize_vec(self.0.len())?;
-        for e in self.0.iter() {
-            Compound::serialize_element(&mut ser, unsafe { e.as_ref() })?;
-        }
-        Ok(())
-    }
-}
+/// The maximum number of events returned by a single cursor lookup.
+const MAX_CURSOR_LIMIT: u32 = 1024;
 
-impl Serialize for TransactionList {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        let mut s = serializer.serialize_tuple(3)?;
-        s.serialize_element(&self.global_offset)?;
-        s.serialize_element(&self.contract)?;
-        s.serialize_element(&EventsWrapper(&self.events))?;
-        s.end()
-    }
-}
+/// The number of events returned by a single range lookup on the time index.
+const TIME_PAGE_SIZE: usize = 64;
 
-impl<'de> Deserialize<'de> for TransactionList {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        #[derive(Deserialize)]
-        struct TransactionListDe(u64, Principal, Vec<Event>);
+/// The key of an event in the time index, the time followed by the local index of the event
+/// both in big endian so the keys are ordered by time.
+type TimeKey = [u8; 12];
 
-        let data = TransactionListDe::deserialize(deserializer)?;
-        let mut list = TransactionList::new(data.1, data.0);
+#[inline]
+fn time_key(time: u64, local: u32) -> TimeKey {
+    let mut key = [0; 12];
+    key[..8].copy_from_slice(&time.to_be_bytes());
+    key[8..].copy_from_slice(&local.to_be_bytes());
+    key
+}
 
-        for event in data.2 {
-            list.insert(event);
-        }
+/// Return the time and the local index of a key of the time index.
+#[inline]
+fn parse_time_key(key: &[u8]) -> (u64, u32) {
+    (u64::load(&key[..8]), u32::load(&key[8..]))
+}
 
-        Ok(list)
-    }
+/// The tags of the keys in `counts` for each of the paged indexes.
+pub(crate) const USER_COUNT: u8 = 0;
+pub(crate) const CONTRACT_COUNT: u8 = 1;
+pub(crate) const TOKEN_COUNT: u8 = 2;
+pub(crate) const OPERATION_COUNT: u8 = 3;
+pub(crate) const DETAIL_COUNT: u8 = 4;
+pub(crate) const ACCOUNT_COUNT: u8 = 5;
+pub(crate) const WRITER_COUNT: u8 = 6;
+
+/// The key in `counts` of the number of events under the given label of a paged index.
+#[inline]
+pub(crate) fn count_key(tag: u8, label: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(label.len() + 1);
+    key.push(tag);
+    key.extend_from_slice(label);
+    key
 }
 
-impl CandidType for TransactionList {
-    fn _ty() -> Type {
-        <(u64, Principal, EventsWrapper)>::_ty()
-    }
+/// The key in `counts` of the number of events with the given value for a detail key, the
+/// length of the detail key is included so the keys of different details can't collide.
+#[inline]
+pub(crate) fn detail_count_key(key: &str, value: &[u8]) -> Vec<u8> {
+    let mut label = Vec::with_capacity(key.len() + value.len() + 4);
+    label.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    label.extend_from_slice(key.as_bytes());
+    label.extend_from_slice(value);
+    count_key(DETAIL_COUNT, &label)
+}
 
-    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
-    where
-        S: ic_kit::candid::types::Serializer,
-    {
-        (
-            &self.global_offset,
-            &self.contract,
-            &EventsWrapper(&self.events),
-        )
-            .idl_serialize(serializer)
-    }
+/// Return the index keys of the values the event has for the given detail key.
+#[inline]
+fn detail_values(event: &Event, key: &str) -> BTreeSet<Vec<u8>> {
+    event
+        .details
+        .iter()
+        .filter(|(k, _)| k == key)
+        .filter_map(|(_, value)| value.index_key())
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ic_kit::candid::{decode_one, encode_one};
+    use crate::transaction::Account;
+    use ic_kit::candid::Nat;
     use ic_kit::mock_principals;
 
     fn e(time: u64, caller: Principal) -> Event {
@@ -418,6 +1673,32 @@ mod tests {
         assert_eq!(list.as_hash_tree().reconstruct(), list.root_hash());
     }
 
+    /// The indexes store references to the events, but should certify the same pages as if
+    /// they stored the events themselves.
+    #[test]
+    fn test_index_hashes() {
+        let mut list = TransactionList::new(mock_principals::xtc(), 0);
+        let mut users = certified_vars::Paged::<Principal, Event, 64>::new();
+
+        for i in 0..200 {
+            let caller = match i % 3 {
+                0 => mock_principals::bob(),
+                _ => mock_principals::alice(),
+            };
+            list.insert(e(i, caller));
+            users.insert(caller, e(i, caller));
+        }
+
+        assert_eq!(list.user_indexer.root_hash(&list.store), users.root_hash());
+        assert_eq!(list.events().count(), 200);
+    }
+
+    #[test]
+    fn test_send() {
+        fn is_send<T: Send>() {}
+        is_send::<TransactionList>();
+    }
+
     /// This test tires to see if the witness created for a lookup is minimal
     /// and reconstructs to the root_hash.
     #[test]
@@ -432,6 +1713,10 @@ mod tests {
         let witness = list.witness_transaction(1);
         assert_eq!(event.time, 1);
         assert_eq!(witness.reconstruct(), list.root_hash());
+
+        let hash = event.hash();
+        assert!(witness.get_labels().contains(&&1u32.to_be_bytes()[..]));
+        assert!(witness.get_leaf_values().contains(&&hash[..]));
     }
 
     #[test]
@@ -446,6 +1731,15 @@ mod tests {
 
         let witness = list.witness_transaction(4);
         assert_eq!(witness.reconstruct(), list.root_hash());
+
+        // The last local index is revealed as the left neighbor of the missing one.
+        let labels = witness.get_labels();
+        assert!(labels.contains(&&3u32.to_be_bytes()[..]));
+        assert!(!labels.contains(&&4u32.to_be_bytes()[..]));
+
+        let witness = list.witness_transaction(u64::MAX);
+        assert_eq!(witness.reconstruct(), list.root_hash());
+        assert!(witness.get_labels().contains(&&3u32.to_be_bytes()[..]));
     }
 
     #[test]
@@ -459,6 +1753,9 @@ mod tests {
         assert_eq!(list.get_transaction(5).is_none(), true);
         let witness = list.witness_transaction(5);
         assert_eq!(witness.reconstruct(), list.root_hash());
+        assert!(witness
+            .get_leaf_values()
+            .contains(&&10u64.to_be_bytes()[..]));
     }
 
     #[test]
@@ -494,6 +1791,665 @@ mod tests {
         assert_eq!(count, 186);
     }
 
+    #[test]
+    fn test_witness_operation_transactions() {
+        let mut list = TransactionList::new(mock_principals::xtc(), 0);
+
+        for i in 0..300 {
+            let mut event = e(i, mock_principals::alice());
+            if i % 3 == 0 {
+                event.operation = "mint".into();
+            }
+            list.insert(event);
+        }
+
+        assert_eq!(list.last_page_for_operation("mint"), 1);
+        assert_eq!(list.last_page_for_operation("burn"), 0);
+
+        let mut count = 0;
+
+        for page in 0..3 {
+            let data = list.get_transactions_for_operation("mint", page);
+            let witness = list.witness_transactions_for_operation("mint", page);
+            assert_eq!(witness.reconstruct(), list.root_hash());
+            assert!(data.iter().all(|e| e.operation == "mint"));
+            count += data.len();
+        }
+
+        assert_eq!(count, 100);
+        assert_eq!(list.get_transactions_for_operation("transfer", 3).len(), 8);
+        assert!(list.get_transactions_for_operation("burn", 0).is_empty());
+    }
+
+    #[test]
+    fn test_token_transactions() {
+        let tokens: Vec<TokenId> = vec![
+            7.into(),
+            Nat::from(7u64).into(),
+            Nat::from(u128::MAX).into(),
+            "7".into(),
+            "7-a".into(),
+        ];
+        let mut list = TransactionList::new(mock_principals::xtc(), 0);
+
+        for i in 0..300 {
+            let mut event = e(i, mock_principals::alice());
+            let token = tokens[i as usize % tokens.len()].clone();
+            event.details = vec![("token".into(), token.into())];
+            list.insert(event);
+        }
+
+        for token in &tokens {
+            assert_eq!(list.count_for_token(token), 60);
+            assert_eq!(list.last_page_for_token(token), 0);
+
+            let data = list.get_transactions_for_token(token, 0);
+            assert_eq!(data.len(), 60);
+            assert!(data.iter().all(|e| e.extract_token_ids().contains(token)));
+
+            let witness = list.witness_transactions_for_token(token, 0);
+            assert_eq!(witness.reconstruct(), list.root_hash());
+        }
+
+        let missing = TokenId::Text("8".into());
+        assert!(list.get_transactions_for_token(&missing, 0).is_empty());
+        let (data, _) =
+            list.get_transactions_for_token_cursor(&tokens[4], None, Direction::Backward, 10, None);
+        assert_eq!(data[0].time, 299);
+    }
+
+    #[test]
+    fn test_account_transactions() {
+        let account = |i: u64| Account {
+            owner: mock_principals::bob(),
+            subaccount: Some(vec![(i % 2) as u8; 32]),
+        };
+        let mut list = TransactionList::new(mock_principals::xtc(), 0);
+
+        for i in 0..200 {
+            let mut event = e(i, mock_principals::alice());
+            event.details = vec![("to".into(), account(i).into())];
+            list.insert(event);
+        }
+
+        // The owner of an account is indexed as a user.
+        assert_eq!(list.count_for_user(&mock_principals::bob()), 200);
+
+        // The default subaccount is found by the identifier of the bare principal.
+        let id = Account {
+            owner: mock_principals::bob(),
+            subaccount: None,
+        }
+        .account_id();
+        assert_eq!(list.count_for_account(&id), 100);
+        assert_eq!(list.last_page_for_account(&id), 1);
+
+        let data = list.get_transactions_for_account(&id, 1);
+        assert_eq!(data.len(), 36);
+        assert!(data.iter().all(|e| e.time % 2 == 0));
+
+        let witness = list.witness_transactions_for_account(&id, 1);
+        assert_eq!(witness.reconstruct(), list.root_hash());
+
+        assert!(list.get_transactions_for_account(&[7; 32], 0).is_empty());
+        let witness = list.witness_transactions_for_account(&[7; 32], 0);
+        assert_eq!(witness.reconstruct(), list.root_hash());
+    }
+
+    #[test]
+    fn test_detail_index() {
+        let pair = |i: u64| {
+            let mut event = e(i, mock_principals::alice());
+            event.details = vec![
+                ("pair_id".into(), DetailValue::U64(i % 4)),
+                ("memo".into(), DetailValue::Text(format!("{}", i))),
+            ];
+            event
+        };
+
+        let mut list = TransactionList::new(mock_principals::xtc(), 0);
+        assert!(list.add_detail_index("pair_id".into()));
+        assert!(!list.add_detail_index("pair_id".into()));
+
+        for i in 0..400 {
+            list.insert(pair(i));
+        }
+
+        // Declaring an index later indexes the existing events in batches, the events inserted
+        // in between are indexed once the backfill reaches them.
+        let mut backfilled = TransactionList::new(mock_principals::xtc(), 0);
+        for i in 0..300 {
+            backfilled.insert(pair(i));
+        }
+        backfilled.add_detail_index("pair_id".into());
+        assert_eq!(backfilled.backfill_detail_indexes(250), 250);
+        for i in 300..400 {
+            backfilled.insert(pair(i));
+        }
+
+        // The progress survives serialization.
+        let data: Vec<u8> = serde_cbor::to_vec(&backfilled).unwrap();
+        let mut backfilled: TransactionList = serde_cbor::from_slice(&data).unwrap();
+        assert_eq!(backfilled.detail_backfills(), vec![("pair_id".into(), 250)]);
+        assert_ne!(backfilled.root_hash(), list.root_hash());
+        assert_eq!(backfilled.backfill_detail_indexes(1_000), 150);
+        assert!(backfilled.detail_backfills().is_empty());
+        assert_eq!(backfilled.root_hash(), list.root_hash());
+
+        // Removing the oldest events keeps the progress.
+        let mut removed = TransactionList::new(mock_principals::xtc(), 0);
+        for i in 0..300 {
+            removed.insert(pair(i));
+        }
+        removed.add_detail_index("pair_id".into());
+        removed.backfill_detail_indexes(250);
+        removed.remove_before(100);
+        assert_eq!(removed.detail_backfills(), vec![("pair_id".into(), 250)]);
+        assert_eq!(removed.backfill_detail_indexes(1_000), 50);
+        assert_eq!(removed.count_for_detail("pair_id", &DetailValue::U64(3)), 50);
+
+        let value = DetailValue::U64(3);
+        assert_eq!(list.last_page_for_detail("pair_id", &value), 1);
+        assert_eq!(
+            list.get_transactions_for_detail("pair_id", &value, 0).len(),
+            64
+        );
+        let data = list.get_transactions_for_detail("pair_id", &value, 1);
+        assert_eq!(data.len(), 36);
+        assert!(data.iter().all(|e| e.time % 4 == 3));
+        assert_eq!(list.count_for_detail("pair_id", &value), 100);
+        assert_eq!(backfilled.count_for_detail("pair_id", &value), 100);
+        assert_eq!(list.count_for_user(&mock_principals::alice()), 400);
+        assert_eq!(list.count_for_operation("transfer"), 400);
+
+        let witness = list.witness_transactions_for_detail("pair_id", &value, 1);
+        assert_eq!(witness.reconstruct(), list.root_hash());
+
+        // Keys which are not declared are not indexed.
+        let memo = DetailValue::Text("7".into());
+        assert!(list
+            .get_transactions_for_detail("memo", &memo, 0)
+            .is_empty());
+        let witness = list.witness_transactions_for_detail("memo", &memo, 0);
+        assert_eq!(witness.reconstruct(), list.root_hash());
+
+        // The declared keys survive serialization.
+        let data: Vec<u8> = serde_cbor::to_vec(&list).unwrap();
+        let decoded: TransactionList = serde_cbor::from_slice(&data).unwrap();
+        assert_eq!(decoded.detail_indexes(), vec!["pair_id".to_string()]);
+        assert_eq!(decoded.root_hash(), list.root_hash());
+    }
+
+    #[test]
+    fn test_many_roles() {
+        let bob = mock_principals::bob();
+        let mut list = TransactionList::new(mock_principals::xtc(), 0);
+        for i in 0..100 {
+            let mut event = e(i, mock_principals::alice());
+            event.details = vec![(format!("role_{}", i), bob.into())];
+            list.insert(event);
+        }
+
+        // Every role is recorded, however many there are.
+        for i in 0..100 {
+            let roles = vec![format!("role_{}", i)];
+            assert_eq!(list.count_for_user_with_roles(&bob, &roles), 1);
+            let data = list.get_transactions_for_user_with_roles(&bob, &roles, 0);
+            assert_eq!(data.iter().map(|e| e.time).collect::<Vec<_>>(), vec![i]);
+        }
+
+        let roles = vec!["role_7".to_string(), "role_93".to_string()];
+        assert_eq!(list.count_for_user_with_roles(&bob, &roles), 2);
+    }
+
+    #[test]
+    fn test_user_roles() {
+        let (alice, bob) = (mock_principals::alice(), mock_principals::bob());
+        let transfer = |i: u64| {
+            let mut event = e(i, alice);
+            event.details = if i % 2 == 1 {
+                vec![("from".into(), bob.into()), ("spender".into(), bob.into())]
+            } else {
+                vec![("to".into(), bob.into()), ("from".into(), alice.into())]
+            };
+            event
+        };
+        let roles = |roles: &[&str]| roles.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+
+        let mut list = TransactionList::new(mock_principals::xtc(), 0);
+        for i in 0..100 {
+            list.insert(transfer(i));
+        }
+
+        assert_eq!(list.count_for_user(&bob), 100);
+        assert_eq!(list.count_for_user_with_roles(&bob, &roles(&["to"])), 50);
+        assert_eq!(
+            list.count_for_user_with_roles(&bob, &roles(&["to", "spender"])),
+            100
+        );
+        assert_eq!(list.count_for_user_with_roles(&bob, &roles(&["caller"])), 0);
+        assert_eq!(list.count_for_user_with_roles(&bob, &roles(&["memo"])), 0);
+        assert_eq!(
+            list.count_for_user_with_roles(&alice, &roles(&["caller"])),
+            100
+        );
+        assert_eq!(
+            list.count_for_user_with_roles(&alice, &roles(&["from"])),
+            50
+        );
+
+        let data = list.get_transactions_for_user_with_roles(&bob, &roles(&["from"]), 0);
+        assert_eq!(data.len(), 50);
+        assert!(data.iter().all(|e| e.time % 2 == 1));
+
+        let spender = roles(&["spender"]);
+        let (data, next) = list.get_transactions_for_user_cursor(
+            &bob,
+            Some(&spender),
+            Some(10),
+            Direction::Backward,
+            3,
+            None,
+        );
+        assert_eq!(
+            data.iter().map(|e| e.time).collect::<Vec<_>>(),
+            vec![9, 7, 5]
+        );
+        assert_eq!(next, Some(3));
+
+        // The events of the different roles are merged in order.
+        let (data, next) = list.get_transactions_for_user_cursor(
+            &bob,
+            Some(&roles(&["to", "spender"])),
+            Some(5),
+            Direction::Forward,
+            4,
+            None,
+        );
+        assert_eq!(
+            data.iter().map(|e| e.time).collect::<Vec<_>>(),
+            vec![5, 6, 7, 8]
+        );
+        assert_eq!(next, Some(9));
+
+        // Excluding a key only applies to the events inserted afterwards, the roles are not
+        // part of the certified tree so the root hash doesn't change.
+        let root_hash = list.root_hash();
+        assert!(list.set_user_index_exclusions(vec!["to".to_string()].into_iter().collect()));
+        assert!(!list.set_user_index_exclusions(vec!["to".to_string()].into_iter().collect()));
+        assert_eq!(list.user_index_exclusions(), vec!["to".to_string()]);
+        assert_eq!(list.root_hash(), root_hash);
+        for i in 100..200 {
+            list.insert(transfer(i));
+        }
+        assert_eq!(list.count_for_user(&bob), 150);
+        assert_eq!(list.count_for_user_with_roles(&bob, &roles(&["to"])), 50);
+        assert_eq!(list.count_for_user(&alice), 200);
+
+        let mut expected = TransactionList::new(mock_principals::xtc(), 0);
+        for i in 0..200 {
+            if i == 100 {
+                expected.set_user_index_exclusions(vec!["to".to_string()].into_iter().collect());
+            }
+            expected.insert(transfer(i));
+        }
+        assert_eq!(expected.root_hash(), list.root_hash());
+
+        // The pages of the filtered transactions follow the order of the events.
+        let from = roles(&["from"]);
+        assert_eq!(list.count_for_user_with_roles(&alice, &from), 100);
+        let data = list.get_transactions_for_user_with_roles(&alice, &from, 1);
+        assert_eq!(
+            data.iter().map(|e| e.time).collect::<Vec<_>>(),
+            (128..200).step_by(2).collect::<Vec<_>>()
+        );
+        let data = list.get_transactions_for_user_with_roles(&bob, &roles(&["from", "to"]), 1);
+        assert_eq!(
+            data.iter().map(|e| e.time).collect::<Vec<_>>(),
+            (64..100).chain((101..200).step_by(2)).take(64).collect::<Vec<_>>()
+        );
+        assert!(list
+            .get_transactions_for_user_with_roles(&bob, &from, 2)
+            .is_empty());
+
+        // The exclusions and the roles survive serialization.
+        let data: Vec<u8> = serde_cbor::to_vec(&list).unwrap();
+        let decoded: TransactionList = serde_cbor::from_slice(&data).unwrap();
+        assert_eq!(decoded.user_index_exclusions(), vec!["to".to_string()]);
+        assert_eq!(decoded.root_hash(), list.root_hash());
+        assert_eq!(decoded.count_for_user_with_roles(&bob, &spender), 100);
+
+        // The exclusions which started before the removed events still apply.
+        let mut removed = list;
+        removed.remove_before(150);
+        assert_eq!(removed.user_index_exclusions(), vec!["to".to_string()]);
+        assert_eq!(removed.count_for_user_with_roles(&bob, &roles(&["to"])), 0);
+        assert_eq!(removed.count_for_user(&bob), 25);
+    }
+
+    #[test]
+    fn test_idempotency_keys() {
+        let key = |i: usize| Some(format!("key-{}", i));
+        let mut list = TransactionList::new(mock_principals::xtc(), 10);
+
+        assert_eq!(
+            list.insert_idempotent(None, e(0, mock_principals::alice()), key(0)),
+            10
+        );
+        assert_eq!(
+            list.insert_idempotent(None, e(1, mock_principals::alice()), None),
+            11
+        );
+        assert_eq!(
+            list.insert_idempotent(None, e(2, mock_principals::bob()), key(0)),
+            10
+        );
+        assert_eq!(
+            list.insert_idempotent(None, e(3, mock_principals::bob()), key(1)),
+            12
+        );
+        assert_eq!(list.events.len(), 3);
+        assert_eq!(list.get_id_for_key("key-1"), Some(12));
+        assert_eq!(list.get_id_for_key("key-2"), None);
+
+        let witness = list.witness_id_for_key("key-1");
+        assert_eq!(witness.reconstruct(), list.root_hash());
+
+        // The keys survive serialization and rollovers.
+        let data: Vec<u8> = serde_cbor::to_vec(&list).unwrap();
+        let mut decoded: TransactionList = serde_cbor::from_slice(&data).unwrap();
+        assert_eq!(decoded.root_hash(), list.root_hash());
+
+        decoded.remove_before(12);
+        assert_eq!(decoded.get_id_for_key("key-0"), Some(10));
+        assert_eq!(
+            decoded.insert_idempotent(None, e(4, mock_principals::bob()), key(0)),
+            10
+        );
+
+        // Only the recent keys are remembered.
+        for i in 2..MAX_IDEMPOTENCY_KEYS + 1 {
+            list.insert_idempotent(None, e(i as u64, mock_principals::alice()), key(i));
+        }
+        assert_eq!(list.get_id_for_key("key-0"), None);
+        assert_eq!(list.get_id_for_key("key-1"), Some(12));
+        list.insert_idempotent(None, e(0, mock_principals::alice()), key(0));
+        assert_eq!(list.get_id_for_key("key-1"), None);
+        assert_eq!(list.idempotency_keys.len(), MAX_IDEMPOTENCY_KEYS as u64);
+    }
+
+    #[test]
+    fn test_remove_before() {
+        let mut list = TransactionList::new(mock_principals::xtc(), 10);
+        let mut expected = TransactionList::new(mock_principals::xtc(), 60);
+        list.add_detail_index("memo".into());
+        expected.add_detail_index("memo".into());
+
+        for i in 10..110 {
+            let mut event = e(i, mock_principals::alice());
+            event.details = vec![("memo".into(), DetailValue::U64(i % 2))];
+            list.insert(event.clone());
+            if i >= 60 {
+                expected.insert(event);
+            } else {
+                // The accumulator still covers the removed events.
+                expected.mmr.push(&mut expected.store, event.hash());
+            }
+        }
+
+        let tip = list.tip();
+        list.remove_before(60);
+        assert_eq!(list.tip(), tip);
+        assert_eq!(list.size(), 110);
+        assert_eq!(list.len(), 50);
+        assert_eq!(list.get_transaction(59), None);
+        assert_eq!(list.get_transaction(60).unwrap().time, 60);
+        assert_eq!(list.root_hash(), expected.root_hash());
+        assert_eq!(list.detail_indexes(), vec!["memo".to_string()]);
+
+        // Removing past the end skips the missing ids.
+        list.remove_before(200);
+        assert!(list.is_empty());
+        assert_eq!(list.size(), 200);
+        assert_eq!(list.insert(e(200, mock_principals::alice())), 200);
+    }
+
+    #[test]
+    fn test_free_retired() {
+        let mut list = TransactionList::new(mock_principals::xtc(), 0);
+        list.add_detail_index("memo".into());
+        let event = |i: u64| {
+            let mut event = e(i, mock_principals::alice());
+            event.details = vec![
+                ("to".into(), DetailValue::Principal(mock_principals::bob())),
+                ("memo".into(), DetailValue::U64(i % 7)),
+            ];
+            event
+        };
+
+        for i in 0..500 {
+            list.insert_from(Some(mock_principals::john()), event(i));
+        }
+
+        // A copy of the list whose old structures are never freed.
+        let bytes = serde_cbor::to_vec(&list).unwrap();
+        let mut leaked: TransactionList = serde_cbor::from_slice(&bytes).unwrap();
+        let size = list.store.size();
+        list.remove_before(490);
+        leaked.remove_before(490);
+        let root_hash = list.root_hash();
+
+        // The old structures are freed in steps, and the list is left intact by each of them.
+        let mut steps = 0;
+        while list.free_retired(100) > 0 {
+            steps += 1;
+            assert_eq!(list.root_hash(), root_hash);
+        }
+        assert!(list.retired.is_empty());
+        assert!(steps > 10);
+        assert_eq!(list.get_transaction(495).unwrap(), event(495));
+
+        // The freed blocks are reused.
+        for i in 500..990 {
+            list.insert_from(Some(mock_principals::john()), event(i));
+            leaked.insert_from(Some(mock_principals::john()), event(i));
+        }
+        assert!(list.store.size() - size < (leaked.store.size() - size) / 4);
+    }
+
+    #[test]
+    fn test_tip() {
+        let mut list = TransactionList::new(mock_principals::xtc(), 0);
+        for i in 0..30 {
+            list.insert(e(i, mock_principals::alice()));
+        }
+
+        let old = list.tip();
+        assert_eq!(old.size, 30);
+        list.remove_before(20);
+        for i in 30..45 {
+            list.insert(e(i, mock_principals::alice()));
+        }
+
+        let new = list.tip();
+        assert_eq!(new.size, 45);
+        assert!(list.consistency_proof(30, 45).unwrap().verify(&old, &new));
+        assert_eq!(list.consistency_proof(30, 46), None);
+
+        // The accumulator over the archived events survives the serialization.
+        let decoded: TransactionList =
+            serde_cbor::from_slice(&serde_cbor::to_vec(&list).unwrap()).unwrap();
+        assert_eq!(decoded.tip(), new);
+        assert_eq!(decoded.root_hash(), list.root_hash());
+    }
+
+    #[test]
+    fn test_writers() {
+        let minter = mock_principals::john();
+        let market = mock_principals::bob();
+        let mut list = TransactionList::new(mock_principals::xtc(), 0);
+
+        list.insert(e(0, mock_principals::alice()));
+        for i in 1..40 {
+            let writer = if (10..30).contains(&i) { market } else { minter };
+            list.insert_from(Some(writer), e(i, mock_principals::alice()));
+        }
+        list.insert(e(40, mock_principals::alice()));
+
+        assert_eq!(list.get_writer(0), None);
+        assert_eq!(list.get_writer(1), Some(minter));
+        assert_eq!(list.get_writer(10), Some(market));
+        assert_eq!(list.get_writer(35), Some(minter));
+        assert_eq!(list.get_writer(40), None);
+        assert_eq!(list.get_writer(41), None);
+        assert_eq!(list.count_for_writer(&minter), 19);
+        assert_eq!(list.count_for_writer(&market), 20);
+        assert_eq!(list.writers, vec![minter, market]);
+
+        // The writers are kept for the remaining events.
+        list.remove_before(15);
+        assert_eq!(list.get_writer(14), None);
+        assert_eq!(list.get_writer(15), Some(market));
+        assert_eq!(list.count_for_writer(&market), 15);
+        assert_eq!(list.writers, vec![minter, market]);
+
+        let decoded: TransactionList =
+            serde_cbor::from_slice(&serde_cbor::to_vec(&list).unwrap()).unwrap();
+        assert_eq!(decoded.root_hash(), list.root_hash());
+        assert_eq!(decoded.get_writer(32), Some(minter));
+    }
+
+    #[test]
+    fn test_cursor() {
+        let mut list = TransactionList::new(mock_principals::xtc(), 10);
+
+        for i in 0..500 {
+            if i % 3 == 0 {
+                list.insert(e(i, mock_principals::bob()));
+            } else {
+                list.insert(e(i, mock_principals::alice()));
+            }
+        }
+
+        let bob = mock_principals::bob();
+        let collect = |direction: Direction, limit: u32| {
+            let mut times = vec![];
+            let mut cursor = None;
+            loop {
+                let (data, next) = list
+                    .get_transactions_for_user_cursor(&bob, None, cursor, direction, limit, None);
+                assert!(data.len() <= limit as usize);
+                times.extend(data.iter().map(|e| e.time));
+                match next {
+                    Some(_) => cursor = next,
+                    None => break times,
+                }
+            }
+        };
+
+        let forward = collect(Direction::Forward, 50);
+        let expected = (0..500).filter(|i| i % 3 == 0).collect::<Vec<_>>();
+        assert_eq!(forward, expected);
+
+        let backward = collect(Direction::Backward, 7);
+        assert_eq!(backward, expected.iter().rev().cloned().collect::<Vec<_>>());
+
+        // A cursor which is not in the index starts at the closest event in the direction.
+        let (data, next) = list.get_transactions_for_user_cursor(
+            &bob,
+            None,
+            Some(11),
+            Direction::Forward,
+            2,
+            None,
+        );
+        assert_eq!(data.iter().map(|e| e.time).collect::<Vec<_>>(), vec![3, 6]);
+        assert_eq!(next, Some(19));
+        let (data, next) = list.get_transactions_for_user_cursor(
+            &bob,
+            None,
+            Some(18),
+            Direction::Backward,
+            2,
+            None,
+        );
+        assert_eq!(data.iter().map(|e| e.time).collect::<Vec<_>>(), vec![6, 3]);
+        assert_eq!(next, Some(10));
+
+        // The newest events of the contract.
+        let (data, next) =
+            list.get_transactions_for_contract_cursor(None, Direction::Backward, 3, None);
+        assert_eq!(
+            data.iter().map(|e| e.time).collect::<Vec<_>>(),
+            vec![499, 498, 497]
+        );
+        assert_eq!(next, Some(506));
+
+        // The size limit still returns one event.
+        let size = (10..13)
+            .map(|id| list.get_transaction(id).unwrap().size_hint() as u32)
+            .sum::<u32>();
+        let (data, next) =
+            list.get_transactions_for_contract_cursor(None, Direction::Forward, 10, Some(1));
+        assert_eq!(data.len(), 1);
+        assert_eq!(next, Some(11));
+        let (data, _) =
+            list.get_transactions_for_contract_cursor(None, Direction::Forward, 10, Some(size));
+        assert_eq!(data.len(), 3);
+
+        let (data, next) =
+            list.get_transactions_for_token_cursor(&0.into(), None, Direction::Forward, 10, None);
+        assert!(data.is_empty() && next.is_none());
+    }
+
+    #[test]
+    fn test_transactions_in_range() {
+        let mut list = TransactionList::new(mock_principals::xtc(), 10);
+
+        // Two events for each ms, inserted out of order.
+        for i in (0..200).rev() {
+            list.insert(e(i / 2, mock_principals::alice()));
+        }
+
+        let mut times = vec![];
+        let mut cursor = None;
+
+        loop {
+            let (data, next) = list.get_transactions_in_range(20, 79, cursor);
+            let witness = list.witness_transactions_in_range(20, 79, cursor);
+            assert_eq!(witness.reconstruct(), list.root_hash());
+
+            let leaves = witness.get_leaf_values();
+            for event in &data {
+                assert!(leaves.contains(&&event.hash()[..]));
+            }
+
+            times.extend(data.iter().map(|e| e.time));
+
+            match next {
+                Some(id) => {
+                    let event = list.get_transaction(id).unwrap();
+                    assert!(event.time <= 79);
+                    cursor = Some(id);
+                }
+                None => break,
+            }
+        }
+
+        assert_eq!(times.len(), 120);
+        assert!(times.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(times[0], 20);
+        assert_eq!(times[119], 79);
+
+        // An empty range, and a cursor before the range.
+        let (data, next) = list.get_transactions_in_range(500, 600, None);
+        assert!(data.is_empty() && next.is_none());
+        let witness = list.witness_transactions_in_range(500, 600, None);
+        assert_eq!(witness.reconstruct(), list.root_hash());
+        let (data, _) = list.get_transactions_in_range(20, 79, Some(10));
+        assert!(data.is_empty());
+    }
+
     #[test]
     fn serde() {
         let mut list = TransactionList::new(mock_principals::xtc(), 0);
@@ -508,17 +2464,25 @@ mod tests {
         assert_eq!(list.root_hash(), expected);
     }
 
+    /// A list in the stable memory only serializes the handles of its structures.
     #[test]
-    fn candid() {
+    fn serde_stable() {
+        ic_kit::MockContext::new().inject();
         let mut list = TransactionList::new(mock_principals::xtc(), 0);
         list.insert(e(0, mock_principals::alice()));
-        list.insert(e(1, mock_principals::alice()));
-        list.insert(e(2, mock_principals::alice()));
-        list.insert(e(3, mock_principals::alice()));
+        list.set_stable();
+        for i in 1..1_000 {
+            list.insert(e(i, mock_principals::alice()));
+        }
+        assert!(list.is_stable());
         let expected = list.root_hash();
 
-        let encoded = encode_one(&list).unwrap();
-        let decoded: TransactionList = decode_one(&encoded).unwrap();
-        assert_eq!(decoded.root_hash(), expected);
+        let data: Vec<u8> = serde_cbor::to_vec(&list).unwrap();
+        assert!(data.len() < 1_000);
+        let mut list: TransactionList = serde_cbor::from_slice(&data).unwrap();
+        assert_eq!(list.root_hash(), expected);
+        assert_eq!(list.get_transaction(500).unwrap().time, 500);
+        list.insert(e(1_000, mock_principals::alice()));
+        assert_eq!(list.count_for_user(&mock_principals::alice()), 1_001);
     }
 }
@@ -0,0 +1,1604 @@
+//! Certified maps in a [`Store`]. The [`Tree`] is a port of the left-leaning red-black tree of
+//! `certified_vars`, where the nodes are blocks of the store, so it has the same shape and
+//! produces the same hash trees as a `RbTree` after the same operations.
+//!
+//! Each node holds the offset of its entry, which is the label of the key followed by the
+//! value, along with the hash of the entry and of its subtree.
+
+use crate::store::{Array, Store, Stored};
+use certified_vars::hashtree::{fork, fork_hash, labeled_hash, leaf_hash};
+use certified_vars::label::Label;
+use certified_vars::{AsHashTree, Hash, HashTree};
+use ic_kit::Principal;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cmp::Ordering::{self, Equal, Greater, Less};
+use std::convert::TryInto;
+use std::marker::PhantomData;
+use HashTree::{Empty, Pruned};
+
+/// The order of the keys of a [`Tree`] given their label, which must be the [`Ord`] of the
+/// key type so a tree has the same shape as an `RbTree` with the same keys.
+pub trait Key {
+    fn cmp_labels(a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+impl Key for u32 {}
+
+impl Key for String {}
+
+impl Key for Vec<u8> {}
+
+impl Key for [u8; 12] {}
+
+impl Key for Principal {
+    /// Principals are ordered by their length first.
+    #[inline]
+    fn cmp_labels(a: &[u8], b: &[u8]) -> Ordering {
+        a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+    }
+}
+
+/// A value of a [`Tree`].
+pub trait Value: Stored {
+    fn root_hash(&self, store: &Store) -> Hash;
+
+    fn as_hash_tree(&self, store: &Store) -> HashTree<'static>;
+
+    /// Free the blocks owned by the value.
+    #[inline]
+    fn free(&self, _store: &mut Store) {}
+
+    /// Free the blocks owned by the value in steps, at most about `budget` blocks are freed
+    /// and their number is subtracted from it. Return `true` once all of them are freed.
+    #[inline]
+    fn free_some(&mut self, store: &mut Store, budget: &mut usize) -> bool {
+        self.free(store);
+        *budget = budget.saturating_sub(1);
+        true
+    }
+}
+
+impl Value for Hash {
+    #[inline]
+    fn root_hash(&self, _store: &Store) -> Hash {
+        leaf_hash(self)
+    }
+
+    #[inline]
+    fn as_hash_tree(&self, _store: &Store) -> HashTree<'static> {
+        HashTree::Leaf(Cow::Owned(self.to_vec()))
+    }
+}
+
+impl Value for u64 {
+    #[inline]
+    fn root_hash(&self, _store: &Store) -> Hash {
+        leaf_hash(&self.to_be_bytes())
+    }
+
+    #[inline]
+    fn as_hash_tree(&self, _store: &Store) -> HashTree<'static> {
+        HashTree::Leaf(Cow::Owned(self.to_be_bytes().to_vec()))
+    }
+}
+
+/// The items of an array are not certified, only its length is.
+impl<T: Stored> Value for Array<T> {
+    #[inline]
+    fn root_hash(&self, _store: &Store) -> Hash {
+        leaf_hash(&self.len().to_be_bytes())
+    }
+
+    #[inline]
+    fn as_hash_tree(&self, store: &Store) -> HashTree<'static> {
+        Pruned(self.root_hash(store))
+    }
+
+    #[inline]
+    fn free(&self, store: &mut Store) {
+        Array::free(self, store)
+    }
+
+    #[inline]
+    fn free_some(&mut self, store: &mut Store, budget: &mut usize) -> bool {
+        Array::free_some(self, store, budget)
+    }
+}
+
+/// A certified map in a [`Store`], the keys are given by their label.
+#[derive(Serialize, Deserialize)]
+pub struct Tree<K, V> {
+    root: u64,
+    len: u64,
+    #[serde(skip)]
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K, V> Clone for Tree<K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V> Copy for Tree<K, V> {}
+
+impl<K, V> Default for Tree<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Tree<K, V> {
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            root: 0,
+            len: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.root == 0
+    }
+}
+
+impl<K: Key, V: Value> Tree<K, V> {
+    /// Insert an entry, returns the previous value of the key.
+    pub fn insert(&mut self, store: &mut Store, label: &[u8], value: V) -> Option<V> {
+        let (root, old) = insert(store, self.root, label, &value, K::cmp_labels);
+        set_red(store, root, false);
+        if old.is_none() {
+            self.len += 1;
+        }
+
+        self.root = root;
+        old
+    }
+
+    /// Return the value of the given key.
+    pub fn get(&self, store: &Store, label: &[u8]) -> Option<V> {
+        let mut h = self.root;
+        while h != 0 {
+            let n = Node::load(store, h);
+            match K::cmp_labels(label, &n.label(store)) {
+                Equal => return Some(n.value(store)),
+                Less => h = n.left,
+                Greater => h = n.right,
+            }
+        }
+        None
+    }
+
+    /// Update the value of the given key, returns `None` if the key is not in the tree.
+    pub fn modify<T>(
+        &mut self,
+        store: &mut Store,
+        label: &[u8],
+        f: impl FnOnce(&mut V, &mut Store) -> T,
+    ) -> Option<T> {
+        fn go<V: Value, T>(
+            store: &mut Store,
+            h: u64,
+            label: &[u8],
+            f: impl FnOnce(&mut V, &mut Store) -> T,
+            cmp: Cmp,
+        ) -> Option<T> {
+            if h == 0 {
+                return None;
+            }
+
+            let n = Node::load(store, h);
+            let res = match cmp(label, &n.label(store)) {
+                Equal => {
+                    let res = n.update_value(store, h, |_, value, store| f(value, store));
+                    Some(res)
+                }
+                Less => go(store, n.left, label, f, cmp),
+                Greater => go(store, n.right, label, f, cmp),
+            };
+
+            if res.is_some() {
+                update_hash(store, h);
+            }
+            res
+        }
+
+        go(store, self.root, label, f, K::cmp_labels)
+    }
+
+    /// Remove the given key, returns its value.
+    pub fn remove(&mut self, store: &mut Store, label: &[u8]) -> Option<V> {
+        self.get(store, label)?;
+
+        let root = Node::load(store, self.root);
+        if !is_red(store, root.left) && !is_red(store, root.right) {
+            set_red(store, self.root, true);
+        }
+
+        let mut result = None;
+        self.root = delete(store, self.root, label, &mut result, K::cmp_labels);
+        if self.root != 0 {
+            set_red(store, self.root, false);
+        }
+
+        self.len -= 1;
+        result
+    }
+
+    /// Return the hash of the tree.
+    #[inline]
+    pub fn root_hash(&self, store: &Store) -> Hash {
+        subtree_hash(store, self.root)
+    }
+
+    /// Return the full hash tree of the tree.
+    #[inline]
+    pub fn as_hash_tree(&self, store: &Store) -> HashTree<'static> {
+        full_witness_tree::<V>(store, self.root, Reveal::Data)
+    }
+
+    /// Return a proof of the value of the given key, or of its absence.
+    #[inline]
+    pub fn witness(&self, store: &Store, label: &[u8]) -> HashTree<'static> {
+        self.nested_witness(store, label, |value, store| value.as_hash_tree(store))
+    }
+
+    /// Like `witness`, but the witness of the value is built by the given function, which is
+    /// used for nested maps.
+    pub fn nested_witness(
+        &self,
+        store: &Store,
+        label: &[u8],
+        f: impl FnOnce(&V, &Store) -> HashTree<'static>,
+    ) -> HashTree<'static> {
+        if let Some(tree) = lookup_and_build_witness(store, self.root, label, f, K::cmp_labels) {
+            return tree;
+        }
+
+        self.range_witness(
+            store,
+            self.lower_bound(store, label),
+            self.upper_bound(store, label),
+            Reveal::Witness,
+        )
+    }
+
+    /// Return a witness of the entries in the given range.
+    #[inline]
+    pub fn value_range(&self, store: &Store, first: &[u8], last: &[u8]) -> HashTree<'static> {
+        self.range_witness(
+            store,
+            self.lower_bound(store, first),
+            self.upper_bound(store, last),
+            Reveal::Data,
+        )
+    }
+
+    /// Return an iterator over the entries starting at the first key which is not smaller
+    /// than the given one, or at the first key.
+    pub fn iter_from<'a>(&self, store: &'a Store, label: Option<&[u8]>) -> Iter<'a, V> {
+        let mut stack = Vec::new();
+        let mut h = self.root;
+        while h != 0 {
+            let n = Node::load(store, h);
+            match label {
+                Some(label) if K::cmp_labels(&n.label(store), label) == Less => h = n.right,
+                _ => {
+                    stack.push(h);
+                    h = n.left;
+                }
+            }
+        }
+
+        Iter {
+            store,
+            stack,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Free the blocks used by the tree and its values.
+    #[inline]
+    pub fn free(&self, store: &mut Store) {
+        free::<V>(store, self.root)
+    }
+
+    /// Free the blocks used by the tree and its values in steps, see [`Value::free_some`]. The
+    /// tree is only valid again once all of them are freed, it is empty then.
+    pub fn free_some(&mut self, store: &mut Store, budget: &mut usize) -> bool {
+        while *budget > 0 && self.root != 0 {
+            let mut n = Node::load(store, self.root);
+
+            // Rotate the left child up until the root has no left child, then free the root.
+            if n.left != 0 {
+                let left = n.left;
+                let mut l = Node::load(store, left);
+                n.left = l.right;
+                n.save(store, self.root);
+                l.right = self.root;
+                l.save(store, left);
+                self.root = left;
+                *budget -= 1;
+                continue;
+            }
+
+            let mut value = n.value::<V>(store);
+            if !value.free_some(store, budget) {
+                store.set(n.entry + n.label_len as u64, &value);
+                return false;
+            }
+
+            store.free(n.entry, n.label_len as usize + V::SIZE);
+            store.free(self.root, Node::SIZE);
+            self.root = n.right;
+            self.len -= 1;
+        }
+
+        self.root == 0
+    }
+
+    fn range_witness(
+        &self,
+        store: &Store,
+        left: Option<Bound>,
+        right: Option<Bound>,
+        f: Reveal,
+    ) -> HashTree<'static> {
+        let cmp = K::cmp_labels;
+        match (left, right) {
+            (None, None) => full_witness_tree::<V>(store, self.root, f),
+            (Some(l), None) => witness_range_above::<V>(store, self.root, &l, f, cmp),
+            (None, Some(r)) => witness_range_below::<V>(store, self.root, &r, f, cmp),
+            (Some(l), Some(r)) => witness_range_between::<V>(store, self.root, &l, &r, f, cmp),
+        }
+    }
+
+    fn lower_bound(&self, store: &Store, label: &[u8]) -> Option<Bound> {
+        fn go(store: &Store, h: u64, label: &[u8], cmp: Cmp) -> Option<Bound> {
+            if h == 0 {
+                return None;
+            }
+
+            let n = Node::load(store, h);
+            let node_label = n.label(store);
+            match cmp(&node_label, label) {
+                Less => go(store, n.right, label, cmp).or(Some(Bound::neighbor(node_label))),
+                Equal => Some(Bound::exact(node_label)),
+                Greater => go(store, n.left, label, cmp),
+            }
+        }
+
+        go(store, self.root, label, K::cmp_labels)
+    }
+
+    fn upper_bound(&self, store: &Store, label: &[u8]) -> Option<Bound> {
+        fn go(store: &Store, h: u64, label: &[u8], cmp: Cmp) -> Option<Bound> {
+            if h == 0 {
+                return None;
+            }
+
+            let n = Node::load(store, h);
+            let node_label = n.label(store);
+            match cmp(&node_label, label) {
+                Less => go(store, n.right, label, cmp),
+                Equal => Some(Bound::exact(node_label)),
+                Greater => go(store, n.left, label, cmp).or(Some(Bound::neighbor(node_label))),
+            }
+        }
+
+        go(store, self.root, label, K::cmp_labels)
+    }
+
+    /// Update the value of the largest key for which `prefix` returns `Equal`, the keys for
+    /// which it returns `Less` must be before them and the ones with `Greater` after them.
+    fn modify_max_with_prefix<T>(
+        &mut self,
+        store: &mut Store,
+        prefix: &impl Fn(&[u8]) -> Ordering,
+        f: impl FnOnce(&[u8], &mut V, &mut Store) -> T,
+    ) -> Option<T> {
+        fn go<V: Value, T, F: FnOnce(&[u8], &mut V, &mut Store) -> T>(
+            store: &mut Store,
+            h: u64,
+            prefix: &impl Fn(&[u8]) -> Ordering,
+            f: F,
+        ) -> (Option<T>, Option<F>) {
+            if h == 0 {
+                return (None, Some(f));
+            }
+
+            let n = Node::load(store, h);
+            let res = match prefix(&n.label(store)) {
+                Equal => match go(store, n.right, prefix, f) {
+                    (None, Some(f)) => (Some(n.update_value(store, h, f)), None),
+                    res => res,
+                },
+                Greater => go(store, n.left, prefix, f),
+                Less => go(store, n.right, prefix, f),
+            };
+
+            if res.0.is_some() {
+                update_hash(store, h);
+            }
+            res
+        }
+
+        go(store, self.root, prefix, f).0
+    }
+
+    /// Return the entry of the largest key for which `prefix` returns `Equal`, see
+    /// [`Tree::modify_max_with_prefix`].
+    fn max_entry_with_prefix(
+        &self,
+        store: &Store,
+        prefix: &impl Fn(&[u8]) -> Ordering,
+    ) -> Option<(Vec<u8>, V)> {
+        fn go<V: Value>(
+            store: &Store,
+            h: u64,
+            prefix: &impl Fn(&[u8]) -> Ordering,
+        ) -> Option<(Vec<u8>, V)> {
+            if h == 0 {
+                return None;
+            }
+
+            let n = Node::load(store, h);
+            let label = n.label(store);
+            match prefix(&label) {
+                Equal => go(store, n.right, prefix).or_else(|| Some((label, n.value(store)))),
+                Greater => go(store, n.left, prefix),
+                Less => go(store, n.right, prefix),
+            }
+        }
+
+        go(store, self.root, prefix)
+    }
+}
+
+impl<K, V> Stored for Tree<K, V> {
+    const SIZE: usize = 16;
+
+    #[inline]
+    fn store(&self, buf: &mut [u8]) {
+        self.root.store(&mut buf[..8]);
+        self.len.store(&mut buf[8..]);
+    }
+
+    #[inline]
+    fn load(buf: &[u8]) -> Self {
+        Self {
+            root: u64::load(&buf[..8]),
+            len: u64::load(&buf[8..]),
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// An iterator over the entries of a [`Tree`], along with the label of their key.
+pub struct Iter<'a, V> {
+    store: &'a Store,
+    /// The nodes whose entry and right subtree are left to visit.
+    stack: Vec<u64>,
+    _marker: PhantomData<V>,
+}
+
+impl<'a, V: Value> Iterator for Iter<'a, V> {
+    type Item = (Vec<u8>, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = Node::load(self.store, self.stack.pop()?);
+        let mut h = n.right;
+        while h != 0 {
+            self.stack.push(h);
+            h = Node::load(self.store, h).left;
+        }
+
+        Some((n.label(self.store), n.value(self.store)))
+    }
+}
+
+type Cmp = fn(&[u8], &[u8]) -> Ordering;
+
+/// The key of a page of a [`Paged`] index, which is the key of the index followed by the
+/// number of the page, in big endian.
+pub struct PagedKey<K>(PhantomData<K>);
+
+impl<K: Key> Key for PagedKey<K> {
+    #[inline]
+    fn cmp_labels(a: &[u8], b: &[u8]) -> Ordering {
+        let (a, a_page) = a.split_at(a.len() - 4);
+        let (b, b_page) = b.split_at(b.len() - 4);
+        K::cmp_labels(a, b).then_with(|| a_page.cmp(b_page))
+    }
+}
+
+impl<K> PagedKey<K> {
+    /// Return the label of the given page of a key.
+    #[inline]
+    pub fn label(key: &[u8], page: u32) -> Vec<u8> {
+        let mut label = Vec::with_capacity(key.len() + 4);
+        label.extend_from_slice(key);
+        label.extend_from_slice(&page.to_be_bytes());
+        label
+    }
+
+    /// Return the page number of the given label.
+    #[inline]
+    pub fn page(label: &[u8]) -> u32 {
+        u32::from_be_bytes(label[label.len() - 4..].try_into().unwrap())
+    }
+}
+
+/// An index which splits the items of each key in pages of `S` items, with the same hash tree
+/// as a `certified_vars::Paged`.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Paged<K, V, const S: usize> {
+    tree: Tree<PagedKey<K>, Page<V, S>>,
+}
+
+impl<K, V, const S: usize> Clone for Paged<K, V, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V, const S: usize> Copy for Paged<K, V, S> {}
+
+impl<K, V, const S: usize> Default for Paged<K, V, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, const S: usize> Paged<K, V, S> {
+    #[inline]
+    pub const fn new() -> Self {
+        Self { tree: Tree::new() }
+    }
+}
+
+impl<K: Key + Label, V: Stored + AsHashTree, const S: usize> Paged<K, V, S> {
+    /// Append an item to the last page of the key.
+    pub fn insert(&mut self, store: &mut Store, key: &K, item: V) {
+        let key = key.as_label();
+        let prefix = |label: &[u8]| K::cmp_labels(&label[..label.len() - 4], &key);
+        let mut item = Some(item);
+
+        let page = self
+            .tree
+            .modify_max_with_prefix(store, &prefix, |label, page, store| {
+                if page.len() == S {
+                    return Some(PagedKey::<K>::page(label) + 1);
+                }
+                page.append(store, item.take().unwrap());
+                None
+            })
+            .unwrap_or(Some(0));
+
+        if let Some(page_number) = page {
+            let mut page = Page::new();
+            page.append(store, item.take().unwrap());
+            self.tree
+                .insert(store, &PagedKey::<K>::label(&key, page_number), page);
+        }
+    }
+
+    /// Return the given page of the key.
+    #[inline]
+    pub fn get(&self, store: &Store, key: &K, page: usize) -> Option<Page<V, S>> {
+        self.tree
+            .get(store, &PagedKey::<K>::label(&key.as_label(), page as u32))
+    }
+
+    /// Return the number of the last page of the key.
+    pub fn get_last_page_number(&self, store: &Store, key: &K) -> Option<usize> {
+        let key = key.as_label();
+        let prefix = |label: &[u8]| K::cmp_labels(&label[..label.len() - 4], &key);
+        self.tree
+            .max_entry_with_prefix(store, &prefix)
+            .map(|(label, _)| PagedKey::<K>::page(&label) as usize)
+    }
+
+    /// Return a proof of the given page of the key.
+    #[inline]
+    pub fn witness(&self, store: &Store, key: &K, page: usize) -> HashTree<'static> {
+        self.tree
+            .witness(store, &PagedKey::<K>::label(&key.as_label(), page as u32))
+    }
+
+    #[inline]
+    pub fn root_hash(&self, store: &Store) -> Hash {
+        self.tree.root_hash(store)
+    }
+
+    #[inline]
+    pub fn as_hash_tree(&self, store: &Store) -> HashTree<'static> {
+        self.tree.as_hash_tree(store)
+    }
+
+    #[inline]
+    pub fn free(&self, store: &mut Store) {
+        self.tree.free(store)
+    }
+
+    /// Free the blocks used by the index in steps, see [`Tree::free_some`].
+    #[inline]
+    pub fn free_some(&mut self, store: &mut Store, budget: &mut usize) -> bool {
+        self.tree.free_some(store, budget)
+    }
+}
+
+impl<K, V, const S: usize> Stored for Paged<K, V, S> {
+    const SIZE: usize = 16;
+
+    #[inline]
+    fn store(&self, buf: &mut [u8]) {
+        self.tree.store(buf)
+    }
+
+    #[inline]
+    fn load(buf: &[u8]) -> Self {
+        Self {
+            tree: Tree::load(buf),
+        }
+    }
+}
+
+impl<K: Key + Label, V: Stored + AsHashTree, const S: usize> Value for Paged<K, V, S> {
+    #[inline]
+    fn root_hash(&self, store: &Store) -> Hash {
+        Paged::root_hash(self, store)
+    }
+
+    #[inline]
+    fn as_hash_tree(&self, store: &Store) -> HashTree<'static> {
+        Paged::as_hash_tree(self, store)
+    }
+
+    #[inline]
+    fn free(&self, store: &mut Store) {
+        Paged::free(self, store)
+    }
+
+    #[inline]
+    fn free_some(&mut self, store: &mut Store, budget: &mut usize) -> bool {
+        Paged::free_some(self, store, budget)
+    }
+}
+
+/// A page of a [`Paged`] index, its hash is the one of a `certified_vars::Seq` with the same
+/// items.
+pub struct Page<V, const S: usize> {
+    hash: Hash,
+    len: u32,
+    /// The offset of a block which can hold `S` items.
+    items: u64,
+    _marker: PhantomData<V>,
+}
+
+impl<V: Stored, const S: usize> Page<V, S> {
+    #[inline]
+    fn new() -> Self {
+        Self {
+            hash: [0; 32],
+            len: 0,
+            items: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Return the number of items in the page.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the item at the given position.
+    #[inline]
+    pub fn get(&self, store: &Store, position: usize) -> Option<V> {
+        if position < self.len() {
+            Some(store.get(self.items + (position * V::SIZE) as u64))
+        } else {
+            None
+        }
+    }
+
+    /// Return the items of the page.
+    pub fn to_vec(&self, store: &Store) -> Vec<V> {
+        let mut buf = vec![0; self.len() * V::SIZE];
+        if self.len > 0 {
+            store.read(self.items, &mut buf);
+        }
+        buf.chunks(V::SIZE).map(V::load).collect()
+    }
+
+    fn append(&mut self, store: &mut Store, item: V)
+    where
+        V: AsHashTree,
+    {
+        if self.items == 0 {
+            self.items = store.alloc(S * V::SIZE);
+        }
+
+        let mut h = Sha256::new();
+        h.update(self.hash);
+        h.update(item.root_hash());
+        self.hash = h.finalize().into();
+
+        store.set(self.items + (self.len() * V::SIZE) as u64, &item);
+        self.len += 1;
+    }
+}
+
+impl<V, const S: usize> Stored for Page<V, S> {
+    const SIZE: usize = 44;
+
+    #[inline]
+    fn store(&self, buf: &mut [u8]) {
+        buf[..32].copy_from_slice(&self.hash);
+        self.len.store(&mut buf[32..36]);
+        self.items.store(&mut buf[36..]);
+    }
+
+    #[inline]
+    fn load(buf: &[u8]) -> Self {
+        Self {
+            hash: Hash::load(&buf[..32]),
+            len: u32::load(&buf[32..36]),
+            items: u64::load(&buf[36..]),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<V: Stored, const S: usize> Value for Page<V, S> {
+    #[inline]
+    fn root_hash(&self, _store: &Store) -> Hash {
+        self.hash
+    }
+
+    #[inline]
+    fn as_hash_tree(&self, _store: &Store) -> HashTree<'static> {
+        Pruned(self.hash)
+    }
+
+    #[inline]
+    fn free(&self, store: &mut Store) {
+        if self.items != 0 {
+            store.free(self.items, S * V::SIZE);
+        }
+    }
+}
+
+/// A node of a [`Tree`], `0` is the null node.
+#[derive(Clone, Copy)]
+struct Node {
+    left: u64,
+    right: u64,
+    /// The offset of the label of the key, followed by the value.
+    entry: u64,
+    label_len: u32,
+    red: bool,
+    /// The labeled hash of the value.
+    data_hash: Hash,
+    /// The hash of the full hash tree of this node and its children.
+    subtree_hash: Hash,
+}
+
+impl Stored for Node {
+    const SIZE: usize = 93;
+
+    fn store(&self, buf: &mut [u8]) {
+        self.left.store(&mut buf[..8]);
+        self.right.store(&mut buf[8..16]);
+        self.entry.store(&mut buf[16..24]);
+        self.label_len.store(&mut buf[24..28]);
+        buf[28] = self.red as u8;
+        buf[29..61].copy_from_slice(&self.data_hash);
+        buf[61..93].copy_from_slice(&self.subtree_hash);
+    }
+
+    fn load(buf: &[u8]) -> Self {
+        Self {
+            left: u64::load(&buf[..8]),
+            right: u64::load(&buf[8..16]),
+            entry: u64::load(&buf[16..24]),
+            label_len: u32::load(&buf[24..28]),
+            red: buf[28] == 1,
+            data_hash: Hash::load(&buf[29..61]),
+            subtree_hash: Hash::load(&buf[61..93]),
+        }
+    }
+}
+
+impl Node {
+    /// Allocate a red node holding the given entry.
+    fn alloc<V: Value>(store: &mut Store, label: &[u8], value: &V) -> u64 {
+        let entry = store.alloc(label.len() + V::SIZE);
+        store.write(entry, label);
+        store.set(entry + label.len() as u64, value);
+
+        let data_hash = labeled_hash(label, &value.root_hash(store));
+        let ptr = store.alloc(Self::SIZE);
+        store.set(
+            ptr,
+            &Node {
+                left: 0,
+                right: 0,
+                entry,
+                label_len: label.len() as u32,
+                red: true,
+                data_hash,
+                subtree_hash: data_hash,
+            },
+        );
+        ptr
+    }
+
+    #[inline]
+    fn load(store: &Store, ptr: u64) -> Self {
+        store.get(ptr)
+    }
+
+    #[inline]
+    fn save(&self, store: &mut Store, ptr: u64) {
+        store.set(ptr, self)
+    }
+
+    #[inline]
+    fn label(&self, store: &Store) -> Vec<u8> {
+        let mut label = vec![0; self.label_len as usize];
+        store.read(self.entry, &mut label);
+        label
+    }
+
+    #[inline]
+    fn value<V: Stored>(&self, store: &Store) -> V {
+        store.get(self.entry + self.label_len as u64)
+    }
+
+    /// Update the value of the node at the given offset along with its data hash, the hash
+    /// of the subtree is left to the caller.
+    fn update_value<V: Value, T>(
+        &self,
+        store: &mut Store,
+        ptr: u64,
+        f: impl FnOnce(&[u8], &mut V, &mut Store) -> T,
+    ) -> T {
+        let label = self.label(store);
+        let mut value = self.value(store);
+        let res = f(&label, &mut value, store);
+        store.set(self.entry + self.label_len as u64, &value);
+
+        let mut n = Node::load(store, ptr);
+        n.data_hash = labeled_hash(&label, &value.root_hash(store));
+        n.save(store, ptr);
+        res
+    }
+
+    fn data_tree<V: Value>(&self, store: &Store) -> HashTree<'static> {
+        HashTree::Labeled(
+            Cow::Owned(self.label(store)),
+            Box::new(self.value::<V>(store).as_hash_tree(store)),
+        )
+    }
+
+    fn witness_tree<V: Value>(&self, store: &Store) -> HashTree<'static> {
+        HashTree::Labeled(
+            Cow::Owned(self.label(store)),
+            Box::new(Pruned(self.value::<V>(store).root_hash(store))),
+        )
+    }
+
+    #[inline]
+    fn reveal<V: Value>(&self, store: &Store, f: Reveal) -> HashTree<'static> {
+        match f {
+            Reveal::Data => self.data_tree::<V>(store),
+            Reveal::Witness => self.witness_tree::<V>(store),
+        }
+    }
+
+    #[inline]
+    fn left_hash_tree(&self, store: &Store) -> HashTree<'static> {
+        child_hash_tree(store, self.left)
+    }
+
+    #[inline]
+    fn right_hash_tree(&self, store: &Store) -> HashTree<'static> {
+        child_hash_tree(store, self.right)
+    }
+
+    /// Compute the hash of the subtree of this node.
+    fn compute_subtree_hash(&self, store: &Store) -> Hash {
+        let h = self.data_hash;
+        match (self.left == 0, self.right == 0) {
+            (true, true) => h,
+            (false, true) => fork_hash(&subtree_hash(store, self.left), &h),
+            (true, false) => fork_hash(&h, &subtree_hash(store, self.right)),
+            (false, false) => fork_hash(
+                &subtree_hash(store, self.left),
+                &fork_hash(&h, &subtree_hash(store, self.right)),
+            ),
+        }
+    }
+}
+
+/// How the entries in the range of a witness are revealed.
+#[derive(Clone, Copy)]
+enum Reveal {
+    /// The label and the value.
+    Data,
+    /// Only the label.
+    Witness,
+}
+
+/// The bound of a range witness, `exact` is `true` if the key is the one that was looked up.
+struct Bound {
+    label: Vec<u8>,
+    exact: bool,
+}
+
+impl Bound {
+    #[inline]
+    fn exact(label: Vec<u8>) -> Self {
+        Self { label, exact: true }
+    }
+
+    #[inline]
+    fn neighbor(label: Vec<u8>) -> Self {
+        Self {
+            label,
+            exact: false,
+        }
+    }
+}
+
+#[inline]
+fn child_hash_tree(store: &Store, h: u64) -> HashTree<'static> {
+    if h == 0 {
+        Empty
+    } else {
+        Pruned(subtree_hash(store, h))
+    }
+}
+
+#[inline]
+fn subtree_hash(store: &Store, h: u64) -> Hash {
+    if h == 0 {
+        Empty.reconstruct()
+    } else {
+        Node::load(store, h).subtree_hash
+    }
+}
+
+#[inline]
+fn update_hash(store: &mut Store, h: u64) {
+    let mut n = Node::load(store, h);
+    n.subtree_hash = n.compute_subtree_hash(store);
+    n.save(store, h);
+}
+
+#[inline]
+fn is_red(store: &Store, h: u64) -> bool {
+    h != 0 && Node::load(store, h).red
+}
+
+/// Returns `true` if the left child of the given node is red.
+#[inline]
+fn is_left_red(store: &Store, h: u64) -> bool {
+    h != 0 && is_red(store, Node::load(store, h).left)
+}
+
+#[inline]
+fn set_red(store: &mut Store, h: u64, red: bool) {
+    let mut n = Node::load(store, h);
+    n.red = red;
+    n.save(store, h);
+}
+
+fn insert<V: Value>(
+    store: &mut Store,
+    h: u64,
+    label: &[u8],
+    value: &V,
+    cmp: Cmp,
+) -> (u64, Option<V>) {
+    if h == 0 {
+        return (Node::alloc(store, label, value), None);
+    }
+
+    let n = Node::load(store, h);
+    let old = match cmp(label, &n.label(store)) {
+        Equal => {
+            let old = n.update_value(store, h, |_, old: &mut V, _| {
+                std::mem::replace(old, V::load(&stored(value)))
+            });
+            update_hash(store, h);
+            Some(old)
+        }
+        Less => {
+            let (left, old) = insert(store, n.left, label, value, cmp);
+            let mut n = Node::load(store, h);
+            n.left = left;
+            n.save(store, h);
+            update_hash(store, h);
+            old
+        }
+        Greater => {
+            let (right, old) = insert(store, n.right, label, value, cmp);
+            let mut n = Node::load(store, h);
+            n.right = right;
+            n.save(store, h);
+            update_hash(store, h);
+            old
+        }
+    };
+
+    (balance(store, h), old)
+}
+
+/// Return the bytes of a value.
+#[inline]
+fn stored<V: Stored>(value: &V) -> Vec<u8> {
+    let mut buf = vec![0; V::SIZE];
+    value.store(&mut buf);
+    buf
+}
+
+fn delete<V: Value>(
+    store: &mut Store,
+    mut h: u64,
+    label: &[u8],
+    result: &mut Option<V>,
+    cmp: Cmp,
+) -> u64 {
+    let n = Node::load(store, h);
+    if cmp(label, &n.label(store)) == Less {
+        if !is_red(store, n.left) && !is_left_red(store, n.left) {
+            h = move_red_left(store, h);
+        }
+        let left = delete(store, Node::load(store, h).left, label, result, cmp);
+        let mut n = Node::load(store, h);
+        n.left = left;
+        n.save(store, h);
+    } else {
+        if is_red(store, n.left) {
+            h = rotate_right(store, h);
+        }
+        let n = Node::load(store, h);
+        if cmp(label, &n.label(store)) == Equal && n.right == 0 {
+            *result = Some(take(store, h));
+            return 0;
+        }
+
+        if !is_red(store, n.right) && !is_left_red(store, n.right) {
+            h = move_red_right(store, h);
+        }
+
+        let mut n = Node::load(store, h);
+        if cmp(label, &n.label(store)) == Equal {
+            let m = min(store, n.right);
+            let mut mn = Node::load(store, m);
+            std::mem::swap(&mut n.entry, &mut mn.entry);
+            std::mem::swap(&mut n.label_len, &mut mn.label_len);
+            std::mem::swap(&mut n.data_hash, &mut mn.data_hash);
+            n.save(store, h);
+            mn.save(store, m);
+
+            let right = delete_min(store, n.right, result);
+            let mut n = Node::load(store, h);
+            n.right = right;
+            n.save(store, h);
+        } else {
+            let right = delete(store, n.right, label, result, cmp);
+            let mut n = Node::load(store, h);
+            n.right = right;
+            n.save(store, h);
+        }
+    }
+
+    update_hash(store, h);
+    balance(store, h)
+}
+
+fn delete_min<V: Value>(store: &mut Store, mut h: u64, result: &mut Option<V>) -> u64 {
+    let n = Node::load(store, h);
+    if n.left == 0 {
+        *result = Some(take(store, h));
+        return 0;
+    }
+
+    if !is_red(store, n.left) && !is_left_red(store, n.left) {
+        h = move_red_left(store, h);
+    }
+
+    let left = delete_min(store, Node::load(store, h).left, result);
+    let mut n = Node::load(store, h);
+    n.left = left;
+    n.save(store, h);
+    update_hash(store, h);
+    balance(store, h)
+}
+
+/// Free a node which was removed from the tree and return its value.
+fn take<V: Value>(store: &mut Store, h: u64) -> V {
+    let n = Node::load(store, h);
+    let value = n.value(store);
+    store.free(n.entry, n.label_len as usize + V::SIZE);
+    store.free(h, Node::SIZE);
+    value
+}
+
+#[inline]
+fn min(store: &Store, mut h: u64) -> u64 {
+    loop {
+        let left = Node::load(store, h).left;
+        if left == 0 {
+            return h;
+        }
+        h = left;
+    }
+}
+
+fn move_red_left(store: &mut Store, mut h: u64) -> u64 {
+    flip_colors(store, h);
+    let n = Node::load(store, h);
+    if is_left_red(store, n.right) {
+        let right = rotate_right(store, n.right);
+        let mut n = Node::load(store, h);
+        n.right = right;
+        n.save(store, h);
+        h = rotate_left(store, h);
+        flip_colors(store, h);
+    }
+    h
+}
+
+fn move_red_right(store: &mut Store, mut h: u64) -> u64 {
+    flip_colors(store, h);
+    if is_left_red(store, Node::load(store, h).left) {
+        h = rotate_right(store, h);
+        flip_colors(store, h);
+    }
+    h
+}
+
+fn balance(store: &mut Store, mut h: u64) -> u64 {
+    let n = Node::load(store, h);
+    if is_red(store, n.right) && !is_red(store, n.left) {
+        h = rotate_left(store, h);
+    }
+
+    let n = Node::load(store, h);
+    if is_red(store, n.left) && is_left_red(store, n.left) {
+        h = rotate_right(store, h);
+    }
+
+    let n = Node::load(store, h);
+    if is_red(store, n.left) && is_red(store, n.right) {
+        flip_colors(store, h);
+    }
+    h
+}
+
+/// Make a left-leaning link lean to the right.
+fn rotate_right(store: &mut Store, h: u64) -> u64 {
+    let mut hn = Node::load(store, h);
+    let x = hn.left;
+    let mut xn = Node::load(store, x);
+    hn.left = xn.right;
+    xn.right = h;
+    xn.red = hn.red;
+    hn.red = true;
+
+    hn.subtree_hash = hn.compute_subtree_hash(store);
+    hn.save(store, h);
+    xn.subtree_hash = xn.compute_subtree_hash(store);
+    xn.save(store, x);
+    x
+}
+
+fn rotate_left(store: &mut Store, h: u64) -> u64 {
+    let mut hn = Node::load(store, h);
+    let x = hn.right;
+    let mut xn = Node::load(store, x);
+    hn.right = xn.left;
+    xn.left = h;
+    xn.red = hn.red;
+    hn.red = true;
+
+    hn.subtree_hash = hn.compute_subtree_hash(store);
+    hn.save(store, h);
+    xn.subtree_hash = xn.compute_subtree_hash(store);
+    xn.save(store, x);
+    x
+}
+
+fn flip_colors(store: &mut Store, h: u64) {
+    let n = Node::load(store, h);
+    for ptr in [h, n.left, n.right] {
+        if ptr != 0 {
+            let red = is_red(store, ptr);
+            set_red(store, ptr, !red);
+        }
+    }
+}
+
+fn free<V: Value>(store: &mut Store, h: u64) {
+    if h == 0 {
+        return;
+    }
+
+    let n = Node::load(store, h);
+    free::<V>(store, n.left);
+    free::<V>(store, n.right);
+    n.value::<V>(store).free(store);
+    store.free(n.entry, n.label_len as usize + V::SIZE);
+    store.free(h, Node::SIZE);
+}
+
+fn three_way_fork(
+    l: HashTree<'static>,
+    m: HashTree<'static>,
+    r: HashTree<'static>,
+) -> HashTree<'static> {
+    match (l, m, r) {
+        (Empty, m, Empty) => m,
+        (l, m, Empty) => fork(l, m),
+        (Empty, m, r) => fork(m, r),
+        (Pruned(lhash), Pruned(mhash), Pruned(rhash)) => {
+            Pruned(fork_hash(&lhash, &fork_hash(&mhash, &rhash)))
+        }
+        (l, Pruned(mhash), Pruned(rhash)) => fork(l, Pruned(fork_hash(&mhash, &rhash))),
+        (l, m, r) => fork(l, fork(m, r)),
+    }
+}
+
+fn full_witness_tree<V: Value>(store: &Store, h: u64, f: Reveal) -> HashTree<'static> {
+    if h == 0 {
+        return Empty;
+    }
+
+    let n = Node::load(store, h);
+    three_way_fork(
+        full_witness_tree::<V>(store, n.left, f),
+        n.reveal::<V>(store, f),
+        full_witness_tree::<V>(store, n.right, f),
+    )
+}
+
+fn lookup_and_build_witness<V: Value>(
+    store: &Store,
+    h: u64,
+    label: &[u8],
+    f: impl FnOnce(&V, &Store) -> HashTree<'static>,
+    cmp: Cmp,
+) -> Option<HashTree<'static>> {
+    if h == 0 {
+        return None;
+    }
+
+    let n = Node::load(store, h);
+    let node_label = n.label(store);
+    match cmp(label, &node_label) {
+        Equal => Some(three_way_fork(
+            n.left_hash_tree(store),
+            HashTree::Labeled(
+                Cow::Owned(node_label),
+                Box::new(f(&n.value(store), store)),
+            ),
+            n.right_hash_tree(store),
+        )),
+        Less => {
+            let subtree = lookup_and_build_witness(store, n.left, label, f, cmp)?;
+            Some(three_way_fork(
+                subtree,
+                Pruned(n.data_hash),
+                n.right_hash_tree(store),
+            ))
+        }
+        Greater => {
+            let subtree = lookup_and_build_witness(store, n.right, label, f, cmp)?;
+            Some(three_way_fork(
+                n.left_hash_tree(store),
+                Pruned(n.data_hash),
+                subtree,
+            ))
+        }
+    }
+}
+
+/// Reveal the entry of a node which is one of the bounds of a range.
+#[inline]
+fn reveal_bound<V: Value>(store: &Store, n: &Node, bound: &Bound, f: Reveal) -> HashTree<'static> {
+    if bound.exact {
+        n.reveal::<V>(store, f)
+    } else {
+        n.witness_tree::<V>(store)
+    }
+}
+
+fn witness_range_above<V: Value>(
+    store: &Store,
+    h: u64,
+    lo: &Bound,
+    f: Reveal,
+    cmp: Cmp,
+) -> HashTree<'static> {
+    if h == 0 {
+        return Empty;
+    }
+
+    let n = Node::load(store, h);
+    match cmp(&n.label(store), &lo.label) {
+        Equal => three_way_fork(
+            n.left_hash_tree(store),
+            reveal_bound::<V>(store, &n, lo, f),
+            full_witness_tree::<V>(store, n.right, f),
+        ),
+        Less => three_way_fork(
+            n.left_hash_tree(store),
+            Pruned(n.data_hash),
+            witness_range_above::<V>(store, n.right, lo, f, cmp),
+        ),
+        Greater => three_way_fork(
+            witness_range_above::<V>(store, n.left, lo, f, cmp),
+            n.reveal::<V>(store, f),
+            full_witness_tree::<V>(store, n.right, f),
+        ),
+    }
+}
+
+fn witness_range_below<V: Value>(
+    store: &Store,
+    h: u64,
+    hi: &Bound,
+    f: Reveal,
+    cmp: Cmp,
+) -> HashTree<'static> {
+    if h == 0 {
+        return Empty;
+    }
+
+    let n = Node::load(store, h);
+    match cmp(&n.label(store), &hi.label) {
+        Equal => three_way_fork(
+            full_witness_tree::<V>(store, n.left, f),
+            reveal_bound::<V>(store, &n, hi, f),
+            n.right_hash_tree(store),
+        ),
+        Greater => three_way_fork(
+            witness_range_below::<V>(store, n.left, hi, f, cmp),
+            Pruned(n.data_hash),
+            n.right_hash_tree(store),
+        ),
+        Less => three_way_fork(
+            full_witness_tree::<V>(store, n.left, f),
+            n.reveal::<V>(store, f),
+            witness_range_below::<V>(store, n.right, hi, f, cmp),
+        ),
+    }
+}
+
+fn witness_range_between<V: Value>(
+    store: &Store,
+    h: u64,
+    lo: &Bound,
+    hi: &Bound,
+    f: Reveal,
+    cmp: Cmp,
+) -> HashTree<'static> {
+    if h == 0 {
+        return Empty;
+    }
+
+    let n = Node::load(store, h);
+    let k = n.label(store);
+    match (cmp(&lo.label, &k), cmp(&k, &hi.label)) {
+        (Less, Less) => {
+            let left = witness_range_between::<V>(store, n.left, lo, hi, f, cmp);
+            let right = witness_range_between::<V>(store, n.right, lo, hi, f, cmp);
+            three_way_fork(left, n.reveal::<V>(store, f), right)
+        }
+        (Equal, Equal) => three_way_fork(
+            n.left_hash_tree(store),
+            if lo.exact || hi.exact {
+                n.reveal::<V>(store, f)
+            } else {
+                n.witness_tree::<V>(store)
+            },
+            n.right_hash_tree(store),
+        ),
+        (_, Equal) => three_way_fork(
+            witness_range_between::<V>(store, n.left, lo, hi, f, cmp),
+            reveal_bound::<V>(store, &n, hi, f),
+            n.right_hash_tree(store),
+        ),
+        (Equal, _) => three_way_fork(
+            n.left_hash_tree(store),
+            reveal_bound::<V>(store, &n, lo, f),
+            witness_range_between::<V>(store, n.right, lo, hi, f, cmp),
+        ),
+        (Less, Greater) => three_way_fork(
+            witness_range_between::<V>(store, n.left, lo, hi, f, cmp),
+            Pruned(n.data_hash),
+            n.right_hash_tree(store),
+        ),
+        (Greater, Less) => three_way_fork(
+            n.left_hash_tree(store),
+            Pruned(n.data_hash),
+            witness_range_between::<V>(store, n.right, lo, hi, f, cmp),
+        ),
+        _ => Pruned(n.subtree_hash),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use certified_vars::rbtree::RbTree;
+    use ic_kit::mock_principals;
+
+    /// A deterministic sequence of pseudo random numbers.
+    fn numbers(n: usize) -> impl Iterator<Item = u32> {
+        let mut x = 0x2545_f491u32;
+        (0..n).map(move |_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x % 1000
+        })
+    }
+
+    #[test]
+    fn test_same_as_rbtree() {
+        let mut store = Store::new();
+        let mut tree = Tree::<u32, u64>::new();
+        let mut expected = RbTree::<u32, u64>::new();
+
+        for (i, key) in numbers(2000).enumerate() {
+            let label = key.to_be_bytes();
+            if i % 3 == 2 {
+                assert_eq!(tree.remove(&mut store, &label), expected.delete(&key).map(|e| e.1));
+            } else {
+                assert_eq!(
+                    tree.insert(&mut store, &label, i as u64),
+                    expected.insert(key, i as u64).0
+                );
+            }
+
+            assert_eq!(tree.len(), expected.len() as u64);
+            assert_eq!(tree.root_hash(&store), expected.root_hash());
+        }
+
+        assert_eq!(tree.as_hash_tree(&store), expected.as_hash_tree());
+        for key in numbers(300) {
+            let label = key.to_be_bytes();
+            assert_eq!(tree.get(&store, &label), expected.get(&key).copied());
+            assert_eq!(tree.witness(&store, &label), expected.witness(&key));
+            assert_eq!(
+                tree.value_range(&store, &label, &(key + 40).to_be_bytes()),
+                expected.value_range(&key, &(key + 40))
+            );
+        }
+
+        assert_eq!(
+            tree.modify(&mut store, &7u32.to_be_bytes(), |v, _| *v += 1),
+            expected.modify(&7, |v| *v += 1)
+        );
+        assert_eq!(tree.root_hash(&store), expected.root_hash());
+
+        let entries = tree
+            .iter_from(&store, Some(&500u32.to_be_bytes()))
+            .map(|(label, value)| (u32::from_be_bytes(label.try_into().unwrap()), value))
+            .collect::<Vec<_>>();
+        let mut all = vec![];
+        expected.for_each(|k, v| all.push((*k, *v)));
+        assert_eq!(entries, all.into_iter().filter(|(k, _)| *k >= 500).collect::<Vec<_>>());
+
+        // The blocks of the removed entries are reused.
+        let size = store.size();
+        for key in numbers(2000) {
+            tree.remove(&mut store, &key.to_be_bytes());
+        }
+        assert!(tree.is_empty());
+        for key in numbers(100) {
+            tree.insert(&mut store, &key.to_be_bytes(), 0);
+        }
+        assert_eq!(store.size(), size);
+    }
+
+    #[test]
+    fn test_same_as_paged() {
+        let mut store = Store::new();
+        let mut paged = Paged::<Principal, u64, 3>::new();
+        let mut expected = certified_vars::Paged::<Principal, u64, 3>::new();
+        let principals = [
+            mock_principals::alice(),
+            mock_principals::bob(),
+            Principal::management_canister(),
+            Principal::anonymous(),
+        ];
+
+        for (i, n) in numbers(200).enumerate() {
+            let principal = principals[n as usize % principals.len()];
+            paged.insert(&mut store, &principal, i as u64);
+            expected.insert(principal, i as u64);
+            assert_eq!(paged.root_hash(&store), expected.root_hash());
+        }
+
+        for principal in &principals {
+            let last = expected.get_last_page_number(principal);
+            assert_eq!(paged.get_last_page_number(&store, principal), last);
+            for page in 0..last.unwrap() + 2 {
+                assert_eq!(
+                    paged.get(&store, principal, page).map(|p| p.to_vec(&store)),
+                    expected.get(principal, page).map(|seq| seq.as_vec().clone())
+                );
+                assert_eq!(
+                    paged.witness(&store, principal, page),
+                    expected.witness(principal, page)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_same_as_nested_rbtree() {
+        let mut store = Store::new();
+        let mut tree = Tree::<Principal, Paged<Principal, u64, 3>>::new();
+        let mut expected = RbTree::<Principal, certified_vars::Paged<Principal, u64, 3>>::new();
+        let principals = [
+            mock_principals::alice(),
+            mock_principals::bob(),
+            mock_principals::xtc(),
+            Principal::management_canister(),
+            Principal::anonymous(),
+        ];
+
+        for (i, n) in numbers(300).enumerate() {
+            let outer = principals[n as usize % principals.len()];
+            let inner = principals[(n / 7) as usize % principals.len()];
+
+            if tree.get(&store, outer.as_slice()).is_none() {
+                tree.insert(&mut store, outer.as_slice(), Paged::new());
+                expected.insert(outer, certified_vars::Paged::new());
+            }
+            tree.modify(&mut store, outer.as_slice(), |paged, store| {
+                paged.insert(store, &inner, i as u64)
+            });
+            expected.modify(&outer, |paged| paged.insert(inner, i as u64));
+
+            assert_eq!(tree.root_hash(&store), expected.root_hash());
+        }
+
+        assert_eq!(tree.as_hash_tree(&store), expected.as_hash_tree());
+        for outer in &principals {
+            for inner in &principals {
+                assert_eq!(
+                    tree.nested_witness(&store, outer.as_slice(), |paged, store| {
+                        paged.witness(store, inner, 0)
+                    }),
+                    expected.nested_witness(outer, |paged| paged.witness(inner, 0))
+                );
+            }
+        }
+    }
+}
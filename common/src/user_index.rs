@@ -0,0 +1,187 @@
+//! The index of the root buckets of each user, which is sharded over the index canisters listed
+//! by the router, see [`index_canister_for`].
+//!
+//! When the list of index canisters changes, the router moves the users which now belong to
+//! another index canister: it exports a page of them from the canister which holds them, imports
+//! them in their new index canister and then removes them from the old one.
+
+use crate::did::{index_canister_for, ExportUsersResponse, IndexCanisterId, RootBucketId, UserId};
+use certified_vars::{Map, Seq};
+
+/// Map each user to the root buckets of the contracts they have transactions on.
+pub type UserCanisters = Map<UserId, Seq<RootBucketId>>;
+
+/// Add the root bucket to the ones of the user, returns `false` if it was already there.
+pub fn add_user_root_bucket(
+    users: &mut UserCanisters,
+    user: UserId,
+    root_bucket: RootBucketId,
+) -> bool {
+    let root_buckets = users.entry(user).or_insert(Seq::new());
+
+    if root_buckets.as_vec().contains(&root_bucket) {
+        return false;
+    }
+
+    root_buckets.append(root_bucket);
+    true
+}
+
+/// Return the users after the cursor which belong to another index canister than `this`. At
+/// most `limit` users are read, the next cursor is the last user read.
+pub fn export_users(
+    users: &UserCanisters,
+    this: &IndexCanisterId,
+    canisters: &[IndexCanisterId],
+    cursor: Option<UserId>,
+    limit: u32,
+) -> ExportUsersResponse {
+    let limit = limit.max(1) as usize;
+    let mut page = users
+        .iter()
+        .skip_while(|(user, _)| cursor.is_some_and(|cursor| **user <= cursor))
+        .take(limit + 1)
+        .collect::<Vec<_>>();
+
+    let next_cursor = match page.len() > limit {
+        true => {
+            page.pop();
+            page.last().map(|(user, _)| **user)
+        }
+        false => None,
+    };
+
+    let users = page
+        .into_iter()
+        .filter(|(user, _)| index_canister_for(canisters, user).is_some_and(|c| c != *this))
+        .map(|(user, root_buckets)| (*user, root_buckets.as_vec().clone()))
+        .collect();
+
+    ExportUsersResponse { users, next_cursor }
+}
+
+/// Add the root buckets of the users moved from another index canister, returns the number of
+/// root buckets which were not there already.
+pub fn import_users(users: &mut UserCanisters, moved: Vec<(UserId, Vec<RootBucketId>)>) -> u64 {
+    let mut added = 0;
+    for (user, root_buckets) in moved {
+        for root_bucket in root_buckets {
+            if add_user_root_bucket(users, user, root_bucket) {
+                added += 1;
+            }
+        }
+    }
+
+    added
+}
+
+/// Remove the users which were moved to another index canister, a user is kept if a root bucket
+/// was added to it since it was exported. Returns the number of removed users.
+pub fn remove_users(users: &mut UserCanisters, moved: &[(UserId, Vec<RootBucketId>)]) -> u64 {
+    let mut removed = 0;
+    for (user, root_buckets) in moved {
+        if users.get(user).map(|current| current.as_vec()) == Some(root_buckets) {
+            users.remove(user);
+            removed += 1;
+        }
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_kit::Principal;
+
+    fn principal(i: u32) -> Principal {
+        Principal::from_slice(&i.to_be_bytes())
+    }
+
+    #[test]
+    fn test_index_canister_for() {
+        let users = (0..1000).map(principal).collect::<Vec<_>>();
+        let canisters = (1..=3)
+            .map(|i| Principal::from_slice(&[0xff, i]))
+            .collect::<Vec<_>>();
+        let added = Principal::from_slice(&[0xff, 4]);
+        let more = [canisters.clone(), vec![added]].concat();
+
+        assert_eq!(index_canister_for(&[], &users[0]), None);
+
+        // Adding a canister only moves the users which go to it, removing it moves them back.
+        let mut moved = 0;
+        for user in &users {
+            let before = index_canister_for(&canisters, user).unwrap();
+            let after = index_canister_for(&more, user).unwrap();
+            if before != after {
+                assert_eq!(after, added);
+                moved += 1;
+            }
+        }
+        assert!((150..350).contains(&moved), "{}", moved);
+
+        // The order of the list doesn't matter.
+        let reversed = more.iter().rev().copied().collect::<Vec<_>>();
+        for user in &users {
+            assert_eq!(
+                index_canister_for(&more, user),
+                index_canister_for(&reversed, user)
+            );
+        }
+    }
+
+    #[test]
+    fn test_move_users() {
+        let (this, other) = (principal(1_000_000), principal(1_000_001));
+        let canisters = vec![this, other];
+        let (root, new_root) = (principal(2_000_000), principal(2_000_001));
+
+        let mut users = UserCanisters::new();
+        for i in 0..100 {
+            add_user_root_bucket(&mut users, principal(i), root);
+        }
+
+        // Read the users in pages.
+        let mut exported = vec![];
+        let mut cursor = None;
+        loop {
+            let page = export_users(&users, &this, &canisters, cursor, 30);
+            exported.extend(page.users);
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        let expected = users
+            .iter()
+            .filter(|(user, _)| index_canister_for(&canisters, user) == Some(other))
+            .map(|(user, _)| *user)
+            .collect::<Vec<_>>();
+        assert!(!expected.is_empty());
+        assert_eq!(
+            exported.iter().map(|(user, _)| *user).collect::<Vec<_>>(),
+            expected
+        );
+
+        let mut imported = UserCanisters::new();
+        assert_eq!(
+            import_users(&mut imported, exported.clone()),
+            expected.len() as u64
+        );
+        assert_eq!(import_users(&mut imported, exported.clone()), 0);
+
+        // A user who got a new root bucket since it was exported is kept.
+        add_user_root_bucket(&mut users, expected[0], new_root);
+        assert_eq!(
+            remove_users(&mut users, &exported),
+            expected.len() as u64 - 1
+        );
+        assert_eq!(users.len(), 100 - expected.len() + 1);
+        assert_eq!(
+            export_users(&users, &this, &canisters, None, 100).users,
+            vec![(expected[0], vec![root, new_root])]
+        );
+    }
+}
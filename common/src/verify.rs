@@ -0,0 +1,2042 @@
+//! Client-side verification of the witnesses returned by the Cap canisters.
+//!
+//! A [`Witness`] is checked in two steps: first [`verify_witness`] validates the certificate
+//! against the IC root key, extracts the `certified_data` of the canister and makes sure the
+//! decoded hash tree reconstructs to it. The trusted tree is then passed to one of the
+//! `verify_*` functions which check that it actually proves the data that was returned by
+//! the query.
+
+use crate::did::*;
+use crate::mmr::Tip;
+use crate::transaction::{DetailValue, Event, TokenId};
+use crate::transaction_list::{
+    count_key, detail_count_key, ACCOUNT_COUNT, CONTRACT_COUNT, OPERATION_COUNT, TOKEN_COUNT,
+    USER_COUNT, WRITER_COUNT,
+};
+use certified_vars::hashtree::{fork, leaf_hash};
+use certified_vars::label::Label;
+use certified_vars::{Hash, HashTree};
+use ic_kit::Principal;
+use serde::Deserialize;
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use thiserror::Error;
+
+/// The DER encoded public key of the IC main net.
+pub const IC_ROOT_KEY: &[u8; 133] = b"\x30\x81\x82\x30\x1d\x06\x0d\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x01\x02\x01\x06\x0c\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x02\x01\x03\x61\x00\x81\x4c\x0e\x6e\xc7\x1f\xab\x58\x3b\x08\xbd\x81\x37\x3c\x25\x5c\x3c\x37\x1b\x2e\x84\x86\x3c\x98\xa4\xf1\xe0\x8b\x74\x23\x5d\x14\xfb\x5d\x9c\x0c\xd5\x46\xd9\x68\x5f\x91\x3a\x0c\x0b\x2c\xc5\x34\x15\x83\xbf\x4b\x43\x92\xe4\x67\xdb\x96\xd6\x5b\x9b\xb4\xcb\x71\x71\x12\xf8\x47\x2e\x0d\x5a\x4d\x14\x50\x5f\xfd\x74\x84\xb0\x12\x91\x09\x1c\x5f\x87\xb9\x88\x83\x46\x3f\x98\x09\x1a\x0b\xaa\xae";
+
+/// The DER prefix of a BLS12-381 public key as used by the IC.
+const DER_PREFIX: &[u8; 37] = b"\x30\x81\x82\x30\x1d\x06\x0d\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x01\x02\x01\x06\x0c\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x02\x01\x03\x61\x00";
+
+/// An error returned when a witness does not prove a response.
+#[derive(Error, Debug, PartialEq)]
+pub enum VerifyError {
+    /// The response was requested with a witness but none was returned.
+    #[error("the response does not contain a witness")]
+    MissingWitness,
+    /// The certificate could not be decoded.
+    #[error("malformed certificate: {0}")]
+    MalformedCertificate(String),
+    /// The hash tree could not be decoded.
+    #[error("malformed hash tree: {0}")]
+    MalformedTree(String),
+    /// The root key or the public key of a subnet is not a valid DER encoded BLS key.
+    #[error("invalid public key")]
+    InvalidPublicKey,
+    /// The signature on the certificate does not match its tree.
+    #[error("invalid certificate signature")]
+    InvalidSignature,
+    /// The certificate is signed by a subnet which is not authorized for the canister.
+    #[error("the subnet delegation is not valid for the canister")]
+    InvalidDelegation,
+    /// The certificate does not contain any certified data for the canister.
+    #[error("the certificate does not contain the certified data of the canister")]
+    MissingCertifiedData,
+    /// The hash tree does not reconstruct to the certified data.
+    #[error("the hash tree does not match the certified data")]
+    CertifiedDataMismatch,
+    /// A part of the tree required to check the response is pruned.
+    #[error("the witness does not reveal enough of the tree")]
+    IncompleteWitness,
+    /// The tree proves a different value than the one returned.
+    #[error("the witness does not prove the returned data")]
+    DataMismatch,
+    /// A delegated answer was returned, which should be verified on the other bucket.
+    #[error("delegated responses can not be verified")]
+    Delegated,
+    /// The response is for another page than the requested one.
+    #[error("the response is not for the requested page")]
+    PageMismatch,
+}
+
+#[derive(Clone, Copy)]
+enum Side {
+    Left,
+    Right,
+}
+
+use Side::*;
+
+// Positions of the data certified by a bucket, see the layout of `Bucket` and `TransactionList`.
+const EVENT_HASHES: &[Side] = &[Left, Left, Left, Left];
+const GLOBAL_OFFSET: &[Side] = &[Left, Left, Left, Right];
+const USER_INDEXER: &[Side] = &[Left, Left, Right, Left];
+const CONTRACT_INDEXER: &[Side] = &[Left, Left, Right, Right, Left];
+const TOKEN_INDEXER: &[Side] = &[Left, Left, Right, Right, Right, Left];
+const TIME_INDEXER: &[Side] = &[Left, Left, Right, Right, Right, Right, Left];
+const OPERATION_INDEXER: &[Side] = &[Left, Left, Right, Right, Right, Right, Right, Left];
+const DETAIL_INDEXER: &[Side] = &[Left, Left, Right, Right, Right, Right, Right, Right, Left];
+const COUNTS: &[Side] = &[
+    Left, Left, Right, Right, Right, Right, Right, Right, Right, Left,
+];
+const ACCOUNT_INDEXER: &[Side] = &[
+    Left, Left, Right, Right, Right, Right, Right, Right, Right, Right, Left,
+];
+const IDEMPOTENCY_KEYS: &[Side] = &[
+    Left, Left, Right, Right, Right, Right, Right, Right, Right, Right, Right, Left,
+];
+const MMR_TIP: &[Side] = &[
+    Left, Left, Right, Right, Right, Right, Right, Right, Right, Right, Right, Right, Left,
+];
+const WRITER_INDEXER: &[Side] = &[
+    Left, Left, Right, Right, Right, Right, Right, Right, Right, Right, Right, Right, Right,
+];
+const BUCKETS: &[Side] = &[Left, Right];
+const NEXT_CANISTERS: &[Side] = &[Right, Left];
+const WRITERS: &[Side] = &[Right, Right];
+
+// Positions of the data certified by the router.
+const ROOT_BUCKETS: &[Side] = &[Left, Left];
+const USER_CANISTERS: &[Side] = &[Left, Right];
+const INDEX_CANISTERS: &[Side] = &[Right];
+
+/// The number of transactions in each page of the paged indexes.
+const PAGE_SIZE: u64 = 64;
+
+/// The number of transactions returned by `get_transactions_in_range` in one call.
+const TIME_PAGE_SIZE: usize = 64;
+
+/// The index a page of transactions was read from.
+#[derive(Clone, Copy, Debug)]
+pub enum TransactionsIndex<'a> {
+    /// The pages returned by `get_transactions`.
+    Contract(&'a Principal),
+    /// The pages returned by `get_user_transactions`.
+    User(&'a Principal),
+    /// The pages returned by `get_token_transactions`.
+    Token(&'a TokenId),
+    /// The pages returned by `get_operation_transactions`.
+    Operation(&'a str),
+    /// The pages returned by `get_transactions_by_detail`.
+    Detail(&'a str, &'a DetailValue),
+    /// The pages returned by `get_account_transactions`.
+    Account(&'a [u8]),
+    /// The pages returned by `get_writer_transactions`.
+    Writer(&'a Principal),
+}
+
+/// Validate the certificate of the witness against the given root key and return the decoded
+/// tree, once it is known to match the certified data of the canister.
+pub fn verify_witness(
+    witness: Option<&Witness>,
+    canister: &Principal,
+    root_key: &[u8],
+) -> Result<HashTree<'static>, VerifyError> {
+    let witness = witness.ok_or(VerifyError::MissingWitness)?;
+    let certified_data = verify_certificate(&witness.certificate, canister, root_key)?;
+    let tree = decode_tree(&witness.tree)?;
+
+    if tree.reconstruct() != certified_data {
+        return Err(VerifyError::CertifiedDataMismatch);
+    }
+
+    Ok(tree)
+}
+
+/// Validate a certificate against the given root key and return the certified data of the
+/// canister.
+pub fn verify_certificate(
+    certificate: &[u8],
+    canister: &Principal,
+    root_key: &[u8],
+) -> Result<Hash, VerifyError> {
+    let (tree, signature, delegation) = decode_certificate(certificate)?;
+
+    let key = match delegation {
+        Some(delegation) => verify_delegation(&delegation, canister, root_key)?,
+        None => root_key.to_vec(),
+    };
+
+    verify_signature(&tree, &signature, &key)?;
+
+    let path: [&[u8]; 3] = [b"canister", canister.as_slice(), b"certified_data"];
+    match lookup_path(&tree, &path) {
+        Lookup::Found(HashTree::Leaf(data)) if data.len() == 32 => {
+            let mut hash = [0; 32];
+            hash.copy_from_slice(data);
+            Ok(hash)
+        }
+        _ => Err(VerifyError::MissingCertifiedData),
+    }
+}
+
+/// Check the response of `get_transaction` against a verified tree.
+pub fn verify_transaction(
+    tree: &HashTree,
+    id: TransactionId,
+    response: &GetTransactionResponse,
+) -> Result<(), VerifyError> {
+    let event = match response {
+        GetTransactionResponse::Found(event, _) => event.as_ref(),
+        GetTransactionResponse::Delegate(_, _) => return Err(VerifyError::Delegated),
+    };
+
+    let offset = read_u64(subtree(tree, GLOBAL_OFFSET)?)?;
+
+    if id < offset {
+        return expect(event.is_none());
+    }
+
+    let local = (id - offset).min(u32::MAX as u64) as u32;
+    match find_label(subtree(tree, EVENT_HASHES)?, &local.to_be_bytes(), cmp_bytes) {
+        Lookup::Found(leaf) => match event {
+            Some(event) => expect(leaf.reconstruct() == leaf_hash(&event.hash())),
+            None => Err(VerifyError::DataMismatch),
+        },
+        Lookup::Absent => expect(event.is_none()),
+        Lookup::Unknown => Err(VerifyError::IncompleteWitness),
+    }
+}
+
+/// Check a page of transactions returned by one of the paginated queries against a
+/// verified tree.
+pub fn verify_transactions(
+    tree: &HashTree,
+    index: TransactionsIndex,
+    page: u32,
+    data: &[Event],
+) -> Result<(), VerifyError> {
+    let expected = seq_hash(data.iter().map(Event::hash));
+
+    let (path, mut label, cmp): (_, Vec<u8>, Cmp) = match index {
+        TransactionsIndex::Contract(p) => (CONTRACT_INDEXER, p.as_slice().into(), cmp_paged),
+        TransactionsIndex::User(p) => (USER_INDEXER, p.as_slice().into(), cmp_paged),
+        TransactionsIndex::Token(id) => (TOKEN_INDEXER, id.as_label().into(), cmp_paged_text),
+        TransactionsIndex::Operation(op) => {
+            (OPERATION_INDEXER, op.as_bytes().into(), cmp_paged_text)
+        }
+        TransactionsIndex::Account(id) => (ACCOUNT_INDEXER, id.into(), cmp_paged_text),
+        TransactionsIndex::Writer(p) => (WRITER_INDEXER, p.as_slice().into(), cmp_paged),
+        TransactionsIndex::Detail(key, value) => {
+            let index = match find_label(subtree(tree, DETAIL_INDEXER)?, key.as_bytes(), cmp_bytes)
+            {
+                Lookup::Found(index) => index,
+                Lookup::Absent => return expect(data.is_empty()),
+                Lookup::Unknown => return Err(VerifyError::IncompleteWitness),
+            };
+            let mut label = value.index_key().ok_or(VerifyError::DataMismatch)?;
+            label.extend_from_slice(&page.to_be_bytes());
+            return verify_seq(index, &label, cmp_paged_text, data.is_empty(), expected);
+        }
+    };
+    label.extend_from_slice(&page.to_be_bytes());
+
+    verify_seq(subtree(tree, path)?, &label, cmp, data.is_empty(), expected)
+}
+
+/// Check a page returned by `get_transactions_in_range` and its `next_cursor` against a
+/// verified tree.
+pub fn verify_transactions_in_range(
+    tree: &HashTree,
+    from: u64,
+    to: u64,
+    cursor: Option<TransactionId>,
+    data: &[Event],
+    next_cursor: Option<TransactionId>,
+) -> Result<(), VerifyError> {
+    let offset = read_u64(subtree(tree, GLOBAL_OFFSET)?)?;
+    let is_empty = data.is_empty() && next_cursor.is_none();
+
+    // The keys of the time index are the time followed by the local index of the event.
+    let start = match cursor {
+        None => time_key(from, 0),
+        Some(id) if id < offset => return expect(is_empty),
+        Some(id) => {
+            let local = (id - offset).min(u32::MAX as u64) as u32;
+            let key = subtree(tree, TIME_INDEXER).ok().and_then(|time_indexer| {
+                flatten(time_indexer).into_iter().find_map(|node| match node {
+                    HashTree::Labeled(key, _) => read_time_key(key).filter(|k| k.1 == local),
+                    _ => None,
+                })
+            });
+
+            match key {
+                Some((time, local)) => time_key(time, local),
+                None => {
+                    let local = local.to_be_bytes();
+                    return match find_label(subtree(tree, EVENT_HASHES)?, &local, cmp_bytes) {
+                        Lookup::Absent => expect(is_empty),
+                        _ => Err(VerifyError::IncompleteWitness),
+                    };
+                }
+            }
+        }
+    };
+
+    if start < time_key(from, 0) || start > time_key(to, u32::MAX) {
+        return expect(is_empty);
+    }
+
+    let end = time_key(to, u32::MAX);
+    let mut hashes = Vec::with_capacity(data.len());
+    let mut next = None;
+    // Whether everything between the start and the current node is revealed.
+    let mut known = true;
+
+    for node in flatten(subtree(tree, TIME_INDEXER)?) {
+        match node {
+            HashTree::Labeled(key, _) if key.as_ref() < &start[..] => known = true,
+            HashTree::Labeled(key, _) if key.as_ref() > &end[..] => break,
+            HashTree::Labeled(key, _) if !known && key.as_ref() != &start[..] => {
+                return Err(VerifyError::IncompleteWitness)
+            }
+            HashTree::Labeled(key, _) if hashes.len() == TIME_PAGE_SIZE => {
+                let (_, local) = read_time_key(key).ok_or(VerifyError::DataMismatch)?;
+                next = Some(offset + local as u64);
+                break;
+            }
+            HashTree::Labeled(_, leaf) => {
+                hashes.push(leaf.reconstruct());
+                known = true;
+            }
+            _ if hashes.is_empty() => known = false,
+            _ => return Err(VerifyError::IncompleteWitness),
+        }
+    }
+
+    if !known {
+        return Err(VerifyError::IncompleteWitness);
+    }
+
+    expect(
+        next == next_cursor
+            && hashes.len() == data.len()
+            && hashes
+                .iter()
+                .zip(data)
+                .all(|(hash, event)| *hash == leaf_hash(&event.hash())),
+    )
+}
+
+/// Check the pagination metadata of a response returned by one of the paginated queries
+/// against a verified tree, the page itself is checked by [`verify_transactions`].
+pub fn verify_page_bounds(
+    tree: &HashTree,
+    index: TransactionsIndex,
+    response: &GetTransactionsResponse,
+) -> Result<(), VerifyError> {
+    let key = match index {
+        TransactionsIndex::Contract(p) => count_key(CONTRACT_COUNT, p.as_slice()),
+        TransactionsIndex::User(p) => count_key(USER_COUNT, p.as_slice()),
+        TransactionsIndex::Token(id) => count_key(TOKEN_COUNT, &id.as_label()),
+        TransactionsIndex::Operation(op) => count_key(OPERATION_COUNT, op.as_bytes()),
+        TransactionsIndex::Account(id) => count_key(ACCOUNT_COUNT, id),
+        TransactionsIndex::Writer(p) => count_key(WRITER_COUNT, p.as_slice()),
+        TransactionsIndex::Detail(key, value) => match value.index_key() {
+            Some(value) => detail_count_key(key, &value),
+            None => return expect(response.total_items == 0 && !response.has_more),
+        },
+    };
+
+    let total_items = match find_label(subtree(tree, COUNTS)?, &key, cmp_bytes) {
+        Lookup::Found(leaf) => read_u64(leaf)?,
+        Lookup::Absent => 0,
+        Lookup::Unknown => return Err(VerifyError::IncompleteWitness),
+    };
+    let last_page = total_items.saturating_sub(1) / PAGE_SIZE;
+
+    expect(
+        response.total_items == total_items
+            && response.last_page as u64 == last_page
+            && response.has_more == (response.page < response.last_page),
+    )
+}
+
+/// Check the response of `get_bucket_for` against a verified tree of the given canister, and
+/// return the range of ids `[start, end)` that is proven to be held by the returned bucket. An
+/// end of `None` means the range is not bounded. `None` is returned when the tree proves that no
+/// bucket holds the id, the canister then answers with itself.
+pub fn verify_bucket_for(
+    tree: &HashTree,
+    canister: &Principal,
+    id: TransactionId,
+    response: &GetBucketResponse,
+) -> Result<Option<(TransactionId, Option<TransactionId>)>, VerifyError> {
+    let (floor, next) = match find_floor(subtree(tree, BUCKETS)?, &id.to_be_bytes()) {
+        Some(result) => result,
+        None => return Err(VerifyError::IncompleteWitness),
+    };
+
+    match floor {
+        Some((start, bucket)) => {
+            expect(bucket.reconstruct() == leaf_hash(response.canister.as_slice()))?;
+            let end = next.map(read_label).transpose()?;
+            Ok(Some((read_label(start)?, end)))
+        }
+        None => {
+            expect(&response.canister == canister)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Check the response of `get_size` against a verified tree.
+pub fn verify_size(tree: &HashTree, size: u64) -> Result<(), VerifyError> {
+    let offset = read_u64(subtree(tree, GLOBAL_OFFSET)?)?;
+    let len = size.checked_sub(offset).ok_or(VerifyError::DataMismatch)?;
+    let event_hashes = subtree(tree, EVENT_HASHES)?;
+    let local = |i: u64| (i.min(u32::MAX as u64) as u32).to_be_bytes();
+
+    // The local indexes are contiguous, so the size is proven by the absence of the local
+    // index `len` and the presence of the one before it.
+    match find_label(event_hashes, &local(len), cmp_bytes) {
+        Lookup::Found(_) => return Err(VerifyError::DataMismatch),
+        Lookup::Absent => {}
+        Lookup::Unknown => return Err(VerifyError::IncompleteWitness),
+    }
+
+    if len == 0 {
+        return Ok(());
+    }
+
+    match find_label(event_hashes, &local(len - 1), cmp_bytes) {
+        Lookup::Found(_) => Ok(()),
+        Lookup::Absent => Err(VerifyError::DataMismatch),
+        Lookup::Unknown => Err(VerifyError::IncompleteWitness),
+    }
+}
+
+/// Check the response of `get_transaction_id_by_key` against a verified tree, `id` is `None`
+/// if the key is not one of the recent idempotency keys.
+pub fn verify_id_for_key(tree: &HashTree, key: &str, id: Option<u64>) -> Result<(), VerifyError> {
+    match find_label(subtree(tree, IDEMPOTENCY_KEYS)?, key.as_bytes(), cmp_bytes) {
+        Lookup::Found(leaf) => expect(Some(read_u64(leaf)?) == id),
+        Lookup::Absent => expect(id.is_none()),
+        Lookup::Unknown => Err(VerifyError::IncompleteWitness),
+    }
+}
+
+/// Check the response of `get_tip` against a verified tree.
+pub fn verify_tip(tree: &HashTree, tip: &Tip) -> Result<(), VerifyError> {
+    expect(subtree(tree, MMR_TIP)?.reconstruct() == leaf_hash(&tip.to_bytes()))
+}
+
+/// Check the response of `get_next_canisters` against a verified tree.
+pub fn verify_next_canisters(tree: &HashTree, canisters: &[BucketId]) -> Result<(), VerifyError> {
+    let expected = seq_hash(canisters.iter().map(|p| leaf_hash(p.as_slice())));
+    expect(subtree(tree, NEXT_CANISTERS)?.reconstruct() == expected)
+}
+
+/// Check the response of `list_writers` against a verified tree, the tree must reveal every
+/// writer.
+pub fn verify_writers(tree: &HashTree, writers: &[Principal]) -> Result<(), VerifyError> {
+    let mut revealed = Vec::new();
+    for node in flatten(subtree(tree, WRITERS)?) {
+        match node {
+            HashTree::Labeled(writer, leaf) => {
+                expect(leaf.reconstruct() == leaf_hash(&[1]))?;
+                revealed.push(writer.as_ref());
+            }
+            _ => return Err(VerifyError::IncompleteWitness),
+        }
+    }
+
+    let mut writers = writers.iter().map(Principal::as_slice).collect::<Vec<_>>();
+    revealed.sort_unstable();
+    writers.sort_unstable();
+    expect(revealed == writers)
+}
+
+/// Check the response of the router's `get_token_contract_root_bucket` against a verified tree.
+pub fn verify_token_contract_root_bucket(
+    tree: &HashTree,
+    contract: &TokenContractId,
+    canister: Option<&RootBucketId>,
+) -> Result<(), VerifyError> {
+    match find_label(subtree(tree, ROOT_BUCKETS)?, contract.as_slice(), cmp_principal) {
+        Lookup::Found(leaf) => match canister {
+            Some(canister) => expect(leaf.reconstruct() == leaf_hash(canister.as_slice())),
+            None => Err(VerifyError::DataMismatch),
+        },
+        Lookup::Absent => expect(canister.is_none()),
+        Lookup::Unknown => Err(VerifyError::IncompleteWitness),
+    }
+}
+
+/// Check the response of the router's `get_user_root_buckets` against a verified tree.
+pub fn verify_user_root_buckets(
+    tree: &HashTree,
+    user: &UserId,
+    contracts: &[RootBucketId],
+) -> Result<(), VerifyError> {
+    let expected = seq_hash(contracts.iter().map(|p| leaf_hash(p.as_slice())));
+    verify_seq(
+        subtree(tree, USER_CANISTERS)?,
+        user.as_slice(),
+        cmp_principal,
+        contracts.is_empty(),
+        expected,
+    )
+}
+
+/// Check the response of the router's `get_index_canisters` against a verified tree.
+pub fn verify_index_canisters(
+    tree: &HashTree,
+    canisters: &[IndexCanisterId],
+) -> Result<(), VerifyError> {
+    let expected = seq_hash(canisters.iter().map(|p| leaf_hash(p.as_slice())));
+    expect(subtree(tree, INDEX_CANISTERS)?.reconstruct() == expected)
+}
+
+/// Verify a response of `get_transaction` as received by an agent, `canister` is the bucket
+/// the query was sent to.
+pub fn verify_transaction_response(
+    response: &GetTransactionResponse,
+    canister: &Principal,
+    id: TransactionId,
+    root_key: &[u8],
+) -> Result<(), VerifyError> {
+    let witness = match response {
+        GetTransactionResponse::Found(_, witness) => witness.as_ref(),
+        GetTransactionResponse::Delegate(_, _) => return Err(VerifyError::Delegated),
+    };
+
+    let tree = verify_witness(witness, canister, root_key)?;
+    verify_transaction(&tree, id, response)
+}
+
+/// Verify a response of one of the paginated queries as received by an agent. When a page was
+/// requested the response must be for that page, otherwise it is the last page of the index.
+pub fn verify_transactions_response(
+    response: &GetTransactionsResponse,
+    canister: &Principal,
+    index: TransactionsIndex,
+    page: Option<u32>,
+    root_key: &[u8],
+) -> Result<(), VerifyError> {
+    let tree = verify_witness(response.witness.as_ref(), canister, root_key)?;
+    verify_transactions(&tree, index, response.page, &response.data)?;
+    verify_page_bounds(&tree, index, response)?;
+
+    if response.page != page.unwrap_or(response.last_page) {
+        return Err(VerifyError::PageMismatch);
+    }
+
+    Ok(())
+}
+
+/// Verify a response of `get_transactions_in_range` as received by an agent.
+pub fn verify_transactions_in_range_response(
+    response: &GetTransactionsInRangeResponse,
+    canister: &Principal,
+    from: u64,
+    to: u64,
+    cursor: Option<TransactionId>,
+    root_key: &[u8],
+) -> Result<(), VerifyError> {
+    let tree = verify_witness(response.witness.as_ref(), canister, root_key)?;
+    verify_transactions_in_range(
+        &tree,
+        from,
+        to,
+        cursor,
+        &response.data,
+        response.next_cursor,
+    )
+}
+
+/// Verify a response of a bucket's `get_next_canisters` as received by an agent.
+pub fn verify_next_canisters_response(
+    response: &GetNextCanistersResponse,
+    canister: &Principal,
+    root_key: &[u8],
+) -> Result<(), VerifyError> {
+    let tree = verify_witness(response.witness.as_ref(), canister, root_key)?;
+    verify_next_canisters(&tree, &response.canisters)
+}
+
+/// Verify a response of the router's `get_token_contract_root_bucket` as received by an agent.
+pub fn verify_token_contract_root_bucket_response(
+    response: &GetTokenContractRootBucketResponse,
+    router: &Principal,
+    contract: &TokenContractId,
+    root_key: &[u8],
+) -> Result<(), VerifyError> {
+    let tree = verify_witness(response.witness.as_ref(), router, root_key)?;
+    verify_token_contract_root_bucket(&tree, contract, response.canister.as_ref())
+}
+
+/// Verify a response of the router's `get_index_canisters` as received by an agent.
+pub fn verify_index_canisters_response(
+    response: &GetIndexCanistersResponse,
+    router: &Principal,
+    root_key: &[u8],
+) -> Result<(), VerifyError> {
+    let tree = verify_witness(response.witness.as_ref(), router, root_key)?;
+    verify_index_canisters(&tree, &response.canisters)
+}
+
+/// Verify a response of `get_user_root_buckets` as received by an agent, `index` is the index
+/// canister of the user the query was sent to.
+pub fn verify_user_root_buckets_response(
+    response: &GetUserRootBucketsResponse,
+    index: &IndexCanisterId,
+    user: &UserId,
+    root_key: &[u8],
+) -> Result<(), VerifyError> {
+    let tree = verify_witness(response.witness.as_ref(), index, root_key)?;
+    verify_user_root_buckets(&tree, user, &response.contracts)
+}
+
+/// Decode a CBOR encoded hash tree.
+pub fn decode_tree(bytes: &[u8]) -> Result<HashTree<'static>, VerifyError> {
+    let value: Value = serde_cbor::from_slice(bytes)
+        .map_err(|e| VerifyError::MalformedTree(e.to_string()))?;
+    tree_from_value(value).map_err(VerifyError::MalformedTree)
+}
+
+fn tree_from_value(value: Value) -> Result<HashTree<'static>, String> {
+    let mut items = match value {
+        Value::Array(items) => items.into_iter(),
+        _ => return Err("expected an array".into()),
+    };
+
+    let tag = match items.next() {
+        Some(Value::Integer(tag)) => tag,
+        _ => return Err("expected a node tag".into()),
+    };
+
+    let tree = match (tag, items.next(), items.next()) {
+        (0, None, None) => HashTree::Empty,
+        (1, Some(left), Some(right)) => fork(tree_from_value(left)?, tree_from_value(right)?),
+        (2, Some(Value::Bytes(label)), Some(tree)) => {
+            HashTree::Labeled(Cow::Owned(label), Box::new(tree_from_value(tree)?))
+        }
+        (3, Some(Value::Bytes(data)), None) => HashTree::Leaf(Cow::Owned(data)),
+        (4, Some(Value::Bytes(hash)), None) if hash.len() == 32 => {
+            let mut digest = [0; 32];
+            digest.copy_from_slice(&hash);
+            HashTree::Pruned(digest)
+        }
+        (tag, _, _) => return Err(format!("invalid node with tag {}", tag)),
+    };
+
+    match items.next() {
+        None => Ok(tree),
+        Some(_) => Err(format!("too many items in node with tag {}", tag)),
+    }
+}
+
+#[derive(Deserialize)]
+struct CertificateDe {
+    tree: Value,
+    #[serde(with = "serde_bytes")]
+    signature: Vec<u8>,
+    delegation: Option<DelegationDe>,
+}
+
+#[derive(Deserialize)]
+struct DelegationDe {
+    #[serde(with = "serde_bytes")]
+    subnet_id: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    certificate: Vec<u8>,
+}
+
+type DecodedCertificate = (HashTree<'static>, Vec<u8>, Option<DelegationDe>);
+
+fn decode_certificate(bytes: &[u8]) -> Result<DecodedCertificate, VerifyError> {
+    let certificate: CertificateDe = serde_cbor::from_slice(bytes)
+        .map_err(|e| VerifyError::MalformedCertificate(e.to_string()))?;
+    let tree = tree_from_value(certificate.tree).map_err(VerifyError::MalformedCertificate)?;
+    Ok((tree, certificate.signature, certificate.delegation))
+}
+
+/// Verify the delegation certificate using the root key, and return the DER encoded public
+/// key of the subnet, if the subnet is allowed to sign for the canister.
+fn verify_delegation(
+    delegation: &DelegationDe,
+    canister: &Principal,
+    root_key: &[u8],
+) -> Result<Vec<u8>, VerifyError> {
+    let (tree, signature, nested) = decode_certificate(&delegation.certificate)?;
+
+    // The delegation certificate itself must be signed by the root key.
+    if nested.is_some() {
+        return Err(VerifyError::InvalidDelegation);
+    }
+
+    verify_signature(&tree, &signature, root_key)?;
+
+    let path: [&[u8]; 3] = [b"subnet", &delegation.subnet_id, b"canister_ranges"];
+    let ranges = match lookup_path(&tree, &path) {
+        Lookup::Found(HashTree::Leaf(data)) => data,
+        _ => return Err(VerifyError::InvalidDelegation),
+    };
+    let ranges: Vec<(serde_bytes::ByteBuf, serde_bytes::ByteBuf)> =
+        serde_cbor::from_slice(ranges).map_err(|_| VerifyError::InvalidDelegation)?;
+
+    let canister = canister.as_slice();
+    if !ranges
+        .iter()
+        .any(|(low, high)| low.as_slice() <= canister && canister <= high.as_slice())
+    {
+        return Err(VerifyError::InvalidDelegation);
+    }
+
+    let path: [&[u8]; 3] = [b"subnet", &delegation.subnet_id, b"public_key"];
+    match lookup_path(&tree, &path) {
+        Lookup::Found(HashTree::Leaf(key)) => Ok(key.to_vec()),
+        _ => Err(VerifyError::InvalidDelegation),
+    }
+}
+
+fn verify_signature(tree: &HashTree, signature: &[u8], der_key: &[u8]) -> Result<(), VerifyError> {
+    if der_key.len() != DER_PREFIX.len() + 96 || !der_key.starts_with(DER_PREFIX) {
+        return Err(VerifyError::InvalidPublicKey);
+    }
+
+    let mut message = b"\x0dic-state-root".to_vec();
+    message.extend_from_slice(&tree.reconstruct());
+
+    ic_verify_bls_signature::verify_bls_signature(
+        signature,
+        &message,
+        &der_key[DER_PREFIX.len()..],
+    )
+    .map_err(|_| VerifyError::InvalidSignature)
+}
+
+fn verify_seq(
+    tree: &HashTree,
+    label: &[u8],
+    cmp: Cmp,
+    is_empty: bool,
+    expected: Hash,
+) -> Result<(), VerifyError> {
+    match find_label(tree, label, cmp) {
+        Lookup::Found(seq) => expect(seq.reconstruct() == expected),
+        Lookup::Absent => expect(is_empty),
+        Lookup::Unknown => Err(VerifyError::IncompleteWitness),
+    }
+}
+
+/// Read a number used as a label.
+fn read_label(label: &[u8]) -> Result<u64, VerifyError> {
+    match label.len() {
+        8 => {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(label);
+            Ok(u64::from_be_bytes(buf))
+        }
+        _ => Err(VerifyError::DataMismatch),
+    }
+}
+
+/// The key of an event in the time index, see `TransactionList`.
+fn time_key(time: u64, local: u32) -> Vec<u8> {
+    let mut key = time.to_be_bytes().to_vec();
+    key.extend_from_slice(&local.to_be_bytes());
+    key
+}
+
+/// Read the time and the local index of an event from its key in the time index.
+fn read_time_key(key: &[u8]) -> Option<(u64, u32)> {
+    if key.len() != 12 {
+        return None;
+    }
+
+    let (mut time, mut local) = ([0; 8], [0; 4]);
+    time.copy_from_slice(&key[..8]);
+    local.copy_from_slice(&key[8..]);
+    Some((u64::from_be_bytes(time), u32::from_be_bytes(local)))
+}
+
+/// Read a number stored in a leaf.
+fn read_u64(tree: &HashTree) -> Result<u64, VerifyError> {
+    match tree {
+        HashTree::Leaf(bytes) if bytes.len() == 8 => {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(bytes);
+            Ok(u64::from_be_bytes(buf))
+        }
+        _ => Err(VerifyError::IncompleteWitness),
+    }
+}
+
+#[inline]
+fn expect(condition: bool) -> Result<(), VerifyError> {
+    if condition {
+        Ok(())
+    } else {
+        Err(VerifyError::DataMismatch)
+    }
+}
+
+/// Compute the hash of a `Seq` containing items with the given hashes.
+fn seq_hash(items: impl Iterator<Item = Hash>) -> Hash {
+    items.fold([0; 32], |hash, item| {
+        let mut h = Sha256::new();
+        h.update(hash);
+        h.update(item);
+        h.finalize().into()
+    })
+}
+
+/// Follow the given forks from the root of the tree.
+fn subtree<'t, 'a>(tree: &'t HashTree<'a>, path: &[Side]) -> Result<&'t HashTree<'a>, VerifyError> {
+    path.iter().try_fold(tree, |tree, side| match (tree, side) {
+        (HashTree::Fork(f), Left) => Ok(f.left()),
+        (HashTree::Fork(f), Right) => Ok(f.right()),
+        _ => Err(VerifyError::IncompleteWitness),
+    })
+}
+
+enum Lookup<'t, 'a> {
+    Found(&'t HashTree<'a>),
+    Absent,
+    Unknown,
+}
+
+type Cmp = fn(&[u8], &[u8]) -> Ordering;
+
+/// Labels of fixed size numbers, and the labels in a certificate.
+fn cmp_bytes(a: &[u8], b: &[u8]) -> Ordering {
+    a.cmp(b)
+}
+
+/// A [`Principal`] is ordered by its length first.
+fn cmp_principal(a: &[u8], b: &[u8]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+/// The keys of a `Paged` collection, a principal followed by a page number.
+fn cmp_paged(a: &[u8], b: &[u8]) -> Ordering {
+    let (a, a_page) = a.split_at(a.len().saturating_sub(4));
+    let (b, b_page) = b.split_at(b.len().saturating_sub(4));
+    cmp_principal(a, b).then_with(|| a_page.cmp(b_page))
+}
+
+/// The keys of a `Paged` collection, a string followed by a page number.
+fn cmp_paged_text(a: &[u8], b: &[u8]) -> Ordering {
+    let (a, a_page) = a.split_at(a.len().saturating_sub(4));
+    let (b, b_page) = b.split_at(b.len().saturating_sub(4));
+    a.cmp(b).then_with(|| a_page.cmp(b_page))
+}
+
+fn lookup_path<'t, 'a>(tree: &'t HashTree<'a>, path: &[&[u8]]) -> Lookup<'t, 'a> {
+    match path.split_first() {
+        None => Lookup::Found(tree),
+        Some((label, rest)) => match find_label(tree, label, cmp_bytes) {
+            Lookup::Found(tree) => lookup_path(tree, rest),
+            result => result,
+        },
+    }
+}
+
+/// Find the subtree labeled with the given label, this implements the lookup algorithm
+/// described in the interface spec, which can prove the absence of a label as long as its
+/// neighbors are revealed.
+fn find_label<'t, 'a>(tree: &'t HashTree<'a>, label: &[u8], cmp: Cmp) -> Lookup<'t, 'a> {
+    let nodes = flatten(tree);
+
+    // Whether everything before the current node is known to be smaller than the label.
+    let mut smaller = true;
+
+    for node in nodes {
+        match node {
+            HashTree::Labeled(l, subtree) => match cmp(label, l) {
+                Ordering::Equal => return Lookup::Found(subtree),
+                Ordering::Less if smaller => return Lookup::Absent,
+                Ordering::Less => return Lookup::Unknown,
+                Ordering::Greater => smaller = true,
+            },
+            _ => smaller = false,
+        }
+    }
+
+    if smaller {
+        Lookup::Absent
+    } else {
+        Lookup::Unknown
+    }
+}
+
+/// Return the labeled and pruned nodes of the tree in order.
+fn flatten<'t, 'a>(tree: &'t HashTree<'a>) -> Vec<&'t HashTree<'a>> {
+    fn go<'t, 'a>(tree: &'t HashTree<'a>, nodes: &mut Vec<&'t HashTree<'a>>) {
+        match tree {
+            HashTree::Empty => {}
+            HashTree::Fork(f) => {
+                go(f.left(), nodes);
+                go(f.right(), nodes);
+            }
+            tree => nodes.push(tree),
+        }
+    }
+
+    let mut nodes = Vec::new();
+    go(tree, &mut nodes);
+    nodes
+}
+
+/// A floor lookup on the labels of a tree, returns the greatest label which is not greater
+/// than the given one along with its subtree, and the smallest label which is greater. Returns
+/// `None` if a pruned node could hide a label between the two.
+fn find_floor<'t, 'a>(tree: &'t HashTree<'a>, label: &[u8]) -> Option<FloorLookup<'t, 'a>> {
+    let nodes = flatten(tree);
+
+    let mut floor = None;
+    // Whether everything after the floor, or the start of the tree, is revealed.
+    let mut known = true;
+
+    for node in nodes {
+        match node {
+            HashTree::Labeled(l, _) if l.as_ref() > label => {
+                return Some((floor, Some(l.as_ref()))).filter(|_| known);
+            }
+            HashTree::Labeled(l, subtree) => {
+                floor = Some((l.as_ref(), subtree.as_ref()));
+                known = true;
+            }
+            _ => known = false,
+        }
+    }
+
+    Some((floor, None)).filter(|_| known)
+}
+
+type FloorLookup<'t, 'a> = (Option<(&'t [u8], &'t HashTree<'a>)>, Option<&'t [u8]>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bucket::Bucket;
+    use crate::transaction::Account;
+    use certified_vars::hashtree::{fork_hash, labeled};
+    use certified_vars::AsHashTree;
+    use ic_kit::{mock_principals, MockContext};
+    use ic_verify_bls_signature::PrivateKey;
+    use serde::Serialize;
+
+    #[derive(Serialize)]
+    struct CertificateSer<'a> {
+        tree: HashTree<'a>,
+        #[serde(with = "serde_bytes")]
+        signature: Vec<u8>,
+    }
+
+    fn key(seed: u8) -> PrivateKey {
+        let mut bytes = [0; 32];
+        bytes[31] = seed;
+        PrivateKey::deserialize(&bytes).unwrap()
+    }
+
+    fn der(key: &PrivateKey) -> Vec<u8> {
+        let mut der = DER_PREFIX.to_vec();
+        der.extend_from_slice(&key.public_key().serialize());
+        der
+    }
+
+    fn sign(key: &PrivateKey, tree: HashTree) -> Vec<u8> {
+        let mut message = b"\x0dic-state-root".to_vec();
+        message.extend_from_slice(&tree.reconstruct());
+        let signature = key.sign(&message).serialize().to_vec();
+        serde_cbor::to_vec(&CertificateSer { tree, signature }).unwrap()
+    }
+
+    /// Fabricate a certificate for the given certified data.
+    fn certificate(key: &PrivateKey, canister: &Principal, certified_data: &Hash) -> Vec<u8> {
+        let tree = labeled(
+            b"canister",
+            labeled(
+                canister.as_slice(),
+                labeled(b"certified_data", HashTree::Leaf(Cow::Borrowed(certified_data))),
+            ),
+        );
+        sign(key, tree)
+    }
+
+    fn e(time: u64, caller: Principal) -> Event {
+        Event {
+            time,
+            caller,
+            operation: "transfer".into(),
+            details: vec![],
+        }
+    }
+
+    /// Create a bucket and a context which can produce witnesses, the certificates should be
+    /// replaced using [`resign`] since the mock certificates are not signed.
+    fn bucket() -> Bucket {
+        let mut bucket = Bucket::new(mock_principals::xtc(), 0);
+        for i in 0..100 {
+            if i % 3 == 0 {
+                bucket.insert(e(i, mock_principals::bob()));
+            } else {
+                bucket.insert(e(i, mock_principals::alice()));
+            }
+        }
+
+        MockContext::new()
+            .with_id(mock_principals::xtc())
+            .with_certified_data(vec![0])
+            .inject();
+        bucket.init_ranges(ic_kit::ic::id());
+
+        bucket
+    }
+
+    fn resign(witness: Option<Witness>, bucket: &Bucket) -> Option<Witness> {
+        witness.map(|mut witness| {
+            witness.certificate = certificate(&key(1), &ic_kit::ic::id(), &bucket.root_hash());
+            witness
+        })
+    }
+
+    #[test]
+    fn test_decode_tree_roundtrip() {
+        let bucket = bucket();
+        let tree = bucket.as_hash_tree();
+        let decoded = decode_tree(&serde_cbor::to_vec(&tree).unwrap()).unwrap();
+        assert_eq!(decoded, tree);
+        assert!(decode_tree(&[0x82, 0x05, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_certificate() {
+        let canister = mock_principals::xtc();
+        let data = [7; 32];
+        let cert = certificate(&key(1), &canister, &data);
+
+        assert_eq!(verify_certificate(&cert, &canister, &der(&key(1))), Ok(data));
+        assert_eq!(
+            verify_certificate(&cert, &canister, &der(&key(2))),
+            Err(VerifyError::InvalidSignature)
+        );
+        assert_eq!(
+            verify_certificate(&cert, &mock_principals::alice(), &der(&key(1))),
+            Err(VerifyError::MissingCertifiedData)
+        );
+        assert_eq!(
+            verify_certificate(&cert, &canister, &der(&key(1))[1..]),
+            Err(VerifyError::InvalidPublicKey)
+        );
+
+        // Certificates might start with the self-describing CBOR tag.
+        let mut tagged = vec![0xd9, 0xd9, 0xf7];
+        tagged.extend_from_slice(&cert);
+        assert_eq!(verify_certificate(&tagged, &canister, &der(&key(1))), Ok(data));
+    }
+
+    #[test]
+    fn test_delegation() {
+        #[derive(Serialize)]
+        struct Delegation {
+            #[serde(with = "serde_bytes")]
+            subnet_id: Vec<u8>,
+            #[serde(with = "serde_bytes")]
+            certificate: Vec<u8>,
+        }
+
+        #[derive(Serialize)]
+        struct DelegatedCertificate<'a> {
+            tree: HashTree<'a>,
+            #[serde(with = "serde_bytes")]
+            signature: Vec<u8>,
+            delegation: Delegation,
+        }
+
+        let canister = mock_principals::xtc();
+        let subnet_key = der(&key(2));
+        let ranges = serde_cbor::to_vec(&vec![(
+            serde_bytes::ByteBuf::from(canister.as_slice().to_vec()),
+            serde_bytes::ByteBuf::from(canister.as_slice().to_vec()),
+        )])
+        .unwrap();
+        let delegation = sign(
+            &key(1),
+            labeled(
+                b"subnet",
+                labeled(
+                    b"subnet-id",
+                    fork(
+                        labeled(b"canister_ranges", HashTree::Leaf(Cow::Owned(ranges))),
+                        labeled(b"public_key", HashTree::Leaf(Cow::Owned(subnet_key))),
+                    ),
+                ),
+            ),
+        );
+
+        let data = [7; 32];
+        let cert: Vec<u8> = {
+            let signed = certificate(&key(2), &canister, &data);
+            let (tree, signature, _) = decode_certificate(&signed).unwrap();
+            serde_cbor::to_vec(&DelegatedCertificate {
+                tree,
+                signature,
+                delegation: Delegation {
+                    subnet_id: b"subnet-id".to_vec(),
+                    certificate: delegation,
+                },
+            })
+            .unwrap()
+        };
+
+        assert_eq!(verify_certificate(&cert, &canister, &der(&key(1))), Ok(data));
+        assert_eq!(
+            verify_certificate(&cert, &canister, &der(&key(2))),
+            Err(VerifyError::InvalidSignature)
+        );
+        assert_eq!(
+            verify_certificate(&cert, &mock_principals::alice(), &der(&key(1))),
+            Err(VerifyError::InvalidDelegation)
+        );
+    }
+
+    #[test]
+    fn test_transaction() {
+        let bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+
+        for id in [0, 42, 99, 100, 1000] {
+            let response = bucket.get_transaction(WithIdArg { id, witness: true });
+            let (event, witness) = match response {
+                GetTransactionResponse::Found(event, witness) => (event, witness),
+                _ => unreachable!(),
+            };
+            let witness = resign(witness, &bucket);
+            let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+            let response = GetTransactionResponse::Found(event.clone(), None);
+            assert_eq!(verify_transaction(&tree, id, &response), Ok(()));
+
+            // Lying about the event, or its existence, must be detected.
+            let forged = match event {
+                Some(_) => GetTransactionResponse::Found(None, None),
+                None => GetTransactionResponse::Found(Some(e(0, mock_principals::bob())), None),
+            };
+            assert_eq!(
+                verify_transaction(&tree, id, &forged),
+                Err(VerifyError::DataMismatch)
+            );
+        }
+
+        let response = GetTransactionResponse::Found(Some(e(1, mock_principals::bob())), None);
+        let witness = match bucket.get_transaction(WithIdArg { id: 1, witness: true }) {
+            GetTransactionResponse::Found(_, witness) => resign(witness, &bucket),
+            _ => unreachable!(),
+        };
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        assert_eq!(
+            verify_transaction(&tree, 1, &response),
+            Err(VerifyError::DataMismatch)
+        );
+    }
+
+    #[test]
+    fn test_delegated_transaction() {
+        let mut bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+        let archive = mock_principals::john();
+        bucket.roll_over(60, archive);
+
+        let witness = match bucket.get_transaction(WithIdArg {
+            id: 10,
+            witness: true,
+        }) {
+            GetTransactionResponse::Delegate(id, witness) => {
+                assert_eq!(id, archive);
+                resign(witness, &bucket)
+            }
+            _ => unreachable!(),
+        };
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        assert_eq!(
+            verify_transaction(&tree, 10, &GetTransactionResponse::Delegate(archive, None)),
+            Err(VerifyError::Delegated)
+        );
+        assert_eq!(
+            verify_next_canisters(&tree, &[]),
+            Err(VerifyError::IncompleteWitness)
+        );
+
+        match bucket.get_transaction(WithIdArg {
+            id: 60,
+            witness: false,
+        }) {
+            GetTransactionResponse::Found(Some(event), None) => assert_eq!(event.time, 60),
+            _ => unreachable!(),
+        }
+
+        // A bucket without its own archive delegates to the next canister in the chain.
+        let mut bucket = Bucket::new(mock_principals::xtc(), 50);
+        bucket.set_next_canisters(vec![archive]);
+        let witness = match bucket.get_transaction(WithIdArg {
+            id: 10,
+            witness: true,
+        }) {
+            GetTransactionResponse::Delegate(id, witness) => {
+                assert_eq!(id, archive);
+                resign(witness, &bucket)
+            }
+            _ => unreachable!(),
+        };
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        assert_eq!(verify_next_canisters(&tree, &[archive]), Ok(()));
+    }
+
+    #[test]
+    fn test_writers() {
+        let mut bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+
+        let verify = |bucket: &Bucket, writers: &[Principal]| {
+            let response = bucket.get_writers(WithWitnessArg { witness: true });
+            let witness = resign(response.witness, bucket);
+            let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+            assert_eq!(verify_writers(&tree, &response.writers), Ok(()));
+            verify_writers(&tree, writers)
+        };
+
+        // Buckets which never had their writers set prove an empty list.
+        assert_eq!(verify(&bucket, &[]), Ok(()));
+
+        let set = vec![mock_principals::bob(), mock_principals::alice()]
+            .into_iter()
+            .collect();
+        bucket.set_writers(&set);
+        let writers = set.into_iter().collect::<Vec<_>>();
+        assert_eq!(bucket.writers(), writers);
+        assert_eq!(verify(&bucket, &writers), Ok(()));
+        assert_eq!(verify(&bucket, &[]), Err(VerifyError::DataMismatch));
+        assert_eq!(
+            verify(&bucket, &writers[..1]),
+            Err(VerifyError::DataMismatch)
+        );
+
+        // The writers are only proven by a witness which reveals them.
+        let response = bucket.get_next_canisters(WithWitnessArg { witness: true });
+        let witness = resign(response.witness, &bucket);
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        assert_eq!(
+            verify_writers(&tree, &writers),
+            Err(VerifyError::IncompleteWitness)
+        );
+    }
+
+    #[test]
+    fn test_bucket_for() {
+        let mut bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+        let archive = mock_principals::john();
+
+        let verify = |bucket: &Bucket, id: TransactionId| {
+            let mut response = bucket.get_bucket_for(WithIdArg { id, witness: true });
+            let witness = resign(response.witness.take(), bucket);
+            let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+            let range = verify_bucket_for(&tree, &canister, id, &response);
+
+            response.canister = mock_principals::alice();
+            assert_eq!(
+                verify_bucket_for(&tree, &canister, id, &response),
+                Err(VerifyError::DataMismatch)
+            );
+            range
+        };
+
+        assert_eq!(verify(&bucket, 10), Ok(Some((0, None))));
+
+        bucket.roll_over(60, archive);
+        assert_eq!(
+            bucket
+                .get_bucket_for(WithIdArg {
+                    id: 59,
+                    witness: false
+                })
+                .canister,
+            archive
+        );
+        assert_eq!(verify(&bucket, 0), Ok(Some((0, Some(60)))));
+        assert_eq!(verify(&bucket, 59), Ok(Some((0, Some(60)))));
+        assert_eq!(verify(&bucket, 60), Ok(Some((60, None))));
+        assert_eq!(verify(&bucket, 1000), Ok(Some((60, None))));
+
+        // A witness of another id does not prove the range.
+        let response = bucket.get_bucket_for(WithIdArg {
+            id: 10,
+            witness: true,
+        });
+        let witness = resign(response.witness.clone(), &bucket);
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        assert_eq!(
+            verify_bucket_for(&tree, &canister, 70, &response),
+            Err(VerifyError::DataMismatch)
+        );
+
+        // The ids before the offset of a bucket are held by the next canister, if there is one.
+        let mut bucket = Bucket::new(mock_principals::xtc(), 50);
+        bucket.set_next_canisters(vec![archive]);
+        bucket.init_ranges(canister);
+        assert_eq!(verify(&bucket, 10), Ok(Some((0, Some(50)))));
+        assert_eq!(verify(&bucket, 50), Ok(Some((50, None))));
+
+        // Otherwise no bucket holds them, which is proven as well.
+        let mut bucket = Bucket::new(mock_principals::xtc(), 50);
+        bucket.init_ranges(canister);
+        assert_eq!(verify(&bucket, 10), Ok(None));
+        assert_eq!(verify(&bucket, 50), Ok(Some((50, None))));
+    }
+
+    #[test]
+    fn test_size() {
+        let mut bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+
+        let verify = |bucket: &Bucket, size: u64| {
+            let response = bucket.get_size(WithWitnessArg { witness: true });
+            assert_eq!(response.size, 100);
+            let witness = resign(response.witness, bucket);
+            let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+            verify_size(&tree, size)
+        };
+
+        assert_eq!(verify(&bucket, 100), Ok(()));
+        assert_eq!(verify(&bucket, 99), Err(VerifyError::DataMismatch));
+        assert_eq!(verify(&bucket, 101), Err(VerifyError::DataMismatch));
+
+        bucket.roll_over(100, mock_principals::john());
+        assert_eq!(verify(&bucket, 100), Ok(()));
+        assert_eq!(verify(&bucket, 0), Err(VerifyError::DataMismatch));
+    }
+
+    #[test]
+    fn test_transactions() {
+        let bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+        let user = mock_principals::bob();
+
+        for page in 0..3 {
+            let response = bucket.get_user_transactions(GetUserTransactionsArg {
+                user,
+                page: Some(page),
+                witness: true,
+                roles: None,
+            });
+            let data = response.data;
+            let witness = resign(response.witness, &bucket);
+            let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+            let index = TransactionsIndex::User(&user);
+
+            assert_eq!(verify_transactions(&tree, index, page, &data), Ok(()));
+
+            let forged = match data.len() {
+                0 => vec![e(0, user)],
+                _ => data[1..].to_vec(),
+            };
+            assert_eq!(
+                verify_transactions(&tree, index, page, &forged),
+                Err(VerifyError::DataMismatch)
+            );
+        }
+
+        let response = bucket.get_transactions(GetTransactionsArg {
+            page: Some(0),
+            witness: true,
+        });
+        let data = response.data;
+        let witness = resign(response.witness, &bucket);
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        let contract = mock_principals::xtc();
+        let index = TransactionsIndex::Contract(&contract);
+        assert_eq!(verify_transactions(&tree, index, 0, &data), Ok(()));
+
+        // The user index is pruned in this witness.
+        assert_eq!(
+            verify_transactions(&tree, TransactionsIndex::User(&user), 0, &data),
+            Err(VerifyError::IncompleteWitness)
+        );
+
+        let response = bucket.get_operation_transactions(GetOperationTransactionsArg {
+            operation: "transfer".into(),
+            page: Some(1),
+            witness: true,
+        });
+        let data = response.data;
+        let witness = resign(response.witness, &bucket);
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        let index = TransactionsIndex::Operation("transfer");
+        assert_eq!(data.len(), 36);
+        assert_eq!(verify_transactions(&tree, index, 1, &data), Ok(()));
+        assert_eq!(
+            verify_transactions(&tree, index, 1, &data[1..]),
+            Err(VerifyError::DataMismatch)
+        );
+
+        // No events have a token id, so this proves the absence of the page.
+        let response = bucket.get_token_transactions(GetTokenTransactionsArg {
+            token_id: 7,
+            page: Some(0),
+            witness: true,
+            token: None,
+        });
+        let witness = resign(response.witness, &bucket);
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        let index = TransactionsIndex::Token(&TokenId::U64(7));
+        assert_eq!(verify_transactions(&tree, index, 0, &[]), Ok(()));
+        assert_eq!(
+            verify_transactions(&tree, index, 0, &[e(0, user)]),
+            Err(VerifyError::DataMismatch)
+        );
+    }
+
+    #[test]
+    fn test_transactions_in_range() {
+        let mut bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+
+        // A second event for each ms, inserted out of order.
+        for i in (0..100).rev() {
+            bucket.insert(e(i, mock_principals::alice()));
+        }
+
+        let get = |from_ms: u64, to_ms: u64, cursor: Option<u64>| {
+            let response = bucket.get_transactions_in_range(GetTransactionsInRangeArg {
+                from_ms,
+                to_ms,
+                cursor,
+                witness: true,
+            });
+            let witness = resign(response.witness, &bucket);
+            let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+            (tree, response.data, response.next_cursor)
+        };
+
+        let mut cursor = None;
+        let mut pages = 0;
+        loop {
+            let (tree, data, next) = get(10, 89, cursor);
+            let forged_next = next.map_or(0, |id| id + 1);
+            assert_eq!(
+                verify_transactions_in_range(&tree, 10, 89, cursor, &data, next),
+                Ok(())
+            );
+            assert_eq!(
+                verify_transactions_in_range(&tree, 10, 89, cursor, &data[1..], next),
+                Err(VerifyError::DataMismatch)
+            );
+            assert_eq!(
+                verify_transactions_in_range(&tree, 10, 89, cursor, &data, Some(forged_next)),
+                Err(VerifyError::DataMismatch)
+            );
+
+            pages += 1;
+            match next {
+                Some(_) => cursor = next,
+                None => break,
+            }
+        }
+        assert_eq!(pages, 3);
+
+        // The last page can not be cut short.
+        let (tree, data, next) = get(10, 89, cursor);
+        assert_eq!(
+            verify_transactions_in_range(&tree, 10, 89, cursor, &data[..10], next),
+            Err(VerifyError::DataMismatch)
+        );
+
+        // A cursor before the range, a cursor past the end, and an inverted range.
+        let forged = [e(30, mock_principals::alice())];
+        for (from, to, cursor) in [(10, 89, Some(5)), (10, 89, Some(1_000)), (50, 20, None)] {
+            let (tree, data, next) = get(from, to, cursor);
+            assert!(data.is_empty() && next.is_none());
+            assert_eq!(
+                verify_transactions_in_range(&tree, from, to, cursor, &[], None),
+                Ok(())
+            );
+            assert_eq!(
+                verify_transactions_in_range(&tree, from, to, cursor, &forged, None),
+                Err(VerifyError::DataMismatch)
+            );
+        }
+
+        // The time index is pruned in this witness.
+        let response = bucket.get_transaction(WithIdArg { id: 5, witness: true });
+        let witness = match response {
+            GetTransactionResponse::Found(_, witness) => resign(witness, &bucket),
+            _ => unreachable!(),
+        };
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        assert_eq!(
+            verify_transactions_in_range(&tree, 10, 89, None, &[], None),
+            Err(VerifyError::IncompleteWitness)
+        );
+    }
+
+    #[test]
+    fn test_id_for_key() {
+        let mut bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+
+        bucket.insert_idempotent(None, e(100, mock_principals::alice()), Some("b".into()));
+        bucket.insert_idempotent(None, e(101, mock_principals::alice()), Some("d".into()));
+
+        let verify = |key: &str, id: Option<u64>| {
+            let response = bucket.get_transaction_id_by_key(GetTransactionIdByKeyArg {
+                key: key.into(),
+                witness: true,
+            });
+            let witness = resign(response.witness, &bucket);
+            let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+            assert_eq!(verify_id_for_key(&tree, key, response.id), Ok(()));
+            verify_id_for_key(&tree, key, id)
+        };
+
+        assert_eq!(verify("b", Some(100)), Ok(()));
+        assert_eq!(verify("d", Some(101)), Ok(()));
+        assert_eq!(verify("c", None), Ok(()));
+        assert_eq!(verify("b", Some(101)), Err(VerifyError::DataMismatch));
+        assert_eq!(verify("b", None), Err(VerifyError::DataMismatch));
+        assert_eq!(verify("c", Some(100)), Err(VerifyError::DataMismatch));
+    }
+
+    #[test]
+    fn test_tip() {
+        let mut bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+
+        let verify = |bucket: &Bucket, tip: &Tip| {
+            let response = bucket.get_tip(WithWitnessArg { witness: true });
+            let witness = resign(response.witness, bucket);
+            let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+            assert_eq!(verify_tip(&tree, &response.tip), Ok(()));
+            verify_tip(&tree, tip)
+        };
+
+        let old = bucket.bucket.tip();
+        assert_eq!(verify(&bucket, &old), Ok(()));
+
+        for i in 0..5 {
+            bucket.insert(e(100 + i, mock_principals::alice()));
+        }
+
+        let tip = bucket.bucket.tip();
+        assert_eq!(tip.size, old.size + 5);
+        assert_eq!(verify(&bucket, &tip), Ok(()));
+        assert_eq!(verify(&bucket, &old), Err(VerifyError::DataMismatch));
+
+        let proof = bucket
+            .get_consistency_proof(GetConsistencyProofArg {
+                old_size: old.size,
+                new_size: tip.size,
+            })
+            .proof
+            .unwrap();
+        assert!(proof.verify(&old, &tip));
+    }
+
+    #[test]
+    fn test_account_transactions() {
+        let mut bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+        let account = Account {
+            owner: mock_principals::bob(),
+            subaccount: Some(vec![1; 32]),
+        };
+        let id = account.account_id();
+
+        for i in 100..170 {
+            let mut event = e(i, mock_principals::alice());
+            event.details = vec![("to".into(), account.clone().into())];
+            bucket.insert(event);
+        }
+
+        let response = bucket.get_account_transactions(GetAccountTransactionsArg {
+            account: id.clone(),
+            page: Some(1),
+            witness: true,
+        });
+        let data = response.data.clone();
+        let witness = resign(response.witness.clone(), &bucket);
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        let index = TransactionsIndex::Account(&id);
+
+        assert_eq!(data.len(), 6);
+        assert_eq!(verify_transactions(&tree, index, 1, &data), Ok(()));
+        assert_eq!(verify_page_bounds(&tree, index, &response), Ok(()));
+        assert_eq!(
+            verify_transactions(&tree, index, 1, &data[1..]),
+            Err(VerifyError::DataMismatch)
+        );
+
+        let other = TransactionsIndex::Account(&[7; 32]);
+        assert_eq!(
+            verify_transactions(&tree, other, 1, &data),
+            Err(VerifyError::IncompleteWitness)
+        );
+    }
+
+    #[test]
+    fn test_writer_transactions() {
+        let mut bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+        let minter = mock_principals::john();
+        let market = mock_principals::bob();
+
+        for i in 100..180 {
+            let writer = if i % 4 == 0 { market } else { minter };
+            bucket.insert_from(Some(writer), e(i, mock_principals::alice()));
+        }
+
+        let response = bucket.get_writer_transactions(GetWriterTransactionsArg {
+            writer: market,
+            page: None,
+            witness: true,
+        });
+        let data = response.data.clone();
+        let witness = resign(response.witness.clone(), &bucket);
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        let index = TransactionsIndex::Writer(&market);
+
+        assert_eq!(response.total_items, 20);
+        assert_eq!(data.len(), 20);
+        assert!(data.iter().all(|event| event.time % 4 == 0));
+        assert_eq!(verify_transactions(&tree, index, 0, &data), Ok(()));
+        assert_eq!(verify_page_bounds(&tree, index, &response), Ok(()));
+        assert_eq!(
+            verify_transactions(&tree, TransactionsIndex::Writer(&minter), 0, &data),
+            Err(VerifyError::IncompleteWitness)
+        );
+
+        // The events inserted without a writer are not indexed.
+        let response = bucket.get_writer_transactions(GetWriterTransactionsArg {
+            writer: *bucket.contract_id(),
+            page: None,
+            witness: false,
+        });
+        assert_eq!(response.total_items, 0);
+    }
+
+    #[test]
+    fn test_token_transactions() {
+        let mut bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+        let tokens = ["a", "ab", "b"];
+
+        for i in 100..190 {
+            let mut event = e(i, mock_principals::alice());
+            let token = TokenId::from(tokens[i as usize % 3]);
+            event.details = vec![("token".into(), token.into())];
+            bucket.insert(event);
+        }
+
+        for token in tokens {
+            let token = TokenId::from(token);
+            let response = bucket.get_token_transactions(GetTokenTransactionsArg {
+                token_id: 0,
+                page: None,
+                witness: true,
+                token: Some(token.clone()),
+            });
+            let data = response.data.clone();
+            let witness = resign(response.witness.clone(), &bucket);
+            let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+            let index = TransactionsIndex::Token(&token);
+
+            assert_eq!(data.len(), 30);
+            assert_eq!(verify_transactions(&tree, index, 0, &data), Ok(()));
+            assert_eq!(verify_page_bounds(&tree, index, &response), Ok(()));
+            assert_eq!(
+                verify_transactions(&tree, index, 0, &data[1..]),
+                Err(VerifyError::DataMismatch)
+            );
+        }
+    }
+
+    #[test]
+    fn test_page_bounds() {
+        let bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+        let user = mock_principals::alice();
+
+        let mut response = bucket.get_user_transactions(GetUserTransactionsArg {
+            user,
+            page: Some(0),
+            witness: true,
+            roles: None,
+        });
+        let witness = resign(response.witness.take(), &bucket);
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        let index = TransactionsIndex::User(&user);
+
+        assert_eq!(
+            (response.total_items, response.last_page, response.has_more),
+            (66, 1, true)
+        );
+        assert_eq!(verify_page_bounds(&tree, index, &response), Ok(()));
+
+        response.total_items = 64;
+        assert_eq!(
+            verify_page_bounds(&tree, index, &response),
+            Err(VerifyError::DataMismatch)
+        );
+
+        response.total_items = 66;
+        response.has_more = false;
+        assert_eq!(
+            verify_page_bounds(&tree, index, &response),
+            Err(VerifyError::DataMismatch)
+        );
+
+        // The count of the contract is pruned in this witness.
+        let contract = mock_principals::xtc();
+        assert_eq!(
+            verify_page_bounds(&tree, TransactionsIndex::Contract(&contract), &response),
+            Err(VerifyError::IncompleteWitness)
+        );
+
+        // No events have a token id, so the count is proven to be zero.
+        let mut response = bucket.get_token_transactions(GetTokenTransactionsArg {
+            token_id: 7,
+            page: None,
+            witness: true,
+            token: None,
+        });
+        let witness = resign(response.witness.take(), &bucket);
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        let index = TransactionsIndex::Token(&TokenId::U64(7));
+        assert_eq!(verify_page_bounds(&tree, index, &response), Ok(()));
+
+        response.total_items = 1;
+        assert_eq!(
+            verify_page_bounds(&tree, index, &response),
+            Err(VerifyError::DataMismatch)
+        );
+    }
+
+    #[test]
+    fn test_detail() {
+        let mut bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+        bucket.add_detail_index("pair_id".into());
+
+        for i in 0..10 {
+            let mut event = e(i, mock_principals::bob());
+            event.details = vec![("pair_id".into(), DetailValue::U64(i % 2))];
+            bucket.insert(event);
+        }
+        bucket.backfill_detail_indexes(usize::MAX);
+
+        let value = DetailValue::U64(1);
+        let response = bucket.get_transactions_by_detail(GetTransactionsByDetailArg {
+            key: "pair_id".into(),
+            value: value.clone(),
+            page: None,
+            witness: true,
+        });
+        let data = response.data;
+        let witness = resign(response.witness, &bucket);
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        let index = TransactionsIndex::Detail("pair_id", &value);
+        assert_eq!(data.len(), 5);
+        assert_eq!(verify_transactions(&tree, index, 0, &data), Ok(()));
+        assert_eq!(
+            verify_transactions(&tree, index, 0, &data[1..]),
+            Err(VerifyError::DataMismatch)
+        );
+
+        // A key which is not indexed is proven to be absent.
+        let response = bucket.get_transactions_by_detail(GetTransactionsByDetailArg {
+            key: "memo".into(),
+            value: value.clone(),
+            page: None,
+            witness: true,
+        });
+        let witness = resign(response.witness, &bucket);
+        let tree = verify_witness(witness.as_ref(), &canister, &root_key).unwrap();
+        let index = TransactionsIndex::Detail("memo", &value);
+        assert_eq!(verify_transactions(&tree, index, 0, &[]), Ok(()));
+        assert_eq!(
+            verify_transactions(&tree, index, 0, &data),
+            Err(VerifyError::DataMismatch)
+        );
+    }
+
+    #[test]
+    fn test_witness() {
+        let bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let response = bucket.get_next_canisters(WithWitnessArg { witness: true });
+
+        assert_eq!(
+            verify_witness(None, &canister, &der(&key(1))),
+            Err(VerifyError::MissingWitness)
+        );
+
+        let mut witness = resign(response.witness, &bucket).unwrap();
+        let tree = verify_witness(Some(&witness), &canister, &der(&key(1))).unwrap();
+        assert_eq!(verify_next_canisters(&tree, &response.canisters), Ok(()));
+        assert_eq!(
+            verify_next_canisters(&tree, &[mock_principals::bob()]),
+            Err(VerifyError::DataMismatch)
+        );
+
+        witness.certificate = certificate(&key(1), &canister, &[0; 32]);
+        assert_eq!(
+            verify_witness(Some(&witness), &canister, &der(&key(1))),
+            Err(VerifyError::CertifiedDataMismatch)
+        );
+    }
+
+    #[test]
+    fn test_router() {
+        use certified_vars::{Map, Seq};
+
+        let mut root_buckets = Map::<Principal, Principal>::new();
+        let mut user_canisters = Map::<Principal, Seq<Principal>>::new();
+        root_buckets.insert(mock_principals::xtc(), mock_principals::alice());
+        let mut seq = Seq::new();
+        seq.append(mock_principals::alice());
+        user_canisters.insert(mock_principals::bob(), seq);
+
+        let contract = mock_principals::xtc();
+        let root = mock_principals::alice();
+        let tree = fork(
+            fork(
+                root_buckets.witness(&contract),
+                HashTree::Pruned(user_canisters.root_hash()),
+            ),
+            HashTree::Pruned([0; 32]),
+        );
+        assert_eq!(
+            verify_token_contract_root_bucket(&tree, &contract, Some(&root)),
+            Ok(())
+        );
+        assert_eq!(
+            verify_token_contract_root_bucket(&tree, &contract, None),
+            Err(VerifyError::DataMismatch)
+        );
+
+        let unknown = mock_principals::john();
+        let tree = fork(
+            fork(
+                root_buckets.witness(&unknown),
+                HashTree::Pruned(user_canisters.root_hash()),
+            ),
+            HashTree::Pruned([0; 32]),
+        );
+        assert_eq!(verify_token_contract_root_bucket(&tree, &unknown, None), Ok(()));
+
+        let user = mock_principals::bob();
+        let tree = fork(
+            fork(
+                HashTree::Pruned(root_buckets.root_hash()),
+                user_canisters.witness(&user),
+            ),
+            HashTree::Pruned([0; 32]),
+        );
+        assert_eq!(verify_user_root_buckets(&tree, &user, &[root]), Ok(()));
+        assert_eq!(
+            verify_user_root_buckets(&tree, &user, &[]),
+            Err(VerifyError::DataMismatch)
+        );
+    }
+
+    #[test]
+    fn test_responses() {
+        let bucket = bucket();
+        let canister = ic_kit::ic::id();
+        let root_key = der(&key(1));
+        let user = mock_principals::alice();
+
+        // The responses are checked as a whole, the way an agent receives them.
+        let mut response = bucket.get_transaction(WithIdArg { id: 3, witness: true });
+        if let GetTransactionResponse::Found(_, witness) = &mut response {
+            *witness = resign(witness.take(), &bucket);
+        }
+        assert_eq!(
+            verify_transaction_response(&response, &canister, 3, &root_key),
+            Ok(())
+        );
+        assert_eq!(
+            verify_transaction_response(&response, &canister, 4, &root_key),
+            Err(VerifyError::IncompleteWitness)
+        );
+        assert_eq!(
+            verify_transaction_response(&response, &canister, 3, &der(&key(2))),
+            Err(VerifyError::InvalidSignature)
+        );
+
+        let mut response = bucket.get_user_transactions(GetUserTransactionsArg {
+            user,
+            page: None,
+            witness: true,
+            roles: None,
+        });
+        response.witness = resign(response.witness, &bucket);
+        let index = TransactionsIndex::User(&user);
+        assert_eq!(
+            verify_transactions_response(&response, &canister, index, None, &root_key),
+            Ok(())
+        );
+        assert_eq!(
+            verify_transactions_response(&response, &canister, index, Some(1), &root_key),
+            Ok(())
+        );
+        assert_eq!(
+            verify_transactions_response(&response, &canister, index, Some(0), &root_key),
+            Err(VerifyError::PageMismatch)
+        );
+
+        let mut response = bucket.get_transactions_in_range(GetTransactionsInRangeArg {
+            from_ms: 0,
+            to_ms: 10,
+            cursor: None,
+            witness: true,
+        });
+        response.witness = resign(response.witness, &bucket);
+        assert_eq!(
+            verify_transactions_in_range_response(&response, &canister, 0, 10, None, &root_key),
+            Ok(())
+        );
+        assert_eq!(
+            verify_transactions_in_range_response(&response, &canister, 0, 9, None, &root_key),
+            Err(VerifyError::DataMismatch)
+        );
+
+        let mut response = bucket.get_next_canisters(WithWitnessArg { witness: true });
+        response.witness = resign(response.witness, &bucket);
+        assert_eq!(
+            verify_next_canisters_response(&response, &canister, &root_key),
+            Ok(())
+        );
+        response.canisters.push(mock_principals::bob());
+        assert_eq!(
+            verify_next_canisters_response(&response, &canister, &root_key),
+            Err(VerifyError::DataMismatch)
+        );
+        response.witness = None;
+        assert_eq!(
+            verify_next_canisters_response(&response, &canister, &root_key),
+            Err(VerifyError::MissingWitness)
+        );
+    }
+
+    #[test]
+    fn test_router_responses() {
+        use certified_vars::{Map, Seq};
+
+        let router = mock_principals::xtc();
+        let root_key = der(&key(1));
+        let mut root_buckets = Map::<Principal, Principal>::new();
+        let mut user_canisters = Map::<Principal, Seq<Principal>>::new();
+        let mut index_canisters = Seq::<Principal>::new();
+        root_buckets.insert(mock_principals::xtc(), mock_principals::alice());
+        let mut seq = Seq::new();
+        seq.append(mock_principals::alice());
+        user_canisters.insert(mock_principals::bob(), seq);
+        index_canisters.append(router);
+
+        let certified_data = fork_hash(
+            &fork_hash(&root_buckets.root_hash(), &user_canisters.root_hash()),
+            &index_canisters.root_hash(),
+        );
+        let witness = |tree: HashTree| {
+            Some(Witness {
+                certificate: certificate(&key(1), &router, &certified_data),
+                tree: serde_cbor::to_vec(&tree).unwrap(),
+            })
+        };
+
+        let contract = mock_principals::xtc();
+        let mut response = GetTokenContractRootBucketResponse {
+            canister: Some(mock_principals::alice()),
+            witness: witness(fork(
+                fork(
+                    root_buckets.witness(&contract),
+                    HashTree::Pruned(user_canisters.root_hash()),
+                ),
+                HashTree::Pruned(index_canisters.root_hash()),
+            )),
+        };
+        assert_eq!(
+            verify_token_contract_root_bucket_response(&response, &router, &contract, &root_key),
+            Ok(())
+        );
+        response.canister = None;
+        assert_eq!(
+            verify_token_contract_root_bucket_response(&response, &router, &contract, &root_key),
+            Err(VerifyError::DataMismatch)
+        );
+
+        let user = mock_principals::bob();
+        let mut response = GetUserRootBucketsResponse {
+            contracts: vec![mock_principals::alice()],
+            witness: witness(fork(
+                fork(
+                    HashTree::Pruned(root_buckets.root_hash()),
+                    user_canisters.witness(&user),
+                ),
+                HashTree::Pruned(index_canisters.root_hash()),
+            )),
+        };
+        assert_eq!(
+            verify_user_root_buckets_response(&response, &router, &user, &root_key),
+            Ok(())
+        );
+        response.contracts.clear();
+        assert_eq!(
+            verify_user_root_buckets_response(&response, &router, &user, &root_key),
+            Err(VerifyError::DataMismatch)
+        );
+
+        let response = GetIndexCanistersResponse {
+            canisters: vec![router],
+            witness: witness(fork(
+                HashTree::Pruned(fork_hash(
+                    &root_buckets.root_hash(),
+                    &user_canisters.root_hash(),
+                )),
+                index_canisters.as_hash_tree(),
+            )),
+        };
+        assert_eq!(
+            verify_index_canisters_response(&response, &router, &root_key),
+            Ok(())
+        );
+        assert_eq!(
+            verify_index_canisters_response(&response, &mock_principals::bob(), &root_key),
+            Err(VerifyError::MissingCertifiedData)
+        );
+    }
+
+    #[test]
+    fn test_ic_root_key() {
+        assert!(IC_ROOT_KEY.starts_with(DER_PREFIX));
+    }
+}
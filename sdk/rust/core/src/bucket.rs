@@ -2,9 +2,13 @@ use ic_kit::ic::call;
 use ic_kit::{Principal, RejectionCode};
 
 use crate::root::RootBucket;
+use cap_common::transaction::{Event, TokenId};
 use cap_common::{
-    GetIndexCanistersResponse, GetTransactionResponse, GetTransactionsArg, GetTransactionsResponse,
-    GetUserTransactionsArg, WithIdArg, WithWitnessArg,
+    Direction, GetIndexCanistersResponse, GetTokenTransactionsArg, GetTokenTransactionsCursorArg,
+    GetTransactionResponse, GetTransactionsArg, GetTransactionsCursorArg,
+    GetTransactionsCursorResponse, GetTransactionsInRangeArg, GetTransactionsInRangeResponse,
+    GetTransactionsResponse, GetUserTransactionsArg, GetUserTransactionsCursorArg,
+    GetWriterTransactionsArg, TransactionId, WithIdArg, WithWitnessArg,
 };
 
 /// A contract-specific bucket canister.
@@ -12,8 +16,11 @@ use cap_common::{
 /// A bucket canister implements storage for its parent contract. The total storage for a given
 /// contract is created using multiple bucket canisters, which are interconnected using a root bucket
 /// and router system. Querying buckets also features pagination.
-#[derive(Copy, Clone)]
-pub struct Bucket(pub(crate) Principal);
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Bucket(pub Principal);
+
+/// The maximum number of `Delegate` responses followed by [`Bucket::find_transaction`].
+const MAX_DELEGATIONS: usize = 16;
 
 impl Bucket {
     /// Returns the list of canisters which have different pages of data.
@@ -48,6 +55,37 @@ impl Bucket {
         Ok(result.0)
     }
 
+    /// Returns the transaction with the given id along with the bucket holding it, following
+    /// the `Delegate` responses from one bucket to the next.
+    pub async fn find_transaction(
+        &self,
+        id: u64,
+    ) -> Result<(Bucket, Option<Event>), (RejectionCode, String)> {
+        let mut bucket = *self;
+
+        for _ in 0..=MAX_DELEGATIONS {
+            match bucket.get_transaction(id).await? {
+                GetTransactionResponse::Found(event, _) => return Ok((bucket, event)),
+                GetTransactionResponse::Delegate(next, _) => bucket = Bucket(next),
+            }
+        }
+
+        Err((
+            RejectionCode::CanisterError,
+            format!(
+                "Transaction {} was delegated more than {} times.",
+                id, MAX_DELEGATIONS
+            ),
+        ))
+    }
+
+    /// Returns the id after the last transaction in this bucket.
+    pub async fn size(&self) -> Result<u64, (RejectionCode, String)> {
+        let result: (u64,) = call(self.0, "size", ()).await?;
+
+        Ok(result.0)
+    }
+
     /// Returns all of the transactions for this contract.
     pub async fn get_transactions(
         &self,
@@ -79,6 +117,173 @@ impl Bucket {
                 user,
                 page,
                 witness: false,
+                roles: None,
+            },),
+        )
+        .await?;
+
+        Ok(result.0)
+    }
+
+    /// Returns the transactions in which the given user has one of the roles, the roles are
+    /// the detail keys the user appears under, or `caller` for the caller of the events.
+    pub async fn get_user_transactions_with_roles(
+        &self,
+        user: Principal,
+        roles: Vec<String>,
+        page: Option<u32>,
+    ) -> Result<GetTransactionsResponse, (RejectionCode, String)> {
+        let result: (GetTransactionsResponse,) = call(
+            self.0,
+            "get_user_transactions",
+            (GetUserTransactionsArg {
+                user,
+                page,
+                witness: false,
+                roles: Some(roles),
+            },),
+        )
+        .await?;
+
+        Ok(result.0)
+    }
+
+    /// Returns the transactions inserted by the given canister, either the token contract or
+    /// one of its writers.
+    pub async fn get_writer_transactions(
+        &self,
+        writer: Principal,
+        page: Option<u32>,
+    ) -> Result<GetTransactionsResponse, (RejectionCode, String)> {
+        let result: (GetTransactionsResponse,) = call(
+            self.0,
+            "get_writer_transactions",
+            (GetWriterTransactionsArg {
+                writer,
+                page,
+                witness: false,
+            },),
+        )
+        .await?;
+
+        Ok(result.0)
+    }
+
+    /// Returns all of the transactions associated with the given token.
+    pub async fn get_token_transactions(
+        &self,
+        token: TokenId,
+        page: Option<u32>,
+    ) -> Result<GetTransactionsResponse, (RejectionCode, String)> {
+        let result: (GetTransactionsResponse,) = call(
+            self.0,
+            "get_token_transactions",
+            (GetTokenTransactionsArg {
+                token_id: legacy_token_id(&token),
+                page,
+                witness: false,
+                token: Some(token),
+            },),
+        )
+        .await?;
+
+        Ok(result.0)
+    }
+
+    /// Returns at most `limit` transactions for this contract, starting at the transaction
+    /// with the `cursor` id and walking in the given direction. If `max_bytes` is set the
+    /// bucket stops adding transactions to the response once their size exceeds it.
+    pub async fn get_transactions_cursor(
+        &self,
+        cursor: Option<TransactionId>,
+        direction: Direction,
+        limit: u32,
+        max_bytes: Option<u32>,
+    ) -> Result<GetTransactionsCursorResponse, (RejectionCode, String)> {
+        let result: (GetTransactionsCursorResponse,) = call(
+            self.0,
+            "get_transactions_cursor",
+            (GetTransactionsCursorArg {
+                cursor,
+                direction,
+                limit,
+                max_bytes,
+            },),
+        )
+        .await?;
+
+        Ok(result.0)
+    }
+
+    /// Like [`Bucket::get_transactions_cursor`] for the transactions of the given user.
+    pub async fn get_user_transactions_cursor(
+        &self,
+        user: Principal,
+        cursor: Option<TransactionId>,
+        direction: Direction,
+        limit: u32,
+        max_bytes: Option<u32>,
+    ) -> Result<GetTransactionsCursorResponse, (RejectionCode, String)> {
+        let result: (GetTransactionsCursorResponse,) = call(
+            self.0,
+            "get_user_transactions_cursor",
+            (GetUserTransactionsCursorArg {
+                user,
+                cursor,
+                direction,
+                limit,
+                max_bytes,
+                roles: None,
+            },),
+        )
+        .await?;
+
+        Ok(result.0)
+    }
+
+    /// Like [`Bucket::get_transactions_cursor`] for the transactions of the given token.
+    pub async fn get_token_transactions_cursor(
+        &self,
+        token: TokenId,
+        cursor: Option<TransactionId>,
+        direction: Direction,
+        limit: u32,
+        max_bytes: Option<u32>,
+    ) -> Result<GetTransactionsCursorResponse, (RejectionCode, String)> {
+        let result: (GetTransactionsCursorResponse,) = call(
+            self.0,
+            "get_token_transactions_cursor",
+            (GetTokenTransactionsCursorArg {
+                token_id: legacy_token_id(&token),
+                cursor,
+                direction,
+                limit,
+                max_bytes,
+                token: Some(token),
+            },),
+        )
+        .await?;
+
+        Ok(result.0)
+    }
+
+    /// Returns the transactions with a time in the inclusive range `from_ms..=to_ms`, ordered by
+    /// their time. The `next_cursor` of the response is passed as the cursor to read the next
+    /// page.
+    pub async fn get_transactions_in_range(
+        &self,
+        from_ms: u64,
+        to_ms: u64,
+        cursor: Option<TransactionId>,
+    ) -> Result<GetTransactionsInRangeResponse, (RejectionCode, String)> {
+        let result: (GetTransactionsInRangeResponse,) = call(
+            self.0,
+            "get_transactions_in_range",
+            (GetTransactionsInRangeArg {
+                from_ms,
+                to_ms,
+                cursor,
+                witness: false,
             },),
         )
         .await?;
@@ -92,3 +297,13 @@ impl From<RootBucket> for Bucket {
         Bucket(root.0)
     }
 }
+
+/// The `token_id` sent along with a token, so buckets which only support `u64` ids still answer
+/// the queries for those tokens.
+#[inline]
+fn legacy_token_id(token: &TokenId) -> u64 {
+    match token {
+        TokenId::U64(id) => *id,
+        _ => 0,
+    }
+}
@@ -6,14 +6,18 @@
 use crate::root::RootBucket;
 use crate::router::Router;
 use cap_common::{
-    GetIndexCanistersResponse, GetTokenContractRootBucketArg, GetTokenContractRootBucketResponse,
-    GetUserRootBucketsArg, GetUserRootBucketsResponse, WithWitnessArg,
+    index_canister_for, GetIndexCanistersResponse, GetTokenContractRootBucketArg,
+    GetTokenContractRootBucketResponse, GetUserRootBucketsArg, GetUserRootBucketsResponse,
+    WithWitnessArg,
 };
 use ic_kit::ic::call;
 use ic_kit::{Principal, RejectionCode};
 use thiserror::Error;
 
 /// A Cap index canister.
+///
+/// The router is the index of the token contracts, the users are sharded over the index
+/// canisters it lists, see [`Index::get_index_canister_for`].
 #[derive(Clone)]
 pub struct Index(Principal);
 
@@ -46,13 +50,38 @@ impl Index {
         }
     }
 
-    /// Returns all roots for contracts a user has transactions on.
+    /// Returns the index canisters the users are sharded over, an empty list means the router
+    /// is the only index canister.
+    pub async fn get_index_canisters(&self) -> Result<Vec<Index>, (RejectionCode, String)> {
+        let result: (GetIndexCanistersResponse,) = call(
+            self.0,
+            "get_index_canisters",
+            (WithWitnessArg { witness: false },),
+        )
+        .await?;
+
+        Ok(result.0.canisters.into_iter().map(Index).collect())
+    }
+
+    /// Returns the index canister which holds the root buckets of the given user, as listed by
+    /// the router's `get_index_canisters`.
+    pub async fn get_index_canister_for(
+        &self,
+        user: Principal,
+    ) -> Result<Index, (RejectionCode, String)> {
+        let canisters = self.get_index_canisters().await?;
+        Ok(self.shard_for(&canisters, &user))
+    }
+
+    /// Returns all roots for contracts a user has transactions on, from the index canister of
+    /// the user.
     pub async fn get_user_root_buckets(
         &self,
         user: Principal,
     ) -> Result<Vec<RootBucket>, (RejectionCode, String)> {
+        let index = self.get_index_canister_for(user).await?;
         let result: (GetUserRootBucketsResponse,) = call(
-            self.0,
+            index.0,
             "get_user_root_buckets",
             (GetUserRootBucketsArg {
                 user,
@@ -87,6 +116,16 @@ impl Index {
     }
 }
 
+impl Index {
+    /// Return the index canister of the user among the given ones, this canister when the list
+    /// is empty.
+    #[inline]
+    fn shard_for(&self, canisters: &[Index], user: &Principal) -> Index {
+        let canisters = canisters.iter().map(|index| index.0).collect::<Vec<_>>();
+        Index(index_canister_for(&canisters, user).unwrap_or(self.0))
+    }
+}
+
 impl From<Router> for Index {
     fn from(router: Router) -> Self {
         Index(router.0)
@@ -4,8 +4,13 @@
 //! [`RootBucket`].
 
 use crate::Bucket;
+use cap_common::mmr::{ConsistencyProof, Tip};
 use cap_common::transaction::IndefiniteEvent;
-use cap_common::{GetBucketResponse, WithIdArg};
+use cap_common::{
+    GetBucketResponse, GetConsistencyProofArg, GetConsistencyProofResponse, GetTipResponse,
+    GetTransactionIdByKeyArg, GetTransactionIdByKeyResponse, GetWritersResponse, InsertError,
+    Replica, WithIdArg, WithWitnessArg,
+};
 use ic_kit::candid::CandidType;
 use ic_kit::{ic::call, Principal, RejectionCode};
 use serde::{Deserialize, Serialize};
@@ -34,23 +39,124 @@ impl RootBucket {
         Ok(Bucket(result.0.canister))
     }
 
+    /// Returns the read replicas of the root bucket, along with the number of transactions
+    /// copied to each one of them.
+    ///
+    /// A replica implements the same interface as [`Bucket`].
+    pub async fn get_replicas(&self) -> Result<Vec<Replica>, (RejectionCode, String)> {
+        let result: (Vec<Replica>,) = call(self.0, "get_replicas", ()).await?;
+
+        Ok(result.0)
+    }
+
     /// Inserts the given transaction and returns it's issued transaction ID.
-    pub async fn insert(&self, event: &IndefiniteEvent) -> Result<u64, (RejectionCode, String)> {
-        let result: (u64,) = call(self.0, "insert", (event,)).await?;
+    pub async fn insert(
+        &self,
+        event: &IndefiniteEvent,
+    ) -> Result<Result<u64, InsertError>, (RejectionCode, String)> {
+        let result: (Result<u64, InsertError>,) = call(self.0, "insert", (event,)).await?;
 
         Ok(result.0)
     }
 
-    /// Inserts the given transactions.
+    /// Inserts the given transactions, none of them is inserted if one of them is invalid.
+    ///
+    /// Returns the id of the first transaction that was inserted, the transactions inserted by
+    /// the call have contiguous ids starting at it. Transactions replayed with a recently used
+    /// idempotency key are not inserted again, if none of them is new the id of the original
+    /// transaction of the first one is returned.
     pub async fn insert_many(
         &self,
         events: &[IndefiniteEvent],
-    ) -> Result<u64, (RejectionCode, String)> {
-        let result: (u64,) = call(self.0, "insert_many", (events,)).await?;
+    ) -> Result<Result<u64, InsertError>, (RejectionCode, String)> {
+        let result: (Result<u64, InsertError>,) = call(self.0, "insert_many", (events,)).await?;
+
+        Ok(result.0)
+    }
+
+    /// Runs the checks of [`RootBucket::insert_many`] without inserting the transactions.
+    pub async fn validate_events(
+        &self,
+        events: &[IndefiniteEvent],
+    ) -> Result<Result<(), InsertError>, (RejectionCode, String)> {
+        let result: (Result<(), InsertError>,) = call(self.0, "validate_events", (events,)).await?;
 
         Ok(result.0)
     }
 
+    /// Returns the id of the transaction inserted with the given idempotency key, if the key
+    /// was used recently.
+    pub async fn get_transaction_id_by_key(
+        &self,
+        key: String,
+    ) -> Result<Option<u64>, (RejectionCode, String)> {
+        let result: (GetTransactionIdByKeyResponse,) = call(
+            self.0,
+            "get_transaction_id_by_key",
+            (GetTransactionIdByKeyArg {
+                key,
+                witness: false,
+            },),
+        )
+        .await?;
+
+        Ok(result.0.id)
+    }
+
+    /// Returns the canisters other than the token contract which are allowed to insert
+    /// transactions.
+    pub async fn list_writers(&self) -> Result<Vec<Principal>, (RejectionCode, String)> {
+        let result: (GetWritersResponse,) =
+            call(self.0, "list_writers", (WithWitnessArg { witness: false },)).await?;
+
+        Ok(result.0.writers)
+    }
+
+    /// Allows the given canister to insert transactions, returns `false` if it already could.
+    ///
+    /// Only the token contract can call this method.
+    pub async fn add_writer(&self, writer: Principal) -> Result<bool, (RejectionCode, String)> {
+        let result: (bool,) = call(self.0, "add_writer", (writer,)).await?;
+
+        Ok(result.0)
+    }
+
+    /// Stops accepting transactions from the given canister, returns `false` if it was not
+    /// a writer.
+    ///
+    /// Only the token contract can call this method.
+    pub async fn remove_writer(&self, writer: Principal) -> Result<bool, (RejectionCode, String)> {
+        let result: (bool,) = call(self.0, "remove_writer", (writer,)).await?;
+
+        Ok(result.0)
+    }
+
+    /// Returns the tip of the accumulator over the hashes of all of the transactions of the
+    /// token contract.
+    pub async fn get_tip(&self) -> Result<Tip, (RejectionCode, String)> {
+        let result: (GetTipResponse,) =
+            call(self.0, "get_tip", (WithWitnessArg { witness: false },)).await?;
+
+        Ok(result.0.tip)
+    }
+
+    /// Returns a proof that the history at `old_size` is a prefix of the history at
+    /// `new_size`, see [`ConsistencyProof::verify`].
+    pub async fn get_consistency_proof(
+        &self,
+        old_size: u64,
+        new_size: u64,
+    ) -> Result<Option<ConsistencyProof>, (RejectionCode, String)> {
+        let result: (GetConsistencyProofResponse,) = call(
+            self.0,
+            "get_consistency_proof",
+            (GetConsistencyProofArg { old_size, new_size },),
+        )
+        .await?;
+
+        Ok(result.0.proof)
+    }
+
     /// The time on the canister.
     ///
     /// The time can be used to check if this bucket is on the same subnet as the caller.
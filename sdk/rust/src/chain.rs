@@ -0,0 +1,425 @@
+//! Walking the chain of buckets that holds the history of the contract.
+//!
+//! The root bucket always holds the newest transactions, once it is full its transactions are
+//! moved to a new bucket which becomes the next canister of the root. The buckets after the
+//! root never change, so what is learned about them is cached.
+//!
+//! Transactions are read from the read replicas of the root when there are any, the root is
+//! only asked when none of the replicas is synced far enough.
+
+use std::collections::{BTreeMap, HashMap};
+
+use cap_sdk_core::transaction::{Event, TokenId};
+use cap_sdk_core::{Bucket, Replica, RootBucket};
+use ic_kit::ic::{time, with, with_mut};
+use ic_kit::{Principal, RejectionCode};
+
+use crate::{GetTransactionsResponse, TransactionId};
+
+/// The maximum number of buckets walked after the root, in case the chain contains a cycle.
+const MAX_CHAIN_LENGTH: usize = 64;
+
+/// A paginated index of the buckets.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum PageIndex {
+    /// The transactions of the contract, returned by `get_transactions`.
+    Contract,
+    /// The transactions of a user, returned by `get_user_transactions`.
+    User(Principal),
+    /// The transactions of a token, returned by `get_token_transactions`.
+    Token(TokenId),
+}
+
+/// What is known about the buckets after the root.
+#[derive(Default)]
+struct BucketCache {
+    /// The buckets after the root, from the newest to the oldest.
+    chain: Vec<Bucket>,
+    /// The range of ids `[start, end)` each bucket is known to hold, keyed by `start`.
+    ranges: BTreeMap<TransactionId, (TransactionId, Bucket)>,
+    /// The `total_items` and `last_page` of each index of the buckets.
+    pages: HashMap<(Bucket, PageIndex), (u64, u32)>,
+    /// The read replicas of the root, as last returned by the root.
+    replicas: Vec<Replica>,
+}
+
+/// Returns the buckets after the root, from the newest to the oldest.
+pub(crate) async fn next_buckets(root: RootBucket) -> Result<Vec<Bucket>, (RejectionCode, String)> {
+    let first = match Bucket::from(root).get_next_canisters().await?.first() {
+        Some(bucket) => *bucket,
+        None => return Ok(vec![]),
+    };
+
+    if let Some(chain) = with(|cache: &BucketCache| {
+        Some(cache.chain.clone()).filter(|chain| chain.first() == Some(&first))
+    }) {
+        return Ok(chain);
+    }
+
+    let mut chain = vec![first];
+    while chain.len() < MAX_CHAIN_LENGTH {
+        let next = chain.last().unwrap().get_next_canisters().await?;
+        match next.first() {
+            Some(bucket) if !chain.contains(bucket) => chain.push(*bucket),
+            _ => break,
+        }
+    }
+
+    with_mut(|cache: &mut BucketCache| cache.chain = chain.clone());
+    Ok(chain)
+}
+
+/// Returns the transaction with the given id from the bucket holding it.
+pub(crate) async fn find_transaction(
+    root: RootBucket,
+    id: TransactionId,
+) -> Result<Option<Event>, (RejectionCode, String)> {
+    let cached = with(|cache: &BucketCache| {
+        cache
+            .ranges
+            .range(..=id)
+            .next_back()
+            .filter(|(_, (end, _))| id < *end)
+            .map(|(_, (_, bucket))| *bucket)
+    });
+
+    let (holder, event) = match cached {
+        Some(bucket) => bucket.find_transaction(id).await?,
+        None => match replica_for(root, id).await? {
+            Some(replica) => match replica.find_transaction(id).await? {
+                // The replica is behind what the root reported, ask the root instead.
+                (_, None) => find_from_root(root, id).await?,
+                found => found,
+            },
+            None => find_from_root(root, id).await?,
+        },
+    };
+
+    if event.is_some() && holder != Bucket::from(root) && !is_replica(holder) {
+        learn_range(holder, id).await?;
+    }
+
+    Ok(event)
+}
+
+async fn find_from_root(
+    root: RootBucket,
+    id: TransactionId,
+) -> Result<(Bucket, Option<Event>), (RejectionCode, String)> {
+    root.get_bucket_for(id).await?.find_transaction(id).await
+}
+
+/// Returns one of the replicas of the root which is synced past the given id, if any.
+async fn replica_for(
+    root: RootBucket,
+    id: TransactionId,
+) -> Result<Option<Bucket>, (RejectionCode, String)> {
+    let pick = |replicas: &[Replica]| {
+        let synced = replicas
+            .iter()
+            .filter(|replica| id < replica.size)
+            .collect::<Vec<_>>();
+
+        match synced.len() {
+            0 => None,
+            len => Some(Bucket(synced[time() as usize % len].canister)),
+        }
+    };
+
+    if let Some(replica) = with(|cache: &BucketCache| pick(&cache.replicas)) {
+        return Ok(Some(replica));
+    }
+
+    let replicas = root.get_replicas().await?;
+    let replica = pick(&replicas);
+    with_mut(|cache: &mut BucketCache| cache.replicas = replicas);
+    Ok(replica)
+}
+
+#[inline]
+fn is_replica(bucket: Bucket) -> bool {
+    with(|cache: &BucketCache| {
+        cache
+            .replicas
+            .iter()
+            .any(|replica| replica.canister == bucket.0)
+    })
+}
+
+/// Record that the given bucket holds the transaction with the given id.
+async fn learn_range(bucket: Bucket, id: TransactionId) -> Result<(), (RejectionCode, String)> {
+    let known = with_mut(|cache: &mut BucketCache| {
+        let (start, end) = cache
+            .ranges
+            .iter()
+            .find(|(_, (_, b))| *b == bucket)
+            .map(|(start, (end, _))| (*start, *end))?;
+        cache.ranges.remove(&start);
+        Some((start, end))
+    });
+
+    let (start, end) = match known {
+        Some((start, end)) => (start.min(id), end),
+        None => (id, bucket.size().await?),
+    };
+
+    with_mut(|cache: &mut BucketCache| cache.ranges.insert(start, (end, bucket)));
+    Ok(())
+}
+
+async fn query_page(
+    bucket: Bucket,
+    index: &PageIndex,
+    page: Option<u32>,
+) -> Result<cap_sdk_core::GetTransactionsResponse, (RejectionCode, String)> {
+    match index {
+        PageIndex::Contract => bucket.get_transactions(page).await,
+        PageIndex::User(user) => bucket.get_user_transactions(*user, page).await,
+        PageIndex::Token(token) => bucket.get_token_transactions(token.clone(), page).await,
+    }
+}
+
+/// Returns the `total_items` and `last_page` of the index of a bucket after the root.
+async fn page_bounds(
+    bucket: Bucket,
+    index: &PageIndex,
+) -> Result<(u64, u32), (RejectionCode, String)> {
+    let key = (bucket, index.clone());
+    if let Some(bounds) = with(|cache: &BucketCache| cache.pages.get(&key).copied()) {
+        return Ok(bounds);
+    }
+
+    let response = query_page(bucket, index, Some(0)).await?;
+    let bounds = (response.total_items, response.last_page);
+    with_mut(|cache: &mut BucketCache| cache.pages.insert(key, bounds));
+    Ok(bounds)
+}
+
+/// The number of non-empty pages of an index.
+#[inline]
+fn page_count(total_items: u64, last_page: u32) -> u32 {
+    if total_items == 0 {
+        0
+    } else {
+        last_page + 1
+    }
+}
+
+/// Returns a page of the index, where the pages of all of the buckets are numbered from the
+/// oldest bucket to the root. `None` returns the last page.
+pub(crate) async fn get_page(
+    root: RootBucket,
+    index: PageIndex,
+    page: Option<u32>,
+) -> Result<GetTransactionsResponse, (RejectionCode, String)> {
+    // The first page and the number of pages of each bucket after the root, oldest first.
+    let mut buckets = Vec::new();
+    let mut base = 0;
+    let mut total_items = 0;
+
+    for bucket in next_buckets(root).await?.into_iter().rev() {
+        let (items, last_page) = page_bounds(bucket, &index).await?;
+        let pages = page_count(items, last_page);
+        buckets.push((bucket, base, pages));
+        base += pages;
+        total_items += items;
+    }
+
+    let root_page = match page {
+        Some(page) if page >= base => Some(page - base),
+        Some(_) => Some(0),
+        None => None,
+    };
+    let response = query_page(root.into(), &index, root_page).await?;
+    let root_pages = page_count(response.total_items, response.last_page);
+
+    total_items += response.total_items;
+    let last_page = (base + root_pages).saturating_sub(1);
+    let page = match page {
+        Some(page) => page,
+        None if root_pages > 0 => base + response.page,
+        None => last_page,
+    };
+
+    let transactions = if page >= base {
+        response.data
+    } else {
+        let (bucket, first, _) = buckets
+            .iter()
+            .find(|(_, first, pages)| page < first + pages)
+            .expect("The page is before the last bucket.");
+        query_page(*bucket, &index, Some(page - first)).await?.data
+    };
+
+    Ok(GetTransactionsResponse {
+        transactions,
+        page,
+        total_items,
+        last_page,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_sdk_core::{
+        GetBucketResponse, GetIndexCanistersResponse, GetTransactionResponse, GetTransactionsArg,
+        WithIdArg,
+    };
+    use ic_kit::candid::{decode_args, encode_args};
+    use ic_kit::{mock_principals, MockContext, RawHandler};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn e(id: u64) -> Event {
+        Event {
+            time: id,
+            caller: mock_principals::alice(),
+            operation: "transfer".into(),
+            details: vec![],
+        }
+    }
+
+    fn page(ids: std::ops::Range<u64>, page: Option<u32>) -> cap_sdk_core::GetTransactionsResponse {
+        let total_items = ids.end - ids.start;
+        let last_page = (total_items.saturating_sub(1) / 64) as u32;
+        let page = page.unwrap_or(last_page);
+        let start = ids.start + page as u64 * 64;
+        let data = (start..ids.end.min(start + 64)).map(e).collect();
+
+        cap_sdk_core::GetTransactionsResponse {
+            data,
+            page,
+            total_items,
+            last_page,
+            has_more: page < last_page,
+            witness: None,
+        }
+    }
+
+    /// The root holds the transactions `100..150` and the bucket after it `0..100`. If there
+    /// is a replica, it holds `100..130` while the root reports it is synced up to `140`.
+    fn inject(with_replica: bool) -> (RootBucket, Bucket, Rc<Cell<usize>>) {
+        let root = mock_principals::alice();
+        let bucket = mock_principals::bob();
+        let replica = mock_principals::john();
+        let lookups = Rc::new(Cell::new(0));
+        let counter = lookups.clone();
+
+        MockContext::new()
+            .with_handler(RawHandler::raw(Box::new(
+                move |_, args, canister, method| {
+                    let is_root = *canister == root;
+                    let ids = match *canister {
+                        c if c == root => 100..150,
+                        c if c == replica => 100..130,
+                        _ => 0..100,
+                    };
+
+                    Ok(match method {
+                        "get_next_canisters" => encode_args((GetIndexCanistersResponse {
+                            canisters: if is_root { vec![bucket] } else { vec![] },
+                            witness: None,
+                        },)),
+                        "get_bucket_for" => {
+                            counter.set(counter.get() + 1);
+                            encode_args((GetBucketResponse {
+                                canister: root,
+                                witness: None,
+                            },))
+                        }
+                        "get_transaction" => {
+                            let (arg,): (WithIdArg,) = decode_args(args).unwrap();
+                            let response = if arg.id < ids.start {
+                                GetTransactionResponse::Delegate(bucket, None)
+                            } else {
+                                GetTransactionResponse::Found(
+                                    Some(e(arg.id)).filter(|_| arg.id < ids.end),
+                                    None,
+                                )
+                            };
+                            encode_args((response,))
+                        }
+                        "get_transactions" => {
+                            let (arg,): (GetTransactionsArg,) = decode_args(args).unwrap();
+                            encode_args((page(ids, arg.page),))
+                        }
+                        "get_replicas" => encode_args((if with_replica {
+                            vec![Replica {
+                                canister: replica,
+                                size: 140,
+                            }]
+                        } else {
+                            vec![]
+                        },)),
+                        "size" => encode_args((ids.end,)),
+                        _ => panic!("Unexpected call to {}", method),
+                    }
+                    .unwrap())
+                },
+            )))
+            .inject();
+
+        (RootBucket(root), Bucket(bucket), lookups)
+    }
+
+    #[async_std::test]
+    async fn test_find_transaction() {
+        let (root, bucket, lookups) = inject(false);
+
+        assert_eq!(find_transaction(root, 42).await.unwrap(), Some(e(42)));
+        assert_eq!(find_transaction(root, 120).await.unwrap(), Some(e(120)));
+        assert_eq!(find_transaction(root, 10).await.unwrap(), Some(e(10)));
+        assert_eq!(lookups.get(), 3);
+
+        // The range of the bucket is known now, so the root is not asked again.
+        assert_eq!(find_transaction(root, 30).await.unwrap(), Some(e(30)));
+        assert_eq!(lookups.get(), 3);
+        assert_eq!(
+            with(|cache: &BucketCache| cache.ranges.get(&10).copied()),
+            Some((100, bucket))
+        );
+
+        assert_eq!(find_transaction(root, 150).await.unwrap(), None);
+    }
+
+    #[async_std::test]
+    async fn test_find_transaction_from_replica() {
+        let (root, bucket, lookups) = inject(true);
+
+        assert_eq!(find_transaction(root, 120).await.unwrap(), Some(e(120)));
+        assert_eq!(find_transaction(root, 42).await.unwrap(), Some(e(42)));
+        assert_eq!(lookups.get(), 0);
+        assert_eq!(
+            with(|cache: &BucketCache| cache.ranges.get(&42).copied()),
+            Some((100, bucket))
+        );
+
+        // The replica does not have the transaction yet, or is not synced that far.
+        assert_eq!(find_transaction(root, 135).await.unwrap(), Some(e(135)));
+        assert_eq!(find_transaction(root, 145).await.unwrap(), Some(e(145)));
+        assert_eq!(lookups.get(), 2);
+    }
+
+    #[async_std::test]
+    async fn test_get_page() {
+        let (root, _, _) = inject(false);
+
+        // The bucket has two pages, followed by the single page of the root.
+        let last = get_page(root, PageIndex::Contract, None).await.unwrap();
+        assert_eq!((last.page, last.last_page, last.total_items), (2, 2, 150));
+        assert_eq!(last.transactions, (100..150).map(e).collect::<Vec<_>>());
+
+        let first = get_page(root, PageIndex::Contract, Some(0)).await.unwrap();
+        assert_eq!(first.transactions, (0..64).map(e).collect::<Vec<_>>());
+        assert!(first.has_more());
+
+        let second = get_page(root, PageIndex::Contract, Some(1)).await.unwrap();
+        assert_eq!(second.transactions, (64..100).map(e).collect::<Vec<_>>());
+        assert_eq!(second.next_page(), Some(2));
+
+        let after = get_page(root, PageIndex::Contract, Some(3)).await.unwrap();
+        assert!(after.transactions.is_empty());
+        assert!(!after.has_more());
+    }
+}
@@ -37,6 +37,7 @@ pub struct IndefiniteEventBuilder {
     operation: Option<String>,
     details: Vec<(String, DetailValue)>,
     operation_from_event: bool,
+    idempotency_key: Option<String>,
 }
 
 impl IndefiniteEventBuilder {
@@ -92,6 +93,16 @@ impl IndefiniteEventBuilder {
         self
     }
 
+    /// Sets the idempotency key of the event, the event is only inserted once when it is sent
+    /// again with the same key. A key is generated when the event is added to the pending
+    /// buffer if none is set.
+    #[inline(always)]
+    pub fn idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+
+        self
+    }
+
     /// Builds an [`IndefiniteEvent`] from the builder.
     ///
     /// # Panics
@@ -103,6 +114,7 @@ impl IndefiniteEventBuilder {
             caller: self.caller.unwrap(),
             operation: self.operation.unwrap(),
             details: self.details,
+            idempotency_key: self.idempotency_key,
         })
     }
 }
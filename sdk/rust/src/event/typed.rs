@@ -139,6 +139,7 @@ impl<T: TryFromEvent + IntoEvent + Clone> Into<IndefiniteEvent> for TypedIndefin
             caller: self.caller,
             operation: self.details.operation().unwrap_or("").to_owned(),
             details: self.details.details(),
+            idempotency_key: None,
         }
     }
 }
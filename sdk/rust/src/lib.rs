@@ -26,6 +26,8 @@
 pub use env::*;
 mod env;
 
+mod chain;
+
 mod transactions;
 pub use transactions::*;
 
@@ -44,7 +46,11 @@ pub use archive::*;
 mod handshake;
 pub use handshake::*;
 
-pub use cap_sdk_core::transaction::{DetailValue, Event, IndefiniteEvent};
+mod writers;
+pub use writers::*;
+
+pub use cap_sdk_core::transaction::{DetailValue, Event, IndefiniteEvent, TokenId};
+pub use cap_sdk_core::Direction;
 
 type Transaction = Event;
 type TransactionId = u64;
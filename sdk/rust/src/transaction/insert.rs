@@ -1,4 +1,5 @@
 use cap_sdk_core::transaction::IndefiniteEvent;
+use ic_kit::ic;
 use std::cell::RefCell;
 
 use crate::{env::CapEnv, InsertTransactionError, TransactionId};
@@ -6,6 +7,23 @@ use crate::{env::CapEnv, InsertTransactionError, TransactionId};
 thread_local! {
     pub(crate) static PENDING: RefCell<Vec<IndefiniteEvent>> = RefCell::new(vec![]);
     pub(crate) static FLUSH_IN_PROGRESS: RefCell<u32> = RefCell::new(0);
+    static NONCE: RefCell<u64> = const { RefCell::new(0) };
+}
+
+/// Attach an idempotency key to the event if it does not have one, so sending it again after
+/// a lost reply does not insert it twice. The key is made of the id of the canister, the time
+/// and a counter, the time keeps the keys unique after the counter is reset by an upgrade.
+fn with_idempotency_key(mut event: IndefiniteEvent) -> IndefiniteEvent {
+    if event.idempotency_key.is_none() {
+        let nonce = NONCE.with(|n| {
+            let mut n = n.borrow_mut();
+            *n += 1;
+            *n
+        });
+        event.idempotency_key = Some(format!("{}-{}-{}", ic::id(), ic::time(), nonce));
+    }
+
+    event
 }
 
 /// Inserts a transaction into the contract's history.
@@ -77,7 +95,9 @@ pub async fn insert(
 pub async fn insert_many<T: Into<IndefiniteEvent>>(
     events: impl Iterator<Item = T>,
 ) -> Result<TransactionId, InsertTransactionError> {
-    let events = events.map(|x| x.into()).collect::<Vec<_>>();
+    let events = events
+        .map(|x| with_idempotency_key(x.into()))
+        .collect::<Vec<_>>();
 
     let (events, offset) = PENDING.with(|p| {
         let mut r = p.borrow_mut();
@@ -105,15 +125,26 @@ pub async fn insert_many<T: Into<IndefiniteEvent>>(
             .root
             .insert_many(&events.unwrap())
             .await
-            .map_err(|(code, details)| match details.as_str() {
-                "The method can only be invoked by one of the writers." => {
-                    InsertTransactionError::CantWrite
-                }
-                _ => InsertTransactionError::Unexpected(code, details),
-            })
+            .map_err(|(code, details)| InsertTransactionError::Unexpected(code, details))
+            .and_then(|result| result.map_err(InsertTransactionError::from))
     }
 }
 
+/// Check that the transactions would be accepted by [`insert_many`], without inserting them.
+pub async fn validate_many<T: Into<IndefiniteEvent>>(
+    events: impl Iterator<Item = T>,
+) -> Result<(), InsertTransactionError> {
+    let events = events.map(|x| x.into()).collect::<Vec<_>>();
+
+    CapEnv::get()
+        .await
+        .root
+        .validate_events(&events)
+        .await
+        .map_err(|(code, details)| InsertTransactionError::Unexpected(code, details))
+        .and_then(|result| result.map_err(InsertTransactionError::from))
+}
+
 /// Insert a transaction into Cap without needing an await, this method guarantees finality of
 /// the transactions and can handle insertion errors that might happen on the root bucket. (e.g
 /// if the root bucket has gone out of cycles)
@@ -125,7 +156,7 @@ pub async fn insert_many<T: Into<IndefiniteEvent>>(
 /// upgrades. You can use [pending_transactions] and [restore_pending_transactions].
 pub fn insert_sync(event: impl Into<IndefiniteEvent>) {
     PENDING.with(|p| {
-        p.borrow_mut().push(event.into());
+        p.borrow_mut().push(with_idempotency_key(event.into()));
     });
 
     ic_cdk::spawn(async {
@@ -136,7 +167,8 @@ pub fn insert_sync(event: impl Into<IndefiniteEvent>) {
 /// Like [insert_sync], but allows you to insert more than one transaction at a time.
 pub fn insert_many_sync<T: Into<IndefiniteEvent>>(events: impl Iterator<Item = T>) {
     PENDING.with(|p| {
-        p.borrow_mut().extend(events.map(|e| e.into()));
+        p.borrow_mut()
+            .extend(events.map(|e| with_idempotency_key(e.into())));
     });
 
     ic_cdk::spawn(async {
@@ -156,7 +188,15 @@ pub fn pending_transactions() -> Vec<IndefiniteEvent> {
 }
 
 /// Restore the transactions, it keeps the previous pending transactions as well.
-pub fn restore_pending_transactions(mut events: Vec<IndefiniteEvent>) {
+///
+/// The events keep their idempotency keys, so the ones which were inserted before the upgrade
+/// are not inserted again.
+pub fn restore_pending_transactions(events: Vec<IndefiniteEvent>) {
+    let mut events = events
+        .into_iter()
+        .map(with_idempotency_key)
+        .collect::<Vec<_>>();
+
     PENDING.with(|p| {
         events.extend(p.take());
         p.replace(events);
@@ -174,12 +214,8 @@ pub async fn flush_to_cap() -> Result<TransactionId, InsertTransactionError> {
         .root
         .insert_many(&events)
         .await
-        .map_err(|(code, details)| match details.as_str() {
-            "The method can only be invoked by one of the writers." => {
-                InsertTransactionError::CantWrite
-            }
-            _ => InsertTransactionError::Unexpected(code, details),
-        })
+        .map_err(|(code, details)| InsertTransactionError::Unexpected(code, details))
+        .and_then(|result| result.map_err(InsertTransactionError::from))
         .map_err(|e| {
             // TODO(qti3e) Is ordering preserved this way?
             // need to be double checked.
@@ -208,6 +244,7 @@ mod test {
             caller: Principal::anonymous(),
             operation: x.to_string(),
             details: vec![],
+            idempotency_key: None,
         }
     }
 
@@ -233,4 +270,44 @@ mod test {
             .collect::<Vec<_>>();
         assert_eq!(tx, vec!["A".to_string(), "C".to_string()])
     }
+
+    #[async_std::test]
+    async fn pending_idempotency_keys() {
+        use crate::{archive, from_archive, Archive};
+        use ic_kit::candid::{decode_one, encode_one};
+
+        MockContext::new()
+            .with_handler(RawHandler::raw(Box::new(move |_, _, _, _| {
+                Err((ic_kit::RejectionCode::CanisterError, "X".into()))
+            })))
+            .with_data(CapEnv {
+                root: RootBucket(Principal::anonymous()),
+                router: Router(Principal::anonymous()),
+            })
+            .inject();
+
+        let mut keyed = t("B");
+        keyed.idempotency_key = Some("b".into());
+        insert_sync(t("A"));
+        insert_sync(keyed);
+        insert_sync(t("C"));
+
+        let keys = pending_transactions()
+            .into_iter()
+            .map(|x| x.idempotency_key.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(keys[1], "b");
+        assert_ne!(keys[0], keys[2]);
+
+        // The keys are kept when the pending transactions are archived during an upgrade.
+        let archived: Archive = decode_one(&encode_one(archive()).unwrap()).unwrap();
+        PENDING.with(|p| p.take());
+        from_archive(archived);
+
+        let restored = pending_transactions()
+            .into_iter()
+            .map(|x| x.idempotency_key.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(restored, keys);
+    }
 }
@@ -1,3 +1,5 @@
+use cap_sdk_core::transaction::EventError;
+use cap_sdk_core::InsertError;
 use ic_kit::RejectionCode;
 use thiserror::Error;
 
@@ -29,4 +31,19 @@ pub enum InsertTransactionError {
     CantWrite,
     #[error("no transaction found with the given id")]
     InvalidId,
+    /// Returned when one of the events is rejected by the root canister, none of the events
+    /// are inserted.
+    #[error("the event at index {index} is invalid: {error:?}")]
+    InvalidEvent { index: u64, error: EventError },
+}
+
+impl From<InsertError> for InsertTransactionError {
+    fn from(error: InsertError) -> Self {
+        match error {
+            InsertError::NotAuthorized => InsertTransactionError::CantWrite,
+            InsertError::InvalidEvent { index, error } => {
+                InsertTransactionError::InvalidEvent { index, error }
+            }
+        }
+    }
 }
@@ -1,12 +1,10 @@
-use cap_sdk_core::GetTransactionResponse;
-
+use crate::chain::find_transaction;
 use crate::{CapEnv, GetTransactionError, Transaction, TransactionId};
 
 /// Gets the transaction with the given id.
 ///
-/// # Panics
-/// Panics if cap is using a multi-canister system, as it
-/// is currently unsupported. In this **alpha** release.
+/// The transaction is read from the bucket holding it, `Delegate` responses are followed and
+/// the range of ids held by each bucket is cached.
 ///
 /// # Examples
 /// ### Query an event and use [`TypedEvent`] to make it easy to work with.
@@ -22,25 +20,8 @@ use crate::{CapEnv, GetTransactionError, Transaction, TransactionId};
 pub async fn get_transaction(id: TransactionId) -> Result<Transaction, GetTransactionError> {
     let context = CapEnv::get().await;
 
-    let bucket = context
-        .root
-        .get_bucket_for(id)
-        .await
-        .map_err(|(code, details)| GetTransactionError::Unexpected(code, details))?;
-
-    let transaction = bucket
-        .get_transaction(id)
+    find_transaction(context.root, id)
         .await
-        .map_err(|(code, details)| GetTransactionError::Unexpected(code, details))?;
-
-    if let GetTransactionResponse::Found(event, _) = transaction {
-        if let Some(event) = event {
-            Ok(event)
-        } else {
-            Err(GetTransactionError::InvalidId)
-        }
-    } else {
-        // TODO: Delegate
-        unimplemented!("This version of cap-sdk does not support multi-canister.")
-    }
+        .map_err(|(code, details)| GetTransactionError::Unexpected(code, details))?
+        .ok_or(GetTransactionError::InvalidId)
 }
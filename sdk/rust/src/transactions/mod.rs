@@ -7,16 +7,24 @@ mod query;
 pub use query::get_transaction_page;
 
 mod stream;
-pub use stream::{get_transactions, get_user_transactions};
+pub use stream::{
+    get_token_transactions, get_token_transactions_by_cursor, get_transactions,
+    get_transactions_by_cursor, get_user_transactions, get_user_transactions_by_cursor,
+};
 
 mod user_query;
 pub use user_query::get_user_transactions_page;
 
+mod token_query;
+pub use token_query::get_token_transactions_page;
+
 /// The response given from a [`get_transactions`] call.
 #[derive(Debug, Clone)]
 pub struct GetTransactionsResponse {
-    transactions: Vec<Transaction>,
-    next_page: u32,
+    pub(crate) transactions: Vec<Transaction>,
+    pub(crate) page: u32,
+    pub(crate) total_items: u64,
+    pub(crate) last_page: u32,
 }
 
 impl GetTransactionsResponse {
@@ -26,10 +34,38 @@ impl GetTransactionsResponse {
         &self.transactions
     }
 
-    /// Returns the next page number.
+    /// Returns the number of this page.
+    #[inline(always)]
+    pub fn page(&self) -> u32 {
+        self.page
+    }
+
+    /// Returns the next page number, or `None` if this is the last page.
+    #[inline(always)]
+    pub fn next_page(&self) -> Option<u32> {
+        if self.has_more() {
+            Some(self.page + 1)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of the last page.
+    #[inline(always)]
+    pub fn last_page(&self) -> u32 {
+        self.last_page
+    }
+
+    /// Returns whether there are pages after this one.
+    #[inline(always)]
+    pub fn has_more(&self) -> bool {
+        self.page < self.last_page
+    }
+
+    /// Returns the number of transactions in all of the pages.
     #[inline(always)]
-    pub fn next_page(&self) -> u32 {
-        self.next_page
+    pub fn total_items(&self) -> u64 {
+        self.total_items
     }
 
     /// Converts a [`GetTransactionsResponse`] to the transactions within it.
@@ -57,9 +93,10 @@ impl AsTransactionsPage for Option<u32> {
     }
 }
 
+/// Requests the page after the response, which is empty once there are no more pages.
 impl AsTransactionsPage for &GetTransactionsResponse {
     fn page(self) -> Option<u32> {
-        Some(self.next_page)
+        Some(self.page + 1)
     }
 }
 
@@ -1,5 +1,4 @@
-use cap_sdk_core::Bucket;
-
+use crate::chain::{get_page, PageIndex};
 use crate::{AsTransactionsPage, CapEnv, GetTransactionsError, GetTransactionsResponse};
 
 /// Gets a transaction for the given page.
@@ -11,9 +10,8 @@ use crate::{AsTransactionsPage, CapEnv, GetTransactionsError, GetTransactionsRes
 /// This allows you to query for the next page from a response, as well as
 /// any given page.
 ///
-/// # Panics
-/// Panics if cap is using a multi-canister system, as it
-/// is currently unsupported. In this **alpha** release.
+/// The pages of all of the buckets holding the history of the contract are numbered from the
+/// oldest bucket to the root bucket, so page `0` always holds the oldest transactions.
 ///
 /// # Examples
 /// TODO
@@ -22,15 +20,7 @@ pub async fn get_transaction_page(
 ) -> Result<GetTransactionsResponse, GetTransactionsError> {
     let context = CapEnv::get().await;
 
-    let bucket: Bucket = context.root.into();
-
-    let transactions = bucket
-        .get_transactions(page.page())
+    get_page(context.root, PageIndex::Contract, page.page())
         .await
-        .map_err(|(code, details)| GetTransactionsError::Unexpected(code, details))?;
-
-    Ok(GetTransactionsResponse {
-        transactions: transactions.data,
-        next_page: transactions.page + 1,
-    })
+        .map_err(|(code, details)| GetTransactionsError::Unexpected(code, details))
 }
@@ -1,10 +1,20 @@
 use async_stream::try_stream;
-use cap_sdk_core::Bucket;
+use cap_sdk_core::{Bucket, Direction, RootBucket};
 use futures::Stream;
-use ic_kit::Principal;
+use ic_kit::{Principal, RejectionCode};
 
-use crate::{CapEnv, GetTransactionsError, Transaction};
+use crate::chain::{get_page, next_buckets, PageIndex};
+use crate::{CapEnv, GetTransactionsError, TokenId, Transaction, TransactionId};
 
+/// The number of transactions requested at once by the cursor streams.
+const CURSOR_LIMIT: u32 = 64;
+
+/// The size limit requested for each response of the cursor streams, leaving enough room
+/// under the message size limit for the encoding overhead.
+const CURSOR_MAX_BYTES: u32 = 1_500_000;
+
+/// Streams the transactions in the given range of pages, the pages are numbered across all of
+/// the buckets like in [`get_transaction_page`](crate::get_transaction_page).
 pub async fn get_transactions(
     start_page: u32,
     end_page: u32,
@@ -12,22 +22,26 @@ pub async fn get_transactions(
     try_stream! {
         let context = CapEnv::get().await;
 
-        let bucket: Bucket = context.root.into();
-
         for page in (start_page..end_page) {
-            let transactions = bucket
-                .get_transactions(Some(page))
+            let transactions = get_page(context.root, PageIndex::Contract, Some(page))
                 .await
                 .map_err(|(code, details)| GetTransactionsError::Unexpected(code, details))?;
 
-            for transaction in transactions.data {
+            let has_more = transactions.has_more();
+
+            for transaction in transactions.transactions {
                 yield transaction
             }
+
+            if !has_more {
+                break;
+            }
         }
 
     }
 }
 
+/// Streams the transactions of the given user in the given range of pages.
 pub async fn get_user_transactions(
     user: Principal,
     start_page: u32,
@@ -36,18 +50,190 @@ pub async fn get_user_transactions(
     try_stream! {
         let context = CapEnv::get().await;
 
-        let bucket: Bucket = context.root.into();
+        for page in (start_page..end_page) {
+            let transactions = get_page(context.root, PageIndex::User(user), Some(page))
+                .await
+                .map_err(|(code, details)| GetTransactionsError::Unexpected(code, details))?;
+
+            let has_more = transactions.has_more();
+
+            for transaction in transactions.transactions {
+                yield transaction
+            }
+
+            if !has_more {
+                break;
+            }
+        }
+
+    }
+}
+
+/// Streams the transactions of the given token in the given range of pages.
+pub async fn get_token_transactions(
+    token: impl Into<TokenId>,
+    start_page: u32,
+    end_page: u32,
+) -> impl Stream<Item = Result<Transaction, GetTransactionsError>> {
+    let index = PageIndex::Token(token.into());
+
+    try_stream! {
+        let context = CapEnv::get().await;
 
         for page in (start_page..end_page) {
-            let transactions = bucket
-                .get_user_transactions(user, Some(page))
+            let transactions = get_page(context.root, index.clone(), Some(page))
                 .await
                 .map_err(|(code, details)| GetTransactionsError::Unexpected(code, details))?;
 
-            for transaction in transactions.data {
+            let has_more = transactions.has_more();
+
+            for transaction in transactions.transactions {
                 yield transaction
             }
+
+            if !has_more {
+                break;
+            }
         }
 
     }
 }
+
+/// Returns the buckets in the order they should be visited when walking in the given direction.
+async fn buckets_in_order(
+    root: RootBucket,
+    direction: Direction,
+) -> Result<Vec<Bucket>, (RejectionCode, String)> {
+    let mut buckets = vec![root.into()];
+    buckets.extend(next_buckets(root).await?);
+
+    if direction == Direction::Forward {
+        buckets.reverse();
+    }
+
+    Ok(buckets)
+}
+
+/// Streams the transactions of this contract, starting at the transaction with the given id,
+/// or the oldest or newest transaction if `cursor` is `None`. The buckets after the root are
+/// walked transparently.
+pub async fn get_transactions_by_cursor(
+    cursor: Option<TransactionId>,
+    direction: Direction,
+) -> impl Stream<Item = Result<Transaction, GetTransactionsError>> {
+    try_stream! {
+        let context = CapEnv::get().await;
+
+        let buckets = buckets_in_order(context.root, direction)
+            .await
+            .map_err(|(code, details)| GetTransactionsError::Unexpected(code, details))?;
+
+        for bucket in buckets {
+            let mut cursor = cursor;
+
+            loop {
+                let response = bucket
+                    .get_transactions_cursor(cursor, direction, CURSOR_LIMIT, Some(CURSOR_MAX_BYTES))
+                    .await
+                    .map_err(|(code, details)| GetTransactionsError::Unexpected(code, details))?;
+
+                for transaction in response.data {
+                    yield transaction
+                }
+
+                match response.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+/// Streams the transactions of the given user, starting at the transaction with the given id,
+/// or the oldest or newest transaction if `cursor` is `None`. The buckets after the root are
+/// walked transparently.
+pub async fn get_user_transactions_by_cursor(
+    user: Principal,
+    cursor: Option<TransactionId>,
+    direction: Direction,
+) -> impl Stream<Item = Result<Transaction, GetTransactionsError>> {
+    try_stream! {
+        let context = CapEnv::get().await;
+
+        let buckets = buckets_in_order(context.root, direction)
+            .await
+            .map_err(|(code, details)| GetTransactionsError::Unexpected(code, details))?;
+
+        for bucket in buckets {
+            let mut cursor = cursor;
+
+            loop {
+                let response = bucket
+                    .get_user_transactions_cursor(
+                        user,
+                        cursor,
+                        direction,
+                        CURSOR_LIMIT,
+                        Some(CURSOR_MAX_BYTES),
+                    )
+                    .await
+                    .map_err(|(code, details)| GetTransactionsError::Unexpected(code, details))?;
+
+                for transaction in response.data {
+                    yield transaction
+                }
+
+                match response.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+/// Streams the transactions of the given token, starting at the transaction with the given id,
+/// or the oldest or newest transaction if `cursor` is `None`. The buckets after the root are
+/// walked transparently.
+pub async fn get_token_transactions_by_cursor(
+    token: impl Into<TokenId>,
+    cursor: Option<TransactionId>,
+    direction: Direction,
+) -> impl Stream<Item = Result<Transaction, GetTransactionsError>> {
+    let token = token.into();
+
+    try_stream! {
+        let context = CapEnv::get().await;
+
+        let buckets = buckets_in_order(context.root, direction)
+            .await
+            .map_err(|(code, details)| GetTransactionsError::Unexpected(code, details))?;
+
+        for bucket in buckets {
+            let mut cursor = cursor;
+
+            loop {
+                let response = bucket
+                    .get_token_transactions_cursor(
+                        token.clone(),
+                        cursor,
+                        direction,
+                        CURSOR_LIMIT,
+                        Some(CURSOR_MAX_BYTES),
+                    )
+                    .await
+                    .map_err(|(code, details)| GetTransactionsError::Unexpected(code, details))?;
+
+                for transaction in response.data {
+                    yield transaction
+                }
+
+                match response.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+        }
+    }
+}
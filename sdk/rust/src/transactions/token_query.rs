@@ -0,0 +1,18 @@
+use crate::chain::{get_page, PageIndex};
+use crate::{AsTransactionsPage, CapEnv, GetTransactionsError, GetTransactionsResponse, TokenId};
+
+/// Gets a page of the transactions of the given token, which gives the provenance of an NFT
+/// whichever kind of id its standard uses.
+///
+/// The pages are numbered across all of the buckets holding the history of the contract, like
+/// in [`get_transaction_page`](crate::get_transaction_page).
+pub async fn get_token_transactions_page(
+    token: impl Into<TokenId>,
+    page: impl AsTransactionsPage,
+) -> Result<GetTransactionsResponse, GetTransactionsError> {
+    let context = CapEnv::get().await;
+
+    get_page(context.root, PageIndex::Token(token.into()), page.page())
+        .await
+        .map_err(|(code, details)| GetTransactionsError::Unexpected(code, details))
+}
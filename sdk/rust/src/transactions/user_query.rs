@@ -1,13 +1,12 @@
-use cap_sdk_core::Bucket;
 use ic_kit::Principal;
 
+use crate::chain::{get_page, PageIndex};
 use crate::{AsTransactionsPage, CapEnv, GetTransactionsError, GetTransactionsResponse};
 
-/// Gets the transaction with the given id.
+/// Gets a page of the transactions of the given user.
 ///
-/// # Panics
-/// Panics if cap is using a multi-canister system, as it
-/// is currently unsupported. In this **alpha** release.
+/// The pages are numbered across all of the buckets holding the history of the contract, like
+/// in [`get_transaction_page`](crate::get_transaction_page).
 ///
 /// # Examples
 /// TODO
@@ -17,15 +16,7 @@ pub async fn get_user_transactions_page(
 ) -> Result<GetTransactionsResponse, GetTransactionsError> {
     let context = CapEnv::get().await;
 
-    let as_bucket: Bucket = context.root.into();
-
-    let transactions = as_bucket
-        .get_user_transactions(user, page.page())
+    get_page(context.root, PageIndex::User(user), page.page())
         .await
-        .map_err(|(code, details)| GetTransactionsError::Unexpected(code, details))?;
-
-    Ok(GetTransactionsResponse {
-        transactions: transactions.data,
-        next_page: transactions.page + 1,
-    })
+        .map_err(|(code, details)| GetTransactionsError::Unexpected(code, details))
 }
@@ -0,0 +1,71 @@
+use std::str::FromStr;
+
+use cap_sdk_core::{RootBucket, Router};
+use ic_kit::{Principal, RejectionCode};
+use thiserror::Error;
+
+use crate::CapEnv;
+
+/// An error returned when the writers of the root bucket could not be read or changed.
+#[derive(Error, Debug)]
+pub enum WritersError {
+    /// The root bucket rejected the call, this is the case when the writers are changed by
+    /// a canister other than the token contract.
+    #[error("the call was rejected")]
+    Unexpected(RejectionCode, String),
+}
+
+/// Uses the root bucket of another canister, instead of doing a handshake.
+///
+/// This is meant for dapps that are made of several canisters writing to one history: the
+/// canister which did the [`handshake`](crate::handshake) adds the other ones with
+/// [`add_writer`], and they join its root bucket with this method. The transactions are then
+/// inserted the same way as from the token contract.
+pub fn join_as_writer(root_bucket: Principal, router_override: Option<Principal>) {
+    let router = router_override
+        .unwrap_or_else(|| Principal::from_str("lj532-6iaaa-aaaah-qcc7a-cai").unwrap());
+
+    CapEnv::store(&CapEnv::create(
+        RootBucket(root_bucket),
+        Router::new(router),
+    ));
+}
+
+/// Allows the given canister to insert transactions into the root bucket of this canister,
+/// returns `false` if it already could.
+///
+/// Only the canister which did the handshake can add writers.
+pub async fn add_writer(writer: Principal) -> Result<bool, WritersError> {
+    let context = CapEnv::get().await;
+
+    context
+        .root
+        .add_writer(writer)
+        .await
+        .map_err(|(code, details)| WritersError::Unexpected(code, details))
+}
+
+/// Stops accepting transactions from the given canister, returns `false` if it was not a
+/// writer.
+///
+/// Only the canister which did the handshake can remove writers.
+pub async fn remove_writer(writer: Principal) -> Result<bool, WritersError> {
+    let context = CapEnv::get().await;
+
+    context
+        .root
+        .remove_writer(writer)
+        .await
+        .map_err(|(code, details)| WritersError::Unexpected(code, details))
+}
+
+/// Returns the canisters other than the token contract which can insert transactions.
+pub async fn list_writers() -> Result<Vec<Principal>, WritersError> {
+    let context = CapEnv::get().await;
+
+    context
+        .root
+        .list_writers()
+        .await
+        .map_err(|(code, details)| WritersError::Unexpected(code, details))
+}